@@ -0,0 +1,75 @@
+//! Immutable index queries on the array of structs and the struct of arrays posting layouts.
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion};
+use sparse_vectors_experiments::sparse_index::common::vector::SparseVector;
+use sparse_vectors_experiments::storage::{
+    ImmutableIndexConfig, PostingLayout, SparseVectorStorage,
+};
+use std::hint::black_box;
+
+const VECTOR_COUNT: usize = 50_000;
+const DIM_COUNT: u32 = 30_000;
+/// Dimension present in every vector, like the hot dimensions of SPLADE
+const HOT_DIM: u32 = 2839;
+
+/// Deterministic vectors of 30 to 90 dimensions, skewed towards the low dimensions
+fn random_storage() -> SparseVectorStorage {
+    let mut state = 0x2545_f491_4f6c_dd1d_u64;
+    let mut next = move || {
+        // xorshift64
+        state ^= state << 13;
+        state ^= state >> 7;
+        state ^= state << 17;
+        state
+    };
+    let mut storage = SparseVectorStorage::new();
+    for id in 0..VECTOR_COUNT {
+        let len = 30 + next() % 60;
+        let mut pairs = Vec::with_capacity(len as usize + 1);
+        for _ in 0..len {
+            let uniform = (next() % 10_000) as f32 / 10_000.0;
+            let dim = (uniform * uniform * DIM_COUNT as f32) as u32;
+            pairs.push((dim, (next() % 1000) as f32 / 1000.0));
+        }
+        pairs.push((HOT_DIM, (next() % 1000) as f32 / 1000.0));
+        storage.add(id, pairs.into_iter().collect());
+    }
+    storage
+}
+
+fn posting_layout(c: &mut Criterion) {
+    let mut storage = random_storage();
+    let queries = [
+        (
+            "easy",
+            SparseVector::new(vec![0, 1000, 2000, 3000], vec![1.0, 0.2, 0.9, 0.5]),
+        ),
+        (
+            "hot",
+            SparseVector::new(vec![0, 1000, HOT_DIM, 3000], vec![1.0, 0.2, 0.9, 0.5]),
+        ),
+    ];
+
+    let mut group = c.benchmark_group("posting_layout");
+    for posting_layout in [PostingLayout::ArrayOfStructs, PostingLayout::StructOfArrays] {
+        let config = ImmutableIndexConfig {
+            posting_layout,
+            ..Default::default()
+        };
+        storage.build_immutable_index_with_config(None, &config);
+        for (name, query) in &queries {
+            let id = BenchmarkId::new(format!("{:?}", posting_layout), name);
+            group.bench_with_input(id, query, |b, query| {
+                b.iter(|| {
+                    storage
+                        .query_immutable_index(100, black_box(query))
+                        .unwrap()
+                })
+            });
+        }
+    }
+    group.finish();
+}
+
+criterion_group!(benches, posting_layout);
+criterion_main!(benches);
@@ -0,0 +1,8 @@
+pub mod mmap_vector_storage;
+pub mod snapshot;
+pub mod sparse_index;
+pub mod storage;
+pub mod vector_store;
+pub mod workload;
+
+pub const SPLADE_DATA_PATH: &str = "./data/sparse-vectors.jsonl";
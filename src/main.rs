@@ -1,14 +1,13 @@
-mod sparse_index;
-mod storage;
-
-use crate::storage::SparseVectorStorage;
 use float_cmp::approx_eq;
-use sparse_index::common::vector::SparseVector;
+use sparse_vectors_experiments::sparse_index::common::summation::Summation;
+use sparse_vectors_experiments::sparse_index::common::vector::SparseVector;
+use sparse_vectors_experiments::storage::{
+    ImmutableIndexConfig, PostingLayout, SparseVectorStorage,
+};
+use sparse_vectors_experiments::SPLADE_DATA_PATH;
 use std::fs::File;
 use tempfile::Builder;
 
-pub const SPLADE_DATA_PATH: &str = "./data/sparse-vectors.jsonl";
-
 fn main() {
     // check file size
     let f = File::open(SPLADE_DATA_PATH).unwrap();
@@ -35,44 +34,97 @@ fn main() {
     storage.print_data_statistics();
     storage.print_mutable_index_statistics();
     storage.print_immutable_index_statistics();
+    storage.print_memory_report();
 
     // how many results to return
     let limit = 100;
 
     // easy because no hot key
     let easy_query = SparseVector::new(vec![0, 1000, 2000, 3000], vec![1.0, 0.2, 0.9, 0.5]);
-    query_and_validate(&storage, limit, easy_query.clone(), "easy");
+    query_and_validate(&storage, limit, &easy_query, "easy");
 
     // '2839' is vey hot (34461 entries)
     let hard_query = SparseVector::new(vec![0, 1000, 2839, 3000], vec![1.0, 0.2, 0.9, 0.5]);
-    query_and_validate(&storage, limit, hard_query.clone(), "hot");
+    query_and_validate(&storage, limit, &hard_query, "hot");
+
+    bench_dot_product();
+
+    // compare the posting layouts on a RAM immutable index
+    for posting_layout in [PostingLayout::ArrayOfStructs, PostingLayout::StructOfArrays] {
+        let config = ImmutableIndexConfig {
+            posting_layout,
+            ..Default::default()
+        };
+        storage.build_immutable_index_with_config(None, &config);
+        let label = format!("{:?}", posting_layout);
+        query_and_validate(&storage, limit, &easy_query, &format!("easy, {}", label));
+        query_and_validate(&storage, limit, &hard_query, &format!("hot, {}", label));
+    }
+}
+
+/// Dot products of vectors sharing 256 consecutive dimensions, the default path is
+/// vectorized with the `simd` feature
+fn bench_dot_product() {
+    let iterations = 100_000;
+    let a = SparseVector::new(
+        (0..256).collect(),
+        (0..256).map(|i| i as f32 / 256.0).collect(),
+    );
+    let b = SparseVector::new((0..256).collect(), vec![0.5; 256]);
+
+    let now = std::time::Instant::now();
+    let mut scalar = 0.0;
+    for _ in 0..iterations {
+        scalar += std::hint::black_box(&a)
+            .view()
+            .dot_product_scalar(&b.view(), Summation::Naive);
+    }
+    println!(
+        "\n{} scalar dot products in {} ms",
+        iterations,
+        now.elapsed().as_millis()
+    );
+
+    let now = std::time::Instant::now();
+    let mut default = 0.0;
+    for _ in 0..iterations {
+        default += std::hint::black_box(&a).dot_product(&b);
+    }
+    println!(
+        "{} dot products in {} ms (simd: {})",
+        iterations,
+        now.elapsed().as_millis(),
+        cfg!(feature = "simd")
+    );
+    assert!(approx_eq!(f32, scalar, default, epsilon = scalar * 1e-4));
 }
 
 fn query_and_validate(
     storage: &SparseVectorStorage,
     limit: usize,
-    query: SparseVector,
+    query: &SparseVector,
     label: &str,
 ) {
     println!("\nQuery with ({}) {:?} with limit {}", label, query, limit);
 
     let now = std::time::Instant::now();
-    let full_scan_results = storage.query_full_scan(limit, &query);
+    let full_scan_results = storage.query_full_scan(limit, query).unwrap();
     let elapsed = now.elapsed();
     println!("Search full scan storage in {} ms", elapsed.as_millis());
 
     let now = std::time::Instant::now();
-    let mutable_index_results = storage.query_mutable_index(limit, &query);
+    let mutable_index_results = storage.query_mutable_index(limit, query).unwrap();
     let elapsed = now.elapsed();
     println!("Search mutable index in {} ms", elapsed.as_millis());
 
     let now = std::time::Instant::now();
-    let immutable_index_results = storage.query_immutable_index(limit, query.clone());
+    let immutable_index_results = storage.query_immutable_index(limit, query).unwrap();
     let elapsed = now.elapsed();
     println!("Search immutable index in {} micros", elapsed.as_micros());
 
     // validate equivalence of results
-    // The ties are not broken in any way, so the order of results may differ in terms of vector ids
+    // Equal scores are ordered by ascending id everywhere, but the backends sum the
+    // contributions in different orders: near ties may swap ids, compare the scores only
     for (((i, full), mutable), immutable) in full_scan_results
         .iter()
         .enumerate()
@@ -0,0 +1,290 @@
+use crate::sparse_index::common::file_operations::TempFileGuard;
+use crate::sparse_index::common::mmap_ops::{
+    create_and_ensure_length, open_read_mmap, open_write_mmap, transmute_from_u8_to_slice,
+    transmute_to_u8, transmute_to_u8_slice,
+};
+use crate::sparse_index::common::types::{DimId, DimWeight, RecordId};
+use crate::sparse_index::common::vector::SparseVectorRef;
+use crate::sparse_index::immutable::inverted_index::inverted_index_mmap::transmute_from_u8;
+use crate::vector_store::VectorStore;
+use memmap2::Mmap;
+use std::mem::{align_of, size_of};
+use std::path::{Path, PathBuf};
+
+const VECTORS_FILE_NAME: &str = "vectors.data";
+const VECTORS_TMP_FILE_NAME: &str = "vectors.data.tmp";
+/// Start offset of the slots without a vector
+const MISSING_VECTOR: u64 = u64::MAX;
+
+#[derive(Default, Clone)]
+#[repr(C)]
+struct VectorsFileHeader {
+    /// Number of vector slots: max vector id + 1, or the number of vectors if sparse
+    slot_count: u64,
+    /// Total number of (index, weight) pairs
+    element_count: u64,
+    /// 1 if the sorted ids of the slots are stored after the header, else slots are by id
+    sparse: u64,
+}
+
+#[derive(Default, Clone)]
+#[repr(C)]
+struct VectorSlot {
+    /// Range of the vector in the indices and weights arrays
+    start: u64,
+    end: u64,
+}
+
+/// Read-only storage of raw vectors in a memory mapped file, CSR style:
+///
+/// header | slots (start, end) by vector id | all indices | all weights
+///
+/// A sparse store, see [`VectorStore::is_sparse`], gets a slot per vector instead of one per
+/// id, the slot ids are stored sorted before the slots and padded to the slot alignment:
+///
+/// header | ids | slots (start, end) | all indices | all weights
+///
+/// Vectors are read from disk on demand, e.g. for exact rescoring.
+pub struct MmapVectorStorage {
+    mmap: Mmap,
+    header: VectorsFileHeader,
+}
+
+impl MmapVectorStorage {
+    pub fn vectors_file_path(path: &Path) -> PathBuf {
+        path.join(VECTORS_FILE_NAME)
+    }
+
+    /// Write the vectors, indexed by id, into the directory `path`.
+    /// A slot is written for every id below `id_end` of a dense store, for every vector
+    /// of a sparse one.
+    pub fn save(path: &Path, vectors: &dyn VectorStore) -> std::io::Result<Self> {
+        let sparse = vectors.is_sparse();
+        let mut stored: Vec<_> = vectors.iter().collect();
+        stored.sort_unstable_by_key(|(id, _)| *id);
+        let element_count: usize = stored.iter().map(|(_, v)| v.indices.len()).sum();
+        let header = VectorsFileHeader {
+            slot_count: match sparse {
+                true => stored.len() as u64,
+                false => vectors.id_end() as u64,
+            },
+            element_count: element_count as u64,
+            sparse: sparse as u64,
+        };
+        let file_length = Self::indices_offset(&header)
+            + element_count * (size_of::<DimId>() + size_of::<DimWeight>());
+
+        let tmp_file_path = path.join(VECTORS_TMP_FILE_NAME);
+        let tmp_file_guard = TempFileGuard::new(tmp_file_path.clone());
+        create_and_ensure_length(&tmp_file_path, file_length)?;
+        let mut mmap = open_write_mmap(&tmp_file_path)?;
+
+        mmap[..size_of::<VectorsFileHeader>()].copy_from_slice(transmute_to_u8(&header));
+        let slots_offset = Self::slots_offset(&header);
+        let indices_offset = Self::indices_offset(&header);
+        let weights_offset = Self::weights_offset(&header);
+        let missing = VectorSlot {
+            start: MISSING_VECTOR,
+            end: MISSING_VECTOR,
+        };
+        for slot_index in 0..header.slot_count as usize {
+            let start = slots_offset + slot_index * size_of::<VectorSlot>();
+            mmap[start..start + size_of::<VectorSlot>()].copy_from_slice(transmute_to_u8(&missing));
+        }
+        let mut element_offset = 0;
+        for (position, (id, vector)) in stored.iter().enumerate() {
+            if sparse {
+                let start = size_of::<VectorsFileHeader>() + position * size_of::<RecordId>();
+                mmap[start..start + size_of::<RecordId>()].copy_from_slice(transmute_to_u8(id));
+            }
+            let len = vector.indices.len();
+            let indices_bytes = transmute_to_u8_slice(&vector.indices);
+            let start = indices_offset + element_offset * size_of::<DimId>();
+            mmap[start..start + indices_bytes.len()].copy_from_slice(indices_bytes);
+            let weights_bytes = transmute_to_u8_slice(&vector.weights);
+            let start = weights_offset + element_offset * size_of::<DimWeight>();
+            mmap[start..start + weights_bytes.len()].copy_from_slice(weights_bytes);
+            let slot = VectorSlot {
+                start: element_offset as u64,
+                end: (element_offset + len) as u64,
+            };
+            element_offset += len;
+
+            let slot_index = if sparse { position } else { *id as usize };
+            let start = slots_offset + slot_index * size_of::<VectorSlot>();
+            mmap[start..start + size_of::<VectorSlot>()].copy_from_slice(transmute_to_u8(&slot));
+        }
+        mmap.flush()?;
+        drop(mmap);
+
+        let file_path = Self::vectors_file_path(path);
+        tmp_file_guard.persist(&file_path)?;
+        Self::open(path)
+    }
+
+    pub fn open(path: &Path) -> std::io::Result<Self> {
+        let mmap = open_read_mmap(&Self::vectors_file_path(path))?;
+        if mmap.len() < size_of::<VectorsFileHeader>() {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                "Vectors file is too short",
+            ));
+        }
+        let header =
+            transmute_from_u8::<VectorsFileHeader>(&mmap[..size_of::<VectorsFileHeader>()]).clone();
+        // ids, slots and arrays must fit in the file, checked against overflow of corrupted
+        // counts
+        let slot_count = header.slot_count as usize;
+        let ids_bytes = match header.sparse {
+            0 => Some(0),
+            _ => slot_count
+                .checked_mul(size_of::<RecordId>())
+                .and_then(|bytes| bytes.checked_next_multiple_of(align_of::<VectorSlot>())),
+        };
+        let file_length = ids_bytes.and_then(|ids_bytes| {
+            let slots_bytes = slot_count.checked_mul(size_of::<VectorSlot>())?;
+            let elements_bytes = (header.element_count as usize)
+                .checked_mul(size_of::<DimId>() + size_of::<DimWeight>())?;
+            size_of::<VectorsFileHeader>()
+                .checked_add(ids_bytes)?
+                .checked_add(slots_bytes)?
+                .checked_add(elements_bytes)
+        });
+        if file_length.is_none_or(|file_length| mmap.len() < file_length) {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                "Vectors file is shorter than its header declares",
+            ));
+        }
+        Ok(MmapVectorStorage { mmap, header })
+    }
+
+    /// One past the highest vector id, missing vectors below it included
+    pub fn len(&self) -> usize {
+        match self.ids() {
+            Some(ids) => ids.last().map_or(0, |id| *id as usize + 1),
+            None => self.header.slot_count as usize,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// `None` for missing vectors and for slots pointing out of the arrays
+    pub fn get(&self, id: RecordId) -> Option<SparseVectorRef<'_>> {
+        let slot_index = match self.ids() {
+            Some(ids) => ids.binary_search(&id).ok()?,
+            None if (id as usize) < self.header.slot_count as usize => id as usize,
+            None => return None,
+        };
+        let start = Self::slots_offset(&self.header) + slot_index * size_of::<VectorSlot>();
+        let slot =
+            transmute_from_u8::<VectorSlot>(&self.mmap[start..start + size_of::<VectorSlot>()]);
+        if slot.start == MISSING_VECTOR
+            || slot.start > slot.end
+            || slot.end > self.header.element_count
+        {
+            return None;
+        }
+        let (start, end) = (slot.start as usize, slot.end as usize);
+
+        let indices_offset = Self::indices_offset(&self.header);
+        let indices = &self.mmap[indices_offset + start * size_of::<DimId>()
+            ..indices_offset + end * size_of::<DimId>()];
+        let weights_offset = Self::weights_offset(&self.header);
+        let weights = &self.mmap[weights_offset + start * size_of::<DimWeight>()
+            ..weights_offset + end * size_of::<DimWeight>()];
+        Some(SparseVectorRef {
+            indices: transmute_from_u8_to_slice(indices),
+            weights: transmute_from_u8_to_slice(weights),
+        })
+    }
+
+    /// Sorted ids of the slots of a sparse file
+    fn ids(&self) -> Option<&[RecordId]> {
+        let start = size_of::<VectorsFileHeader>();
+        let end = start + self.header.slot_count as usize * size_of::<RecordId>();
+        (self.header.sparse != 0).then(|| transmute_from_u8_to_slice(&self.mmap[start..end]))
+    }
+
+    fn slots_offset(header: &VectorsFileHeader) -> usize {
+        let ids_bytes = match header.sparse {
+            0 => 0,
+            _ => (header.slot_count as usize * size_of::<RecordId>())
+                .next_multiple_of(align_of::<VectorSlot>()),
+        };
+        size_of::<VectorsFileHeader>() + ids_bytes
+    }
+
+    fn indices_offset(header: &VectorsFileHeader) -> usize {
+        Self::slots_offset(header) + header.slot_count as usize * size_of::<VectorSlot>()
+    }
+
+    fn weights_offset(header: &VectorsFileHeader) -> usize {
+        Self::indices_offset(header) + header.element_count as usize * size_of::<DimId>()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sparse_index::common::vector::SparseVector;
+    use crate::storage::SparseVectorStorage;
+    use tempfile::Builder;
+
+    #[test]
+    fn test_mmap_vectors_round_trip() {
+        let mut storage = SparseVectorStorage::new();
+        storage.add(0, SparseVector::new(vec![3, 1, 7], vec![0.5, 1.0, 2.0]));
+        storage.add(1, SparseVector::new(vec![], vec![]));
+        storage.add(3, SparseVector::new(vec![7, 2], vec![4.0, 0.25]));
+
+        let tmp_dir_path = Builder::new().prefix("vectors_dir").tempdir().unwrap();
+        storage.save_vectors_mmap(tmp_dir_path.path()).unwrap();
+        let mmap_storage = MmapVectorStorage::open(tmp_dir_path.path()).unwrap();
+
+        assert_eq!(mmap_storage.len(), 4);
+        assert!(mmap_storage.get(4).is_none());
+        for id in 0..4 {
+            assert_eq!(
+                mmap_storage.get(id).map(|v| v.to_vector()),
+                storage.get(id).cloned(),
+                "id {}",
+                id
+            );
+        }
+
+        let query = SparseVector::new(vec![7, 1], vec![1.0, 3.0]);
+        for id in [0, 1, 3] {
+            let vector = mmap_storage.get(id).unwrap();
+            let expected = query.dot_product(storage.get(id).unwrap());
+            assert_eq!(query.view().dot_product(&vector), expected);
+        }
+    }
+
+    #[test]
+    fn test_open_rejects_truncated_file() {
+        let mut storage = SparseVectorStorage::new();
+        storage.add(0, SparseVector::new(vec![3, 1, 7], vec![0.5, 1.0, 2.0]));
+        storage.add(2, SparseVector::new(vec![7, 2], vec![4.0, 0.25]));
+
+        let tmp_dir_path = Builder::new().prefix("vectors_dir").tempdir().unwrap();
+        storage.save_vectors_mmap(tmp_dir_path.path()).unwrap();
+        let file_path = MmapVectorStorage::vectors_file_path(tmp_dir_path.path());
+        let file = std::fs::OpenOptions::new()
+            .write(true)
+            .open(&file_path)
+            .unwrap();
+        // drop the last weight
+        let length = file.metadata().unwrap().len();
+        file.set_len(length - size_of::<DimWeight>() as u64)
+            .unwrap();
+        drop(file);
+
+        let error = MmapVectorStorage::open(tmp_dir_path.path())
+            .err()
+            .expect("truncated file must be rejected");
+        assert_eq!(error.kind(), std::io::ErrorKind::InvalidData);
+    }
+}
@@ -0,0 +1,94 @@
+use crate::sparse_index::common::file_operations::{atomic_save_json, read_json};
+use crate::sparse_index::common::scored_candidate::ScoredCandidate;
+use crate::sparse_index::common::vector::SparseVector;
+use serde::{Deserialize, Serialize};
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// Regression snapshot of a search: the query and optionally its expected top results.
+/// Snapshots without expected results only check equivalence between the search paths.
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub struct SearchSnapshot {
+    pub top: usize,
+    pub query: SparseVector,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub expected: Option<Vec<ScoredCandidate>>,
+}
+
+impl SearchSnapshot {
+    pub fn new(top: usize, query: SparseVector, results: &[ScoredCandidate]) -> SearchSnapshot {
+        let expected = results
+            .iter()
+            .map(|candidate| ScoredCandidate {
+                score: candidate.score,
+                vector_id: candidate.vector_id,
+            })
+            .collect();
+        SearchSnapshot {
+            top,
+            query,
+            expected: Some(expected),
+        }
+    }
+
+    pub fn save(&self, path: &Path) -> io::Result<()> {
+        atomic_save_json(path, self)
+    }
+
+    pub fn load(path: &Path) -> io::Result<SearchSnapshot> {
+        read_json(path)
+    }
+
+    /// Load all `.json` snapshots of a directory, ordered by file name
+    pub fn load_dir(dir: &Path) -> io::Result<Vec<(PathBuf, SearchSnapshot)>> {
+        let mut paths = Vec::new();
+        for entry in std::fs::read_dir(dir)? {
+            let path = entry?.path();
+            if path.extension().is_some_and(|ext| ext == "json") {
+                paths.push(path);
+            }
+        }
+        paths.sort();
+
+        paths
+            .into_iter()
+            .map(|path| {
+                let snapshot = Self::load(&path)?;
+                Ok((path, snapshot))
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::Builder;
+
+    #[test]
+    fn snapshot_round_trip() {
+        let tmp_dir_path = Builder::new()
+            .prefix("test_snapshot_dir")
+            .tempdir()
+            .unwrap();
+        let query = SparseVector::new(vec![1, 5], vec![0.5, 2.0]);
+        let results = vec![
+            ScoredCandidate {
+                score: 3.0,
+                vector_id: 7,
+            },
+            ScoredCandidate {
+                score: 1.5,
+                vector_id: 2,
+            },
+        ];
+        let snapshot = SearchSnapshot::new(2, query, &results);
+        snapshot
+            .save(&tmp_dir_path.path().join("snapshot.json"))
+            .unwrap();
+
+        let loaded = SearchSnapshot::load_dir(tmp_dir_path.path()).unwrap();
+        assert_eq!(loaded.len(), 1);
+        assert_eq!(loaded[0].1, snapshot);
+    }
+}
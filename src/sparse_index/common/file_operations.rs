@@ -4,7 +4,7 @@ use serde::Serialize;
 use std::fs::File;
 use std::io;
 use std::io::{BufReader, BufWriter};
-use std::path::Path;
+use std::path::{Path, PathBuf};
 
 pub fn atomic_save_json<T: Serialize>(path: &Path, object: &T) -> io::Result<()> {
     let af = AtomicFile::new(path, OverwriteBehavior::AllowOverwrite);
@@ -18,3 +18,33 @@ pub fn atomic_save_json<T: Serialize>(path: &Path, object: &T) -> io::Result<()>
 pub fn read_json<T: DeserializeOwned>(path: &Path) -> io::Result<T> {
     Ok(serde_json::from_reader(BufReader::new(File::open(path)?))?)
 }
+
+/// Removes the guarded file on drop unless it was persisted.
+/// Prevents partially written files from being left behind on errors.
+pub struct TempFileGuard {
+    path: Option<PathBuf>,
+}
+
+impl TempFileGuard {
+    pub fn new(path: PathBuf) -> TempFileGuard {
+        TempFileGuard { path: Some(path) }
+    }
+
+    /// Move the guarded file to its final location, it is no longer removed on drop
+    pub fn persist(mut self, final_path: &Path) -> io::Result<()> {
+        if let Some(path) = &self.path {
+            std::fs::rename(path, final_path)?;
+        }
+        self.path = None;
+        Ok(())
+    }
+}
+
+impl Drop for TempFileGuard {
+    fn drop(&mut self) {
+        if let Some(path) = self.path.take() {
+            // best effort cleanup, the file may not have been created
+            let _ = std::fs::remove_file(path);
+        }
+    }
+}
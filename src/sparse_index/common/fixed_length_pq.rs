@@ -47,6 +47,18 @@ impl<T: Ord> FixedLengthPriorityQueue<T> {
             .collect()
     }
 
+    /// Iterates over the elements in arbitrary order without consuming the queue
+    pub fn iter(&self) -> impl Iterator<Item = &T> {
+        self.heap.iter().map(|x| &x.0)
+    }
+
+    /// Elements sorted from largest to smallest, in the order of `into_vec`
+    pub fn iter_sorted(&self) -> impl Iterator<Item = &T> {
+        let mut elements: Vec<_> = self.iter().collect();
+        elements.sort_by(|a, b| b.cmp(a));
+        elements.into_iter()
+    }
+
     pub fn top(&self) -> Option<&T> {
         self.heap.peek().map(|x| &x.0)
     }
@@ -55,4 +67,67 @@ impl<T: Ord> FixedLengthPriorityQueue<T> {
     pub fn len(&self) -> usize {
         self.heap.len()
     }
+
+    pub fn is_empty(&self) -> bool {
+        self.heap.is_empty()
+    }
+
+    /// Returns the maximum number of elements the queue keeps
+    pub fn capacity(&self) -> usize {
+        self.length.get()
+    }
+
+    /// Removes all elements, the heap allocation is kept for the next use
+    pub fn clear(&mut self) {
+        self.heap.clear();
+    }
+}
+
+impl<T: Ord> Extend<T> for FixedLengthPriorityQueue<T> {
+    fn extend<I: IntoIterator<Item = T>>(&mut self, iter: I) {
+        for value in iter {
+            self.push(value);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_iter_without_consuming() {
+        let mut queue = FixedLengthPriorityQueue::new(3);
+        for value in [5, 1, 8, 3, 9, 2] {
+            queue.push(value);
+        }
+
+        let mut values: Vec<_> = queue.iter().copied().collect();
+        values.sort_unstable();
+        assert_eq!(values, vec![5, 8, 9]);
+        assert_eq!(
+            queue.iter_sorted().copied().collect::<Vec<_>>(),
+            vec![9, 8, 5]
+        );
+
+        // the queue is still usable
+        queue.push(7);
+        assert_eq!(queue.into_vec(), vec![9, 8, 7]);
+    }
+
+    #[test]
+    fn test_clear_and_extend() {
+        let mut queue = FixedLengthPriorityQueue::new(2);
+        assert!(queue.is_empty());
+        queue.extend([4, 1, 6]);
+        assert!(!queue.is_empty());
+        assert_eq!(queue.iter_sorted().copied().collect::<Vec<_>>(), vec![6, 4]);
+
+        // reused for another batch
+        queue.clear();
+        assert!(queue.is_empty());
+        assert_eq!(queue.capacity(), 2);
+        queue.extend([3, 2]);
+        assert_eq!(queue.into_vec(), vec![3, 2]);
+    }
 }
@@ -0,0 +1,27 @@
+/// Counters emitted along the search path
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum SearchCounter {
+    /// Searches executed
+    Queries,
+    /// Candidates for which a score was computed
+    CandidatesScored,
+    /// Posting lists skipped forward by the pruning
+    PruningSkips,
+    /// Non empty posting lists iterated by a search
+    PostingListsTouched,
+}
+
+/// Receives the search counters, implemented by the caller to forward them
+/// to a metrics system such as Prometheus or statsd.
+pub trait MetricsSink {
+    fn increment(&self, counter: SearchCounter, value: u64);
+}
+
+/// Drops all counters, used when no sink is configured
+#[derive(Debug, Default, Clone, Copy)]
+pub struct NoopMetricsSink;
+
+impl MetricsSink for NoopMetricsSink {
+    #[inline]
+    fn increment(&self, _counter: SearchCounter, _value: u64) {}
+}
@@ -98,6 +98,8 @@ pub fn transmute_to_u8<T>(v: &T) -> &[u8] {
 
 pub fn transmute_from_u8_to_slice<T>(data: &[u8]) -> &[T] {
     debug_assert_eq!(data.len() % size_of::<T>(), 0);
+    // a misaligned slice is undefined behavior and can read shifted fields
+    debug_assert_eq!(data.as_ptr() as usize % mem::align_of::<T>(), 0);
     let len = data.len() / size_of::<T>();
     let ptr = data.as_ptr() as *const T;
     unsafe { std::slice::from_raw_parts(ptr, len) }
@@ -113,3 +115,7 @@ pub fn transmute_from_u8_to_mut_slice<T>(data: &mut [u8]) -> &mut [T] {
 pub fn transmute_to_u8_slice<T>(v: &[T]) -> &[u8] {
     unsafe { std::slice::from_raw_parts(v.as_ptr() as *const u8, mem::size_of_val(v)) }
 }
+
+pub fn transmute_to_u8_mut_slice<T>(v: &mut [T]) -> &mut [u8] {
+    unsafe { std::slice::from_raw_parts_mut(v.as_mut_ptr() as *mut u8, mem::size_of_val(v)) }
+}
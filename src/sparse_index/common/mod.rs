@@ -1,7 +1,11 @@
 pub mod file_operations;
 pub mod fixed_length_pq;
 pub mod madvise;
+pub mod metrics;
 pub mod mmap_ops;
+pub mod query_interner;
 pub mod scored_candidate;
+pub mod summation;
 pub mod types;
 pub mod vector;
+pub mod weight;
@@ -0,0 +1,33 @@
+use crate::sparse_index::common::types::DimId;
+use std::collections::HashMap;
+
+/// Maps the string keys of SPLADE queries to the dimensions of the index vocabulary,
+/// built once so incoming queries are interned without parsing numbers.
+#[derive(Debug, Default, Clone)]
+pub struct QueryInterner {
+    dims: HashMap<String, DimId>,
+}
+
+impl QueryInterner {
+    /// Build from `(dim_id, df)` pairs, e.g. `SparseVectorStorage::vocabulary`
+    pub fn from_vocabulary(vocabulary: &[(DimId, usize)]) -> QueryInterner {
+        let dims = vocabulary
+            .iter()
+            .map(|(dim_id, _)| (dim_id.to_string(), *dim_id))
+            .collect();
+        QueryInterner { dims }
+    }
+
+    /// Dimension of a term, None if the term is not in the vocabulary
+    pub fn get(&self, term: &str) -> Option<DimId> {
+        self.dims.get(term).copied()
+    }
+
+    pub fn len(&self) -> usize {
+        self.dims.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.dims.is_empty()
+    }
+}
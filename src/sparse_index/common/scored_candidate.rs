@@ -1,18 +1,24 @@
 use crate::sparse_index::common::types::{DimWeight, RecordId};
 use ordered_float::OrderedFloat;
+use serde::{Deserialize, Serialize};
 use std::cmp::Ordering;
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
 pub struct ScoredCandidate {
     pub score: DimWeight,
+    #[serde(rename = "id")]
     pub vector_id: RecordId,
 }
 
 impl Eq for ScoredCandidate {}
 
+/// Ordered by score, on equal scores the lower id ranks higher so that the
+/// content of a bounded queue does not depend on the insertion order
 impl Ord for ScoredCandidate {
     fn cmp(&self, other: &Self) -> Ordering {
-        OrderedFloat(self.score).cmp(&OrderedFloat(other.score))
+        OrderedFloat(self.score)
+            .cmp(&OrderedFloat(other.score))
+            .then_with(|| other.vector_id.cmp(&self.vector_id))
     }
 }
 
@@ -21,3 +27,77 @@ impl PartialOrd for ScoredCandidate {
         Some(self.cmp(other))
     }
 }
+
+/// How candidates with equal scores are ordered across the search backends
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum TieBreak {
+    /// Each backend sums the score contributions in the order that suits it best,
+    /// nearly equal scores may then come out in a different order per backend
+    #[default]
+    Unspecified,
+    /// Contributions are summed in query dimension order by every backend, equal
+    /// vectors get bit-identical scores and ties are ordered by ascending id
+    ById,
+}
+
+/// Serialize results as `[{"score":..,"id":..}, ...]`
+pub fn results_to_json(results: &[ScoredCandidate]) -> String {
+    serde_json::to_string(results).expect("Results are always serializable")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sparse_index::common::fixed_length_pq::FixedLengthPriorityQueue;
+
+    #[test]
+    fn equal_scores_ordered_by_ascending_id() {
+        let candidates = [(1.0, 4), (2.0, 9), (1.0, 2), (1.0, 7), (3.0, 5), (1.0, 3)];
+        let expected = vec![(3.0, 5), (2.0, 9), (1.0, 2), (1.0, 3)];
+        // same top whatever the push order
+        for reversed in [false, true] {
+            let mut queue = FixedLengthPriorityQueue::new(4);
+            let mut push = |(score, vector_id)| {
+                queue.push(ScoredCandidate { score, vector_id });
+            };
+            match reversed {
+                false => candidates.into_iter().for_each(&mut push),
+                true => candidates.into_iter().rev().for_each(&mut push),
+            }
+            let top: Vec<_> = queue
+                .into_vec()
+                .into_iter()
+                .map(|candidate| (candidate.score, candidate.vector_id))
+                .collect();
+            assert_eq!(top, expected);
+        }
+
+        // same order as a full sort
+        let mut sorted: Vec<_> = candidates
+            .iter()
+            .map(|&(score, vector_id)| ScoredCandidate { score, vector_id })
+            .collect();
+        sorted.sort_by(|a, b| b.cmp(a));
+        let ids: Vec<_> = sorted.iter().map(|candidate| candidate.vector_id).collect();
+        assert_eq!(ids, vec![5, 9, 2, 3, 4, 7]);
+    }
+
+    #[test]
+    fn results_json_round_trip() {
+        let results = vec![
+            ScoredCandidate {
+                score: 2.5,
+                vector_id: 42,
+            },
+            ScoredCandidate {
+                score: 0.125,
+                vector_id: 7,
+            },
+        ];
+        let json = results_to_json(&results);
+        assert_eq!(json, r#"[{"score":2.5,"id":42},{"score":0.125,"id":7}]"#);
+
+        let parsed: Vec<ScoredCandidate> = serde_json::from_str(&json).unwrap();
+        assert_eq!(parsed, results);
+    }
+}
@@ -0,0 +1,47 @@
+use crate::sparse_index::common::types::DimWeight;
+
+/// Strategy used to accumulate score contributions.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum Summation {
+    /// Plain f32 additions, fastest
+    #[default]
+    Naive,
+    /// Kahan compensated summation, reduces rounding error on long chains of additions
+    Kahan,
+}
+
+/// Accumulates f32 scores according to a [`Summation`] strategy.
+#[derive(Debug, Clone, Copy)]
+pub struct ScoreAccumulator {
+    summation: Summation,
+    sum: DimWeight,
+    compensation: DimWeight,
+}
+
+impl ScoreAccumulator {
+    pub fn new(summation: Summation) -> ScoreAccumulator {
+        ScoreAccumulator {
+            summation,
+            sum: 0.0,
+            compensation: 0.0,
+        }
+    }
+
+    #[inline]
+    pub fn add(&mut self, value: DimWeight) {
+        match self.summation {
+            Summation::Naive => self.sum += value,
+            Summation::Kahan => {
+                let y = value - self.compensation;
+                let t = self.sum + y;
+                // recover the low-order bits lost when adding y to sum
+                self.compensation = (t - self.sum) - y;
+                self.sum = t;
+            }
+        }
+    }
+
+    pub fn sum(&self) -> DimWeight {
+        self.sum
+    }
+}
@@ -1,18 +1,335 @@
+use crate::sparse_index::common::query_interner::QueryInterner;
+use crate::sparse_index::common::summation::{ScoreAccumulator, Summation};
 use crate::sparse_index::common::types::{DimId, DimWeight};
+use crate::sparse_index::common::weight::Weight;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::fmt;
 
-#[derive(Debug, PartialEq, Clone)]
-pub struct SparseVector {
+/// Similarity used to score a vector against a query, higher is better
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum SimilarityMetric {
+    #[default]
+    Dot,
+    /// Negated squared euclidean distance over the union of dimensions
+    NegL2,
+}
+
+/// Sparse vector with f32 weights by default, the search stack works on f32 vectors.
+/// Other [`Weight`] types are converted with [`SparseVector::from_f32`].
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
+pub struct SparseVector<W = DimWeight> {
     pub indices: Vec<DimId>,
-    pub weights: Vec<DimWeight>,
+    pub weights: Vec<W>,
+}
+
+/// Violation of the invariants of a [`SparseVector`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SparseVectorError {
+    /// `indices` and `weights` have different lengths
+    LengthMismatch { indices: usize, weights: usize },
+    /// The index at `position` is not greater than the previous one
+    NotStrictlyIncreasing { position: usize },
+    /// The weight at `position` is NaN or infinite
+    NonFiniteWeight { position: usize },
+}
+
+impl fmt::Display for SparseVectorError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SparseVectorError::LengthMismatch { indices, weights } => {
+                write!(f, "{} indices but {} weights", indices, weights)
+            }
+            SparseVectorError::NotStrictlyIncreasing { position } => {
+                write!(
+                    f,
+                    "Index at position {} is not strictly increasing",
+                    position
+                )
+            }
+            SparseVectorError::NonFiniteWeight { position } => {
+                write!(f, "Weight at position {} is not finite", position)
+            }
+        }
+    }
+}
+
+impl std::error::Error for SparseVectorError {}
+
+impl<W: Weight> SparseVector<W> {
+    /// Convert the weights of an f32 vector, see [`Weight::from_f32`]
+    pub fn from_f32(vector: &SparseVector) -> SparseVector<W> {
+        SparseVector {
+            indices: vector.indices.clone(),
+            weights: vector.weights.iter().map(|w| W::from_f32(*w)).collect(),
+        }
+    }
+
+    pub fn to_f32(&self) -> SparseVector {
+        SparseVector {
+            indices: self.indices.clone(),
+            weights: self.weights.iter().map(|w| w.to_f32()).collect(),
+        }
+    }
+
+    /// Dot product of the f32 conversions of both vectors, the weights are not multiplied
+    /// in `W` which could overflow for integer weights
+    pub fn dot_product_f32(&self, other: &SparseVector<W>) -> f32 {
+        self.to_f32().dot_product(&other.to_f32())
+    }
 }
 
 impl SparseVector {
+    /// Unchecked: the search stack expects sorted unique indices with one weight each,
+    /// see [`Self::try_new`] for untrusted input
     pub fn new(indices: Vec<DimId>, weights: Vec<DimWeight>) -> SparseVector {
         SparseVector { indices, weights }
     }
 
-    // Can't assume the vectors are aligned
+    /// Checked constructor: same number of indices and weights, strictly increasing
+    /// indices and finite weights
+    pub fn try_new(
+        indices: Vec<DimId>,
+        weights: Vec<DimWeight>,
+    ) -> Result<SparseVector, SparseVectorError> {
+        if indices.len() != weights.len() {
+            return Err(SparseVectorError::LengthMismatch {
+                indices: indices.len(),
+                weights: weights.len(),
+            });
+        }
+        if let Some(position) = indices.windows(2).position(|pair| pair[0] >= pair[1]) {
+            return Err(SparseVectorError::NotStrictlyIncreasing {
+                position: position + 1,
+            });
+        }
+        if let Some(position) = weights.iter().position(|weight| !weight.is_finite()) {
+            return Err(SparseVectorError::NonFiniteWeight { position });
+        }
+        Ok(SparseVector { indices, weights })
+    }
+
+    /// Sort the dimensions by index, each weight moving with its index
+    pub fn sorted_by_indices(&mut self) {
+        if self.indices.is_sorted() {
+            return;
+        }
+        let mut pairs: Vec<_> = self
+            .indices
+            .iter()
+            .copied()
+            .zip(self.weights.iter().copied())
+            .collect();
+        pairs.sort_by_key(|(index, _)| *index);
+        (self.indices, self.weights) = pairs.into_iter().unzip();
+    }
+
+    /// Query from string keyed terms, terms unknown to the interner are dropped.
+    /// The result is canonical, the weights of a repeated term are summed.
+    pub fn from_interned(terms: &[(&str, f32)], interner: &QueryInterner) -> SparseVector {
+        terms
+            .iter()
+            .filter_map(|(term, weight)| Some((interner.get(term)?, *weight)))
+            .collect()
+    }
+
+    /// Replace NaN and infinite weights by 0, keeping their dimensions
+    pub fn zero_non_finite(&mut self) {
+        for weight in self.weights.iter_mut() {
+            if !weight.is_finite() {
+                *weight = 0.0;
+            }
+        }
+    }
+
+    /// Flush subnormal weights to zero and drop the resulting zero entries.
+    /// Subnormal floats can be very slow to multiply on some hardware.
+    pub fn flush_subnormals(&mut self) {
+        if !self.weights.iter().any(|w| w.is_subnormal()) {
+            return;
+        }
+        let (indices, weights) = self
+            .indices
+            .iter()
+            .zip(&self.weights)
+            .filter(|(_, weight)| !weight.is_subnormal())
+            .unzip();
+        self.indices = indices;
+        self.weights = weights;
+    }
+
+    /// Expand each dimension to its related dimensions, weighted by `weight * decay`.
+    /// Weights landing on the same dimension are summed and the result is canonical.
+    pub fn expand(&mut self, expansions: &HashMap<DimId, Vec<(DimId, f32)>>) {
+        let expanded: Vec<_> = self
+            .indices
+            .iter()
+            .zip(&self.weights)
+            .flat_map(|(dim, weight)| {
+                expansions
+                    .get(dim)
+                    .into_iter()
+                    .flatten()
+                    .map(move |(related, decay)| (*related, weight * decay))
+            })
+            .collect();
+
+        let original = self
+            .indices
+            .iter()
+            .copied()
+            .zip(self.weights.iter().copied());
+        *self = original.chain(expanded).collect();
+    }
+
+    /// `alpha * self + (1 - alpha) * other` over the union of dimensions, canonical.
+    /// A dimension missing from one of the vectors counts as 0 in it.
+    pub fn blend(&self, other: &SparseVector, alpha: f32) -> SparseVector {
+        self.merge_weighted(other, alpha, 1.0 - alpha)
+    }
+
+    /// `self_weight * self + other_weight * other` over the union of dimensions, canonical.
+    /// A dimension missing from one of the vectors counts as 0 in it.
+    pub fn merge_weighted(
+        &self,
+        other: &SparseVector,
+        self_weight: f32,
+        other_weight: f32,
+    ) -> SparseVector {
+        let this = self
+            .indices
+            .iter()
+            .zip(&self.weights)
+            .map(|(dim, weight)| (*dim, self_weight * weight));
+        let other = other
+            .indices
+            .iter()
+            .zip(&other.weights)
+            .map(|(dim, weight)| (*dim, other_weight * weight));
+        this.chain(other).collect()
+    }
+
+    /// Can't assume the vectors are aligned.
+    /// Non-finite weights of the shared dimensions follow IEEE 754: the result is NaN if a
+    /// weight is NaN, an infinite weight meets a 0 or infinities of opposite signs add up,
+    /// infinite otherwise. See [`Self::zero_non_finite`] and [`Self::try_new`] to avoid them.
     pub fn dot_product(&self, other: &SparseVector) -> f32 {
+        self.dot_product_with(other, Summation::Naive)
+    }
+
+    /// Dot product accumulated with the given summation strategy
+    pub fn dot_product_with(&self, other: &SparseVector, summation: Summation) -> f32 {
+        self.view().dot_product_with(&other.view(), summation)
+    }
+
+    /// Dot product ignoring the dimensions in `blacklist`, accumulated with the given
+    /// summation strategy
+    pub fn dot_product_excluding(
+        &self,
+        other: &SparseVector,
+        blacklist: Option<&HashSet<DimId>>,
+        summation: Summation,
+    ) -> f32 {
+        match blacklist {
+            Some(blacklist) => self
+                .without_dims(blacklist)
+                .dot_product_with(other, summation),
+            None => self.dot_product_with(other, summation),
+        }
+    }
+
+    /// Copy without the dimensions in `dims`, the other dimensions keep their order
+    pub fn without_dims(&self, dims: &HashSet<DimId>) -> SparseVector {
+        let (indices, weights) = self
+            .indices
+            .iter()
+            .zip(&self.weights)
+            .filter(|(dim, _)| !dims.contains(dim))
+            .unzip();
+        SparseVector { indices, weights }
+    }
+
+    /// Borrowed view of the vector
+    pub fn view(&self) -> SparseVectorRef<'_> {
+        SparseVectorRef {
+            indices: &self.indices,
+            weights: &self.weights,
+        }
+    }
+
+    /// Euclidean norm of the weights
+    pub fn l2_norm(&self) -> f32 {
+        self.weights.iter().map(|w| w * w).sum::<f32>().sqrt()
+    }
+
+    /// Copy scaled to a unit euclidean norm, the dot product of normalized vectors is
+    /// their cosine similarity. A zero vector stays zero.
+    pub fn normalized(&self) -> SparseVector {
+        let norm = self.l2_norm();
+        if norm == 0.0 {
+            return self.clone();
+        }
+        SparseVector {
+            indices: self.indices.clone(),
+            weights: self.weights.iter().map(|w| w / norm).collect(),
+        }
+    }
+
+    /// Negated squared euclidean distance, dimensions missing from one vector count as 0
+    pub fn neg_squared_l2_distance(&self, other: &SparseVector) -> f32 {
+        let mut distance = 0.0;
+        for (dim, weight) in self.indices.iter().zip(&self.weights) {
+            let other_weight = other
+                .indices
+                .iter()
+                .position(|x| x == dim)
+                .map_or(0.0, |i| other.weights[i]);
+            distance += (weight - other_weight) * (weight - other_weight);
+        }
+        for (dim, weight) in other.indices.iter().zip(&other.weights) {
+            if !self.indices.contains(dim) {
+                distance += weight * weight;
+            }
+        }
+        -distance
+    }
+
+    /// Dot product summed in the order of `self` indices whatever the vector lengths,
+    /// so any backend visiting the dimensions of `self` in order gets the same result
+    pub fn dot_product_in_order(&self, other: &SparseVector) -> f32 {
+        let mut result = ScoreAccumulator::new(Summation::Naive);
+        for (dim, weight) in self.indices.iter().zip(&self.weights) {
+            if let Some(i) = other.indices.iter().position(|&x| x == *dim) {
+                result.add(weight * other.weights[i]);
+            }
+        }
+        result.sum()
+    }
+}
+
+/// Borrowed sparse vector, e.g. read from a memory mapped storage
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SparseVectorRef<'a> {
+    pub indices: &'a [DimId],
+    pub weights: &'a [DimWeight],
+}
+
+impl<'a> SparseVectorRef<'a> {
+    pub fn dot_product(&self, other: &SparseVectorRef) -> f32 {
+        self.dot_product_with(other, Summation::Naive)
+    }
+
+    /// Dot product accumulated with the given summation strategy
+    pub fn dot_product_with(&self, other: &SparseVectorRef, summation: Summation) -> f32 {
+        #[cfg(feature = "simd")]
+        if summation == Summation::Naive {
+            return self.dot_product_simd(other);
+        }
+        self.dot_product_scalar(other, summation)
+    }
+
+    /// Dot product one dimension at a time, whatever the enabled features
+    pub fn dot_product_scalar(&self, other: &SparseVectorRef, summation: Summation) -> f32 {
         // find shorter vector to place in outer position
         let (outer, inner) = if self.indices.len() > other.indices.len() {
             (other, self)
@@ -20,24 +337,320 @@ impl SparseVector {
             (self, other)
         };
 
-        let mut result = 0.0;
+        let mut result = ScoreAccumulator::new(summation);
         // dot product
-        for (dim, weight) in outer.indices.iter().zip(&outer.weights) {
+        for (dim, weight) in outer.indices.iter().zip(outer.weights) {
             let index_in_inner = inner.indices.iter().position(|&x| x == *dim);
             if let Some(i) = index_in_inner {
                 // dot product
-                result += weight * inner.weights[i];
+                result.add(weight * inner.weights[i]);
             }
         }
 
+        result.sum()
+    }
+
+    /// Naive dot product where the runs of identical consecutive dimensions of both
+    /// vectors are multiplied and accumulated with SIMD instructions
+    #[cfg(feature = "simd")]
+    fn dot_product_simd(&self, other: &SparseVectorRef) -> f32 {
+        let (outer, inner) = if self.indices.len() > other.indices.len() {
+            (other, self)
+        } else {
+            (self, other)
+        };
+
+        let mut result = 0.0;
+        let mut i = 0;
+        while i < outer.indices.len() {
+            let dim = outer.indices[i];
+            let Some(j) = inner.indices.iter().position(|&x| x == dim) else {
+                i += 1;
+                continue;
+            };
+            let run = outer.indices[i..]
+                .iter()
+                .zip(&inner.indices[j..])
+                .take_while(|(a, b)| a == b)
+                .count();
+            result += simd_dot(&outer.weights[i..i + run], &inner.weights[j..j + run]);
+            i += run;
+        }
         result
     }
+
+    pub fn to_vector(self) -> SparseVector {
+        SparseVector::new(self.indices.to_vec(), self.weights.to_vec())
+    }
+}
+
+/// Collects `(index, weight)` pairs into a canonical vector:
+/// indices are sorted and weights of duplicate indices are summed.
+impl FromIterator<(DimId, DimWeight)> for SparseVector {
+    fn from_iter<I: IntoIterator<Item = (DimId, DimWeight)>>(iter: I) -> Self {
+        let mut pairs: Vec<_> = iter.into_iter().collect();
+        pairs.sort_by_key(|(index, _)| *index);
+
+        let mut indices: Vec<DimId> = Vec::with_capacity(pairs.len());
+        let mut weights: Vec<DimWeight> = Vec::with_capacity(pairs.len());
+        for (index, weight) in pairs {
+            if indices.last() == Some(&index) {
+                // duplicate index, accumulate weight
+                *weights.last_mut().unwrap() += weight;
+            } else {
+                indices.push(index);
+                weights.push(weight);
+            }
+        }
+
+        SparseVector { indices, weights }
+    }
+}
+
+/// Dot product of two weight slices of the same length, 8 lanes at a time
+#[cfg(feature = "simd")]
+fn simd_dot(a: &[DimWeight], b: &[DimWeight]) -> f32 {
+    use wide::f32x8;
+
+    let chunks_a = a.chunks_exact(8);
+    let chunks_b = b.chunks_exact(8);
+    let mut result: f32 = chunks_a
+        .remainder()
+        .iter()
+        .zip(chunks_b.remainder())
+        .map(|(a, b)| a * b)
+        .sum();
+    let mut lanes = f32x8::ZERO;
+    for (a, b) in chunks_a.zip(chunks_b) {
+        let a: [f32; 8] = a.try_into().unwrap();
+        let b: [f32; 8] = b.try_into().unwrap();
+        lanes += f32x8::from(a) * f32x8::from(b);
+    }
+    result += lanes.reduce_add();
+    result
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    #[test]
+    fn test_dot_product_matches_scalar() {
+        // 256 shared consecutive dimensions surrounded by unaligned ones
+        let shared = (100..356).map(|dim| (dim, (dim % 17) as f32 / 7.0));
+        let a = SparseVector::from_iter(shared.clone().chain([(1, 2.0), (400, 1.5), (7, 0.5)]));
+        let b = SparseVector::from_iter(shared.chain([(2, 4.0), (400, 0.25), (9, 3.0)]));
+        // the shared run shifted in the other vector
+        let c = SparseVector::new(
+            b.indices[3..]
+                .iter()
+                .chain(&b.indices[..3])
+                .copied()
+                .collect(),
+            b.weights[3..]
+                .iter()
+                .chain(&b.weights[..3])
+                .copied()
+                .collect(),
+        );
+
+        for (x, y) in [(&a, &b), (&b, &a), (&a, &c), (&c, &c)] {
+            let scalar = x.view().dot_product_scalar(&y.view(), Summation::Naive);
+            let dot = x.dot_product(y);
+            assert!(
+                float_cmp::approx_eq!(f32, dot, scalar, ulps = 64),
+                "{} vs {}",
+                dot,
+                scalar
+            );
+        }
+        let empty = SparseVector::new(vec![], vec![]);
+        assert_eq!(a.dot_product(&empty), 0.0);
+    }
+
+    #[test]
+    fn test_try_new() {
+        assert_eq!(
+            SparseVector::try_new(vec![1, 3, 7], vec![0.5, 1.0, 2.0]),
+            Ok(SparseVector::new(vec![1, 3, 7], vec![0.5, 1.0, 2.0]))
+        );
+        assert_eq!(
+            SparseVector::try_new(vec![1, 3], vec![0.5]),
+            Err(SparseVectorError::LengthMismatch {
+                indices: 2,
+                weights: 1
+            })
+        );
+        assert_eq!(
+            SparseVector::try_new(vec![1, 3, 3], vec![0.5, 1.0, 2.0]),
+            Err(SparseVectorError::NotStrictlyIncreasing { position: 2 })
+        );
+        assert_eq!(
+            SparseVector::try_new(vec![3, 1], vec![0.5, 1.0]),
+            Err(SparseVectorError::NotStrictlyIncreasing { position: 1 })
+        );
+        assert_eq!(
+            SparseVector::try_new(vec![1, 3], vec![0.5, f32::NAN]),
+            Err(SparseVectorError::NonFiniteWeight { position: 1 })
+        );
+
+        let mut vector = SparseVector::new(vec![7, 1, 3], vec![2.0, 0.5, 1.0]);
+        vector.sorted_by_indices();
+        assert_eq!(
+            vector,
+            SparseVector::new(vec![1, 3, 7], vec![0.5, 1.0, 2.0])
+        );
+        assert!(SparseVector::try_new(vector.indices, vector.weights).is_ok());
+    }
+
+    #[test]
+    fn test_from_iter_canonical() {
+        let pairs = vec![(5, 0.5), (1, 1.0), (3, 2.0), (1, 0.25), (2, 3.0)];
+        let vector = SparseVector::from_iter(pairs);
+
+        assert_eq!(
+            vector,
+            SparseVector::new(vec![1, 2, 3, 5], vec![1.25, 3.0, 2.0, 0.5])
+        );
+    }
+
+    #[test]
+    fn test_flush_subnormals() {
+        let subnormal = f32::MIN_POSITIVE / 2.0;
+        assert!(subnormal.is_subnormal());
+        let mut vector = SparseVector::new(
+            vec![1, 2, 3, 4],
+            vec![1.0, subnormal, f32::MIN_POSITIVE, -subnormal],
+        );
+        vector.flush_subnormals();
+
+        assert_eq!(
+            vector,
+            SparseVector::new(vec![1, 3], vec![1.0, f32::MIN_POSITIVE])
+        );
+    }
+
+    #[test]
+    fn test_expand() {
+        let mut query = SparseVector::new(vec![10, 20], vec![1.0, 2.0]);
+        let expansions = HashMap::from([
+            (10, vec![(11, 0.5), (20, 0.25)]),
+            (20, vec![(5, 0.5), (11, 0.125)]),
+            (99, vec![(100, 1.0)]),
+        ]);
+        query.expand(&expansions);
+
+        assert_eq!(
+            query,
+            SparseVector::new(
+                vec![5, 10, 11, 20],
+                // 11: 1.0 * 0.5 + 2.0 * 0.125, 20: 2.0 + 1.0 * 0.25
+                vec![1.0, 1.0, 0.75, 2.25]
+            )
+        );
+    }
+
+    #[test]
+    fn test_from_interned() {
+        let interner = QueryInterner::from_vocabulary(&[(3, 10), (17, 1), (2048, 4)]);
+        assert_eq!(interner.len(), 3);
+
+        let terms = [("17", 0.5), ("3", 1.5), ("2048", 2.0)];
+        let expected = SparseVector::new(vec![3, 17, 2048], vec![1.5, 0.5, 2.0]);
+        assert_eq!(SparseVector::from_interned(&terms, &interner), expected);
+        assert_eq!(
+            expected,
+            [(17, 0.5), (3, 1.5), (2048, 2.0)].into_iter().collect()
+        );
+
+        // repeated terms are merged
+        let terms = [("17", 0.5), ("3", 1.5), ("17", 0.25)];
+        assert_eq!(
+            SparseVector::from_interned(&terms, &interner),
+            SparseVector::new(vec![3, 17], vec![1.5, 0.75])
+        );
+
+        // unknown terms, including numbers outside the vocabulary, are dropped
+        let terms = [("5", 1.0), ("17", 0.5), ("hello", 3.0), ("003", 1.0)];
+        assert_eq!(
+            SparseVector::from_interned(&terms, &interner),
+            SparseVector::new(vec![17], vec![0.5])
+        );
+    }
+
+    #[test]
+    fn test_blend() {
+        let v1 = SparseVector::new(vec![3, 1, 2], vec![4.0, 2.0, 1.0]);
+        let v2 = SparseVector::new(vec![2, 5, 3], vec![3.0, 8.0, 2.0]);
+        let alpha = 0.25;
+
+        assert_eq!(
+            v1.blend(&v2, alpha),
+            SparseVector::new(
+                vec![1, 2, 3, 5],
+                vec![
+                    alpha * 2.0,
+                    alpha * 1.0 + (1.0 - alpha) * 3.0,
+                    alpha * 4.0 + (1.0 - alpha) * 2.0,
+                    (1.0 - alpha) * 8.0,
+                ]
+            )
+        );
+        // blending a vector with itself gives the canonical form of the vector
+        assert_eq!(
+            v1.blend(&v1, alpha),
+            SparseVector::new(vec![1, 2, 3], vec![2.0, 1.0, 4.0])
+        );
+    }
+
+    #[test]
+    fn test_merge_weighted() {
+        let query = SparseVector::new(vec![1, 4, 7], vec![1.0, 2.0, 3.0]);
+        let boost = SparseVector::new(vec![9, 4, 0], vec![1.0, 0.5, 2.0]);
+
+        let merged = query.merge_weighted(&boost, 2.0, 0.5);
+        assert_eq!(
+            merged,
+            SparseVector::new(
+                vec![0, 1, 4, 7, 9],
+                vec![
+                    0.5 * 2.0,
+                    2.0 * 1.0,
+                    2.0 * 2.0 + 0.5 * 0.5,
+                    2.0 * 3.0,
+                    0.5 * 1.0
+                ]
+            )
+        );
+        assert!(merged.indices.windows(2).all(|pair| pair[0] < pair[1]));
+        assert!(SparseVector::try_new(merged.indices, merged.weights).is_ok());
+    }
+
+    #[test]
+    fn test_neg_squared_l2_distance() {
+        let v1 = SparseVector::new(vec![1, 2, 3], vec![1.0, 2.0, 3.0]);
+        let v2 = SparseVector::new(vec![3, 1, 4], vec![1.0, 0.5, 2.0]);
+
+        // dim 1: 0.5², dim 2: 2², dim 3: 2², dim 4: 2²
+        let expected = -(0.25 + 4.0 + 4.0 + 4.0);
+        assert_eq!(v1.neg_squared_l2_distance(&v2), expected);
+        assert_eq!(v2.neg_squared_l2_distance(&v1), expected);
+        assert_eq!(v1.neg_squared_l2_distance(&v1), 0.0);
+    }
+
+    #[test]
+    fn test_normalized() {
+        let v = SparseVector::new(vec![1, 4], vec![3.0, -4.0]);
+        let normalized = v.normalized();
+        assert_eq!(normalized, SparseVector::new(vec![1, 4], vec![0.6, -0.8]));
+        assert!((normalized.l2_norm() - 1.0).abs() < 1e-6);
+
+        let zero = SparseVector::new(vec![1, 2], vec![0.0, 0.0]);
+        assert_eq!(zero.normalized(), zero);
+        let empty = SparseVector::new(vec![], vec![]);
+        assert_eq!(empty.normalized(), empty);
+    }
+
     #[test]
     fn test_dot_product_aligned() {
         let v1 = SparseVector::new(vec![1, 2, 3], vec![1.0, 2.0, 3.0]);
@@ -56,6 +669,21 @@ mod tests {
         assert_eq!(v2.dot_product(&v1), 7.0);
     }
 
+    #[test]
+    fn test_dot_product_kahan() {
+        let len = 10_000;
+        let query = SparseVector::new((0..len).collect(), vec![1.0; len as usize]);
+        let mut weights = vec![1e-8; len as usize];
+        weights[0] = 1.0;
+        let doc = SparseVector::new((0..len).collect(), weights);
+
+        let reference: f64 = doc.weights.iter().map(|w| *w as f64).sum();
+        let naive = query.dot_product_with(&doc, Summation::Naive);
+        let kahan = query.dot_product_with(&doc, Summation::Kahan);
+
+        assert!((kahan as f64 - reference).abs() < (naive as f64 - reference).abs());
+    }
+
     #[test]
     fn test_dot_product_splade() {
         let query = SparseVector::new(vec![0, 1000, 2000, 3000], vec![1.0, 0.2, 0.9, 0.5]);
@@ -0,0 +1,52 @@
+use half::f16;
+use std::fmt::Debug;
+
+/// Weight type of [`SparseVector`](super::vector::SparseVector) and
+/// [`PostingList`](crate::sparse_index::immutable::posting_list::PostingList).
+/// Scores are always accumulated in f32, `to_f32` is the value seen by the search.
+pub trait Weight: Copy + Default + Debug + PartialOrd {
+    fn to_f32(self) -> f32;
+
+    /// Nearest representable weight, saturating for integer weights
+    fn from_f32(value: f32) -> Self;
+}
+
+impl Weight for f32 {
+    #[inline]
+    fn to_f32(self) -> f32 {
+        self
+    }
+
+    #[inline]
+    fn from_f32(value: f32) -> Self {
+        value
+    }
+}
+
+/// f16 keeps 11 significant bits: a weight in the normal range `[6.1e-5, 65504]` is
+/// converted within a relative error of 2^-11 (about 0.05%), so a score differs from
+/// the f32 one by at most that relative error times the sum of its absolute contributions.
+impl Weight for f16 {
+    #[inline]
+    fn to_f32(self) -> f32 {
+        f16::to_f32(self)
+    }
+
+    #[inline]
+    fn from_f32(value: f32) -> Self {
+        f16::from_f32(value)
+    }
+}
+
+impl Weight for i8 {
+    #[inline]
+    fn to_f32(self) -> f32 {
+        self as f32
+    }
+
+    #[inline]
+    fn from_f32(value: f32) -> Self {
+        // `as` saturates, NaN becomes 0
+        value.round() as i8
+    }
+}
@@ -0,0 +1,102 @@
+use crate::sparse_index::common::types::DimId;
+use crate::sparse_index::immutable::inverted_index::inverted_index_ram::InvertedIndexRam;
+use crate::sparse_index::immutable::posting_list::PostingElement;
+use std::mem::size_of;
+
+/// Inverted flatten index from dimension id to posting list.
+/// All posting elements live in a single arena, like the mmap layout but in RAM.
+pub struct InvertedIndexArena {
+    /// Posting elements of all dimensions, ordered by dimension id then record id
+    elements: Vec<PostingElement>,
+    /// Posting list of dimension `id` is `elements[offsets[id]..offsets[id + 1]]`
+    offsets: Vec<usize>,
+}
+
+impl InvertedIndexArena {
+    pub fn from_ram(inverted_index_ram: &InvertedIndexRam) -> InvertedIndexArena {
+        let total_elements = inverted_index_ram
+            .postings
+            .iter()
+            .map(|posting| posting.elements.len())
+            .sum();
+        let mut elements = Vec::with_capacity(total_elements);
+        let mut offsets = Vec::with_capacity(inverted_index_ram.postings.len() + 1);
+        offsets.push(0);
+        for posting in &inverted_index_ram.postings {
+            elements.extend_from_slice(&posting.elements);
+            offsets.push(elements.len());
+        }
+        InvertedIndexArena { elements, offsets }
+    }
+
+    pub fn get(&self, id: &DimId) -> Option<&[PostingElement]> {
+        let id = *id as usize;
+        let start = *self.offsets.get(id)?;
+        let end = *self.offsets.get(id + 1)?;
+        Some(&self.elements[start..end])
+    }
+
+    /// Number of dimensions, including empty ones
+    pub fn posting_count(&self) -> usize {
+        self.offsets.len() - 1
+    }
+
+    /// Allocated bytes of the arena and the offsets
+    pub fn memory_bytes(&self) -> usize {
+        self.elements.capacity() * size_of::<PostingElement>()
+            + self.offsets.capacity() * size_of::<usize>()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sparse_index::common::vector::SparseVector;
+    use crate::sparse_index::immutable::inverted_index::inverted_index_ram::InvertedIndexBuilder;
+    use crate::sparse_index::immutable::inverted_index::InvertedIndex;
+    use crate::sparse_index::immutable::posting_list::PostingList;
+    use crate::sparse_index::immutable::search_context::SearchContext;
+
+    #[test]
+    fn test_arena_equivalent_to_ram() {
+        let build = || {
+            InvertedIndexBuilder::new()
+                .add(
+                    1,
+                    PostingList::from(vec![
+                        (1, 10.0),
+                        (2, 20.0),
+                        (3, 30.0),
+                        (4, 1.0),
+                        (5, 2.0),
+                        (6, 3.0),
+                        (7, 4.0),
+                        (8, 5.0),
+                        (9, 6.0),
+                    ]),
+                )
+                .add(2, PostingList::from(vec![(1, 10.0), (2, 20.0), (3, 30.0)]))
+                .add(4, PostingList::from(vec![(2, 2.0), (9, 7.0)]))
+                .build()
+        };
+        let inverted_index_ram = build();
+        let arena = InvertedIndexArena::from_ram(&inverted_index_ram);
+
+        assert_eq!(arena.posting_count(), inverted_index_ram.postings.len());
+        for id in 0..=5 {
+            assert_eq!(
+                arena.get(&id),
+                inverted_index_ram.get(&id).map(|p| p.elements.as_slice())
+            );
+        }
+
+        let ram_index = InvertedIndex::Ram(inverted_index_ram);
+        let arena_index = InvertedIndex::Arena(arena);
+        let query = SparseVector::new(vec![1, 2, 3, 4], vec![1.0, 0.5, 2.0, 3.0]);
+        for top in 1..10 {
+            let ram_results = SearchContext::new(&query, top, &ram_index).search();
+            let arena_results = SearchContext::new(&query, top, &arena_index).search();
+            assert_eq!(ram_results, arena_results);
+        }
+    }
+}
@@ -1,32 +1,83 @@
-use std::mem::size_of;
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom};
+use std::mem::{align_of, size_of};
 use std::path::{Path, PathBuf};
 use std::sync::Arc;
 
-use crate::sparse_index::common::file_operations::{atomic_save_json, read_json};
+use crate::sparse_index::common::file_operations::{atomic_save_json, read_json, TempFileGuard};
 use crate::sparse_index::common::madvise;
+use crate::sparse_index::common::madvise::Advice;
 use memmap2::{Mmap, MmapMut};
 use serde::{Deserialize, Serialize};
 
-use super::inverted_index_ram::InvertedIndexRam;
+use super::inverted_index_ram::{InvertedIndexBuilder, InvertedIndexRam};
 use crate::sparse_index::common::mmap_ops::{
-    transmute_from_u8_to_slice, transmute_to_u8, transmute_to_u8_slice,
+    transmute_from_u8_to_slice, transmute_to_u8, transmute_to_u8_mut_slice, transmute_to_u8_slice,
 };
-use crate::sparse_index::common::types::DimId;
-use crate::sparse_index::immutable::posting_list::PostingElement;
+use crate::sparse_index::common::types::{DimId, DimWeight};
+use crate::sparse_index::immutable::posting_list::{
+    PostingElement, PostingList, PostingListIter, PostingListIterator, PostingSummary,
+    WeightPostingListIterator,
+};
+use crate::sparse_index::immutable::posting_list_compressed::{
+    encode_posting, CompressedPostingListIterator,
+};
+use half::f16;
 
 const POSTING_HEADER_SIZE: usize = size_of::<PostingListFileHeader>();
 const INDEX_FILE_NAME: &str = "index.data";
+const INDEX_TMP_FILE_NAME: &str = "index.data.tmp";
 const INDEX_CONFIG_FILE_NAME: &str = "index_config.json";
+/// A [`PostingSummary`] per dimension, read on load so that a search can order and prune
+/// the posting lists before faulting their elements in
+const SUMMARIES_FILE_NAME: &str = "index_summaries.data";
+const SUMMARIES_TMP_FILE_NAME: &str = "index_summaries.data.tmp";
+/// A mapping can not be larger than `isize::MAX` bytes
+const MAX_INDEX_FILE_LENGTH: usize = isize::MAX as usize;
+
+/// Encoding of the posting element weights in the index file
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum WeightEncoding {
+    #[default]
+    F32,
+    F16,
+    QuantizedU8,
+}
+
+/// Encoding of the posting element record ids in the index file
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum IdEncoding {
+    /// Fixed width elements
+    #[default]
+    Plain,
+    /// Delta + varint record ids with a skip table per block, see [`encode_posting`]
+    DeltaVarint,
+}
 
 #[derive(Debug, Default, Clone, Serialize, Deserialize)]
 pub struct InvertedIndexFileHeader {
     pub posting_count: usize,
+    /// Missing in files written before the encoding was recorded, those are f32
+    #[serde(default)]
+    pub weight_encoding: WeightEncoding,
+    /// Missing in files written before the id encoding was recorded, those are plain
+    #[serde(default)]
+    pub id_encoding: IdEncoding,
+    /// Bytes between the posting headers and the elements section, so that the elements
+    /// are aligned for `PostingElement`. Missing in files written without padding.
+    #[serde(default)]
+    pub alignment_padding: usize,
 }
 
 /// Inverted flatten index from dimension id to posting list
 pub struct InvertedIndexMmap {
     mmap: Arc<Mmap>,
     file_header: InvertedIndexFileHeader,
+    file_path: PathBuf,
+    /// None for files written before the summaries were recorded
+    summaries: Option<Vec<PostingSummary>>,
 }
 
 #[derive(Default, Clone)]
@@ -40,89 +91,488 @@ impl InvertedIndexMmap {
         path.join(INDEX_FILE_NAME)
     }
 
+    fn index_tmp_file_path(path: &Path) -> PathBuf {
+        path.join(INDEX_TMP_FILE_NAME)
+    }
+
     pub fn index_config_file_path(path: &Path) -> PathBuf {
         path.join(INDEX_CONFIG_FILE_NAME)
     }
 
+    pub fn summaries_file_path(path: &Path) -> PathBuf {
+        path.join(SUMMARIES_FILE_NAME)
+    }
+
+    /// Change the access pattern advised for the whole mapping, an index returned by
+    /// `convert_and_save` is advised with the global advice until then
+    pub fn set_advice(&self, advice: Advice) -> std::io::Result<()> {
+        madvise::madvise(self.mmap.as_ref(), advice)
+    }
+
+    /// Number of dimensions, including empty ones
+    pub fn posting_count(&self) -> usize {
+        self.file_header.posting_count
+    }
+
+    /// Length of the mapped index file, paged in by the OS on access
+    pub fn file_bytes(&self) -> u64 {
+        self.mmap.len() as u64
+    }
+
+    pub fn weight_encoding(&self) -> WeightEncoding {
+        self.file_header.weight_encoding
+    }
+
+    pub fn id_encoding(&self) -> IdEncoding {
+        self.file_header.id_encoding
+    }
+
+    /// Posting list of an f32 index, None for an index with another encoding
     pub fn get(&self, id: &DimId) -> Option<&[PostingElement]> {
-        if *id > self.file_header.posting_count as DimId {
+        if self.file_header.weight_encoding != WeightEncoding::F32
+            || self.file_header.id_encoding != IdEncoding::Plain
+        {
+            return None;
+        }
+        self.get_elements(id)
+    }
+
+    /// Posting list of an f16 index, None for an index with another encoding
+    pub fn get_f16(&self, id: &DimId) -> Option<&[PostingElement<f16>]> {
+        if self.file_header.weight_encoding != WeightEncoding::F16
+            || self.file_header.id_encoding != IdEncoding::Plain
+        {
+            return None;
+        }
+        self.get_elements(id)
+    }
+
+    /// Posting list of a delta encoded index, None for an index with another encoding
+    pub fn get_compressed(&self, id: &DimId) -> Option<CompressedPostingListIterator<'_>> {
+        if self.file_header.id_encoding != IdEncoding::DeltaVarint {
             return None;
         }
+        self.get_elements(id)
+            .map(CompressedPostingListIterator::new)
+    }
+
+    /// Length, first record id and maximum weight of a posting list, None for an unknown
+    /// dimension. Stored apart from the elements, which are only read for files written
+    /// before the summaries were recorded.
+    pub fn posting_summary(&self, id: &DimId) -> Option<PostingSummary> {
+        match &self.summaries {
+            Some(summaries) => summaries.get(*id as usize).copied(),
+            None => self.read_summary(id),
+        }
+    }
+
+    /// Summary of a posting list read from its elements, with the weights as decoded
+    fn read_summary(&self, id: &DimId) -> Option<PostingSummary> {
+        match (
+            self.file_header.id_encoding,
+            self.file_header.weight_encoding,
+        ) {
+            (IdEncoding::DeltaVarint, _) => self
+                .get_compressed(id)
+                .map(|posting_iterator| PostingSummary::of(&posting_iterator)),
+            (IdEncoding::Plain, WeightEncoding::F16) => self
+                .get_f16(id)
+                .map(|elements| PostingSummary::of(&WeightPostingListIterator::new(elements))),
+            // u8 quantized indexes are rejected on load
+            (IdEncoding::Plain, WeightEncoding::F32 | WeightEncoding::QuantizedU8) => self
+                .get(id)
+                .map(|elements| PostingSummary::of(&PostingListIterator::new(elements))),
+        }
+    }
 
-        let header = transmute_from_u8::<PostingListFileHeader>(
-            &self.mmap
-                [*id as usize * POSTING_HEADER_SIZE..(*id as usize + 1) * POSTING_HEADER_SIZE],
+    fn posting_header(&self, id: &DimId) -> Option<PostingListFileHeader> {
+        if *id >= self.file_header.posting_count as DimId {
+            return None;
+        }
+        Some(
+            transmute_from_u8::<PostingListFileHeader>(
+                &self.mmap
+                    [*id as usize * POSTING_HEADER_SIZE..(*id as usize + 1) * POSTING_HEADER_SIZE],
+            )
+            .clone(),
         )
-        .clone();
+    }
+
+    fn get_elements<T>(&self, id: &DimId) -> Option<&[T]> {
+        let header = self.posting_header(id)?;
         let elements_bytes = &self.mmap[header.start_offset as usize..header.end_offset as usize];
         Some(transmute_from_u8_to_slice(elements_bytes))
     }
 
+    /// Read the posting lists of the given dimensions with positioned reads on the index file
+    /// instead of page faults on the mapping, unknown dimensions are skipped.
+    pub fn read_postings(&self, ids: &[DimId]) -> std::io::Result<InvertedIndexRam> {
+        let file = File::open(&self.file_path)?;
+        let mut builder = InvertedIndexBuilder::new();
+        for id in ids {
+            if *id as usize >= self.file_header.posting_count {
+                continue;
+            }
+            builder.add(*id, Self::read_posting(&file, *id, &self.file_header)?);
+        }
+        Ok(builder.build())
+    }
+
+    /// Read the whole index in RAM with plain file reads, for environments where mmap
+    /// is not available. The weights of an f16 index are dequantized.
+    pub fn load_ram<P: AsRef<Path>>(path: P) -> std::io::Result<InvertedIndexRam> {
+        let file_header = Self::read_file_header(path.as_ref())?;
+        let file = File::open(Self::index_file_path(path.as_ref()))?;
+        let mut builder = InvertedIndexBuilder::new();
+        for id in 0..file_header.posting_count as DimId {
+            builder.add(id, Self::read_posting(&file, id, &file_header)?);
+        }
+        Ok(builder.build())
+    }
+
+    fn read_posting(
+        file: &File,
+        id: DimId,
+        file_header: &InvertedIndexFileHeader,
+    ) -> std::io::Result<PostingList> {
+        let mut header = PostingListFileHeader::default();
+        read_exact_at(
+            file,
+            transmute_to_u8_mut_slice(std::slice::from_mut(&mut header)),
+            (id as usize * POSTING_HEADER_SIZE) as u64,
+        )?;
+        let posting_size = (header.end_offset - header.start_offset) as usize;
+        let element = PostingElement {
+            record_id: 0,
+            weight: 0.0,
+            max_next_weight: 0.0,
+        };
+        if file_header.id_encoding == IdEncoding::DeltaVarint {
+            // read into words for the alignment of the encoded posting list
+            let mut words = vec![0u32; posting_size / size_of::<u32>()];
+            read_exact_at(
+                file,
+                transmute_to_u8_mut_slice(&mut words),
+                header.start_offset,
+            )?;
+            let mut iterator = CompressedPostingListIterator::new(transmute_to_u8_slice(&words));
+            let elements = std::iter::from_fn(|| iterator.next_element()).collect();
+            return Ok(PostingList { elements });
+        }
+        let elements = match file_header.weight_encoding {
+            WeightEncoding::F16 => {
+                let mut elements_f16 = vec![
+                    PostingElement::<f16>::from_f32(&element);
+                    posting_size / size_of::<PostingElement<f16>>()
+                ];
+                read_exact_at(
+                    file,
+                    transmute_to_u8_mut_slice(&mut elements_f16),
+                    header.start_offset,
+                )?;
+                elements_f16
+                    .into_iter()
+                    .map(PostingElement::to_f32)
+                    .collect()
+            }
+            // u8 quantized indexes are rejected on load
+            WeightEncoding::F32 | WeightEncoding::QuantizedU8 => {
+                let mut elements = vec![element; posting_size / size_of::<PostingElement>()];
+                read_exact_at(
+                    file,
+                    transmute_to_u8_mut_slice(&mut elements),
+                    header.start_offset,
+                )?;
+                elements
+            }
+        };
+        Ok(PostingList { elements })
+    }
+
     pub fn convert_and_save<P: AsRef<Path>>(
         inverted_index_ram: &InvertedIndexRam,
         path: P,
     ) -> std::io::Result<Self> {
+        let posting_lens: Vec<_> = inverted_index_ram
+            .postings
+            .iter()
+            .map(|posting| posting.elements.len())
+            .collect();
+        let postings = inverted_index_ram
+            .postings
+            .iter()
+            .map(|posting| &posting.elements);
+        Self::save_postings(path, &posting_lens, postings)
+    }
+
+    /// Like [`Self::convert_and_save`] with the weights stored in the given encoding,
+    /// the encoding is recorded in the file header for [`Self::load`].
+    /// See the [`Weight`](crate::sparse_index::common::weight::Weight) impl of f16 for
+    /// the precision of f16 weights.
+    pub fn convert_and_save_with_encoding<P: AsRef<Path>>(
+        inverted_index_ram: &InvertedIndexRam,
+        path: P,
+        weight_encoding: WeightEncoding,
+    ) -> std::io::Result<Self> {
+        let posting_lens: Vec<_> = inverted_index_ram
+            .postings
+            .iter()
+            .map(|posting| posting.elements.len())
+            .collect();
+        match weight_encoding {
+            WeightEncoding::F32 => Self::convert_and_save(inverted_index_ram, path),
+            WeightEncoding::F16 => {
+                let postings = inverted_index_ram.postings.iter().map(|posting| {
+                    posting
+                        .elements
+                        .iter()
+                        .map(PostingElement::<f16>::from_f32)
+                        .collect::<Vec<_>>()
+                });
+                Self::save_postings_as(
+                    path,
+                    &posting_lens,
+                    postings,
+                    weight_encoding,
+                    IdEncoding::Plain,
+                )
+            }
+            WeightEncoding::QuantizedU8 => Err(Self::unsupported_encoding(weight_encoding)),
+        }
+    }
+
+    /// Like [`Self::convert_and_save`] with delta + varint encoded record ids, much smaller
+    /// on dense record ids. The posting lists are read with [`Self::get_compressed`], or
+    /// decoded by [`InvertedIndex::get`](super::InvertedIndex::get) when searching.
+    pub fn convert_and_save_compressed<P: AsRef<Path>>(
+        inverted_index_ram: &InvertedIndexRam,
+        path: P,
+    ) -> std::io::Result<Self> {
+        let postings: Vec<_> = inverted_index_ram
+            .postings
+            .iter()
+            .map(|posting| encode_posting(&posting.elements))
+            .collect();
+        // posting lists of bytes
+        let posting_lens: Vec<_> = postings.iter().map(Vec::len).collect();
+        Self::save_postings_as(
+            path,
+            &posting_lens,
+            postings,
+            WeightEncoding::F32,
+            IdEncoding::DeltaVarint,
+        )
+    }
+
+    /// Write posting lists produced one at a time, without holding a whole RAM index.
+    /// `posting_lens[id]` is the number of elements of the posting list for dimension `id`,
+    /// `postings` yields the posting lists elements in dimension order.
+    pub fn save_postings<P, I, E>(
+        path: P,
+        posting_lens: &[usize],
+        postings: I,
+    ) -> std::io::Result<Self>
+    where
+        P: AsRef<Path>,
+        I: IntoIterator<Item = E>,
+        E: AsRef<[PostingElement]>,
+    {
+        Self::save_postings_as(
+            path,
+            posting_lens,
+            postings,
+            WeightEncoding::F32,
+            IdEncoding::Plain,
+        )
+    }
+
+    /// Write posting lists of `T` elements, the element type of the encodings
+    fn save_postings_as<T, P, I, E>(
+        path: P,
+        posting_lens: &[usize],
+        postings: I,
+        weight_encoding: WeightEncoding,
+        id_encoding: IdEncoding,
+    ) -> std::io::Result<Self>
+    where
+        P: AsRef<Path>,
+        I: IntoIterator<Item = E>,
+        E: AsRef<[T]>,
+    {
         let (total_posting_headers_size, total_posting_elements_size) =
-            Self::calculate_file_length(inverted_index_ram);
-        let file_length = total_posting_headers_size + total_posting_elements_size;
-        let file_path = Self::index_file_path(path.as_ref());
-        Self::create_and_ensure_length(file_path.as_ref(), file_length)?;
+            Self::calculate_file_length(posting_lens, size_of::<T>())?;
+        let alignment_padding = Self::alignment_padding(total_posting_headers_size);
+        let elements_offset = total_posting_headers_size + alignment_padding;
+        let file_length = elements_offset + total_posting_elements_size;
+        // write into a temporary file, removed on error, renamed once complete
+        let tmp_file_path = Self::index_tmp_file_path(path.as_ref());
+        let tmp_file_guard = TempFileGuard::new(tmp_file_path.clone());
+        Self::create_and_ensure_length(tmp_file_path.as_ref(), file_length)?;
 
-        let mut mmap = Self::open_write_mmap(file_path.as_ref())?;
+        let mut mmap = Self::open_write_mmap(tmp_file_path.as_ref())?;
         madvise::madvise(&mmap, madvise::get_global())?;
 
         // file index data
-        Self::save_posting_headers(&mut mmap, inverted_index_ram, total_posting_headers_size);
-        Self::save_posting_elements(&mut mmap, inverted_index_ram, total_posting_headers_size);
+        Self::save_posting_headers(&mut mmap, posting_lens, size_of::<T>(), elements_offset);
+        Self::save_posting_elements(&mut mmap, posting_lens, postings, elements_offset)?;
+        mmap.flush()?;
 
-        let posting_count = inverted_index_ram.postings.len();
+        let file_path = Self::index_file_path(path.as_ref());
+        tmp_file_guard.persist(&file_path)?;
 
-        // finalize data with index file.
-        let file_header = InvertedIndexFileHeader { posting_count };
-        let config_file_path = Self::index_config_file_path(path.as_ref());
-        atomic_save_json(&config_file_path, &file_header)?;
+        let posting_count = posting_lens.len();
 
-        Ok(Self {
+        // finalize data with index file.
+        let file_header = InvertedIndexFileHeader {
+            posting_count,
+            weight_encoding,
+            id_encoding,
+            alignment_padding,
+        };
+        let mut index = Self {
             mmap: Arc::new(mmap.make_read_only()?),
             file_header,
-        })
+            file_path,
+            summaries: None,
+        };
+        let summaries: Vec<_> = (0..posting_count as DimId)
+            .map(|id| index.read_summary(&id).expect("dimension of the index"))
+            .collect();
+        Self::save_summaries(path.as_ref(), &summaries)?;
+        index.summaries = Some(summaries);
+
+        let config_file_path = Self::index_config_file_path(path.as_ref());
+        atomic_save_json(&config_file_path, &index.file_header)?;
+
+        Ok(index)
+    }
+
+    fn save_summaries(path: &Path, summaries: &[PostingSummary]) -> std::io::Result<()> {
+        let tmp_file_path = path.join(SUMMARIES_TMP_FILE_NAME);
+        let tmp_file_guard = TempFileGuard::new(tmp_file_path.clone());
+        std::fs::write(&tmp_file_path, transmute_to_u8_slice(summaries))?;
+        tmp_file_guard.persist(&Self::summaries_file_path(path))
+    }
+
+    /// None if the file has no summaries, written before they were recorded
+    fn read_summaries(
+        path: &Path,
+        posting_count: usize,
+    ) -> std::io::Result<Option<Vec<PostingSummary>>> {
+        let mut file = match File::open(Self::summaries_file_path(path)) {
+            Ok(file) => file,
+            Err(error) if error.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(error) => return Err(error),
+        };
+        let expected_length = posting_count as u64 * size_of::<PostingSummary>() as u64;
+        if file.metadata()?.len() != expected_length {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                "Posting summaries file does not match the posting count",
+            ));
+        }
+        let empty = PostingSummary {
+            len: 0,
+            first_record_id: 0,
+            max_weight: DimWeight::NEG_INFINITY,
+        };
+        let mut summaries = vec![empty; posting_count];
+        file.read_exact(transmute_to_u8_mut_slice(&mut summaries))?;
+        Ok(Some(summaries))
     }
 
     pub fn load<P: AsRef<Path>>(path: P) -> std::io::Result<Self> {
+        Self::load_with_advice(path, madvise::get_global())
+    }
+
+    /// Like [`Self::load`] advising the mapping with `advice` instead of the global advice,
+    /// e.g. [`Advice::Random`] for hot point queries or [`Advice::Sequential`] for scans
+    pub fn load_with_advice<P: AsRef<Path>>(path: P, advice: Advice) -> std::io::Result<Self> {
         let file_path = Self::index_file_path(path.as_ref());
         let mmap = Self::open_read_mmap(file_path.as_ref())?;
-        madvise::madvise(&mmap, madvise::get_global())?;
-        // read from index file
-        let config_file_path = Self::index_config_file_path(path.as_ref());
-        // if the file header does not exist, the index is malformed
-        let file_header: InvertedIndexFileHeader = read_json(&config_file_path)?;
+        madvise::madvise(&mmap, advice)?;
+        let file_header = Self::read_file_header(path.as_ref())?;
+        let summaries = Self::read_summaries(path.as_ref(), file_header.posting_count)?;
         Ok(Self {
             mmap: Arc::new(mmap),
             file_header,
+            file_path,
+            summaries,
         })
     }
 
-    /// Calculate file length in bytes
+    fn read_file_header(path: &Path) -> std::io::Result<InvertedIndexFileHeader> {
+        // read from index file
+        let config_file_path = Self::index_config_file_path(path);
+        // if the file header does not exist, the index is malformed
+        let file_header: InvertedIndexFileHeader = read_json(&config_file_path)?;
+        match file_header.weight_encoding {
+            WeightEncoding::F32 | WeightEncoding::F16 => Ok(file_header),
+            encoding @ WeightEncoding::QuantizedU8 => Err(Self::unsupported_encoding(encoding)),
+        }
+    }
+
+    fn unsupported_encoding(weight_encoding: WeightEncoding) -> std::io::Error {
+        std::io::Error::new(
+            std::io::ErrorKind::Unsupported,
+            format!("Unsupported weight encoding {:?}", weight_encoding),
+        )
+    }
+
+    /// Calculate file length in bytes for elements of `element_size` bytes
     /// Returns (posting headers size, posting elements size)
-    fn calculate_file_length(inverted_index_ram: &InvertedIndexRam) -> (usize, usize) {
-        let total_posting_headers_size = inverted_index_ram.postings.len() * POSTING_HEADER_SIZE;
+    /// Fails if the total length overflows or exceeds the maximum mapping size.
+    fn calculate_file_length(
+        posting_lens: &[usize],
+        element_size: usize,
+    ) -> std::io::Result<(usize, usize)> {
+        let too_large = || {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!(
+                    "Index file length exceeds the maximum of {} bytes",
+                    MAX_INDEX_FILE_LENGTH
+                ),
+            )
+        };
+        let total_posting_headers_size = posting_lens
+            .len()
+            .checked_mul(POSTING_HEADER_SIZE)
+            .ok_or_else(too_large)?;
+
+        let mut total_posting_elements_size: usize = 0;
+        for posting_len in posting_lens {
+            total_posting_elements_size = posting_len
+                .checked_mul(element_size)
+                .and_then(|size| total_posting_elements_size.checked_add(size))
+                .ok_or_else(too_large)?;
+        }
 
-        let mut total_posting_elements_size = 0;
-        for posting in &inverted_index_ram.postings {
-            total_posting_elements_size += posting.elements.len() * size_of::<PostingElement>();
+        let file_length = total_posting_headers_size
+            .checked_add(total_posting_elements_size)
+            .ok_or_else(too_large)?;
+        if file_length > MAX_INDEX_FILE_LENGTH {
+            return Err(too_large());
         }
 
-        (total_posting_headers_size, total_posting_elements_size)
+        Ok((total_posting_headers_size, total_posting_elements_size))
+    }
+
+    /// Padding after the posting headers for the elements section to start aligned
+    fn alignment_padding(total_posting_headers_size: usize) -> usize {
+        let alignment = align_of::<PostingElement>();
+        (alignment - total_posting_headers_size % alignment) % alignment
     }
 
     fn save_posting_headers(
         mmap: &mut MmapMut,
-        inverted_index_ram: &InvertedIndexRam,
-        total_posting_headers_size: usize,
+        posting_lens: &[usize],
+        element_size: usize,
+        elements_offset: usize,
     ) {
-        let mut elements_offset: usize = total_posting_headers_size;
-        for (id, posting) in inverted_index_ram.postings.iter().enumerate() {
-            let posting_elements_size = posting.elements.len() * size_of::<PostingElement>();
+        let mut elements_offset: usize = elements_offset;
+        for (id, posting_len) in posting_lens.iter().enumerate() {
+            let posting_elements_size = posting_len * element_size;
             let posting_header = PostingListFileHeader {
                 start_offset: elements_offset as u64,
                 end_offset: (elements_offset + posting_elements_size) as u64,
@@ -137,19 +587,44 @@ impl InvertedIndexMmap {
         }
     }
 
-    fn save_posting_elements(
+    fn save_posting_elements<T, I, E>(
         mmap: &mut MmapMut,
-        inverted_index_ram: &InvertedIndexRam,
-        total_posting_headers_size: usize,
-    ) {
-        let mut offset = total_posting_headers_size;
-        for posting in &inverted_index_ram.postings {
+        posting_lens: &[usize],
+        postings: I,
+        elements_offset: usize,
+    ) -> std::io::Result<()>
+    where
+        I: IntoIterator<Item = E>,
+        E: AsRef<[T]>,
+    {
+        let mut offset = elements_offset;
+        let mut postings = postings.into_iter();
+        for (id, posting_len) in posting_lens.iter().enumerate() {
+            let Some(posting) = postings.next() else {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidInput,
+                    format!("Missing posting list for dimension {}", id),
+                ));
+            };
+            let posting_elements = posting.as_ref();
+            if posting_elements.len() != *posting_len {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidInput,
+                    format!(
+                        "Posting list {} has {} elements, expected {}",
+                        id,
+                        posting_elements.len(),
+                        posting_len
+                    ),
+                ));
+            }
             // save posting element
-            let posting_elements_bytes = transmute_to_u8_slice(&posting.elements);
+            let posting_elements_bytes = transmute_to_u8_slice(posting_elements);
             mmap[offset..offset + posting_elements_bytes.len()]
                 .copy_from_slice(posting_elements_bytes);
             offset += posting_elements_bytes.len();
         }
+        Ok(())
     }
 
     fn open_read_mmap(path: &Path) -> std::io::Result<Mmap> {
@@ -189,11 +664,21 @@ pub fn transmute_from_u8<T>(v: &[u8]) -> &T {
     unsafe { &*(v.as_ptr() as *const T) }
 }
 
+/// Positioned read with a seek, `&File` is `Read + Seek` on every platform
+fn read_exact_at(mut file: &File, buf: &mut [u8], offset: u64) -> std::io::Result<()> {
+    file.seek(SeekFrom::Start(offset))?;
+    file.read_exact(buf)
+}
+
 #[cfg(test)]
 mod tests {
-    use crate::sparse_index::common::types::DimId;
+    use crate::sparse_index::common::types::{DimId, RecordId};
+    use crate::sparse_index::common::vector::SparseVector;
     use crate::sparse_index::immutable::inverted_index::inverted_index_ram::InvertedIndexBuilder;
+    use crate::sparse_index::immutable::inverted_index::InvertedIndex;
     use crate::sparse_index::immutable::posting_list::PostingList;
+    use crate::sparse_index::immutable::search_context::SearchContext;
+    use std::collections::HashMap;
     use tempfile::Builder;
 
     use super::*;
@@ -249,9 +734,342 @@ mod tests {
         assert!(inverted_index_mmap.get(&0).is_some());
         assert!(inverted_index_mmap.get(&1).is_some());
         assert!(inverted_index_mmap.get(&2).is_some());
-        // TODO fix off by one error
-        //assert!(inverted_index_mmap.get(&3).is_some());
-        //assert!(inverted_index_mmap.get(&4).is_none());
+        assert!(inverted_index_mmap.get(&3).is_some());
+        // dimension equal to the posting count
+        assert_eq!(inverted_index_mmap.posting_count(), 4);
+        assert!(inverted_index_mmap.get(&4).is_none());
         assert!(inverted_index_mmap.get(&100).is_none());
     }
+
+    #[test]
+    fn test_load_with_advice() {
+        let inverted_index_ram = InvertedIndexBuilder::new()
+            .add(1, PostingList::from(vec![(1, 10.0), (2, 20.0), (3, 30.0)]))
+            .add(2, PostingList::from(vec![(2, 1.0)]))
+            .build();
+        let tmp_dir_path = Builder::new().prefix("test_index_dir").tempdir().unwrap();
+        let inverted_index_mmap =
+            InvertedIndexMmap::convert_and_save(&inverted_index_ram, &tmp_dir_path).unwrap();
+        inverted_index_mmap.set_advice(Advice::Sequential).unwrap();
+        compare_indexes(&inverted_index_ram, &inverted_index_mmap);
+
+        for advice in [Advice::Normal, Advice::Random, Advice::Sequential] {
+            let inverted_index_mmap =
+                InvertedIndexMmap::load_with_advice(&tmp_dir_path, advice).unwrap();
+            compare_indexes(&inverted_index_ram, &inverted_index_mmap);
+        }
+    }
+
+    #[test]
+    fn test_posting_elements_aligned() {
+        let alignment = align_of::<PostingElement>();
+        for headers_size in [0, 1, 16, 17, 30] {
+            let padding = InvertedIndexMmap::alignment_padding(headers_size);
+            assert!(padding < alignment);
+            assert_eq!((headers_size + padding) % alignment, 0);
+        }
+
+        let inverted_index_ram = InvertedIndexBuilder::new()
+            .add(1, PostingList::from(vec![(1, 10.0), (2, 20.0), (3, 30.0)]))
+            .add(2, PostingList::from(vec![(4, 1.0)]))
+            .add(4, PostingList::from(vec![(1, 10.0), (5, 20.0)]))
+            .build();
+        let tmp_dir_path = Builder::new().prefix("test_index_dir").tempdir().unwrap();
+        InvertedIndexMmap::convert_and_save(&inverted_index_ram, &tmp_dir_path).unwrap();
+        let inverted_index_mmap = InvertedIndexMmap::load(&tmp_dir_path).unwrap();
+
+        let posting_count = inverted_index_mmap.file_header.posting_count;
+        assert_eq!(
+            (posting_count * POSTING_HEADER_SIZE
+                + inverted_index_mmap.file_header.alignment_padding)
+                % alignment,
+            0
+        );
+        for id in 0..posting_count {
+            let header = transmute_from_u8::<PostingListFileHeader>(
+                &inverted_index_mmap.mmap[id * POSTING_HEADER_SIZE..(id + 1) * POSTING_HEADER_SIZE],
+            );
+            assert_eq!(
+                header.start_offset as usize % alignment,
+                0,
+                "posting {}",
+                id
+            );
+        }
+        compare_indexes(&inverted_index_ram, &inverted_index_mmap);
+    }
+
+    #[test]
+    fn test_max_next_weight_round_trip() {
+        let mut builder = InvertedIndexBuilder::new();
+        for dim in 0..5 {
+            let records = (0..(dim as RecordId * 37 + 1))
+                .map(|id| (id, ((id * 13 + dim) % 17) as f32 / 3.0))
+                .collect();
+            builder.add(dim, PostingList::from(records));
+        }
+        let inverted_index_ram = builder.build();
+        let tmp_dir_path = Builder::new().prefix("test_index_dir").tempdir().unwrap();
+        InvertedIndexMmap::convert_and_save(&inverted_index_ram, &tmp_dir_path).unwrap();
+        let inverted_index_mmap = InvertedIndexMmap::load(&tmp_dir_path).unwrap();
+
+        for id in 0..inverted_index_ram.postings.len() as DimId {
+            let posting_list_ram = &inverted_index_ram.get(&id).unwrap().elements;
+            let posting_list_mmap = inverted_index_mmap.get(&id).unwrap();
+            assert_eq!(posting_list_ram.len(), posting_list_mmap.len());
+            for (ram, mmap) in posting_list_ram.iter().zip(posting_list_mmap) {
+                assert_eq!(ram.record_id, mmap.record_id);
+                assert_eq!(ram.weight.to_bits(), mmap.weight.to_bits());
+                assert_eq!(
+                    ram.max_next_weight.to_bits(),
+                    mmap.max_next_weight.to_bits(),
+                    "dim {} record {}",
+                    id,
+                    ram.record_id
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_weight_encoding_dispatch() {
+        let inverted_index_ram = InvertedIndexBuilder::new()
+            .add(1, PostingList::from(vec![(1, 10.0), (2, 20.0), (3, 30.0)]))
+            .build();
+        let tmp_dir_path = Builder::new().prefix("test_index_dir").tempdir().unwrap();
+        let inverted_index_mmap =
+            InvertedIndexMmap::convert_and_save(&inverted_index_ram, &tmp_dir_path).unwrap();
+        assert_eq!(
+            inverted_index_mmap.file_header.weight_encoding,
+            WeightEncoding::F32
+        );
+        drop(inverted_index_mmap);
+
+        let config_file_path = InvertedIndexMmap::index_config_file_path(tmp_dir_path.path());
+        for encoding in [
+            WeightEncoding::F32,
+            WeightEncoding::F16,
+            WeightEncoding::QuantizedU8,
+        ] {
+            let file_header = InvertedIndexFileHeader {
+                posting_count: inverted_index_ram.postings.len(),
+                weight_encoding: encoding,
+                id_encoding: IdEncoding::Plain,
+                alignment_padding: 0,
+            };
+            atomic_save_json(&config_file_path, &file_header).unwrap();
+
+            let loaded = InvertedIndexMmap::load(&tmp_dir_path);
+            match encoding {
+                WeightEncoding::F32 => compare_indexes(&inverted_index_ram, &loaded.unwrap()),
+                WeightEncoding::F16 => {
+                    // the f32 accessor does not misread the elements
+                    let loaded = loaded.unwrap();
+                    assert_eq!(loaded.weight_encoding(), WeightEncoding::F16);
+                    assert!(loaded.get(&1).is_none());
+                }
+                WeightEncoding::QuantizedU8 => {
+                    let error = loaded.err().unwrap();
+                    assert_eq!(error.kind(), std::io::ErrorKind::Unsupported);
+                }
+            }
+        }
+
+        // config written before the encoding was recorded
+        std::fs::write(&config_file_path, r#"{"posting_count":2}"#).unwrap();
+        let loaded = InvertedIndexMmap::load(&tmp_dir_path).unwrap();
+        assert_eq!(loaded.file_header.weight_encoding, WeightEncoding::F32);
+    }
+
+    #[test]
+    fn test_f16_weight_encoding() {
+        let mut builder = InvertedIndexBuilder::new();
+        for dim in 0..5 {
+            let records = (0..(dim as RecordId * 37 + 1))
+                .map(|id| (id, ((id * 13 + dim) % 17) as f32 / 3.0 + 0.01))
+                .collect();
+            builder.add(dim, PostingList::from(records));
+        }
+        let inverted_index_ram = builder.build();
+        let f32_dir = Builder::new().prefix("test_index_dir").tempdir().unwrap();
+        let f16_dir = Builder::new().prefix("test_index_dir").tempdir().unwrap();
+        InvertedIndexMmap::convert_and_save(&inverted_index_ram, &f32_dir).unwrap();
+        InvertedIndexMmap::convert_and_save_with_encoding(
+            &inverted_index_ram,
+            &f16_dir,
+            WeightEncoding::F16,
+        )
+        .unwrap();
+
+        let inverted_index_mmap = InvertedIndexMmap::load(&f16_dir).unwrap();
+        assert_eq!(inverted_index_mmap.weight_encoding(), WeightEncoding::F16);
+        let close = |a: f32, b: f32| a == b || (a - b).abs() <= a.abs() / 2048.0;
+        for id in 0..inverted_index_ram.postings.len() as DimId {
+            let posting_list_ram = &inverted_index_ram.get(&id).unwrap().elements;
+            let posting_list_f16 = inverted_index_mmap.get_f16(&id).unwrap();
+            assert_eq!(posting_list_ram.len(), posting_list_f16.len());
+            for (ram, f16) in posting_list_ram.iter().zip(posting_list_f16) {
+                let f16 = f16.to_f32();
+                assert_eq!(ram.record_id, f16.record_id);
+                assert!(close(ram.weight, f16.weight), "{:?} {:?}", ram, f16);
+                assert!(close(ram.max_next_weight, f16.max_next_weight));
+            }
+        }
+
+        // the elements section is 8 bytes per element instead of 12
+        let file_len = |dir: &Path| {
+            std::fs::metadata(InvertedIndexMmap::index_file_path(dir))
+                .unwrap()
+                .len() as usize
+        };
+        let element_count: usize = inverted_index_ram
+            .postings
+            .iter()
+            .map(|posting| posting.elements.len())
+            .sum();
+        assert_eq!(
+            file_len(f32_dir.path()) - file_len(f16_dir.path()),
+            element_count * 4
+        );
+
+        // all the records scored, so that near ties can not change the candidates
+        let query = SparseVector::new(vec![1, 2, 4], vec![1.0, 0.5, 2.0]);
+        let inverted_index_f32 = InvertedIndex::Mmap(InvertedIndexMmap::load(&f32_dir).unwrap());
+        let expected: HashMap<_, _> = SearchContext::new(&query, 1000, &inverted_index_f32)
+            .search()
+            .into_iter()
+            .map(|candidate| (candidate.vector_id, candidate.score))
+            .collect();
+        let inverted_index_f16 = InvertedIndex::Mmap(InvertedIndexMmap::load(&f16_dir).unwrap());
+        let results = SearchContext::new(&query, 1000, &inverted_index_f16).search();
+        assert_eq!(results.len(), expected.len());
+        for candidate in results {
+            // the weights are positive, the relative error of the sum is that of the weights
+            assert!(close(expected[&candidate.vector_id], candidate.score));
+        }
+
+        // dequantized on a RAM load
+        let inverted_index_dequantized = InvertedIndexMmap::load_ram(&f16_dir).unwrap();
+        for id in 0..inverted_index_ram.postings.len() as DimId {
+            let expected: Vec<_> = inverted_index_mmap
+                .get_f16(&id)
+                .unwrap()
+                .iter()
+                .map(|element| element.to_f32())
+                .collect();
+            assert_eq!(
+                inverted_index_dequantized.get(&id).unwrap().elements,
+                expected
+            );
+        }
+
+        assert_eq!(
+            InvertedIndexMmap::convert_and_save_with_encoding(
+                &inverted_index_ram,
+                &f16_dir,
+                WeightEncoding::QuantizedU8,
+            )
+            .err()
+            .unwrap()
+            .kind(),
+            std::io::ErrorKind::Unsupported
+        );
+    }
+
+    #[test]
+    fn test_delta_encoded_ids_round_trip() {
+        // dense record ids, plus a sparse dimension and an empty one
+        let dense: Vec<_> = (0..1000).map(|id| (id, (id % 13) as f32 + 0.5)).collect();
+        let inverted_index_ram = InvertedIndexBuilder::new()
+            .add(0, PostingList::from(dense))
+            .add(
+                1,
+                PostingList::from(vec![(7, 1.0), (70_000, 2.0), (4_000_000, 3.0)]),
+            )
+            .add(3, PostingList::from(vec![(2, 4.0)]))
+            .build();
+
+        let plain_dir = Builder::new().prefix("test_plain_dir").tempdir().unwrap();
+        InvertedIndexMmap::convert_and_save(&inverted_index_ram, &plain_dir).unwrap();
+        let tmp_dir_path = Builder::new().prefix("test_index_dir").tempdir().unwrap();
+        InvertedIndexMmap::convert_and_save_compressed(&inverted_index_ram, &tmp_dir_path).unwrap();
+        let inverted_index_mmap = InvertedIndexMmap::load(&tmp_dir_path).unwrap();
+        assert_eq!(inverted_index_mmap.id_encoding(), IdEncoding::DeltaVarint);
+        assert!(inverted_index_mmap.get(&0).is_none());
+
+        let file_len = |path: &Path| {
+            std::fs::metadata(InvertedIndexMmap::index_file_path(path))
+                .unwrap()
+                .len()
+        };
+        assert!(file_len(tmp_dir_path.path()) < file_len(plain_dir.path()));
+
+        for id in 0..inverted_index_ram.postings.len() as DimId {
+            let mut iterator = inverted_index_mmap.get_compressed(&id).unwrap();
+            let elements: Vec<_> = std::iter::from_fn(|| iterator.next_element()).collect();
+            assert_eq!(elements, inverted_index_ram.get(&id).unwrap().elements);
+        }
+        let mut iterator = inverted_index_mmap.get_compressed(&1).unwrap();
+        assert_eq!(iterator.skip_to(70_000).unwrap().weight, 2.0);
+        assert_eq!(iterator.len_to_end(), 2);
+
+        // searched like the plain index
+        let query = SparseVector::new(vec![0, 1, 3], vec![1.0, 2.0, 0.5]);
+        let compressed = InvertedIndex::Mmap(inverted_index_mmap);
+        let plain = InvertedIndex::Mmap(InvertedIndexMmap::load(&plain_dir).unwrap());
+        assert_eq!(compressed.search(&query, 10), plain.search(&query, 10));
+        assert_eq!(
+            compressed.top_documents(1, 2),
+            vec![(4_000_000, 3.0), (70_000, 2.0)]
+        );
+
+        let loaded = InvertedIndexMmap::load_ram(&tmp_dir_path).unwrap();
+        for (loaded, posting) in loaded.postings.iter().zip(&inverted_index_ram.postings) {
+            assert_eq!(loaded.elements, posting.elements);
+        }
+    }
+
+    #[test]
+    fn test_failed_convert_leaves_no_partial_file() {
+        let inverted_index_ram = InvertedIndexBuilder::new()
+            .add(1, PostingList::from(vec![(1, 10.0), (2, 20.0), (3, 30.0)]))
+            .build();
+
+        let tmp_dir_path = Builder::new().prefix("test_index_dir").tempdir().unwrap();
+        // a directory in place of the index file makes finalizing the data fail
+        let index_file_path = InvertedIndexMmap::index_file_path(tmp_dir_path.path());
+        std::fs::create_dir(&index_file_path).unwrap();
+
+        assert!(InvertedIndexMmap::convert_and_save(&inverted_index_ram, &tmp_dir_path).is_err());
+
+        assert!(!InvertedIndexMmap::index_tmp_file_path(tmp_dir_path.path()).exists());
+        assert!(!InvertedIndexMmap::index_config_file_path(tmp_dir_path.path()).exists());
+        assert!(index_file_path.is_dir());
+    }
+
+    #[test]
+    fn test_file_length_overflow() {
+        let element_size = size_of::<PostingElement>();
+        assert_eq!(
+            InvertedIndexMmap::calculate_file_length(&[0, 3], element_size).unwrap(),
+            (2 * POSTING_HEADER_SIZE, 3 * element_size)
+        );
+
+        // a single posting length overflowing the multiplication
+        let err =
+            InvertedIndexMmap::calculate_file_length(&[usize::MAX / 2], element_size).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput);
+        // the sum of the posting sizes overflows
+        let huge = usize::MAX / element_size;
+        assert!(InvertedIndexMmap::calculate_file_length(&[huge, huge], element_size).is_err());
+        // no overflow but larger than a mapping can be
+        let too_long = MAX_INDEX_FILE_LENGTH / element_size + 1;
+        assert!(InvertedIndexMmap::calculate_file_length(&[too_long], element_size).is_err());
+
+        // nothing is written to disk
+        let tmp_dir_path = Builder::new().prefix("test_index_dir").tempdir().unwrap();
+        let postings: Vec<Vec<PostingElement>> = vec![];
+        assert!(InvertedIndexMmap::save_postings(&tmp_dir_path, &[huge, huge], postings).is_err());
+        assert!(!InvertedIndexMmap::index_tmp_file_path(tmp_dir_path.path()).exists());
+    }
 }
@@ -1,18 +1,289 @@
-use crate::sparse_index::common::types::{DimId, RecordId};
-use crate::sparse_index::immutable::posting_list::PostingList;
-use std::collections::HashMap;
+use crate::sparse_index::common::types::{DimId, DimWeight, RecordId};
+use crate::sparse_index::common::vector::SparseVector;
+use crate::sparse_index::immutable::posting_list::{PostingBuilder, PostingElement, PostingList};
+use atomicwrites::{AtomicFile, OverwriteBehavior};
+use std::collections::{BTreeSet, HashMap};
+use std::fs::File;
+use std::io;
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::mem::size_of;
+use std::path::Path;
+
+/// Magic bytes at the start of a portable index file
+const PORTABLE_MAGIC: &[u8; 4] = b"SPIX";
+/// Version of the portable format, bumped on any layout change
+const PORTABLE_VERSION: u32 = 1;
 
 /// Inverted flatten index from dimension id to posting list
 pub struct InvertedIndexRam {
     pub postings: Vec<PostingList>,
+    /// Dimensions modified by `insert` since the last `finalize`
+    pending: BTreeSet<DimId>,
 }
 
 impl InvertedIndexRam {
     pub fn get(&self, id: &RecordId) -> Option<&PostingList> {
         self.postings.get((*id) as usize)
     }
+
+    /// Build the posting lists in one pass over the vectors, each vector pairs its
+    /// dimensions with their weights. Record ids must be unique, the posting lists are
+    /// sorted by id when built so the vectors can come in any order.
+    pub fn from_vectors<'a>(
+        vectors: impl Iterator<Item = (RecordId, &'a SparseVector)>,
+    ) -> InvertedIndexRam {
+        let mut builders: Vec<PostingBuilder> = Vec::new();
+        for (id, vector) in vectors {
+            for (dim, weight) in vector.indices.iter().zip(&vector.weights) {
+                let dim = *dim as usize;
+                if dim >= builders.len() {
+                    builders.resize_with(dim + 1, PostingBuilder::new);
+                }
+                builders[dim].add(id, *weight);
+            }
+        }
+        // like `InvertedIndexBuilder`, an empty index has a single empty posting list
+        if builders.is_empty() {
+            builders.push(PostingBuilder::new());
+        }
+        builders.shrink_to_fit();
+        let postings = builders
+            .into_iter()
+            .map(|builder| {
+                let mut posting_list = builder.build();
+                // the builders grew without knowing the posting list lengths
+                posting_list.elements.shrink_to_fit();
+                posting_list
+            })
+            .collect();
+        InvertedIndexRam {
+            postings,
+            pending: BTreeSet::new(),
+        }
+    }
+
+    /// Non-empty posting lists with their dimension id, in dimension order
+    pub fn iter(&self) -> impl Iterator<Item = (DimId, &PostingList)> {
+        self.postings
+            .iter()
+            .enumerate()
+            .filter(|(_, posting)| !posting.elements.is_empty())
+            .map(|(dim, posting)| (dim as DimId, posting))
+    }
+
+    /// Allocated bytes of the postings vector and all posting elements
+    pub fn memory_bytes(&self) -> usize {
+        let postings_bytes = self.postings.capacity() * size_of::<PostingList>();
+        let elements_bytes: usize = self
+            .postings
+            .iter()
+            .map(|posting| posting.elements.capacity() * size_of::<PostingElement>())
+            .sum();
+        postings_bytes + elements_bytes
+    }
+
+    /// Save in a portable format, independent of the in-memory layout and byte order:
+    ///
+    /// magic | version u32 | posting count u64 | per posting list: element count u64,
+    /// then (record id u32, weight f32, max next weight f32) per element
+    ///
+    /// All numbers are little-endian.
+    pub fn save_portable(&self, path: &Path) -> io::Result<()> {
+        let file = AtomicFile::new(path, OverwriteBehavior::AllowOverwrite);
+        file.write(|f| {
+            let mut writer = BufWriter::new(f);
+            writer.write_all(PORTABLE_MAGIC)?;
+            writer.write_all(&PORTABLE_VERSION.to_le_bytes())?;
+            writer.write_all(&(self.postings.len() as u64).to_le_bytes())?;
+            for posting in &self.postings {
+                writer.write_all(&(posting.elements.len() as u64).to_le_bytes())?;
+                for element in &posting.elements {
+                    writer.write_all(&element.record_id.to_le_bytes())?;
+                    writer.write_all(&element.weight.to_le_bytes())?;
+                    writer.write_all(&element.max_next_weight.to_le_bytes())?;
+                }
+            }
+            writer.flush()
+        })
+        .map_err(|e| io::Error::other(e.to_string()))
+    }
+
+    /// Load an index written by [`Self::save_portable`]
+    pub fn load_portable(path: &Path) -> io::Result<InvertedIndexRam> {
+        let mut reader = BufReader::new(File::open(path)?);
+        let mut magic = [0; 4];
+        reader.read_exact(&mut magic)?;
+        if &magic != PORTABLE_MAGIC {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "Not a portable index file",
+            ));
+        }
+        let version = u32::from_le_bytes(read_bytes(&mut reader)?);
+        if version != PORTABLE_VERSION {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "Unsupported portable index version {}, expected {}",
+                    version, PORTABLE_VERSION
+                ),
+            ));
+        }
+        let posting_count = u64::from_le_bytes(read_bytes(&mut reader)?);
+        let mut postings = Vec::new();
+        for _ in 0..posting_count {
+            let element_count = u64::from_le_bytes(read_bytes(&mut reader)?);
+            let mut elements = Vec::new();
+            for _ in 0..element_count {
+                elements.push(PostingElement {
+                    record_id: RecordId::from_le_bytes(read_bytes(&mut reader)?),
+                    weight: DimWeight::from_le_bytes(read_bytes(&mut reader)?),
+                    max_next_weight: DimWeight::from_le_bytes(read_bytes(&mut reader)?),
+                });
+            }
+            postings.push(PostingList { elements });
+        }
+        Ok(InvertedIndexRam {
+            postings,
+            pending: BTreeSet::new(),
+        })
+    }
+
+    /// Add a record to the posting lists of its dimensions, growing the postings for
+    /// new dimensions. Searches may prune incorrectly until [`Self::finalize`] is called.
+    pub fn insert(&mut self, id: RecordId, vector: &SparseVector) {
+        for (dim, weight) in vector.indices.iter().zip(&vector.weights) {
+            if *dim as usize >= self.postings.len() {
+                self.postings
+                    .resize(*dim as usize + 1, PostingList::default());
+            }
+            self.postings[*dim as usize].upsert(id, *weight);
+            self.pending.insert(*dim);
+        }
+    }
+
+    /// Merge the posting lists of another index, e.g. a shard built separately, without
+    /// the source vectors. Record ids must be disjoint across the two indexes.
+    ///
+    /// # Panics
+    ///
+    /// If a record id is in the posting lists of the same dimension in both indexes.
+    pub fn merge(mut self, other: InvertedIndexRam) -> InvertedIndexRam {
+        if other.postings.len() > self.postings.len() {
+            self.postings
+                .resize(other.postings.len(), PostingList::default());
+        }
+        for (dim, other_posting) in other.postings.into_iter().enumerate() {
+            if other_posting.elements.is_empty() {
+                continue;
+            }
+            let posting = &mut self.postings[dim];
+            if posting.elements.is_empty() {
+                *posting = other_posting;
+                continue;
+            }
+            posting.elements.extend(other_posting.elements);
+            posting
+                .elements
+                .sort_unstable_by_key(|element| element.record_id);
+            if let Some(pair) = posting
+                .elements
+                .windows(2)
+                .find(|pair| pair[0].record_id == pair[1].record_id)
+            {
+                panic!(
+                    "Record {} is in the posting list of dimension {} in both merged indexes",
+                    pair[0].record_id, dim
+                );
+            }
+            posting.recompute_max_next_weight();
+        }
+        self.pending.extend(other.pending);
+        self
+    }
+
+    /// Recompute the pruning bounds of the posting lists modified since the last call
+    pub fn finalize(&mut self) {
+        for dim in std::mem::take(&mut self.pending) {
+            self.postings[dim as usize].recompute_max_next_weight();
+        }
+    }
+
+    /// Store only the non-empty posting lists, contiguously in dimension order.
+    /// The index is then searched with queries translated by the returned remap.
+    pub fn compact(self) -> (InvertedIndexRam, DimRemap) {
+        let mut remap = DimRemap::default();
+        let mut postings = Vec::new();
+        for (dim, posting) in self.postings.into_iter().enumerate() {
+            if posting.elements.is_empty() {
+                continue;
+            }
+            remap
+                .compact_ids
+                .insert(dim as DimId, postings.len() as DimId);
+            remap.original_ids.push(dim as DimId);
+            postings.push(posting);
+        }
+        // like `InvertedIndexBuilder`, an empty index has a single empty posting list
+        if postings.is_empty() {
+            postings.push(PostingList::default());
+        }
+        let pending = self
+            .pending
+            .iter()
+            .filter_map(|dim| remap.compact_id(*dim))
+            .collect();
+        (InvertedIndexRam { postings, pending }, remap)
+    }
+}
+
+/// Dimension ids of a compacted index, see [`InvertedIndexRam::compact`]
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct DimRemap {
+    compact_ids: HashMap<DimId, DimId>,
+    /// Original dimension by compact id
+    original_ids: Vec<DimId>,
+}
+
+impl DimRemap {
+    /// Compact id of a dimension, None if the dimension has no posting
+    pub fn compact_id(&self, dim: DimId) -> Option<DimId> {
+        self.compact_ids.get(&dim).copied()
+    }
+
+    pub fn original_id(&self, compact_id: DimId) -> Option<DimId> {
+        self.original_ids.get(compact_id as usize).copied()
+    }
+
+    /// Number of dimensions with a posting
+    pub fn len(&self) -> usize {
+        self.original_ids.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.original_ids.is_empty()
+    }
+
+    /// Query for the compacted index, the dimensions without a posting are dropped
+    /// as they match nothing. The compact ids keep the dimension order.
+    pub fn translate(&self, query: &SparseVector) -> SparseVector {
+        let (indices, weights) = query
+            .indices
+            .iter()
+            .zip(&query.weights)
+            .filter_map(|(dim, weight)| Some((self.compact_id(*dim)?, *weight)))
+            .unzip();
+        SparseVector::new(indices, weights)
+    }
+}
+
+fn read_bytes<const N: usize>(reader: &mut impl Read) -> io::Result<[u8; N]> {
+    let mut bytes = [0; N];
+    reader.read_exact(&mut bytes)?;
+    Ok(bytes)
 }
 
+#[derive(Default)]
 pub struct InvertedIndexBuilder {
     postings: HashMap<DimId, PostingList>,
 }
@@ -29,6 +300,12 @@ impl InvertedIndexBuilder {
         self
     }
 
+    /// Add a posting list from records already sorted by unique id, e.g. produced
+    /// by another system, skipping the sort of the posting builder.
+    pub fn add_presorted(&mut self, id: DimId, elements: Vec<(RecordId, DimWeight)>) -> &mut Self {
+        self.add(id, PostingList::from_presorted(elements))
+    }
+
     pub fn build(&mut self) -> InvertedIndexRam {
         // Get sorted keys
         let mut keys: Vec<u32> = self.postings.keys().copied().collect();
@@ -43,6 +320,222 @@ impl InvertedIndexBuilder {
         for key in keys {
             postings[key as usize] = self.postings.remove(&key).unwrap();
         }
-        InvertedIndexRam { postings }
+        InvertedIndexRam {
+            postings,
+            pending: BTreeSet::new(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sparse_index::common::scored_candidate::ScoredCandidate;
+    use crate::sparse_index::immutable::inverted_index::InvertedIndex;
+    use crate::sparse_index::immutable::search_context::SearchContext;
+    use crate::storage::IndexStatistics;
+    use tempfile::Builder;
+
+    #[test]
+    fn test_add_presorted_matches_add() {
+        let records = vec![(1, 3.0), (4, 1.0), (5, 7.5), (9, 2.0), (12, 0.5)];
+        let mut shuffled = records.clone();
+        shuffled.reverse();
+
+        let presorted = InvertedIndexBuilder::new()
+            .add_presorted(2, records.clone())
+            .add_presorted(7, vec![])
+            .build();
+        let built = InvertedIndexBuilder::new()
+            .add(2, PostingList::from(shuffled))
+            .add(7, PostingList::from(vec![]))
+            .build();
+
+        assert_eq!(presorted.postings.len(), built.postings.len());
+        for (presorted, built) in presorted.postings.iter().zip(&built.postings) {
+            assert_eq!(presorted.elements, built.elements);
+        }
+    }
+
+    #[test]
+    fn test_portable_round_trip() {
+        let inverted_index_ram = InvertedIndexBuilder::new()
+            .add(1, PostingList::from(vec![(1, 3.0), (4, 1.0), (7, 2.5)]))
+            .add(3, PostingList::from(vec![(1, 1.0), (4, 2.0)]))
+            .build();
+        let tmp_dir_path = Builder::new().prefix("test_index_dir").tempdir().unwrap();
+        let file_path = tmp_dir_path.path().join("index.portable");
+        inverted_index_ram.save_portable(&file_path).unwrap();
+
+        let loaded = InvertedIndexRam::load_portable(&file_path).unwrap();
+        assert_eq!(loaded.postings.len(), inverted_index_ram.postings.len());
+        for (loaded, saved) in loaded.postings.iter().zip(&inverted_index_ram.postings) {
+            assert_eq!(loaded.elements, saved.elements);
+        }
+
+        let query = SparseVector::new(vec![1, 3], vec![1.0, 2.0]);
+        let loaded = InvertedIndex::Ram(loaded);
+        let saved = InvertedIndex::Ram(inverted_index_ram);
+        assert_eq!(
+            SearchContext::new(&query, 3, &loaded).search(),
+            SearchContext::new(&query, 3, &saved).search()
+        );
+
+        // unknown version
+        let mut bytes = std::fs::read(&file_path).unwrap();
+        bytes[4..8].copy_from_slice(&(PORTABLE_VERSION + 1).to_le_bytes());
+        std::fs::write(&file_path, bytes).unwrap();
+        let error = InvertedIndexRam::load_portable(&file_path).err().unwrap();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn test_iter_skips_empty_postings() {
+        let inverted_index_ram = InvertedIndexBuilder::new()
+            .add(1, PostingList::from(vec![(1, 3.0), (4, 1.0)]))
+            .add(2, PostingList::from(vec![]))
+            .add(5, PostingList::from(vec![(2, 4.0)]))
+            .build();
+        let dims: Vec<_> = inverted_index_ram
+            .iter()
+            .map(|(dim, posting)| (dim, posting.elements.len()))
+            .collect();
+        assert_eq!(dims, vec![(1, 2), (5, 1)]);
+    }
+
+    #[test]
+    fn test_merge() {
+        let shard_1 = InvertedIndexBuilder::new()
+            .add(1, PostingList::from(vec![(1, 3.0), (6, 1.0)]))
+            .add(2, PostingList::from(vec![(1, 1.0)]))
+            .build();
+        let shard_2 = InvertedIndexBuilder::new()
+            .add(1, PostingList::from(vec![(2, 5.0), (9, 2.0)]))
+            .add(4, PostingList::from(vec![(2, 4.0)]))
+            .build();
+
+        let merged = shard_1.merge(shard_2);
+        let expected = InvertedIndexBuilder::new()
+            .add(
+                1,
+                PostingList::from(vec![(1, 3.0), (2, 5.0), (6, 1.0), (9, 2.0)]),
+            )
+            .add(2, PostingList::from(vec![(1, 1.0)]))
+            .add(4, PostingList::from(vec![(2, 4.0)]))
+            .build();
+        assert_eq!(merged.postings.len(), expected.postings.len());
+        for (merged, built) in merged.postings.iter().zip(&expected.postings) {
+            assert_eq!(merged.elements, built.elements);
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "Record 4 is in the posting list of dimension 1")]
+    fn test_merge_overlapping_records() {
+        let shard_1 = InvertedIndexBuilder::new()
+            .add(1, PostingList::from(vec![(1, 3.0), (4, 1.0)]))
+            .build();
+        let shard_2 = InvertedIndexBuilder::new()
+            .add(1, PostingList::from(vec![(4, 2.0)]))
+            .build();
+        shard_1.merge(shard_2);
+    }
+
+    #[test]
+    fn test_from_vectors_matches_builder() {
+        let vectors = [
+            (4, SparseVector::new(vec![1, 2], vec![1.0, 2.0])),
+            (1, SparseVector::new(vec![1, 5], vec![3.0, 4.0])),
+            (2, SparseVector::new(vec![2], vec![0.5])),
+        ];
+        let inverted_index_ram =
+            InvertedIndexRam::from_vectors(vectors.iter().map(|(id, vector)| (*id, vector)));
+
+        let expected = InvertedIndexBuilder::new()
+            .add(1, PostingList::from(vec![(1, 3.0), (4, 1.0)]))
+            .add(2, PostingList::from(vec![(2, 0.5), (4, 2.0)]))
+            .add(5, PostingList::from(vec![(1, 4.0)]))
+            .build();
+        assert_eq!(inverted_index_ram.postings.len(), expected.postings.len());
+        for (from_vectors, built) in inverted_index_ram.postings.iter().zip(&expected.postings) {
+            assert_eq!(from_vectors.elements, built.elements);
+        }
+
+        let empty = InvertedIndexRam::from_vectors(std::iter::empty());
+        assert_eq!(
+            empty.postings.len(),
+            InvertedIndexBuilder::new().build().postings.len()
+        );
+    }
+
+    #[test]
+    fn test_insert_after_build() {
+        let mut inverted_index_ram = InvertedIndexBuilder::new()
+            .add(1, PostingList::from(vec![(1, 3.0), (4, 1.0)]))
+            .add(2, PostingList::from(vec![(1, 1.0), (4, 2.0)]))
+            .build();
+
+        // inserted between existing ids and on a dimension beyond the built ones
+        let vector = SparseVector::new(vec![5, 1], vec![4.0, 2.0]);
+        inverted_index_ram.insert(2, &vector);
+        inverted_index_ram.finalize();
+
+        let expected = InvertedIndexBuilder::new()
+            .add(1, PostingList::from(vec![(1, 3.0), (2, 2.0), (4, 1.0)]))
+            .add(2, PostingList::from(vec![(1, 1.0), (4, 2.0)]))
+            .add(5, PostingList::from(vec![(2, 4.0)]))
+            .build();
+        assert_eq!(inverted_index_ram.postings.len(), expected.postings.len());
+        for (inserted, built) in inverted_index_ram.postings.iter().zip(&expected.postings) {
+            assert_eq!(inserted.elements, built.elements);
+        }
+
+        let inverted_index = InvertedIndex::Ram(inverted_index_ram);
+        let query = SparseVector::new(vec![1, 5], vec![1.0, 1.0]);
+        assert_eq!(
+            SearchContext::new(&query, 1, &inverted_index).search(),
+            vec![ScoredCandidate {
+                score: 6.0,
+                vector_id: 2
+            }]
+        );
+    }
+
+    #[test]
+    fn test_compact() {
+        let build = || {
+            InvertedIndexBuilder::new()
+                .add(3, PostingList::from(vec![(1, 3.0), (4, 1.0)]))
+                .add(7, PostingList::from(vec![]))
+                .add(30000, PostingList::from(vec![(2, 4.0), (4, 2.0)]))
+                .build()
+        };
+        let original = InvertedIndex::Ram(build());
+        let (compacted, remap) = build().compact();
+
+        assert_eq!(remap.len(), 2);
+        assert_eq!(remap.compact_id(30000), Some(1));
+        assert_eq!(remap.compact_id(7), None);
+        assert_eq!(remap.original_id(0), Some(3));
+        assert_eq!(compacted.postings.len(), 2);
+
+        // same results, the query dimensions without posting are dropped
+        let query = SparseVector::new(vec![3, 7, 42, 30000], vec![1.0, 1.0, 1.0, 2.0]);
+        let translated = remap.translate(&query);
+        assert_eq!(translated, SparseVector::new(vec![0, 1], vec![1.0, 2.0]));
+        let compacted = InvertedIndex::Ram(compacted);
+        assert_eq!(compacted.search(&translated, 3), original.search(&query, 3));
+
+        let statistics = IndexStatistics::of_immutable_index(&compacted);
+        assert_eq!(statistics.key_count, 2);
+        assert_eq!(statistics.posting_count, 2);
+        let statistics = IndexStatistics::of_immutable_index(&original);
+        assert_eq!(statistics.key_count, 2);
+        assert_eq!(statistics.posting_count, 30001);
+
+        // an empty index keeps its single empty posting list
+        let (empty, remap) = InvertedIndexBuilder::new().build().compact();
+        assert!(remap.is_empty());
+        assert_eq!(empty.postings.len(), 1);
     }
 }
@@ -0,0 +1,40 @@
+use crate::sparse_index::common::types::{DimId, DimWeight, RecordId};
+use crate::sparse_index::immutable::inverted_index::inverted_index_ram::InvertedIndexRam;
+use crate::sparse_index::immutable::posting_list_soa::{PostingListSoa, PostingListSoaIterator};
+use std::mem::size_of;
+
+/// Inverted index from dimension id to posting list stored as struct of arrays
+pub struct InvertedIndexSoa {
+    pub postings: Vec<PostingListSoa>,
+}
+
+impl InvertedIndexSoa {
+    pub fn from_ram(inverted_index_ram: &InvertedIndexRam) -> InvertedIndexSoa {
+        InvertedIndexSoa {
+            postings: inverted_index_ram
+                .postings
+                .iter()
+                .map(PostingListSoa::from)
+                .collect(),
+        }
+    }
+
+    pub fn get(&self, id: &DimId) -> Option<PostingListSoaIterator<'_>> {
+        self.postings.get(*id as usize).map(PostingListSoa::iter)
+    }
+
+    /// Allocated bytes of the postings vector and the arrays of all posting lists
+    pub fn memory_bytes(&self) -> usize {
+        let postings_bytes = self.postings.capacity() * size_of::<PostingListSoa>();
+        let arrays_bytes: usize = self
+            .postings
+            .iter()
+            .map(|posting| {
+                posting.record_ids.capacity() * size_of::<RecordId>()
+                    + (posting.weights.capacity() + posting.max_next_weights.capacity())
+                        * size_of::<DimWeight>()
+            })
+            .sum();
+        postings_bytes + arrays_bytes
+    }
+}
@@ -1,23 +1,372 @@
-use crate::sparse_index::common::types::DimId;
-use crate::sparse_index::immutable::inverted_index::inverted_index_mmap::InvertedIndexMmap;
+use crate::sparse_index::common::fixed_length_pq::FixedLengthPriorityQueue;
+use crate::sparse_index::common::scored_candidate::ScoredCandidate;
+use crate::sparse_index::common::types::{DimId, DimWeight, RecordId};
+use crate::sparse_index::common::vector::SparseVector;
+use crate::sparse_index::immutable::inverted_index::inverted_index_arena::InvertedIndexArena;
+use crate::sparse_index::immutable::inverted_index::inverted_index_mmap::{
+    IdEncoding, InvertedIndexMmap, WeightEncoding,
+};
 use crate::sparse_index::immutable::inverted_index::inverted_index_ram::InvertedIndexRam;
-use crate::sparse_index::immutable::posting_list::PostingListIterator;
+use crate::sparse_index::immutable::inverted_index::inverted_index_soa::InvertedIndexSoa;
+use crate::sparse_index::immutable::posting_list::{
+    PostingElement, PostingListIter, PostingListIterator, PostingSummary, WeightPostingListIterator,
+};
+use crate::sparse_index::immutable::posting_list_compressed::CompressedPostingListIterator;
+use crate::sparse_index::immutable::posting_list_soa::PostingListSoaIterator;
+use crate::sparse_index::immutable::search_context::SearchContext;
+use half::f16;
+use std::io;
+use std::path::Path;
 
+pub mod inverted_index_arena;
 pub mod inverted_index_mmap;
 pub mod inverted_index_ram;
+pub mod inverted_index_soa;
 
 pub enum InvertedIndex {
     Ram(InvertedIndexRam),
     Mmap(InvertedIndexMmap),
+    Arena(InvertedIndexArena),
+    /// RAM index with the struct of arrays layout
+    Soa(InvertedIndexSoa),
+}
+
+/// Cursor over a posting list of an [`InvertedIndex`], whatever its storage and the
+/// encoding of a mmap index. Also an iterator over the remaining elements.
+pub enum InvertedIndexIterator<'a> {
+    Plain(PostingListIterator<'a>),
+    F16(WeightPostingListIterator<'a, f16>),
+    Compressed(CompressedPostingListIterator<'a>),
+    Soa(PostingListSoaIterator<'a>),
+}
+
+impl<'a> PostingListIter for InvertedIndexIterator<'a> {
+    #[inline]
+    fn peek(&self) -> Option<PostingElement> {
+        match self {
+            InvertedIndexIterator::Plain(iterator) => PostingListIter::peek(iterator),
+            InvertedIndexIterator::F16(iterator) => iterator.peek(),
+            InvertedIndexIterator::Compressed(iterator) => iterator.peek(),
+            InvertedIndexIterator::Soa(iterator) => iterator.peek(),
+        }
+    }
+
+    #[inline]
+    fn next_element(&mut self) -> Option<PostingElement> {
+        match self {
+            InvertedIndexIterator::Plain(iterator) => iterator.next_element(),
+            InvertedIndexIterator::F16(iterator) => iterator.next_element(),
+            InvertedIndexIterator::Compressed(iterator) => iterator.next_element(),
+            InvertedIndexIterator::Soa(iterator) => iterator.next_element(),
+        }
+    }
+
+    #[inline]
+    fn len_to_end(&self) -> usize {
+        match self {
+            InvertedIndexIterator::Plain(iterator) => PostingListIter::len_to_end(iterator),
+            InvertedIndexIterator::F16(iterator) => iterator.len_to_end(),
+            InvertedIndexIterator::Compressed(iterator) => iterator.len_to_end(),
+            InvertedIndexIterator::Soa(iterator) => iterator.len_to_end(),
+        }
+    }
+
+    #[inline]
+    fn skip_to(&mut self, id: RecordId) -> Option<PostingElement> {
+        match self {
+            InvertedIndexIterator::Plain(iterator) => PostingListIter::skip_to(iterator, id),
+            InvertedIndexIterator::F16(iterator) => iterator.skip_to(id),
+            InvertedIndexIterator::Compressed(iterator) => iterator.skip_to(id),
+            InvertedIndexIterator::Soa(iterator) => iterator.skip_to(id),
+        }
+    }
+
+    #[inline]
+    fn skip_to_end(&mut self) {
+        match self {
+            InvertedIndexIterator::Plain(iterator) => PostingListIter::skip_to_end(iterator),
+            InvertedIndexIterator::F16(iterator) => iterator.skip_to_end(),
+            InvertedIndexIterator::Compressed(iterator) => iterator.skip_to_end(),
+            InvertedIndexIterator::Soa(iterator) => iterator.skip_to_end(),
+        }
+    }
+
+    fn rewound(&self) -> Self {
+        match self {
+            InvertedIndexIterator::Plain(iterator) => {
+                InvertedIndexIterator::Plain(iterator.rewound())
+            }
+            InvertedIndexIterator::F16(iterator) => InvertedIndexIterator::F16(iterator.rewound()),
+            InvertedIndexIterator::Compressed(iterator) => {
+                InvertedIndexIterator::Compressed(iterator.rewound())
+            }
+            InvertedIndexIterator::Soa(iterator) => InvertedIndexIterator::Soa(iterator.rewound()),
+        }
+    }
+}
+
+impl<'a> Iterator for InvertedIndexIterator<'a> {
+    type Item = PostingElement;
+
+    fn next(&mut self) -> Option<PostingElement> {
+        self.next_element()
+    }
 }
 
 impl InvertedIndex {
-    pub fn get(&self, id: &DimId) -> Option<PostingListIterator> {
+    /// Posting list of a dimension, the mmap index is read in the encoding of its file
+    pub fn get(&self, id: &DimId) -> Option<InvertedIndexIterator<'_>> {
+        match self {
+            InvertedIndex::Ram(index) => index.get(id).map(|posting_list| {
+                InvertedIndexIterator::Plain(PostingListIterator::new(&posting_list.elements))
+            }),
+            InvertedIndex::Mmap(index) => match (index.id_encoding(), index.weight_encoding()) {
+                (IdEncoding::DeltaVarint, _) => index
+                    .get_compressed(id)
+                    .map(InvertedIndexIterator::Compressed),
+                (IdEncoding::Plain, WeightEncoding::F16) => index.get_f16(id).map(|elements| {
+                    InvertedIndexIterator::F16(WeightPostingListIterator::new(elements))
+                }),
+                // u8 quantized indexes are rejected on load
+                (IdEncoding::Plain, WeightEncoding::F32 | WeightEncoding::QuantizedU8) => {
+                    index.get(id).map(|elements| {
+                        InvertedIndexIterator::Plain(PostingListIterator::new(elements))
+                    })
+                }
+            },
+            InvertedIndex::Arena(index) => index
+                .get(id)
+                .map(|elements| InvertedIndexIterator::Plain(PostingListIterator::new(elements))),
+            InvertedIndex::Soa(index) => index.get(id).map(InvertedIndexIterator::Soa),
+        }
+    }
+
+    /// Summary of the posting list of a dimension without opening it,
+    /// see [`InvertedIndexMmap::posting_summary`]
+    pub fn posting_summary(&self, id: &DimId) -> Option<PostingSummary> {
         match self {
-            InvertedIndex::Ram(index) => index
+            InvertedIndex::Mmap(index) => index.posting_summary(id),
+            InvertedIndex::Ram(_) | InvertedIndex::Arena(_) | InvertedIndex::Soa(_) => self
                 .get(id)
-                .map(|posting_list| PostingListIterator::new(&posting_list.elements)),
-            InvertedIndex::Mmap(index) => index.get(id).map(PostingListIterator::new),
+                .map(|posting_iterator| PostingSummary::of(&posting_iterator)),
+        }
+    }
+
+    /// Number of dimensions, including empty ones
+    pub fn posting_count(&self) -> usize {
+        match self {
+            InvertedIndex::Ram(index) => index.postings.len(),
+            InvertedIndex::Mmap(index) => index.posting_count(),
+            InvertedIndex::Arena(index) => index.posting_count(),
+            InvertedIndex::Soa(index) => index.postings.len(),
+        }
+    }
+
+    /// Heap bytes of a RAM index, 0 for a mmap index, see [`InvertedIndexMmap::file_bytes`]
+    pub fn memory_bytes(&self) -> usize {
+        match self {
+            InvertedIndex::Ram(index) => index.memory_bytes(),
+            InvertedIndex::Mmap(_) => 0,
+            InvertedIndex::Arena(index) => index.memory_bytes(),
+            InvertedIndex::Soa(index) => index.memory_bytes(),
+        }
+    }
+
+    /// Length of the index file of a mmap index, 0 for a RAM index
+    pub fn file_bytes(&self) -> u64 {
+        match self {
+            InvertedIndex::Mmap(index) => index.file_bytes(),
+            InvertedIndex::Ram(_) | InvertedIndex::Arena(_) | InvertedIndex::Soa(_) => 0,
+        }
+    }
+
+    /// Load the mmap index in `path`, or read it whole in RAM if the file can not be
+    /// mapped on this platform or filesystem.
+    pub fn load_with_fallback<P: AsRef<Path>>(path: P) -> io::Result<InvertedIndex> {
+        Self::load_with_fallback_from(path.as_ref(), |path| InvertedIndexMmap::load(path))
+    }
+
+    fn load_with_fallback_from(
+        path: &Path,
+        load_mmap: impl FnOnce(&Path) -> io::Result<InvertedIndexMmap>,
+    ) -> io::Result<InvertedIndex> {
+        match load_mmap(path) {
+            Ok(index) => Ok(InvertedIndex::Mmap(index)),
+            // mmap fails with EINVAL or ENOTSUP where it is not supported
+            Err(err)
+                if matches!(
+                    err.kind(),
+                    io::ErrorKind::InvalidInput | io::ErrorKind::Unsupported
+                ) =>
+            {
+                InvertedIndexMmap::load_ram(path).map(InvertedIndex::Ram)
+            }
+            Err(err) => Err(err),
+        }
+    }
+
+    /// Top `top` documents of `query` by dot product with the block-max WAND traversal
+    /// of [`SearchContext::search_wand`], whatever the index storage and encoding
+    pub fn search(&self, query: &SparseVector, top: usize) -> Vec<ScoredCandidate> {
+        if top == 0 {
+            return Vec::new();
+        }
+        SearchContext::new(query, top, self).search_wand()
+    }
+
+    /// Top documents of a single term query, scored by `weight * query_weight`
+    pub fn term_results(
+        &self,
+        dim: DimId,
+        query_weight: DimWeight,
+        top: usize,
+    ) -> Vec<ScoredCandidate> {
+        let posting_list_iterator = match self.get(&dim) {
+            Some(iterator) if top > 0 => iterator,
+            _ => return Vec::new(),
+        };
+        let mut result_queue = FixedLengthPriorityQueue::new(top);
+        for element in posting_list_iterator {
+            result_queue.push(ScoredCandidate {
+                score: element.weight * query_weight,
+                vector_id: element.record_id,
+            });
+        }
+        result_queue.into_vec()
+    }
+
+    /// The `n` highest weight documents of a dimension, by decreasing weight
+    pub fn top_documents(&self, dim: DimId, n: usize) -> Vec<(RecordId, DimWeight)> {
+        let posting_list_iterator = match self.get(&dim) {
+            Some(iterator) if n > 0 => iterator,
+            _ => return Vec::new(),
+        };
+        let mut top = FixedLengthPriorityQueue::new(n);
+        for element in posting_list_iterator {
+            top.push(ScoredCandidate {
+                score: element.weight,
+                vector_id: element.record_id,
+            });
+        }
+        top.into_vec()
+            .into_iter()
+            .map(|candidate| (candidate.vector_id, candidate.score))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sparse_index::immutable::inverted_index::inverted_index_ram::InvertedIndexBuilder;
+    use crate::sparse_index::immutable::posting_list::PostingList;
+    use tempfile::Builder;
+
+    #[test]
+    fn test_top_documents() {
+        let inverted_index = InvertedIndex::Ram(
+            InvertedIndexBuilder::new()
+                .add(
+                    1,
+                    PostingList::from(vec![(1, 3.0), (2, 9.0), (3, 1.0), (4, 7.0), (5, 5.0)]),
+                )
+                .build(),
+        );
+
+        assert_eq!(
+            inverted_index.top_documents(1, 3),
+            vec![(2, 9.0), (4, 7.0), (5, 5.0)]
+        );
+        assert_eq!(inverted_index.top_documents(1, 10).len(), 5);
+        assert!(inverted_index.top_documents(1, 0).is_empty());
+        assert!(inverted_index.top_documents(0, 3).is_empty());
+        assert!(inverted_index.top_documents(42, 3).is_empty());
+    }
+
+    #[test]
+    fn test_load_with_fallback() {
+        let inverted_index_ram = InvertedIndexBuilder::new()
+            .add(1, PostingList::from(vec![(1, 3.0), (2, 9.0), (4, 7.0)]))
+            .add(3, PostingList::from(vec![(2, 1.0), (4, 4.0), (6, 2.0)]))
+            .build();
+        let tmp_dir_path = Builder::new().prefix("test_index_dir").tempdir().unwrap();
+        InvertedIndexMmap::convert_and_save(&inverted_index_ram, &tmp_dir_path).unwrap();
+
+        let mmap_index = InvertedIndex::load_with_fallback(tmp_dir_path.path()).unwrap();
+        assert!(matches!(mmap_index, InvertedIndex::Mmap(_)));
+        // mmap not available
+        let ram_index = InvertedIndex::load_with_fallback_from(tmp_dir_path.path(), |_| {
+            Err(io::Error::from(io::ErrorKind::InvalidInput))
+        })
+        .unwrap();
+        assert!(matches!(ram_index, InvertedIndex::Ram(_)));
+
+        let query = SparseVector::new(vec![1, 2, 3], vec![1.0, 5.0, 0.5]);
+        assert_eq!(
+            SearchContext::new(&query, 3, &ram_index).search(),
+            SearchContext::new(&query, 3, &mmap_index).search()
+        );
+
+        // other failures are not hidden by the fallback
+        let error = InvertedIndex::load_with_fallback_from(tmp_dir_path.path(), |_| {
+            Err(io::Error::from(io::ErrorKind::NotFound))
+        });
+        assert_eq!(error.err().unwrap().kind(), io::ErrorKind::NotFound);
+    }
+
+    #[test]
+    fn test_search() {
+        let inverted_index_ram = InvertedIndexBuilder::new()
+            .add(1, PostingList::from(vec![(1, 3.0), (2, 9.0), (4, 7.0)]))
+            .add(3, PostingList::from(vec![(2, 1.0), (4, 4.0), (6, 2.0)]))
+            .build();
+        let tmp_dir_path = Builder::new().prefix("test_index_dir").tempdir().unwrap();
+        let mmap_index = InvertedIndex::Mmap(
+            InvertedIndexMmap::convert_and_save(&inverted_index_ram, &tmp_dir_path).unwrap(),
+        );
+        let compressed_dir = Builder::new().prefix("test_index_dir").tempdir().unwrap();
+        let compressed_index = InvertedIndex::Mmap(
+            InvertedIndexMmap::convert_and_save_compressed(&inverted_index_ram, &compressed_dir)
+                .unwrap(),
+        );
+        let ram_index = InvertedIndex::Ram(inverted_index_ram);
+
+        let query = SparseVector::new(vec![1, 2, 3], vec![1.0, 5.0, 0.5]);
+        let expected = vec![
+            ScoredCandidate {
+                score: 9.5,
+                vector_id: 2,
+            },
+            ScoredCandidate {
+                score: 9.0,
+                vector_id: 4,
+            },
+        ];
+        assert_eq!(ram_index.search(&query, 2), expected);
+        assert_eq!(mmap_index.search(&query, 2), expected);
+        assert_eq!(compressed_index.search(&query, 2), expected);
+        assert_eq!(SearchContext::new(&query, 2, &ram_index).search(), expected);
+        assert!(ram_index.search(&query, 0).is_empty());
+    }
+
+    #[test]
+    fn test_term_results_matches_search() {
+        let inverted_index = InvertedIndex::Ram(
+            InvertedIndexBuilder::new()
+                .add(
+                    1,
+                    PostingList::from(vec![(1, 3.0), (2, 9.0), (3, 1.0), (4, 7.0), (5, 5.0)]),
+                )
+                .add(2, PostingList::from(vec![(2, 1.0), (6, 4.0)]))
+                .build(),
+        );
+
+        for top in [1, 3, 10] {
+            let query = SparseVector::new(vec![1], vec![0.5]);
+            let expected = SearchContext::new(&query, top, &inverted_index).search();
+            assert_eq!(inverted_index.term_results(1, 0.5, top), expected);
         }
+        assert!(inverted_index.term_results(1, 0.5, 0).is_empty());
+        assert!(inverted_index.term_results(42, 0.5, 3).is_empty());
     }
 }
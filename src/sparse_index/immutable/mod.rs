@@ -1,3 +1,5 @@
 pub mod inverted_index;
 pub mod posting_list;
+pub mod posting_list_compressed;
+pub mod posting_list_soa;
 pub mod search_context;
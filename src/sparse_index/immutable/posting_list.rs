@@ -1,16 +1,77 @@
 use crate::sparse_index::common::types::{DimWeight, RecordId};
+use crate::sparse_index::common::weight::Weight;
+use std::cell::OnceCell;
+use std::fmt;
 
+/// Element of a posting list, `repr(C)` as the elements are mapped from the index file
 #[derive(Debug, Copy, Clone, PartialEq)]
-pub struct PostingElement {
+#[repr(C)]
+pub struct PostingElement<W = DimWeight> {
     pub record_id: RecordId,
-    pub weight: DimWeight,
-    pub max_next_weight: DimWeight,
+    pub weight: W,
+    pub max_next_weight: W,
 }
 
+impl<W: Weight> PostingElement<W> {
+    /// Convert the weights of an f32 element, see [`Weight::from_f32`].
+    /// The conversion is monotonic, `max_next_weight` still bounds the converted weights.
+    #[inline]
+    pub fn from_f32(element: &PostingElement) -> PostingElement<W> {
+        PostingElement {
+            record_id: element.record_id,
+            weight: W::from_f32(element.weight),
+            max_next_weight: W::from_f32(element.max_next_weight),
+        }
+    }
+
+    /// The element with f32 weights, as seen by the search
+    #[inline]
+    pub fn to_f32(self) -> PostingElement {
+        PostingElement {
+            record_id: self.record_id,
+            weight: self.weight.to_f32(),
+            max_next_weight: self.max_next_weight.to_f32(),
+        }
+    }
+}
+
+/// Posting list with f32 weights by default, the indexes store f32 posting lists.
+/// Other [`Weight`] types are converted with [`PostingList::from_f32`] and searched
+/// with [`WeightPostingListIterator`].
 #[derive(Debug, Default, Clone)]
-pub struct PostingList {
+pub struct PostingList<W = DimWeight> {
     /// List of the posting elements ordered by id
-    pub elements: Vec<PostingElement>,
+    pub elements: Vec<PostingElement<W>>,
+}
+
+impl<W: Weight> PostingList<W> {
+    /// Convert the weights of an f32 posting list, see [`PostingElement::from_f32`]
+    pub fn from_f32(posting_list: &PostingList) -> PostingList<W> {
+        let elements = posting_list
+            .elements
+            .iter()
+            .map(PostingElement::from_f32)
+            .collect();
+        PostingList { elements }
+    }
+
+    /// Whether `record_id` is in the posting list, without an iterator
+    pub fn contains(&self, record_id: RecordId) -> bool {
+        self.get_weight(record_id).is_some()
+    }
+
+    /// Weight of `record_id` in the posting list, if present
+    pub fn get_weight(&self, record_id: RecordId) -> Option<W> {
+        find_weight(&self.elements, record_id)
+    }
+}
+
+/// Binary search of `record_id` in elements ordered by id
+fn find_weight<W: Copy>(elements: &[PostingElement<W>], record_id: RecordId) -> Option<W> {
+    elements
+        .binary_search_by_key(&record_id, |element| element.record_id)
+        .ok()
+        .map(|position| elements[position].weight)
 }
 
 impl PostingList {
@@ -22,8 +83,78 @@ impl PostingList {
         }
         posting_list.build()
     }
+
+    /// Build from records already sorted by strictly increasing id,
+    /// only the `max_next_weight` of each element is computed.
+    pub fn from_presorted(records: Vec<(RecordId, DimWeight)>) -> PostingList {
+        debug_assert!(
+            records.windows(2).all(|pair| pair[0].0 < pair[1].0),
+            "Posting list records must be sorted by unique id"
+        );
+        let mut elements: Vec<_> = records
+            .into_iter()
+            .map(|(record_id, weight)| PostingElement {
+                record_id,
+                weight,
+                max_next_weight: f32::NEG_INFINITY,
+            })
+            .collect();
+        fill_max_next_weight(&mut elements);
+        PostingList { elements }
+    }
+
+    /// Insert a record keeping the elements ordered by id, the weight of an existing
+    /// record is replaced. `max_next_weight` is stale until [`Self::recompute_max_next_weight`].
+    pub fn upsert(&mut self, record_id: RecordId, weight: DimWeight) {
+        let element = PostingElement {
+            record_id,
+            weight,
+            max_next_weight: f32::NEG_INFINITY,
+        };
+        match self
+            .elements
+            .binary_search_by_key(&record_id, |element| element.record_id)
+        {
+            Ok(position) => self.elements[position] = element,
+            Err(position) => self.elements.insert(position, element),
+        }
+    }
+
+    /// Recompute the `max_next_weight` of all elements
+    pub fn recompute_max_next_weight(&mut self) {
+        fill_max_next_weight(&mut self.elements);
+    }
 }
 
+/// Set the `max_next_weight` of each element from the weights of the following ones
+fn fill_max_next_weight(elements: &mut [PostingElement]) {
+    let mut max_next_weight = f32::NEG_INFINITY;
+    for element in elements.iter_mut().rev() {
+        element.max_next_weight = max_next_weight;
+        max_next_weight = max_next_weight.max(element.weight);
+    }
+}
+
+/// Invalid posting list content, see [`PostingBuilder::build_checked`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PostingError {
+    /// The record was added more than once
+    DuplicateId { record_id: RecordId },
+}
+
+impl fmt::Display for PostingError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PostingError::DuplicateId { record_id } => {
+                write!(f, "Duplicate id {} in posting list", record_id)
+            }
+        }
+    }
+}
+
+impl std::error::Error for PostingError {}
+
+#[derive(Default)]
 pub struct PostingBuilder {
     elements: Vec<PostingElement>,
 }
@@ -35,6 +166,12 @@ impl PostingBuilder {
         }
     }
 
+    pub fn with_capacity(capacity: usize) -> PostingBuilder {
+        PostingBuilder {
+            elements: Vec::with_capacity(capacity),
+        }
+    }
+
     pub fn add(&mut self, record_id: RecordId, weight: DimWeight) {
         self.elements.push(PostingElement {
             record_id,
@@ -43,6 +180,28 @@ impl PostingBuilder {
         });
     }
 
+    /// Multiply all the weights by `factor`, e.g. a global term weight
+    pub fn scale_weights(&mut self, factor: DimWeight) {
+        for element in self.elements.iter_mut() {
+            element.weight *= factor;
+        }
+    }
+
+    /// Build a posting list keeping only the `max_len` highest weight elements.
+    /// Lowest weight documents are dropped, trading exactness for bounded size.
+    pub fn build_capped(mut self, max_len: usize) -> PostingList {
+        if self.elements.len() > max_len {
+            if max_len > 0 {
+                // partition by weight descending, then drop the tail
+                self.elements
+                    .select_nth_unstable_by(max_len - 1, |a, b| b.weight.total_cmp(&a.weight));
+            }
+            self.elements.truncate(max_len);
+        }
+        self.build()
+    }
+
+    /// Build from trusted records, duplicate ids are only checked in debug builds
     pub fn build(mut self) -> PostingList {
         // Sort by id
         self.elements.sort_unstable_by_key(|e| e.record_id);
@@ -50,21 +209,39 @@ impl PostingBuilder {
         // Check for duplicates
         #[cfg(debug_assertions)]
         {
-            if let Some(e) = self
-                .elements
-                .windows(2)
-                .find(|e| e[0].record_id == e[1].record_id)
-            {
-                panic!("Duplicate id {} in posting list", e[0].record_id);
+            if let Err(err) = self.check_duplicates() {
+                panic!("{}", err);
             }
         }
 
-        // Calculate max_next_weight
-        let mut max_next_weight = f32::NEG_INFINITY;
-        for element in self.elements.iter_mut().rev() {
-            element.max_next_weight = max_next_weight;
-            max_next_weight = max_next_weight.max(element.weight);
+        self.finish()
+    }
+
+    /// Like [`Self::build`] but duplicate ids are always checked and reported as an error,
+    /// for the records of untrusted input
+    pub fn build_checked(mut self) -> Result<PostingList, PostingError> {
+        self.elements.sort_unstable_by_key(|e| e.record_id);
+        self.check_duplicates()?;
+        Ok(self.finish())
+    }
+
+    /// Elements must be sorted by id
+    fn check_duplicates(&self) -> Result<(), PostingError> {
+        match self
+            .elements
+            .windows(2)
+            .find(|e| e[0].record_id == e[1].record_id)
+        {
+            Some(e) => Err(PostingError::DuplicateId {
+                record_id: e[0].record_id,
+            }),
+            None => Ok(()),
         }
+    }
+
+    fn finish(mut self) -> PostingList {
+        // Calculate max_next_weight
+        fill_max_next_weight(&mut self.elements);
 
         PostingList {
             elements: self.elements,
@@ -72,6 +249,39 @@ impl PostingBuilder {
     }
 }
 
+/// Cursor over a posting list, implemented by each posting layout
+/// so that the search can be generic over the layout.
+pub trait PostingListIter {
+    /// Returns the next element without advancing.
+    fn peek(&self) -> Option<PostingElement>;
+
+    /// Record id of the next element and an upper bound of the weights from it to the end,
+    /// what a search needs to order and prune the posting list without scoring it.
+    fn peek_bound(&self) -> Option<(RecordId, DimWeight)> {
+        self.peek().map(|element| {
+            (
+                element.record_id,
+                element.weight.max(element.max_next_weight),
+            )
+        })
+    }
+
+    /// Returns the next element and advances.
+    fn next_element(&mut self) -> Option<PostingElement>;
+
+    /// Returns the number of elements from the current position to the end of the list.
+    fn len_to_end(&self) -> usize;
+
+    /// Same contract as [`PostingListIterator::skip_to`].
+    fn skip_to(&mut self, id: RecordId) -> Option<PostingElement>;
+
+    /// Skips to the end of the posting list.
+    fn skip_to_end(&mut self);
+
+    /// A fresh cursor over the same posting list, positioned at its start.
+    fn rewound(&self) -> Self;
+}
+
 /// Iterator over posting list elements offering skipping abilities to avoid full iteration.
 pub struct PostingListIterator<'a> {
     pub elements: &'a [PostingElement],
@@ -110,11 +320,24 @@ impl<'a> PostingListIterator<'a> {
         self.elements.len() - self.current_index
     }
 
+    /// Whether `record_id` is at or after the current position, the iterator does not move.
+    pub fn contains(&self, record_id: RecordId) -> bool {
+        self.get_weight(record_id).is_some()
+    }
+
+    /// Weight of `record_id` if at or after the current position, the iterator does not move.
+    pub fn get_weight(&self, record_id: RecordId) -> Option<DimWeight> {
+        find_weight(&self.elements[self.current_index..], record_id)
+    }
+
     /// Tries to find the element with ID == id and returns it.
     /// If the element is not found, the iterator is advanced to the next element with ID > id
     /// and None is returned.
     /// If the iterator is already at the end, None is returned.
     /// If the iterator skipped to the end, None is returned and current index is set to the length of the list.
+    /// The iterator never moves backward: if the current element already has ID > id,
+    /// None is returned and the position is unchanged, even if an element with ID == id
+    /// was passed earlier.
     /// Uses binary search.
     pub fn skip_to(&mut self, id: RecordId) -> Option<&PostingElement> {
         if self.current_index >= self.elements.len() {
@@ -144,9 +367,266 @@ impl<'a> PostingListIterator<'a> {
     }
 }
 
+impl<'a> PostingListIter for PostingListIterator<'a> {
+    #[inline]
+    fn peek(&self) -> Option<PostingElement> {
+        PostingListIterator::peek(self).copied()
+    }
+
+    #[inline]
+    fn next_element(&mut self) -> Option<PostingElement> {
+        self.next().copied()
+    }
+
+    #[inline]
+    fn len_to_end(&self) -> usize {
+        PostingListIterator::len_to_end(self)
+    }
+
+    #[inline]
+    fn skip_to(&mut self, id: RecordId) -> Option<PostingElement> {
+        PostingListIterator::skip_to(self, id).copied()
+    }
+
+    #[inline]
+    fn skip_to_end(&mut self) {
+        PostingListIterator::skip_to_end(self);
+    }
+
+    fn rewound(&self) -> Self {
+        PostingListIterator::new(self.elements)
+    }
+}
+
+/// Cursor over a posting list of any [`Weight`] type, yielding f32 elements
+pub struct WeightPostingListIterator<'a, W: Weight> {
+    elements: &'a [PostingElement<W>],
+    current_index: usize,
+}
+
+impl<'a, W: Weight> WeightPostingListIterator<'a, W> {
+    pub fn new(elements: &'a [PostingElement<W>]) -> WeightPostingListIterator<'a, W> {
+        WeightPostingListIterator {
+            elements,
+            current_index: 0,
+        }
+    }
+}
+
+impl<'a, W: Weight> PostingListIter for WeightPostingListIterator<'a, W> {
+    #[inline]
+    fn peek(&self) -> Option<PostingElement> {
+        self.elements
+            .get(self.current_index)
+            .copied()
+            .map(PostingElement::to_f32)
+    }
+
+    #[inline]
+    fn next_element(&mut self) -> Option<PostingElement> {
+        let element = self.peek()?;
+        self.current_index += 1;
+        Some(element)
+    }
+
+    #[inline]
+    fn len_to_end(&self) -> usize {
+        self.elements.len() - self.current_index
+    }
+
+    fn skip_to(&mut self, id: RecordId) -> Option<PostingElement> {
+        if self.current_index >= self.elements.len() {
+            return None;
+        }
+        match self.elements[self.current_index..].binary_search_by(|e| e.record_id.cmp(&id)) {
+            Ok(found_offset) => {
+                self.current_index += found_offset;
+                self.peek()
+            }
+            Err(insert_index) => {
+                self.current_index += insert_index;
+                None
+            }
+        }
+    }
+
+    #[inline]
+    fn skip_to_end(&mut self) {
+        self.current_index = self.elements.len();
+    }
+
+    fn rewound(&self) -> Self {
+        WeightPostingListIterator::new(self.elements)
+    }
+}
+
+/// What a search needs of a posting list before reading its elements
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[repr(C)]
+pub struct PostingSummary {
+    /// Number of elements
+    pub len: u64,
+    /// Record id of the first element, 0 for an empty list
+    pub first_record_id: RecordId,
+    /// Maximum weight of the elements, negative infinity for an empty list
+    pub max_weight: DimWeight,
+}
+
+impl PostingSummary {
+    /// Summary of the posting list from the position of `iterator`
+    pub fn of(iterator: &impl PostingListIter) -> PostingSummary {
+        let (first_record_id, max_weight) = iterator
+            .peek_bound()
+            .unwrap_or((0, DimWeight::NEG_INFINITY));
+        PostingSummary {
+            len: iterator.len_to_end() as u64,
+            first_record_id,
+            max_weight,
+        }
+    }
+}
+
+/// Cursor created on the first access to the elements of the posting list by `open`, e.g.
+/// the slice of a mmap posting list whose element pages are then only faulted for the lists
+/// a search reads. Its length, first record id and maximum weight are known from `summary`
+/// so a search can order and prune it before opening it: skipping to a record before the
+/// first one or to the end does not open the list.
+pub struct LazyPostingListIterator<P, F> {
+    summary: PostingSummary,
+    open: F,
+    inner: OnceCell<P>,
+    exhausted: bool,
+}
+
+impl<P: PostingListIter, F: Fn() -> P + Clone> LazyPostingListIterator<P, F> {
+    pub fn new(summary: PostingSummary, open: F) -> LazyPostingListIterator<P, F> {
+        LazyPostingListIterator {
+            summary,
+            open,
+            inner: OnceCell::new(),
+            exhausted: false,
+        }
+    }
+
+    /// Number of elements of the posting list
+    pub fn len(&self) -> usize {
+        self.summary.len as usize
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.summary.len == 0
+    }
+
+    /// Whether the posting list was opened
+    pub fn is_opened(&self) -> bool {
+        self.inner.get().is_some()
+    }
+
+    fn inner(&self) -> &P {
+        self.inner.get_or_init(&self.open)
+    }
+
+    fn inner_mut(&mut self) -> &mut P {
+        self.inner.get_or_init(&self.open);
+        self.inner.get_mut().expect("just opened")
+    }
+}
+
+impl<P: PostingListIter, F: Fn() -> P + Clone> PostingListIter for LazyPostingListIterator<P, F> {
+    #[inline]
+    fn peek(&self) -> Option<PostingElement> {
+        if self.exhausted {
+            return None;
+        }
+        self.inner().peek()
+    }
+
+    #[inline]
+    fn peek_bound(&self) -> Option<(RecordId, DimWeight)> {
+        match (self.exhausted, self.inner.get()) {
+            (true, _) => None,
+            (false, Some(inner)) => inner.peek_bound(),
+            (false, None) if self.is_empty() => None,
+            (false, None) => Some((self.summary.first_record_id, self.summary.max_weight)),
+        }
+    }
+
+    #[inline]
+    fn next_element(&mut self) -> Option<PostingElement> {
+        if self.exhausted {
+            return None;
+        }
+        self.inner_mut().next_element()
+    }
+
+    #[inline]
+    fn len_to_end(&self) -> usize {
+        match (self.exhausted, self.inner.get()) {
+            (true, _) => 0,
+            (false, Some(inner)) => inner.len_to_end(),
+            (false, None) => self.len(),
+        }
+    }
+
+    fn skip_to(&mut self, id: RecordId) -> Option<PostingElement> {
+        if self.exhausted {
+            return None;
+        }
+        if !self.is_opened() && (self.is_empty() || id < self.summary.first_record_id) {
+            // the cursor stays at the first element
+            return None;
+        }
+        self.inner_mut().skip_to(id)
+    }
+
+    fn skip_to_end(&mut self) {
+        self.exhausted = true;
+    }
+
+    fn rewound(&self) -> Self {
+        LazyPostingListIterator::new(self.summary, self.open.clone())
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use half::f16;
+
+    #[test]
+    fn test_build_checked() {
+        let mut builder = PostingBuilder::new();
+        builder.add(3, 1.0);
+        builder.add(1, 2.0);
+        let posting_list = builder.build_checked().unwrap();
+        assert_eq!(posting_list.elements[0].record_id, 1);
+        assert_eq!(posting_list.elements[0].max_next_weight, 1.0);
+
+        let mut builder = PostingBuilder::new();
+        builder.add(3, 1.0);
+        builder.add(1, 2.0);
+        builder.add(3, 4.0);
+        let err = builder.build_checked().unwrap_err();
+        assert_eq!(err, PostingError::DuplicateId { record_id: 3 });
+        assert_eq!(err.to_string(), "Duplicate id 3 in posting list");
+    }
+
+    #[test]
+    fn test_contains_and_get_weight() {
+        let posting_list = PostingList::from(vec![(1, 1.0), (5, 5.0), (3, 2.0)]);
+        assert!(posting_list.contains(3));
+        assert!(!posting_list.contains(4));
+        assert_eq!(posting_list.get_weight(5), Some(5.0));
+        assert_eq!(posting_list.get_weight(0), None);
+        assert_eq!(posting_list.get_weight(6), None);
+        assert!(!PostingList::<DimWeight>::default().contains(1));
+
+        let mut iterator = PostingListIterator::new(&posting_list.elements);
+        iterator.next();
+        assert_eq!(iterator.get_weight(3), Some(2.0));
+        assert!(!iterator.contains(1));
+        assert_eq!(iterator.peek().unwrap().record_id, 3);
+    }
 
     #[test]
     fn test_posting_operations() {
@@ -184,4 +664,155 @@ mod tests {
         assert!(iter.skip_to(21).is_none());
         assert!(iter.peek().is_none());
     }
+
+    #[test]
+    fn test_skip_to_backward() {
+        let posting_list = PostingList::from(vec![(1, 1.0), (3, 2.0), (5, 3.0), (7, 4.0)]);
+        let mut iter = PostingListIterator::new(&posting_list.elements);
+
+        assert_eq!(iter.skip_to(5).unwrap().record_id, 5);
+
+        // target before the current element, already passed: no rewind
+        assert!(iter.skip_to(3).is_none());
+        assert_eq!(iter.peek().unwrap().record_id, 5);
+
+        // target before the current element, never present: no move
+        assert!(iter.skip_to(4).is_none());
+        assert_eq!(iter.peek().unwrap().record_id, 5);
+
+        // skipping to the current element is a no-op returning it
+        assert_eq!(iter.skip_to(5).unwrap().record_id, 5);
+        assert_eq!(iter.len_to_end(), 2);
+
+        // backward skip once exhausted stays at the end
+        iter.skip_to_end();
+        assert!(iter.skip_to(1).is_none());
+        assert!(iter.peek().is_none());
+    }
+
+    #[test]
+    fn test_posting_build_capped() {
+        let mut builder = PostingBuilder::new();
+        builder.add(1, 1.0);
+        builder.add(2, 5.0);
+        builder.add(3, 2.0);
+        builder.add(4, 4.0);
+        builder.add(5, 3.0);
+
+        let posting_list = builder.build_capped(3);
+
+        // highest weights kept, still ordered by id
+        let ids: Vec<_> = posting_list.elements.iter().map(|e| e.record_id).collect();
+        assert_eq!(ids, vec![2, 4, 5]);
+        let max_next: Vec<_> = posting_list
+            .elements
+            .iter()
+            .map(|e| e.max_next_weight)
+            .collect();
+        assert_eq!(max_next, vec![4.0, 3.0, f32::NEG_INFINITY]);
+    }
+
+    #[test]
+    fn test_recompute_max_next_weight() {
+        let mut posting_list = PostingList::from(vec![(1, 3.0), (2, 1.0), (4, 2.0), (6, 0.5)]);
+        posting_list.upsert(3, 5.0);
+        posting_list.upsert(5, 0.2);
+        posting_list.recompute_max_next_weight();
+
+        let ids: Vec<_> = posting_list.elements.iter().map(|e| e.record_id).collect();
+        assert_eq!(ids, vec![1, 2, 3, 4, 5, 6]);
+        for (position, element) in posting_list.elements.iter().enumerate() {
+            let expected = posting_list.elements[position + 1..]
+                .iter()
+                .map(|e| e.weight)
+                .fold(f32::NEG_INFINITY, f32::max);
+            assert_eq!(element.max_next_weight, expected);
+        }
+    }
+
+    #[test]
+    fn test_f16_iterator_matches_f32() {
+        let posting_list = PostingList::from(vec![
+            (1, 1.0),
+            (2, 2.1),
+            (3, 2.0),
+            (5, 5.0),
+            (7, 4.0),
+            (8, 3.4),
+            (10, 3.0),
+            (20, 0.001),
+        ]);
+        let quantized = PostingList::<f16>::from_f32(&posting_list);
+        assert_eq!(std::mem::size_of::<PostingElement<f16>>(), 8);
+
+        let mut f32_iter = PostingListIterator::new(&posting_list.elements);
+        let mut f16_iter = WeightPostingListIterator::new(&quantized.elements);
+        let close_weight = |a: f32, b: f32| a == b || (a - b).abs() <= a.abs() / 2048.0;
+        let close = |a: Option<PostingElement>, b: Option<PostingElement>| match (a, b) {
+            (Some(a), Some(b)) => {
+                assert_eq!(a.record_id, b.record_id);
+                assert!(close_weight(a.weight, b.weight), "{:?} {:?}", a, b);
+                // the last element has an infinite max_next_weight
+                assert!(close_weight(a.max_next_weight, b.max_next_weight));
+            }
+            (a, b) => assert_eq!(a, b),
+        };
+
+        close(f32_iter.next_element(), f16_iter.next_element());
+        for id in [3, 4, 6, 10, 2, 21] {
+            close(
+                PostingListIter::skip_to(&mut f32_iter, id),
+                f16_iter.skip_to(id),
+            );
+            assert_eq!(
+                PostingListIter::len_to_end(&f32_iter),
+                f16_iter.len_to_end()
+            );
+        }
+        assert_eq!(f16_iter.next_element(), None);
+        close(
+            f16_iter.rewound().peek(),
+            posting_list.elements.first().copied(),
+        );
+    }
+
+    #[test]
+    fn lazy_iterator_opens_on_first_access() {
+        let posting_list = PostingList::from(vec![(1, 1.0), (3, 2.0), (5, 3.0)]);
+        let opened = std::cell::Cell::new(0);
+        let open = || {
+            opened.set(opened.get() + 1);
+            PostingListIterator::new(&posting_list.elements)
+        };
+
+        let summary = PostingSummary::of(&PostingListIterator::new(&posting_list.elements));
+        assert_eq!(
+            summary,
+            PostingSummary {
+                len: 3,
+                first_record_id: 1,
+                max_weight: 3.0,
+            }
+        );
+        let mut iter = LazyPostingListIterator::new(summary, open);
+        assert_eq!(iter.len_to_end(), 3);
+        // ordered and skipped before the first record from the summary
+        assert_eq!(iter.peek_bound(), Some((1, 3.0)));
+        assert_eq!(iter.skip_to(0), None);
+        assert!(!iter.is_opened());
+        assert_eq!(iter.peek().map(|element| element.record_id), Some(1));
+        assert_eq!(iter.skip_to(3).map(|element| element.record_id), Some(3));
+        assert_eq!(iter.len_to_end(), 2);
+        assert_eq!(opened.get(), 1);
+
+        // skipping an unopened list to its end does not open it
+        let mut rewound = iter.rewound();
+        assert_eq!(rewound.len_to_end(), 3);
+        rewound.skip_to_end();
+        assert_eq!(rewound.peek(), None);
+        assert_eq!(rewound.next_element(), None);
+        assert_eq!(rewound.len_to_end(), 0);
+        assert!(!rewound.is_opened());
+        assert_eq!(opened.get(), 1);
+    }
 }
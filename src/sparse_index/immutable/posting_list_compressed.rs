@@ -0,0 +1,248 @@
+use crate::sparse_index::common::mmap_ops::{transmute_from_u8_to_slice, transmute_to_u8_slice};
+use crate::sparse_index::common::types::{DimWeight, RecordId};
+use crate::sparse_index::immutable::posting_list::{PostingElement, PostingListIter};
+use std::mem::size_of;
+
+/// Number of elements per block, the first record id of each block is stored in full
+/// in the skip table, the following ones as varint deltas
+const BLOCK_SIZE: usize = 128;
+
+/// Entry of the skip table, one per block
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+#[repr(C)]
+struct SkipEntry {
+    first_record_id: RecordId,
+    /// Offset of the deltas of the block in the varint section
+    deltas_offset: u32,
+}
+
+/// Encode a posting list with delta + varint record ids. Layout, in u32 words then bytes:
+///
+/// `len`, `block_count`, the skip table, the weights, the max next weights, the varint
+/// deltas, padded to 4 bytes so that the next posting list stays aligned.
+pub fn encode_posting(elements: &[PostingElement]) -> Vec<u8> {
+    let mut skip_table = Vec::with_capacity(elements.len().div_ceil(BLOCK_SIZE));
+    let mut deltas = Vec::new();
+    for block in elements.chunks(BLOCK_SIZE) {
+        skip_table.push(SkipEntry {
+            first_record_id: block[0].record_id,
+            deltas_offset: deltas.len() as u32,
+        });
+        for pair in block.windows(2) {
+            write_varint(&mut deltas, pair[1].record_id - pair[0].record_id);
+        }
+    }
+    let weights: Vec<DimWeight> = elements.iter().map(|e| e.weight).collect();
+    let max_next_weights: Vec<DimWeight> = elements.iter().map(|e| e.max_next_weight).collect();
+
+    let mut bytes = Vec::new();
+    bytes.extend_from_slice(transmute_to_u8_slice(&[
+        elements.len() as u32,
+        skip_table.len() as u32,
+    ]));
+    bytes.extend_from_slice(transmute_to_u8_slice(&skip_table));
+    bytes.extend_from_slice(transmute_to_u8_slice(&weights));
+    bytes.extend_from_slice(transmute_to_u8_slice(&max_next_weights));
+    bytes.extend_from_slice(&deltas);
+    bytes.resize(bytes.len().next_multiple_of(size_of::<u32>()), 0);
+    bytes
+}
+
+fn write_varint(bytes: &mut Vec<u8>, mut value: u32) {
+    while value >= 0x80 {
+        bytes.push(value as u8 | 0x80);
+        value >>= 7;
+    }
+    bytes.push(value as u8);
+}
+
+/// Cursor over an encoded posting list, the record ids are decoded while iterating.
+/// `skip_to` jumps over whole blocks with the skip table, then decodes within the block.
+pub struct CompressedPostingListIterator<'a> {
+    skip_table: &'a [SkipEntry],
+    weights: &'a [DimWeight],
+    max_next_weights: &'a [DimWeight],
+    deltas: &'a [u8],
+    current_index: usize,
+    current_record_id: RecordId,
+    deltas_position: usize, // varint of the element after the current one
+}
+
+impl<'a> CompressedPostingListIterator<'a> {
+    /// `bytes` must come from [`encode_posting`] and be aligned on 4 bytes
+    pub fn new(bytes: &'a [u8]) -> CompressedPostingListIterator<'a> {
+        let words = size_of::<u32>();
+        let header: &[u32] = transmute_from_u8_to_slice(&bytes[..2 * words]);
+        let (len, block_count) = (header[0] as usize, header[1] as usize);
+        let skip_table_end = 2 * words + block_count * size_of::<SkipEntry>();
+        let weights_end = skip_table_end + len * size_of::<DimWeight>();
+        let max_next_weights_end = weights_end + len * size_of::<DimWeight>();
+        let skip_table: &[SkipEntry] =
+            transmute_from_u8_to_slice(&bytes[2 * words..skip_table_end]);
+        let mut iterator = CompressedPostingListIterator {
+            skip_table,
+            weights: transmute_from_u8_to_slice(&bytes[skip_table_end..weights_end]),
+            max_next_weights: transmute_from_u8_to_slice(&bytes[weights_end..max_next_weights_end]),
+            deltas: &bytes[max_next_weights_end..],
+            current_index: 0,
+            current_record_id: 0,
+            deltas_position: 0,
+        };
+        iterator.enter_block(0);
+        iterator
+    }
+
+    /// Position on the first element of the block
+    fn enter_block(&mut self, block: usize) {
+        self.current_index = block * BLOCK_SIZE;
+        if let Some(entry) = self.skip_table.get(block) {
+            self.current_record_id = entry.first_record_id;
+            self.deltas_position = entry.deltas_offset as usize;
+        }
+    }
+
+    fn read_varint(&mut self) -> u32 {
+        let mut value = 0;
+        let mut shift = 0;
+        loop {
+            let byte = self.deltas[self.deltas_position];
+            self.deltas_position += 1;
+            value |= ((byte & 0x7f) as u32) << shift;
+            if byte < 0x80 {
+                return value;
+            }
+            shift += 7;
+        }
+    }
+
+    fn advance(&mut self) {
+        self.current_index += 1;
+        if self.current_index >= self.weights.len() {
+            return;
+        }
+        if self.current_index.is_multiple_of(BLOCK_SIZE) {
+            self.enter_block(self.current_index / BLOCK_SIZE);
+        } else {
+            self.current_record_id += self.read_varint();
+        }
+    }
+}
+
+impl<'a> PostingListIter for CompressedPostingListIterator<'a> {
+    #[inline]
+    fn peek(&self) -> Option<PostingElement> {
+        (self.current_index < self.weights.len()).then(|| PostingElement {
+            record_id: self.current_record_id,
+            weight: self.weights[self.current_index],
+            max_next_weight: self.max_next_weights[self.current_index],
+        })
+    }
+
+    #[inline]
+    fn next_element(&mut self) -> Option<PostingElement> {
+        let element = self.peek()?;
+        self.advance();
+        Some(element)
+    }
+
+    #[inline]
+    fn len_to_end(&self) -> usize {
+        self.weights.len() - self.current_index
+    }
+
+    fn skip_to(&mut self, id: RecordId) -> Option<PostingElement> {
+        self.peek()?;
+        if self.current_record_id >= id {
+            // never moves backward
+            return self.peek().filter(|element| element.record_id == id);
+        }
+        // last block starting at or before `id`
+        let current_block = self.current_index / BLOCK_SIZE;
+        let following_blocks = self.skip_table[current_block + 1..]
+            .partition_point(|entry| entry.first_record_id <= id);
+        if following_blocks > 0 {
+            self.enter_block(current_block + following_blocks);
+        }
+        while self.peek()?.record_id < id {
+            self.advance();
+        }
+        self.peek().filter(|element| element.record_id == id)
+    }
+
+    #[inline]
+    fn skip_to_end(&mut self) {
+        self.current_index = self.weights.len();
+    }
+
+    fn rewound(&self) -> Self {
+        let mut iterator = CompressedPostingListIterator {
+            current_index: 0,
+            current_record_id: 0,
+            deltas_position: 0,
+            ..*self
+        };
+        iterator.enter_block(0);
+        iterator
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sparse_index::immutable::posting_list::{PostingList, PostingListIterator};
+
+    #[test]
+    fn test_compressed_iterator_matches_plain() {
+        // dense ids with a few large gaps, over several blocks
+        let records: Vec<_> = (0..1000)
+            .map(|i| (i * 3 + if i > 500 { 100_000 } else { 0 }, (i % 17) as f32))
+            .collect();
+        let posting_list = PostingList::from(records);
+        let bytes = encode_posting(&posting_list.elements);
+        assert_eq!(bytes.len() % size_of::<u32>(), 0);
+        // 1 byte deltas mostly, instead of 4 bytes ids
+        assert!(bytes.len() < posting_list.elements.len() * size_of::<PostingElement>());
+        // copy into u32 words for the alignment
+        let words: Vec<u32> = bytes
+            .chunks(4)
+            .map(|word| u32::from_ne_bytes(word.try_into().unwrap()))
+            .collect();
+        let bytes = transmute_to_u8_slice(&words);
+
+        let decoded: Vec<_> = std::iter::from_fn({
+            let mut iterator = CompressedPostingListIterator::new(bytes);
+            move || iterator.next_element()
+        })
+        .collect();
+        assert_eq!(decoded, posting_list.elements);
+
+        let mut plain = PostingListIterator::new(&posting_list.elements);
+        let mut compressed = CompressedPostingListIterator::new(bytes);
+        for id in [
+            0, 1, 3, 2, 300, 301, 1497, 1500, 101_503, 101_504, 500, 102_997, 200_000,
+        ] {
+            assert_eq!(
+                PostingListIter::skip_to(&mut plain, id),
+                compressed.skip_to(id),
+                "skip to {}",
+                id
+            );
+            assert_eq!(PostingListIter::peek(&plain), compressed.peek(), "{}", id);
+            assert_eq!(PostingListIter::len_to_end(&plain), compressed.len_to_end());
+        }
+        assert_eq!(
+            compressed.rewound().peek(),
+            posting_list.elements.first().copied()
+        );
+
+        // empty posting list
+        let empty = encode_posting(&[]);
+        let words: Vec<u32> = empty
+            .chunks(4)
+            .map(|word| u32::from_ne_bytes(word.try_into().unwrap()))
+            .collect();
+        let mut iterator = CompressedPostingListIterator::new(transmute_to_u8_slice(&words));
+        assert_eq!(iterator.len_to_end(), 0);
+        assert_eq!(iterator.skip_to(5), None);
+    }
+}
@@ -0,0 +1,142 @@
+use crate::sparse_index::common::types::{DimWeight, RecordId};
+use crate::sparse_index::immutable::posting_list::{PostingElement, PostingList, PostingListIter};
+
+/// Posting list stored as struct of arrays, one array per posting element field.
+/// Skipping by id only touches the ids array.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct PostingListSoa {
+    pub record_ids: Vec<RecordId>,
+    pub weights: Vec<DimWeight>,
+    pub max_next_weights: Vec<DimWeight>,
+}
+
+impl From<&PostingList> for PostingListSoa {
+    fn from(posting_list: &PostingList) -> Self {
+        let elements = &posting_list.elements;
+        PostingListSoa {
+            record_ids: elements.iter().map(|e| e.record_id).collect(),
+            weights: elements.iter().map(|e| e.weight).collect(),
+            max_next_weights: elements.iter().map(|e| e.max_next_weight).collect(),
+        }
+    }
+}
+
+impl PostingListSoa {
+    pub fn iter(&self) -> PostingListSoaIterator<'_> {
+        PostingListSoaIterator {
+            record_ids: &self.record_ids,
+            weights: &self.weights,
+            max_next_weights: &self.max_next_weights,
+            current_index: 0,
+        }
+    }
+}
+
+/// Cursor over a [`PostingListSoa`]
+pub struct PostingListSoaIterator<'a> {
+    record_ids: &'a [RecordId],
+    weights: &'a [DimWeight],
+    max_next_weights: &'a [DimWeight],
+    current_index: usize,
+}
+
+impl<'a> PostingListSoaIterator<'a> {
+    #[inline]
+    fn element(&self, index: usize) -> PostingElement {
+        PostingElement {
+            record_id: self.record_ids[index],
+            weight: self.weights[index],
+            max_next_weight: self.max_next_weights[index],
+        }
+    }
+}
+
+impl<'a> PostingListIter for PostingListSoaIterator<'a> {
+    #[inline]
+    fn peek(&self) -> Option<PostingElement> {
+        (self.current_index < self.record_ids.len()).then(|| self.element(self.current_index))
+    }
+
+    #[inline]
+    fn next_element(&mut self) -> Option<PostingElement> {
+        let element = self.peek()?;
+        self.current_index += 1;
+        Some(element)
+    }
+
+    #[inline]
+    fn len_to_end(&self) -> usize {
+        self.record_ids.len() - self.current_index
+    }
+
+    fn skip_to(&mut self, id: RecordId) -> Option<PostingElement> {
+        if self.current_index >= self.record_ids.len() {
+            return None;
+        }
+        match self.record_ids[self.current_index..].binary_search(&id) {
+            Ok(found_offset) => {
+                self.current_index += found_offset;
+                Some(self.element(self.current_index))
+            }
+            Err(insert_index) => {
+                self.current_index += insert_index;
+                None
+            }
+        }
+    }
+
+    #[inline]
+    fn skip_to_end(&mut self) {
+        self.current_index = self.record_ids.len();
+    }
+
+    fn rewound(&self) -> Self {
+        PostingListSoaIterator {
+            current_index: 0,
+            ..*self
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sparse_index::immutable::posting_list::PostingListIterator;
+
+    #[test]
+    fn test_soa_iterator_matches_aos() {
+        let posting_list = PostingList::from(vec![
+            (1, 1.0),
+            (2, 2.1),
+            (3, 2.0),
+            (5, 5.0),
+            (7, 4.0),
+            (8, 3.4),
+            (10, 3.0),
+            (20, 3.0),
+        ]);
+        let soa = PostingListSoa::from(&posting_list);
+        let mut aos_iter = PostingListIterator::new(&posting_list.elements);
+        let mut soa_iter = soa.iter();
+
+        assert_eq!(PostingListIter::peek(&aos_iter), soa_iter.peek());
+        assert_eq!(aos_iter.next_element(), soa_iter.next_element());
+        for id in [3, 4, 6, 10, 2, 21] {
+            assert_eq!(
+                PostingListIter::skip_to(&mut aos_iter, id),
+                soa_iter.skip_to(id),
+                "skip to {}",
+                id
+            );
+            assert_eq!(
+                PostingListIter::len_to_end(&aos_iter),
+                soa_iter.len_to_end()
+            );
+        }
+        assert_eq!(soa_iter.next_element(), None);
+        assert_eq!(
+            soa_iter.rewound().peek(),
+            posting_list.elements.first().copied()
+        );
+    }
+}
@@ -1,35 +1,325 @@
 use crate::sparse_index::common::fixed_length_pq::FixedLengthPriorityQueue;
-use crate::sparse_index::common::scored_candidate::ScoredCandidate;
+use crate::sparse_index::common::metrics::{MetricsSink, NoopMetricsSink, SearchCounter};
+use crate::sparse_index::common::scored_candidate::{ScoredCandidate, TieBreak};
+use crate::sparse_index::common::summation::{ScoreAccumulator, Summation};
+use crate::sparse_index::common::types::{DimId, DimWeight, RecordId};
 use crate::sparse_index::common::vector::SparseVector;
-use crate::sparse_index::immutable::inverted_index::InvertedIndex;
-use crate::sparse_index::immutable::posting_list::PostingListIterator;
+use crate::sparse_index::immutable::inverted_index::inverted_index_mmap::InvertedIndexMmap;
+use crate::sparse_index::immutable::inverted_index::{InvertedIndex, InvertedIndexIterator};
+use crate::sparse_index::immutable::posting_list::{LazyPostingListIterator, PostingListIter};
+use ordered_float::OrderedFloat;
+use std::cmp::Ordering;
+use std::collections::{BinaryHeap, HashMap, HashSet};
+use std::ops::ControlFlow;
+use std::ops::Range;
+use std::sync::mpsc::Sender;
 
-pub struct IndexedPostingListIterator<'a> {
-    posting_list_iterator: PostingListIterator<'a>,
+/// Candidate ranked by score, then by decreasing sort value, then by increasing id,
+/// with the number of query dimensions it matched
+struct RankedCandidate {
+    candidate: ScoredCandidate,
+    sort_value: DimWeight,
+    matched: u32,
+}
+
+impl PartialEq for RankedCandidate {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for RankedCandidate {}
+
+impl Ord for RankedCandidate {
+    fn cmp(&self, other: &Self) -> Ordering {
+        OrderedFloat(self.candidate.score)
+            .cmp(&OrderedFloat(other.candidate.score))
+            .then_with(|| OrderedFloat(self.sort_value).cmp(&OrderedFloat(other.sort_value)))
+            .then_with(|| other.candidate.vector_id.cmp(&self.candidate.vector_id))
+    }
+}
+
+impl PartialOrd for RankedCandidate {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+/// Result queue entry of the search loop, ranked by the score of its candidate first
+trait Ranked: Ord {
+    fn candidate(&self) -> &ScoredCandidate;
+
+    fn score(&self) -> DimWeight {
+        self.candidate().score
+    }
+}
+
+impl Ranked for ScoredCandidate {
+    fn candidate(&self) -> &ScoredCandidate {
+        self
+    }
+}
+
+impl Ranked for RankedCandidate {
+    fn candidate(&self) -> &ScoredCandidate {
+        &self.candidate
+    }
+}
+
+/// Whether a term contribution is trimmed by the `min_term_contribution` threshold
+fn is_below_min(min: Option<DimWeight>, query_weight: DimWeight, weight: DimWeight) -> bool {
+    min.is_some_and(|min| query_weight < min || weight < min)
+}
+
+/// Number of posting elements summarized by a block maximum in `search_wand`
+const WAND_BLOCK_SIZE: usize = 128;
+
+/// Last record id and maximum weight of the consecutive blocks of a posting list
+struct BlockMaxima {
+    blocks: Vec<(RecordId, DimWeight)>,
+}
+
+impl BlockMaxima {
+    fn new<P: PostingListIter>(posting_list_iterator: &P) -> BlockMaxima {
+        let mut iterator = posting_list_iterator.rewound();
+        let mut blocks = Vec::with_capacity(iterator.len_to_end().div_ceil(WAND_BLOCK_SIZE));
+        let mut block_len = 0;
+        let mut block_max = DimWeight::NEG_INFINITY;
+        while let Some(element) = iterator.next_element() {
+            block_len += 1;
+            if element.weight.is_nan() || element.weight > block_max {
+                block_max = element.weight;
+            }
+            if block_len == WAND_BLOCK_SIZE || iterator.len_to_end() == 0 {
+                blocks.push((element.record_id, block_max));
+                block_len = 0;
+                block_max = DimWeight::NEG_INFINITY;
+            }
+        }
+        BlockMaxima { blocks }
+    }
+
+    /// The block which would contain `id`, None if the list has no record from `id` on
+    fn block_of(&self, id: RecordId) -> Option<(RecordId, DimWeight)> {
+        let index = self.blocks.partition_point(|(last_id, _)| *last_id < id);
+        self.blocks.get(index).copied()
+    }
+}
+
+/// Upper bound of a term contribution, unbounded if NaN as a NaN score ranks first
+fn contribution_bound(weight: DimWeight, query_weight: DimWeight) -> DimWeight {
+    let bound = weight * query_weight;
+    if bound.is_nan() {
+        DimWeight::INFINITY
+    } else {
+        bound.max(0.0)
+    }
+}
+
+pub struct IndexedPostingListIterator<P: PostingListIter> {
+    posting_list_iterator: P,
     query_weight_offset: usize,
+    required: bool, // filter only, never pruned and not scored
+}
+
+/// Work done by a search, to measure the pruning effectiveness
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct SearchStats {
+    /// Calls of `advance`, one per candidate plus the final exhausted call
+    pub advance_calls: usize,
+    /// Posting elements consumed by `advance`
+    pub elements_scored: usize,
+    /// Prunings which moved the longest posting list forward
+    pub pruning_skips: usize,
+    /// Posting elements passed over by the prunings
+    pub elements_skipped: usize,
+}
+
+/// Order in which [`SearchContext::search`] tries to prune the posting lists, the list
+/// at the head is moved forward when it can not reach the top-k minimum on its own.
+/// Both orders return the same results, they differ in the candidates scored.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum PruningOrder {
+    /// Longest posting list first, pruning it skips the most elements
+    #[default]
+    Length,
+    /// Lowest maximum contribution `max weight * query weight` first, the head list is
+    /// the most likely to be pruned and the candidates of high weight lists are scored first
+    MaxContribution,
+}
+
+/// Query dimensions acting as filters: a candidate must contain all of them,
+/// their weights do not contribute to the score.
+#[derive(Debug, Default, Clone)]
+pub struct FilterSpec {
+    pub required: HashSet<DimId>,
+}
+
+/// Secondary ranking key of the records, see [`SearchContext::with_sort_values`]
+#[derive(Debug, Clone)]
+pub enum SortValues {
+    /// Indexed by record id
+    Dense(Vec<DimWeight>),
+    /// Keyed by record id, for sparse record ids
+    Sparse(HashMap<RecordId, DimWeight>),
+}
+
+impl SortValues {
+    /// Sort value of a record, `NEG_INFINITY` for a record without one
+    pub fn get(&self, id: RecordId) -> DimWeight {
+        let sort_value = match self {
+            SortValues::Dense(sort_values) => sort_values.get(id as usize),
+            SortValues::Sparse(sort_values) => sort_values.get(&id),
+        };
+        sort_value.copied().unwrap_or(DimWeight::NEG_INFINITY)
+    }
+
+    pub fn set(&mut self, id: RecordId, sort_value: DimWeight) {
+        match self {
+            SortValues::Dense(sort_values) => {
+                if sort_values.len() <= id as usize {
+                    sort_values.resize(id as usize + 1, DimWeight::NEG_INFINITY);
+                }
+                sort_values[id as usize] = sort_value;
+            }
+            SortValues::Sparse(sort_values) => {
+                sort_values.insert(id, sort_value);
+            }
+        }
+    }
+
+    /// The record then ranks like a record without sort value
+    pub fn remove(&mut self, id: RecordId) {
+        match self {
+            SortValues::Dense(sort_values) => {
+                if let Some(sort_value) = sort_values.get_mut(id as usize) {
+                    *sort_value = DimWeight::NEG_INFINITY;
+                }
+            }
+            SortValues::Sparse(sort_values) => {
+                sort_values.remove(&id);
+            }
+        }
+    }
 }
 
-pub struct SearchContext<'a> {
-    postings_iterators: Vec<IndexedPostingListIterator<'a>>,
-    query: SparseVector,
+/// Search over posting lists of any layout, defaults to the posting lists of an
+/// [`InvertedIndex`]
+pub struct SearchContext<'a, P: PostingListIter = InvertedIndexIterator<'a>> {
+    postings_iterators: Vec<IndexedPostingListIterator<P>>,
+    query: &'a SparseVector,
     top: usize,
     result_queue: FixedLengthPriorityQueue<ScoredCandidate>, // keep the largest elements and peek smallest
+    summation: Summation,
+    id_range_end: Option<RecordId>, // exclusive upper bound of the candidate ids
+    tie_break: TieBreak,
+    contributions: Vec<(usize, DimWeight)>, // scratch buffer to sum in query order
+    min_term_contribution: Option<DimWeight>,
+    excluded_id: Option<RecordId>,
+    required_count: usize, // number of posting lists a candidate must all be in
+    matched_count: u32,    // query dimensions matched by the last candidate of `advance`
+    early_termination: bool,
+    pivot_pruning: bool,
+    pruning_order: PruningOrder,
+    max_candidates: Option<usize>,
+    score_threshold: Option<DimWeight>,
+    sort_values: Option<&'a SortValues>, // ranks equal scores
+    stats: SearchStats,
+    metrics: &'a dyn MetricsSink,
+    unmatched_dims: Vec<DimId>, // query dimensions without posting list
 }
 
 impl<'a> SearchContext<'a> {
+    /// Only the posting list headers are read here, element pages of a mmap index are
+    /// faulted lazily by the iterators on first `peek`/`next`.
+    /// Dimensions absent from the index or with an empty posting list get no iterator,
+    /// so their data is never touched during the search.
     pub fn new(
-        query: SparseVector,
+        query: &'a SparseVector,
+        top: usize,
+        inverted_index: &'a InvertedIndex,
+    ) -> SearchContext<'a> {
+        SearchContext::new_with_blacklist(query, top, inverted_index, None)
+    }
+
+    /// Query dimensions in `blacklist` get no iterator, they are ignored for this search only
+    pub fn new_with_blacklist(
+        query: &'a SparseVector,
         top: usize,
         inverted_index: &'a InvertedIndex,
+        blacklist: Option<&HashSet<DimId>>,
     ) -> SearchContext<'a> {
+        SearchContext::from_postings(query, top, |id| {
+            if blacklist.is_some_and(|blacklist| blacklist.contains(id)) {
+                return None;
+            }
+            inverted_index.get(id)
+        })
+    }
+
+    /// Like [`Self::new`] with posting lists opened on their first access, only their
+    /// [`PostingSummary`](crate::sparse_index::immutable::posting_list::PostingSummary) is
+    /// read from the index here.
+    /// A list is opened once a candidate reaches its first record: a list pruned before,
+    /// or dropped by an unsatisfiable [`Self::with_filter`], is never read.
+    pub fn new_lazy(
+        query: &'a SparseVector,
+        top: usize,
+        inverted_index: &'a InvertedIndex,
+    ) -> SearchContext<
+        'a,
+        LazyPostingListIterator<
+            InvertedIndexIterator<'a>,
+            impl Fn() -> InvertedIndexIterator<'a> + Clone + 'a,
+        >,
+    > {
+        SearchContext::from_postings(query, top, |id| {
+            let summary = inverted_index.posting_summary(id)?;
+            let id = *id;
+            Some(LazyPostingListIterator::new(summary, move || {
+                inverted_index
+                    .get(&id)
+                    .expect("posting list of a known dimension")
+            }))
+        })
+    }
+
+    /// Read the posting lists of the query dimensions from the mmap index file up front,
+    /// the search then runs on owned memory with a predictable IO for the query.
+    /// `prefetched` holds the read posting lists for the lifetime of the context.
+    pub fn new_prefetched(
+        query: &'a SparseVector,
+        top: usize,
+        inverted_index: &InvertedIndexMmap,
+        prefetched: &'a mut Option<InvertedIndex>,
+    ) -> std::io::Result<SearchContext<'a>> {
+        let postings = inverted_index.read_postings(&query.indices)?;
+        let prefetched = prefetched.insert(InvertedIndex::Ram(postings));
+        Ok(SearchContext::new(query, top, prefetched))
+    }
+}
+
+impl<'a, P: PostingListIter> SearchContext<'a, P> {
+    /// Search over the posting lists returned by `get_posting` for each query dimension
+    pub fn from_postings(
+        query: &'a SparseVector,
+        top: usize,
+        get_posting: impl Fn(&DimId) -> Option<P>,
+    ) -> SearchContext<'a, P> {
         let mut postings_iterators = Vec::new();
+        let mut unmatched_dims = Vec::new();
 
         for (query_weight_offset, id) in query.indices.iter().enumerate() {
-            if let Some(posting_list_iterator) = inverted_index.get(id) {
-                postings_iterators.push(IndexedPostingListIterator {
-                    posting_list_iterator,
-                    query_weight_offset,
-                });
+            match get_posting(id) {
+                // empty placeholder posting list
+                Some(posting_list_iterator) if posting_list_iterator.len_to_end() > 0 => {
+                    postings_iterators.push(IndexedPostingListIterator {
+                        posting_list_iterator,
+                        query_weight_offset,
+                        required: false,
+                    });
+                }
+                _ => unmatched_dims.push(*id),
             }
         }
         let result_queue = FixedLengthPriorityQueue::new(top);
@@ -39,7 +329,150 @@ impl<'a> SearchContext<'a> {
             query,
             top,
             result_queue,
+            summation: Summation::default(),
+            id_range_end: None,
+            tie_break: TieBreak::default(),
+            contributions: Vec::new(),
+            min_term_contribution: None,
+            excluded_id: None,
+            required_count: 0,
+            matched_count: 0,
+            early_termination: false,
+            pivot_pruning: false,
+            pruning_order: PruningOrder::default(),
+            max_candidates: None,
+            score_threshold: None,
+            sort_values: None,
+            stats: SearchStats::default(),
+            metrics: &NoopMetricsSink,
+            unmatched_dims,
+        }
+    }
+
+    /// Query dimensions without a posting list, or with an empty one, in query order.
+    /// They match no vector and contribute to no score. Blacklisted dimensions are included.
+    pub fn unmatched_dims(&self) -> &[DimId] {
+        &self.unmatched_dims
+    }
+
+    /// Only consider candidates with an id in the given range.
+    /// Posting iterators are moved to the range start, the search stops at the range end.
+    pub fn with_id_range(mut self, id_range: Range<RecordId>) -> Self {
+        for posting_iterator in self.postings_iterators.iter_mut() {
+            posting_iterator
+                .posting_list_iterator
+                .skip_to(id_range.start);
+        }
+        self.id_range_end = Some(id_range.end);
+        self
+    }
+
+    /// With [`TieBreak::ById`] the score contributions are summed in query order,
+    /// even after the posting lists were reordered for pruning.
+    pub fn with_tie_break(mut self, tie_break: TieBreak) -> Self {
+        self.tie_break = tie_break;
+        self
+    }
+
+    /// Ignore the contribution of a matched dimension when the query weight or the
+    /// document weight is below `min`, other dimensions of the record still count.
+    /// Scores only decrease, so the pruning bounds remain valid.
+    pub fn with_min_term_contribution(mut self, min: DimWeight) -> Self {
+        self.min_term_contribution = Some(min);
+        self
+    }
+
+    /// Never return the given record, e.g. the stored document used as query
+    pub fn with_excluded_id(mut self, id: RecordId) -> Self {
+        self.excluded_id = Some(id);
+        self
+    }
+
+    /// Only emit candidates containing all the required dimensions of `filter`.
+    /// Required dimensions must be part of the query, usually with a weight of 0,
+    /// a required dimension missing from the query or the index matches nothing.
+    pub fn with_filter(mut self, filter: &FilterSpec) -> Self {
+        for posting_iterator in self.postings_iterators.iter_mut() {
+            let dim = self.query.indices[posting_iterator.query_weight_offset];
+            posting_iterator.required = filter.required.contains(&dim);
+        }
+        self.required_count = self
+            .postings_iterators
+            .iter()
+            .filter(|posting_iterator| posting_iterator.required)
+            .count();
+        if self.required_count < filter.required.len() {
+            // no candidate can satisfy the filter
+            self.postings_iterators.clear();
         }
+        self
+    }
+
+    /// Stop [`Self::search`] as soon as the maximum score of the records left in the
+    /// posting lists is below the current top-k minimum, the results are unchanged.
+    pub fn with_early_termination(mut self) -> Self {
+        self.early_termination = true;
+        self
+    }
+
+    /// Prune with [`Self::prune_with_pivot`] in [`Self::search`] instead of pruning the
+    /// longest posting list only, the results are unchanged.
+    pub fn with_pivot_pruning(mut self) -> Self {
+        self.pivot_pruning = true;
+        self
+    }
+
+    /// Choose which posting list [`Self::search`] tries to prune first
+    pub fn with_pruning_order(mut self, pruning_order: PruningOrder) -> Self {
+        self.pruning_order = pruning_order;
+        self
+    }
+
+    /// Stop [`Self::search`] once `max_candidates` candidates have been scored and return
+    /// the best ones so far, the results are approximate: records after the budget are
+    /// never considered. The pruning order is set to [`PruningOrder::MaxContribution`]
+    /// to spend the budget on the candidates of high weight postings.
+    pub fn with_max_candidates(mut self, max_candidates: usize) -> Self {
+        self.max_candidates = Some(max_candidates);
+        self.pruning_order = PruningOrder::MaxContribution;
+        self
+    }
+
+    /// Never return candidates scoring below `threshold`.
+    /// The threshold also bounds the pruning while the result queue is not full.
+    pub fn with_score_threshold(mut self, threshold: DimWeight) -> Self {
+        self.score_threshold = Some(threshold);
+        self
+    }
+
+    /// Rank candidates with equal scores by decreasing sort value in [`Self::search`],
+    /// records without a sort value rank last among them
+    pub fn with_sort_values(mut self, sort_values: &'a SortValues) -> Self {
+        self.sort_values = Some(sort_values);
+        self
+    }
+
+    /// Emit the search counters to the given sink
+    pub fn with_metrics(mut self, metrics: &'a dyn MetricsSink) -> Self {
+        self.metrics = metrics;
+        self
+    }
+
+    /// Accumulate results into an existing queue, e.g. shared across several indexes.
+    /// The number of results is then given by the queue capacity.
+    pub fn with_result_queue(
+        mut self,
+        result_queue: FixedLengthPriorityQueue<ScoredCandidate>,
+    ) -> Self {
+        self.top = result_queue.capacity();
+        self.result_queue = result_queue;
+        self
+    }
+
+    /// Set the summation strategy used to accumulate candidate scores
+    pub fn with_summation(mut self, summation: Summation) -> Self {
+        self.summation = summation;
+        self
     }
 
     /// Example
@@ -72,39 +505,145 @@ impl<'a> SearchContext<'a> {
     /// b,  21, 34, 60, 200
     /// b,  30, 34, 60, 230
     fn advance(&mut self) -> Option<ScoredCandidate> {
-        let min_record_id = Self::next_min(&self.postings_iterators)?;
-        let mut score = 0.0;
+        self.stats.advance_calls += 1;
+        loop {
+            let min_record_id = Self::next_min(&self.postings_iterators)?;
+            if self.id_range_end.is_some_and(|end| min_record_id >= end) {
+                return None;
+            }
+            if self.excluded_id == Some(min_record_id) {
+                // move past the excluded record without scoring it
+                for posting_iterator in self.postings_iterators.iter_mut() {
+                    let iterator = &mut posting_iterator.posting_list_iterator;
+                    if iterator.peek_bound().map(|(record_id, _)| record_id) == Some(min_record_id)
+                    {
+                        iterator.next_element();
+                    }
+                }
+                continue;
+            }
+            let Some(candidate) = self.score_record(min_record_id) else {
+                continue;
+            };
+            match self.score_threshold {
+                Some(threshold) if candidate.score < threshold => {
+                    // skip the records which can not reach the threshold either
+                    self.sort_posting_lists_by_len();
+                    self.prune_longest_posting_list(threshold);
+                }
+                _ => return Some(candidate),
+            }
+        }
+    }
+
+    /// Consume the posting elements of `record_id` at the head of the iterators and score
+    /// them, returns None if the record is filtered out
+    fn score_record(&mut self, record_id: RecordId) -> Option<ScoredCandidate> {
+        let mut score = ScoreAccumulator::new(self.summation);
+        let mut required_matches = 0;
+        let mut matched_count = 0;
 
-        // Iterate second time to advance posting iterators
         for posting_iterator in self.postings_iterators.iter_mut() {
-            if let Some(record_id) = posting_iterator
+            // accumulate score for the current record id
+            if posting_iterator
                 .posting_list_iterator
-                .peek()
-                .map(|element| element.record_id)
+                .peek_bound()
+                .is_some_and(|(head_id, _)| head_id == record_id)
             {
-                // accumulate score for the current record id
-                if record_id == min_record_id {
-                    let element = posting_iterator.posting_list_iterator.next().unwrap();
-                    score +=
-                        element.weight * self.query.weights[posting_iterator.query_weight_offset];
+                let element = posting_iterator
+                    .posting_list_iterator
+                    .next_element()
+                    .unwrap();
+                matched_count += 1;
+                self.stats.elements_scored += 1;
+                if posting_iterator.required {
+                    required_matches += 1;
+                    continue;
                 }
+                let offset = posting_iterator.query_weight_offset;
+                let query_weight = self.query.weights[offset];
+                if is_below_min(self.min_term_contribution, query_weight, element.weight) {
+                    continue;
+                }
+                let contribution = element.weight * query_weight;
+                match self.tie_break {
+                    TieBreak::Unspecified => score.add(contribution),
+                    TieBreak::ById => self.contributions.push((offset, contribution)),
+                }
+            }
+        }
+        if required_matches < self.required_count {
+            // filtered out
+            self.contributions.clear();
+            return None;
+        }
+        if self.tie_break == TieBreak::ById {
+            self.contributions
+                .sort_unstable_by_key(|(offset, _)| *offset);
+            for (_, contribution) in self.contributions.drain(..) {
+                score.add(contribution);
             }
         }
 
+        self.metrics.increment(SearchCounter::CandidatesScored, 1);
+        self.matched_count = matched_count;
         Some(ScoredCandidate {
-            score,
-            vector_id: min_record_id,
+            score: score.sum(),
+            vector_id: record_id,
         })
     }
 
-    fn next_min(to_inspect: &[IndexedPostingListIterator<'_>]) -> Option<u32> {
+    /// Full score of the given record, independent of the DAAT traversal.
+    /// Each posting list is looked up with a fresh iterator so the search state is untouched.
+    /// Returns None if the record is in none of the query posting lists.
+    pub fn score_of(&self, id: RecordId) -> Option<DimWeight> {
+        let mut contributions: Vec<_> = self
+            .postings_iterators
+            .iter()
+            .filter(|posting_iterator| !posting_iterator.required)
+            .filter_map(|posting_iterator| {
+                let mut fresh = posting_iterator.posting_list_iterator.rewound();
+                let offset = posting_iterator.query_weight_offset;
+                let query_weight = self.query.weights[offset];
+                fresh.skip_to(id).map(|element| {
+                    let contribution =
+                        if is_below_min(self.min_term_contribution, query_weight, element.weight) {
+                            0.0
+                        } else {
+                            element.weight * query_weight
+                        };
+                    (offset, contribution)
+                })
+            })
+            .collect();
+        if contributions.is_empty() {
+            return None;
+        }
+        // sum in query order whatever the current order of the iterators
+        contributions.sort_unstable_by_key(|(offset, _)| *offset);
+        let mut score = ScoreAccumulator::new(self.summation);
+        for (_, contribution) in contributions {
+            score.add(contribution);
+        }
+        Some(score.sum())
+    }
+
+    fn record_query(&self) {
+        self.metrics.increment(SearchCounter::Queries, 1);
+        self.metrics.increment(
+            SearchCounter::PostingListsTouched,
+            self.postings_iterators.len() as u64,
+        );
+    }
+
+    fn next_min(to_inspect: &[IndexedPostingListIterator<P>]) -> Option<u32> {
         let mut min_record_id = None;
 
         // Iterate first time to find min record id at the head of the posting lists
         for posting_iterator in to_inspect.iter() {
-            if let Some(next_element) = posting_iterator.posting_list_iterator.peek() {
-                if Some(next_element.record_id) < min_record_id || min_record_id.is_none() {
-                    min_record_id = Some(next_element.record_id);
+            if let Some((head_id, _)) = posting_iterator.posting_list_iterator.peek_bound() {
+                if Some(head_id) < min_record_id || min_record_id.is_none() {
+                    min_record_id = Some(head_id);
                 }
             }
         }
@@ -112,6 +651,24 @@ impl<'a> SearchContext<'a> {
         min_record_id
     }
 
+    /// Put the posting list with the lowest maximum contribution at the head of the posting
+    /// list iterators, required and exhausted lists last as they are never pruned
+    pub fn sort_posting_lists_by_max_contribution(&mut self) {
+        let query_weights = &self.query.weights;
+        self.postings_iterators
+            .sort_by_cached_key(|posting_iterator| {
+                let bound = match posting_iterator.posting_list_iterator.peek_bound() {
+                    _ if posting_iterator.required => DimWeight::INFINITY,
+                    Some((_, max_weight)) => contribution_bound(
+                        max_weight,
+                        query_weights[posting_iterator.query_weight_offset],
+                    ),
+                    None => DimWeight::INFINITY,
+                };
+                OrderedFloat(bound)
+            });
+    }
+
     /// Make sure the longest posting list is at the head of the posting list iterators
     fn sort_posting_lists_by_len(&mut self) {
         // decreasing order
@@ -123,32 +680,416 @@ impl<'a> SearchContext<'a> {
     }
 
     pub fn search(&mut self) -> Vec<ScoredCandidate> {
+        self.search_with_stats().0
+    }
+
+    /// Like [`Self::search`] with the work done by the search
+    pub fn search_with_stats(&mut self) -> (Vec<ScoredCandidate>, SearchStats) {
+        let results = match self.sort_values {
+            None => self.search_queue().into_vec(),
+            Some(_) => self
+                .search_detailed()
+                .into_iter()
+                .map(|(candidate, _)| candidate)
+                .collect(),
+        };
+        (results, self.stats.clone())
+    }
+
+    /// Run the search and return the result queue without converting it,
+    /// so it can keep accumulating results from other searches.
+    /// The queue ranks equal scores by id, the sort values are ignored.
+    pub fn search_queue(&mut self) -> FixedLengthPriorityQueue<ScoredCandidate> {
+        let mut result_queue = std::mem::take(&mut self.result_queue);
+        self.search_loop(
+            &mut result_queue,
+            |candidate, _| candidate,
+            |_, _| ControlFlow::Continue(()),
+        );
+        result_queue
+    }
+
+    /// Search loop of the top-k searches, `rank` makes the queue entry of a candidate
+    /// from it and the number of query dimensions it matched.
+    /// `emit` sees the queue after each candidate and its pruning, the search stops when
+    /// it breaks.
+    fn search_loop<R: Ranked>(
+        &mut self,
+        result_queue: &mut FixedLengthPriorityQueue<R>,
+        rank: impl Fn(ScoredCandidate, u32) -> R,
+        mut emit: impl FnMut(&Self, &FixedLengthPriorityQueue<R>) -> ControlFlow<()>,
+    ) {
+        self.record_query();
         if self.postings_iterators.is_empty() {
-            return Vec::new();
+            return;
         }
 
-        while let Some(candidate) = self.advance() {
+        let mut candidates_scored = 0;
+        while self
+            .max_candidates
+            .is_none_or(|max_candidates| candidates_scored < max_candidates)
+        {
+            let Some(candidate) = self.advance() else {
+                break;
+            };
+            candidates_scored += 1;
             // push candidate to result queue
-            self.result_queue.push(candidate);
+            result_queue.push(rank(candidate, self.matched_count));
 
             // we potentially have enough results to prune low performing posting lists
-            if self.result_queue.len() == self.top {
-                // current min score
-                let min_score = self.result_queue.top().unwrap().score;
+            if let Some(min_score) = self.entry_min_score(result_queue) {
+                // no remaining record can enter the results
+                if self.early_termination && self.remaining_score_bound() < min_score {
+                    break;
+                }
+
+                if self.pivot_pruning {
+                    self.prune_with_pivot(min_score);
+                } else {
+                    match self.pruning_order {
+                        // sort posting lists by length to try to prune the longest one
+                        PruningOrder::Length => self.sort_posting_lists_by_len(),
+                        PruningOrder::MaxContribution => {
+                            self.sort_posting_lists_by_max_contribution()
+                        }
+                    }
+                    self.prune_longest_posting_list(min_score);
+                }
+            }
+            if emit(self, result_queue).is_break() {
+                break;
+            }
+        }
+    }
+
+    /// Block-max WAND traversal, same results as [`Self::search`] with fewer scored candidates.
+    /// The posting lists are ordered by their next record, the pivot is the first record
+    /// whose lists can reach the top-k minimum given their maximum weights. It is only scored
+    /// if the maxima of the blocks which may contain it also reach the minimum, otherwise
+    /// these lists jump to the end of the smallest of their blocks.
+    /// The block maxima are read from the posting lists when the search starts.
+    pub fn search_wand(&mut self) -> Vec<ScoredCandidate> {
+        self.record_query();
+        let block_maxima: Vec<_> = self
+            .postings_iterators
+            .iter()
+            .map(|posting_iterator| BlockMaxima::new(&posting_iterator.posting_list_iterator))
+            .collect();
+        // required lists are not scored, their bound is null
+        let query_weights: Vec<_> = self
+            .postings_iterators
+            .iter()
+            .map(|posting_iterator| match posting_iterator.required {
+                true => 0.0,
+                false => self.query.weights[posting_iterator.query_weight_offset],
+            })
+            .collect();
+        let mut order: Vec<usize> = (0..self.postings_iterators.len()).collect();
+
+        loop {
+            order.retain(|&i| self.head_id(i).is_some());
+            order.sort_unstable_by_key(|&i| self.head_id(i));
+            let min_score = self.entry_min_score(&self.result_queue);
+
+            let mut bound = 0.0;
+            let pivot = order.iter().position(|&i| {
+                let (_, max_weight) = self.postings_iterators[i]
+                    .posting_list_iterator
+                    .peek_bound()
+                    .unwrap();
+                bound += contribution_bound(max_weight, query_weights[i]);
+                min_score.is_none_or(|min_score| bound >= min_score)
+            });
+            // no remaining record can enter the results
+            let Some(pivot) = pivot else {
+                break;
+            };
+            let pivot_id = self.head_id(order[pivot]).unwrap();
+            if self.id_range_end.is_some_and(|end| pivot_id >= end) {
+                break;
+            }
+            // lists which may contain the pivot record
+            let end = pivot
+                + 1
+                + order[pivot + 1..]
+                    .iter()
+                    .take_while(|&&i| self.head_id(i) == Some(pivot_id))
+                    .count();
+
+            if let Some(min_score) = min_score {
+                let mut block_bound = 0.0;
+                // records up to the next list head are only in the lists of the pivot
+                let mut skip_to = order
+                    .get(end)
+                    .and_then(|&i| self.head_id(i))
+                    .map_or(u64::MAX, u64::from);
+                for &i in &order[..end] {
+                    if let Some((last_id, max_weight)) = block_maxima[i].block_of(pivot_id) {
+                        block_bound += contribution_bound(max_weight, query_weights[i]);
+                        skip_to = skip_to.min(u64::from(last_id) + 1);
+                    }
+                }
+                if block_bound < min_score {
+                    for &i in &order[..end] {
+                        self.skip_posting_list(i, RecordId::try_from(skip_to).ok());
+                    }
+                    continue;
+                }
+            }
+
+            if self.head_id(order[0]) != Some(pivot_id) {
+                // move the lists before the pivot to it
+                for &i in &order[..pivot] {
+                    self.skip_posting_list(i, Some(pivot_id));
+                }
+                continue;
+            }
+            if self.excluded_id == Some(pivot_id) {
+                for &i in &order[..end] {
+                    self.postings_iterators[i]
+                        .posting_list_iterator
+                        .next_element();
+                }
+                continue;
+            }
+            if let Some(candidate) = self.score_record(pivot_id) {
+                if !min_score.is_some_and(|min_score| candidate.score < min_score) {
+                    self.result_queue.push(candidate);
+                }
+            }
+        }
+        std::mem::take(&mut self.result_queue).into_vec()
+    }
+
+    fn head_id(&self, index: usize) -> Option<RecordId> {
+        self.postings_iterators[index]
+            .posting_list_iterator
+            .peek_bound()
+            .map(|(record_id, _)| record_id)
+    }
+
+    /// Move a posting list to `id` without scoring, to its end if `id` is None
+    fn skip_posting_list(&mut self, index: usize, id: Option<RecordId>) {
+        let iterator = &mut self.postings_iterators[index].posting_list_iterator;
+        let len_before = iterator.len_to_end();
+        match id {
+            Some(id) => {
+                iterator.skip_to(id);
+            }
+            None => iterator.skip_to_end(),
+        }
+        let skipped = len_before - iterator.len_to_end();
+        if skipped > 0 {
+            self.metrics.increment(SearchCounter::PruningSkips, 1);
+            self.stats.pruning_skips += 1;
+            self.stats.elements_skipped += skipped;
+        }
+    }
+
+    /// The score a new candidate needs to enter the result queue, from the queue minimum
+    /// once it is full and the score threshold. Pruning on it is strict, candidates tied
+    /// with the minimum are still visited to be ranked by the queue.
+    fn entry_min_score<R: Ranked>(
+        &self,
+        result_queue: &FixedLengthPriorityQueue<R>,
+    ) -> Option<f32> {
+        let queue_min_score = match result_queue.len() == self.top {
+            true => result_queue.top().map(Ranked::score),
+            false => None,
+        };
+        match (queue_min_score, self.score_threshold) {
+            (Some(min_score), Some(threshold)) => Some(min_score.max(threshold)),
+            (min_score, threshold) => min_score.or(threshold),
+        }
+    }
+
+    /// Queue entry of a candidate ranking equal scores by the sort values
+    fn rank_by_sort_value(&self) -> impl Fn(ScoredCandidate, u32) -> RankedCandidate + 'a {
+        let sort_values = self.sort_values;
+        move |candidate, matched| {
+            let sort_value = sort_values.map_or(DimWeight::NEG_INFINITY, |sort_values| {
+                sort_values.get(candidate.vector_id)
+            });
+            RankedCandidate {
+                candidate,
+                sort_value,
+                matched,
+            }
+        }
+    }
+
+    /// Like [`Self::search`] with the number of query dimensions each result matched
+    pub fn search_detailed(&mut self) -> Vec<(ScoredCandidate, u32)> {
+        let mut result_queue = FixedLengthPriorityQueue::new(self.top);
+        self.search_loop(&mut result_queue, self.rank_by_sort_value(), |_, _| {
+            ControlFlow::Continue(())
+        });
+        result_queue
+            .into_vec()
+            .into_iter()
+            .map(|ranked| (ranked.candidate, ranked.matched))
+            .collect()
+    }
+
+    /// Like [`Self::search`] but each result is sent as soon as it is final, i.e. no
+    /// record left in the posting lists can outrank it. The order of the sent results
+    /// is unspecified. The search stops early if the receiver is dropped.
+    pub fn search_streaming(&mut self, tx: Sender<ScoredCandidate>) {
+        let mut result_queue = FixedLengthPriorityQueue::new(self.top);
+        let mut sent = HashSet::new();
+        let mut disconnected = false;
+        let send = |candidate: &ScoredCandidate| {
+            let candidate = ScoredCandidate {
+                score: candidate.score,
+                vector_id: candidate.vector_id,
+            };
+            tx.send(candidate).is_ok()
+        };
+        // the records left have higher ids, on equal scores they rank lower unless
+        // their sort value lifts them
+        let final_on_ties = self.sort_values.is_none();
+        self.search_loop(
+            &mut result_queue,
+            self.rank_by_sort_value(),
+            |search_context, result_queue| {
+                let bound = search_context.remaining_score_bound();
+                for ranked in result_queue.iter() {
+                    let candidate = ranked.candidate();
+                    let is_final =
+                        candidate.score > bound || (final_on_ties && candidate.score == bound);
+                    if is_final && sent.insert(candidate.vector_id) && !send(candidate) {
+                        disconnected = true;
+                        return ControlFlow::Break(());
+                    }
+                }
+                ControlFlow::Continue(())
+            },
+        );
+        if disconnected {
+            return;
+        }
+        for ranked in result_queue.iter() {
+            let candidate = ranked.candidate();
+            if !sent.contains(&candidate.vector_id) && !send(candidate) {
+                return;
+            }
+        }
+    }
+
+    /// All the matching records in descending score order, without a fixed number of
+    /// results: a candidate is yielded once no record left in the posting lists can
+    /// outrank it, candidates waiting for that are buffered. Nothing is pruned.
+    pub fn search_iter(&mut self) -> impl Iterator<Item = ScoredCandidate> + use<'_, 'a, P> {
+        self.record_query();
+        let mut pending = BinaryHeap::new();
+        let mut bound = f32::INFINITY;
+        std::iter::from_fn(move || loop {
+            // later records have higher ids, on equal scores they rank lower
+            if pending
+                .peek()
+                .is_some_and(|best: &ScoredCandidate| best.score >= bound)
+            {
+                return pending.pop();
+            }
+            match self.advance() {
+                Some(candidate) => {
+                    pending.push(candidate);
+                    bound = self.remaining_score_bound();
+                }
+                // exhausted, the buffered candidates are final
+                None => return pending.pop(),
+            }
+        })
+    }
+
+    /// Upper bound of the score of any record not visited yet
+    fn remaining_score_bound(&self) -> f32 {
+        self.postings_iterators
+            .iter()
+            .filter(|posting_iterator| !posting_iterator.required)
+            .filter_map(|posting_iterator| {
+                let (_, max_weight) = posting_iterator.posting_list_iterator.peek_bound()?;
+                let query_weight = self.query.weights[posting_iterator.query_weight_offset];
+                Some(contribution_bound(max_weight, query_weight))
+            })
+            .sum()
+    }
+
+    /// Best candidate of each group, for the `top_groups` best groups.
+    /// `group_ids` is indexed by record id, records without a group id are skipped.
+    /// All candidates are scored, the group bound does not feed the pruning.
+    pub fn search_grouped(&mut self, group_ids: &[u32], top_groups: usize) -> Vec<ScoredCandidate> {
+        self.record_query();
+        let mut best_per_group: HashMap<u32, ScoredCandidate> = HashMap::new();
+        while let Some(candidate) = self.advance() {
+            let Some(&group_id) = group_ids.get(candidate.vector_id as usize) else {
+                continue;
+            };
+            match best_per_group.get_mut(&group_id) {
+                Some(best) if *best >= candidate => {}
+                Some(best) => *best = candidate,
+                None => {
+                    best_per_group.insert(group_id, candidate);
+                }
+            }
+        }
+
+        if top_groups == 0 {
+            return Vec::new();
+        }
+        let mut top = FixedLengthPriorityQueue::new(top_groups);
+        for candidate in best_per_group.into_values() {
+            top.push(candidate);
+        }
+        top.into_vec()
+    }
 
-                // sort posting lists by length to try to prune the longest one
-                self.sort_posting_lists_by_len();
+    /// WAND pivot selection over all the posting lists: ordered by their next record, the
+    /// pivot is the first list at which the summed maximum contributions reach `min_score`.
+    /// Records before the pivot record can not reach it, the lists before the pivot skip
+    /// to the pivot record, or to their end if there is no pivot.
+    /// Returns true if a posting list moved
+    pub fn prune_with_pivot(&mut self, min_score: f32) -> bool {
+        let mut order: Vec<usize> = (0..self.postings_iterators.len())
+            .filter(|&i| self.head_id(i).is_some())
+            .collect();
+        order.sort_unstable_by_key(|&i| self.head_id(i));
 
-                self.prune_longest_posting_list(min_score);
+        let mut bound = 0.0;
+        let pivot = order.iter().position(|&i| {
+            let posting_iterator = &self.postings_iterators[i];
+            let (_, max_weight) = posting_iterator.posting_list_iterator.peek_bound().unwrap();
+            // required lists are not scored
+            if !posting_iterator.required {
+                bound += contribution_bound(
+                    max_weight,
+                    self.query.weights[posting_iterator.query_weight_offset],
+                );
             }
+            bound >= min_score
+        });
+        let (to_skip, pivot_id) = match pivot {
+            Some(pivot) => (&order[..pivot], self.head_id(order[pivot])),
+            None => (&order[..], None),
+        };
+
+        let mut pruned = false;
+        for &i in to_skip {
+            let len_before = self.postings_iterators[i]
+                .posting_list_iterator
+                .len_to_end();
+            self.skip_posting_list(i, pivot_id);
+            pruned |= self.postings_iterators[i]
+                .posting_list_iterator
+                .len_to_end()
+                < len_before;
         }
-        // posting iterators exhausted, return result queue
-        let queue = std::mem::take(&mut self.result_queue);
-        queue.into_vec()
+        pruned
     }
 
     /// Prune posting lists that cannot possibly contribute to the top results
-    /// Assumes longest posting list is at the head of the posting list iterators
+    /// Assumes the posting list to prune is at the head of the posting list iterators,
+    /// the longest one or the one of lowest contribution, see [`PruningOrder`]
     /// Returns true if the longest posting list was pruned
     pub fn prune_longest_posting_list(&mut self, min_score: f32) -> bool {
         // compute skip target before acquiring mutable reference to posting list iterator
@@ -161,13 +1102,19 @@ impl<'a> SearchContext<'a> {
         };
 
         let posting_iterator = &mut self.postings_iterators[0];
+        if posting_iterator.required {
+            // skipping would drop the records of the filter
+            return false;
+        }
         let posting_query_offset = posting_iterator.query_weight_offset;
-        if let Some(element) = posting_iterator.posting_list_iterator.peek() {
-            let max_weight_from_list = element.weight.max(element.max_next_weight);
+        if let Some((_, max_weight_from_list)) = posting_iterator.posting_list_iterator.peek_bound()
+        {
             let max_score_contribution =
                 max_weight_from_list * self.query.weights[posting_query_offset];
             if max_score_contribution < min_score {
-                return match skip_to {
+                self.metrics.increment(SearchCounter::PruningSkips, 1);
+                let len_before = posting_iterator.posting_list_iterator.len_to_end();
+                let pruned = match skip_to {
                     None => {
                         posting_iterator.posting_list_iterator.skip_to_end();
                         true
@@ -177,6 +1124,12 @@ impl<'a> SearchContext<'a> {
                         moved.is_some()
                     }
                 };
+                let skipped = len_before - posting_iterator.posting_list_iterator.len_to_end();
+                if skipped > 0 {
+                    self.stats.pruning_skips += 1;
+                    self.stats.elements_skipped += skipped;
+                }
+                return pruned;
             }
         }
         // no pruning occurred
@@ -187,8 +1140,15 @@ impl<'a> SearchContext<'a> {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::sparse_index::immutable::inverted_index::inverted_index_mmap::WeightEncoding;
     use crate::sparse_index::immutable::inverted_index::inverted_index_ram::InvertedIndexBuilder;
-    use crate::sparse_index::immutable::posting_list::PostingList;
+    use crate::sparse_index::immutable::inverted_index::inverted_index_soa::InvertedIndexSoa;
+    use crate::sparse_index::immutable::posting_list::{
+        PostingElement, PostingList, PostingListIterator, WeightPostingListIterator,
+    };
+    use half::f16;
+    use std::cell::{Cell, RefCell};
+    use tempfile::Builder;
 
     #[test]
     fn advance_basic_test() {
@@ -200,14 +1160,11 @@ mod tests {
 
         let inverted_index = &InvertedIndex::Ram(inverted_index_ram);
 
-        let mut search_context = SearchContext::new(
-            SparseVector {
-                indices: vec![1, 2, 3],
-                weights: vec![1.0, 1.0, 1.0],
-            },
-            10,
-            inverted_index,
-        );
+        let query = SparseVector {
+            indices: vec![1, 2, 3],
+            weights: vec![1.0, 1.0, 1.0],
+        };
+        let mut search_context = SearchContext::new(&query, 10, inverted_index);
 
         assert_eq!(
             search_context.advance(),
@@ -233,23 +1190,267 @@ mod tests {
     }
 
     #[test]
-    fn search() {
+    fn advance_kahan_test() {
+        let dims = 10_000;
+        let mut builder = InvertedIndexBuilder::new();
+        builder.add(0, PostingList::from(vec![(1, 1.0)]));
+        for dim in 1..dims {
+            builder.add(dim, PostingList::from(vec![(1, 1e-8)]));
+        }
+        let inverted_index = InvertedIndex::Ram(builder.build());
+
+        let query = SparseVector::new((0..dims).collect(), vec![1.0; dims as usize]);
+        let reference = 1.0 + (dims - 1) as f64 * 1e-8_f32 as f64;
+
+        let mut naive = SearchContext::new(&query, 1, &inverted_index);
+        let naive_score = naive.advance().unwrap().score;
+        let mut kahan =
+            SearchContext::new(&query, 1, &inverted_index).with_summation(Summation::Kahan);
+        let kahan_score = kahan.advance().unwrap().score;
+
+        assert!((kahan_score as f64 - reference).abs() < (naive_score as f64 - reference).abs());
+    }
+
+    #[test]
+    fn skip_absent_and_empty_posting_lists() {
         let inverted_index_ram = InvertedIndexBuilder::new()
             .add(1, PostingList::from(vec![(1, 10.0), (2, 20.0), (3, 30.0)]))
-            .add(2, PostingList::from(vec![(1, 10.0), (2, 20.0), (3, 30.0)]))
-            .add(3, PostingList::from(vec![(1, 10.0), (2, 20.0), (3, 30.0)]))
+            .add(3, PostingList::from(vec![(2, 5.0)]))
             .build();
+        let tmp_dir_path = Builder::new().prefix("test_index_dir").tempdir().unwrap();
+        let inverted_index_mmap =
+            InvertedIndexMmap::convert_and_save(&inverted_index_ram, &tmp_dir_path).unwrap();
+        let inverted_index = InvertedIndex::Mmap(inverted_index_mmap);
 
-        let inverted_index = InvertedIndex::Ram(inverted_index_ram);
+        // dimensions 0 and 2 are empty placeholders, dimension 100 is absent
+        let query = SparseVector {
+            indices: vec![0, 1, 2, 3, 100],
+            weights: vec![1.0, 1.0, 1.0, 1.0, 1.0],
+        };
+        let mut search_context = SearchContext::new(&query, 10, &inverted_index);
 
-        let mut search_context = SearchContext::new(
-            SparseVector {
-                indices: vec![1, 2, 3],
-                weights: vec![1.0, 1.0, 1.0],
-            },
-            10,
-            &inverted_index,
+        let offsets: Vec<_> = search_context
+            .postings_iterators
+            .iter()
+            .map(|it| it.query_weight_offset)
+            .collect();
+        assert_eq!(offsets, vec![1, 3]);
+
+        assert_eq!(
+            search_context.search(),
+            vec![
+                ScoredCandidate {
+                    score: 30.0,
+                    vector_id: 3
+                },
+                ScoredCandidate {
+                    score: 25.0,
+                    vector_id: 2
+                },
+                ScoredCandidate {
+                    score: 10.0,
+                    vector_id: 1
+                },
+            ]
         );
+    }
+
+    /// Counts the element reads of a posting list, the page faults of a cold mmap list
+    struct PageFaultCounter<'c, P> {
+        inner: P,
+        faults: &'c Cell<usize>,
+    }
+
+    impl<P: PostingListIter> PostingListIter for PageFaultCounter<'_, P> {
+        fn peek(&self) -> Option<PostingElement> {
+            self.faults.set(self.faults.get() + 1);
+            self.inner.peek()
+        }
+
+        fn next_element(&mut self) -> Option<PostingElement> {
+            self.faults.set(self.faults.get() + 1);
+            self.inner.next_element()
+        }
+
+        fn len_to_end(&self) -> usize {
+            self.inner.len_to_end()
+        }
+
+        fn skip_to(&mut self, id: RecordId) -> Option<PostingElement> {
+            self.faults.set(self.faults.get() + 1);
+            self.inner.skip_to(id)
+        }
+
+        fn skip_to_end(&mut self) {
+            self.inner.skip_to_end()
+        }
+
+        fn rewound(&self) -> Self {
+            PageFaultCounter {
+                inner: self.inner.rewound(),
+                faults: self.faults,
+            }
+        }
+    }
+
+    #[test]
+    fn lazy_posting_lists_opened_on_first_access() {
+        // the long list of dimension 1 starts after the records of the other lists, its
+        // contribution can not reach the top 2
+        let inverted_index_ram = InvertedIndexBuilder::new()
+            .add(
+                1,
+                PostingList::from((50..150).map(|id| (id, 1.0)).collect()),
+            )
+            .add(2, PostingList::from(vec![(1, 10.0), (2, 20.0), (3, 30.0)]))
+            .add(3, PostingList::from(vec![(2, 5.0), (4, 6.0)]))
+            .build();
+        let query = SparseVector::new(vec![0, 1, 2, 3, 100], vec![1.0, 0.1, 1.0, 1.0, 1.0]);
+        let tmp_dir_paths: Vec<_> = (0..3)
+            .map(|_| Builder::new().prefix("test_index_dir").tempdir().unwrap())
+            .collect();
+        // the posting summaries of each file encoding
+        let mmap_indexes = [
+            InvertedIndexMmap::convert_and_save(&inverted_index_ram, &tmp_dir_paths[0]),
+            InvertedIndexMmap::convert_and_save_with_encoding(
+                &inverted_index_ram,
+                &tmp_dir_paths[1],
+                WeightEncoding::F16,
+            ),
+            InvertedIndexMmap::convert_and_save_compressed(&inverted_index_ram, &tmp_dir_paths[2]),
+        ]
+        .map(|index| InvertedIndex::Mmap(index.unwrap()));
+        // and as loaded
+        let loaded_indexes = tmp_dir_paths
+            .iter()
+            .map(|path| InvertedIndex::Mmap(InvertedIndexMmap::load(path).unwrap()));
+        let indexes: Vec<_> = [InvertedIndex::Ram(inverted_index_ram)]
+            .into_iter()
+            .chain(mmap_indexes)
+            .chain(loaded_indexes)
+            .collect();
+        for inverted_index in &indexes {
+            // the dimensions opened and the reads of their elements
+            let opened = RefCell::new(Vec::new());
+            let faults = Cell::new(0);
+            let new_search = || {
+                SearchContext::from_postings(&query, 2, |id| {
+                    let summary = inverted_index.posting_summary(id)?;
+                    let id = *id;
+                    let (opened, faults) = (&opened, &faults);
+                    Some(LazyPostingListIterator::new(summary, move || {
+                        opened.borrow_mut().push(id);
+                        PageFaultCounter {
+                            inner: inverted_index.get(&id).unwrap(),
+                            faults,
+                        }
+                    }))
+                })
+            };
+
+            let mut search_context = new_search();
+            assert!(opened.borrow().is_empty());
+            let (results, stats) = search_context.search_with_stats();
+            // the list of dimension 1 is pruned without being read
+            assert!(stats.pruning_skips > 0);
+            assert_eq!(stats.elements_skipped, 100);
+            opened.borrow_mut().sort_unstable();
+            assert_eq!(*opened.borrow(), vec![2, 3]);
+            assert!(faults.get() > 0);
+            assert_eq!(
+                results,
+                SearchContext::new(&query, 2, inverted_index).search()
+            );
+            assert_eq!(
+                SearchContext::new_lazy(&query, 2, inverted_index).search(),
+                results
+            );
+
+            // lists dropped before the search starts are never opened
+            opened.borrow_mut().clear();
+            faults.set(0);
+            let filter = FilterSpec {
+                required: HashSet::from([100]),
+            };
+            assert!(new_search().with_filter(&filter).search().is_empty());
+            assert!(new_search().with_max_candidates(0).search().is_empty());
+            assert!(opened.borrow().is_empty());
+            assert_eq!(faults.get(), 0);
+        }
+    }
+
+    #[test]
+    fn search_same_results_for_both_posting_layouts() {
+        let inverted_index_ram = InvertedIndexBuilder::new()
+            .add(
+                1,
+                PostingList::from((1..200).map(|id| (id, (id % 13) as f32)).collect()),
+            )
+            .add(2, PostingList::from(vec![(1, 10.0), (2, 20.0), (3, 30.0)]))
+            .add(3, PostingList::from(vec![(3, 5.0), (50, 12.0), (150, 1.5)]))
+            .build();
+        let inverted_index_soa = InvertedIndexSoa::from_ram(&inverted_index_ram);
+        let inverted_index = InvertedIndex::Ram(inverted_index_ram);
+        let query = SparseVector::new(vec![1, 2, 3, 4], vec![1.0, 0.5, 2.0, 1.0]);
+
+        for top in [1, 3, 10, 500] {
+            let aos = SearchContext::new(&query, top, &inverted_index).search();
+            let soa =
+                SearchContext::from_postings(&query, top, |id| inverted_index_soa.get(id)).search();
+            assert_eq!(aos, soa, "top {}", top);
+        }
+    }
+
+    #[test]
+    fn search_prefetched_matches_mmap() {
+        let inverted_index_ram = InvertedIndexBuilder::new()
+            .add(1, PostingList::from(vec![(1, 10.0), (2, 20.0), (3, 30.0)]))
+            .add(2, PostingList::from(vec![(1, 11.0), (3, 5.0), (7, 8.0)]))
+            .add(4, PostingList::from(vec![(2, 3.0), (7, 40.0)]))
+            .add(5, PostingList::from(vec![(4, 50.0)]))
+            .build();
+        let tmp_dir_path = Builder::new().prefix("test_index_dir").tempdir().unwrap();
+        let inverted_index_mmap =
+            InvertedIndexMmap::convert_and_save(&inverted_index_ram, &tmp_dir_path).unwrap();
+
+        // dimension 5 is not queried, dimension 100 is absent
+        let query = SparseVector::new(vec![1, 2, 4, 100], vec![1.0, 0.5, 2.0, 1.0]);
+
+        let mut prefetched = None;
+        let prefetched_results =
+            SearchContext::new_prefetched(&query, 3, &inverted_index_mmap, &mut prefetched)
+                .unwrap()
+                .search();
+
+        let prefetched_index = prefetched.unwrap();
+        assert!(prefetched_index
+            .get(&5)
+            .is_none_or(|it| it.len_to_end() == 0));
+
+        let inverted_index = InvertedIndex::Mmap(inverted_index_mmap);
+        let mmap_results = SearchContext::new(&query, 3, &inverted_index).search();
+        assert_eq!(prefetched_results, mmap_results);
+        assert_eq!(
+            mmap_results.iter().map(|c| c.vector_id).collect::<Vec<_>>(),
+            vec![7, 3, 2]
+        );
+    }
+
+    #[test]
+    fn search() {
+        let inverted_index_ram = InvertedIndexBuilder::new()
+            .add(1, PostingList::from(vec![(1, 10.0), (2, 20.0), (3, 30.0)]))
+            .add(2, PostingList::from(vec![(1, 10.0), (2, 20.0), (3, 30.0)]))
+            .add(3, PostingList::from(vec![(1, 10.0), (2, 20.0), (3, 30.0)]))
+            .build();
+
+        let inverted_index = InvertedIndex::Ram(inverted_index_ram);
+
+        let query = SparseVector {
+            indices: vec![1, 2, 3],
+            weights: vec![1.0, 1.0, 1.0],
+        };
+        let mut search_context = SearchContext::new(&query, 10, &inverted_index);
 
         assert_eq!(
             search_context.search(),
@@ -270,6 +1471,44 @@ mod tests {
         );
     }
 
+    #[test]
+    fn search_shared_queue() {
+        let shard_one = InvertedIndex::Ram(
+            InvertedIndexBuilder::new()
+                .add(1, PostingList::from(vec![(1, 10.0), (2, 25.0), (3, 30.0)]))
+                .add(2, PostingList::from(vec![(1, 11.0), (3, 5.0)]))
+                .build(),
+        );
+        let shard_two = InvertedIndex::Ram(
+            InvertedIndexBuilder::new()
+                .add(1, PostingList::from(vec![(10, 12.0), (11, 1.0), (12, 7.0)]))
+                .add(2, PostingList::from(vec![(10, 3.0), (12, 22.0)]))
+                .build(),
+        );
+        let query = SparseVector::new(vec![1, 2], vec![1.0, 1.0]);
+        let top = 3;
+
+        // separate searches merged afterwards
+        let mut merged: Vec<_> = [&shard_one, &shard_two]
+            .iter()
+            .flat_map(|index| SearchContext::new(&query, top, index).search())
+            .collect();
+        merged.sort_by(|a, b| b.cmp(a));
+        merged.truncate(top);
+
+        // searches accumulating into one queue
+        let queue = SearchContext::new(&query, top, &shard_one).search_queue();
+        let shared = SearchContext::new(&query, top, &shard_two)
+            .with_result_queue(queue)
+            .search();
+
+        assert_eq!(shared, merged);
+        assert_eq!(
+            shared.iter().map(|c| c.vector_id).collect::<Vec<_>>(),
+            vec![3, 12, 2]
+        );
+    }
+
     #[test]
     fn search_with_hot_key() {
         let inverted_index = InvertedIndexBuilder::new()
@@ -293,14 +1532,11 @@ mod tests {
 
         let inverted_index = &InvertedIndex::Ram(inverted_index);
 
-        let mut search_context = SearchContext::new(
-            SparseVector {
-                indices: vec![1, 2, 3],
-                weights: vec![1.0, 1.0, 1.0],
-            },
-            3,
-            &inverted_index,
-        );
+        let query = SparseVector {
+            indices: vec![1, 2, 3],
+            weights: vec![1.0, 1.0, 1.0],
+        };
+        let mut search_context = SearchContext::new(&query, 3, inverted_index);
 
         assert_eq!(
             search_context.search(),
@@ -320,14 +1556,11 @@ mod tests {
             ]
         );
 
-        let mut search_context = SearchContext::new(
-            SparseVector {
-                indices: vec![1, 2, 3],
-                weights: vec![1.0, 1.0, 1.0],
-            },
-            4,
-            &inverted_index,
-        );
+        let query = SparseVector {
+            indices: vec![1, 2, 3],
+            weights: vec![1.0, 1.0, 1.0],
+        };
+        let mut search_context = SearchContext::new(&query, 4, inverted_index);
 
         assert_eq!(
             search_context.search(),
@@ -352,6 +1585,745 @@ mod tests {
         );
     }
 
+    #[test]
+    fn score_of_matches_dot_product() {
+        let vectors = [
+            SparseVector::new(vec![1, 3], vec![0.5, 2.0]),
+            SparseVector::new(vec![1, 2, 3], vec![1.5, 0.25, 3.0]),
+            SparseVector::new(vec![2], vec![4.0]),
+            SparseVector::new(vec![7], vec![1.0]),
+        ];
+        let mut builder = InvertedIndexBuilder::new();
+        for dim in 1..=7 {
+            let records = vectors
+                .iter()
+                .enumerate()
+                .filter_map(|(id, vector)| {
+                    let i = vector.indices.iter().position(|&d| d == dim)?;
+                    Some((id as RecordId, vector.weights[i]))
+                })
+                .collect();
+            builder.add(dim, PostingList::from(records));
+        }
+        let inverted_index = InvertedIndex::Ram(builder.build());
+        let query = SparseVector::new(vec![3, 1, 2], vec![1.0, 2.0, 0.5]);
+
+        let mut search_context = SearchContext::new(&query, 2, &inverted_index);
+        // consume part of the traversal, score_of must not depend on it
+        search_context.advance();
+        search_context.sort_posting_lists_by_len();
+
+        for (id, vector) in vectors.iter().enumerate() {
+            let expected = query.dot_product(vector);
+            match search_context.score_of(id as RecordId) {
+                Some(score) => assert_eq!(score, expected, "id {}", id),
+                None => assert_eq!(expected, 0.0, "id {}", id),
+            }
+        }
+        assert_eq!(search_context.score_of(3), None);
+        assert_eq!(search_context.score_of(42), None);
+
+        // the traversal continues from where it was
+        assert_eq!(search_context.advance().map(|c| c.vector_id), Some(1));
+    }
+
+    #[test]
+    fn search_with_required_dimensions() {
+        let inverted_index = InvertedIndex::Ram(
+            InvertedIndexBuilder::new()
+                .add(
+                    1,
+                    PostingList::from(vec![(1, 1.0), (2, 9.0), (4, 2.0), (5, 0.5)]),
+                )
+                .add(
+                    2,
+                    PostingList::from(vec![(1, 5.0), (3, 5.0), (4, 1.0), (5, 1.0)]),
+                )
+                .add(3, PostingList::from(vec![(1, 1.0), (5, 1.0)]))
+                .build(),
+        );
+        let filter = FilterSpec {
+            required: HashSet::from([2]),
+        };
+
+        // required dimension with a zero weight: 2 lacks it, 3 has no score
+        let query = SparseVector::new(vec![1, 2], vec![1.0, 0.0]);
+        let mut search_context =
+            SearchContext::new(&query, 2, &inverted_index).with_filter(&filter);
+        assert_eq!(
+            search_context.search(),
+            vec![
+                ScoredCandidate {
+                    score: 2.0,
+                    vector_id: 4
+                },
+                ScoredCandidate {
+                    score: 1.0,
+                    vector_id: 1
+                },
+            ]
+        );
+
+        // the weight of a required dimension is never scored, 3 only matches the filter
+        let query = SparseVector::new(vec![1, 2], vec![1.0, 3.0]);
+        let mut search_context =
+            SearchContext::new(&query, 10, &inverted_index).with_filter(&filter);
+        let results = search_context.search();
+        assert_eq!(
+            results
+                .iter()
+                .map(|c| (c.vector_id, c.score))
+                .collect::<Vec<_>>(),
+            vec![(4, 2.0), (1, 1.0), (5, 0.5), (3, 0.0)]
+        );
+
+        // a required dimension missing from the query matches nothing
+        let filter = FilterSpec {
+            required: HashSet::from([2, 3]),
+        };
+        let query = SparseVector::new(vec![1, 2], vec![1.0, 0.0]);
+        let mut search_context =
+            SearchContext::new(&query, 2, &inverted_index).with_filter(&filter);
+        assert!(search_context.search().is_empty());
+    }
+
+    #[test]
+    fn search_iter_is_lazy() {
+        let inverted_index = InvertedIndex::Ram(
+            InvertedIndexBuilder::new()
+                .add(
+                    1,
+                    PostingList::from(vec![(1, 30.0), (2, 1.0), (3, 2.0), (4, 1.0), (5, 3.0)]),
+                )
+                .add(2, PostingList::from(vec![(1, 10.0), (4, 1.0)]))
+                .build(),
+        );
+        let query = SparseVector::new(vec![1, 2], vec![1.0, 1.0]);
+
+        let mut search_context = SearchContext::new(&query, 1, &inverted_index);
+        let first = search_context.search_iter().next();
+        assert_eq!(
+            first,
+            Some(ScoredCandidate {
+                score: 40.0,
+                vector_id: 1
+            })
+        );
+        // yielded before the other records were visited
+        assert_eq!(search_context.stats.advance_calls, 1);
+
+        let all: Vec<_> = SearchContext::new(&query, 1, &inverted_index)
+            .search_iter()
+            .collect();
+        assert_eq!(all, SearchContext::new(&query, 5, &inverted_index).search());
+    }
+
+    #[test]
+    fn search_streaming_matches_search() {
+        let mut builder = InvertedIndexBuilder::new();
+        for dim in 1..6 {
+            let records = (0..40)
+                .filter(|id| (id + dim) % (dim + 1) != 0)
+                .map(|id| (id, ((id * 7 + dim * 3) % 11) as f32 + 0.5))
+                .collect();
+            builder.add(dim, PostingList::from(records));
+        }
+        let inverted_index = InvertedIndex::Ram(builder.build());
+        let query = SparseVector::new(vec![1, 2, 3, 4, 5], vec![1.0, 0.5, 2.0, 0.25, 1.5]);
+
+        for top in [1, 3, 10, 50] {
+            let expected = SearchContext::new(&query, top, &inverted_index).search();
+
+            let (tx, rx) = std::sync::mpsc::channel();
+            SearchContext::new(&query, top, &inverted_index).search_streaming(tx);
+            let mut streamed: Vec<_> = rx.iter().collect();
+            streamed.sort_by(|a, b| b.cmp(a));
+            assert_eq!(streamed, expected, "top {}", top);
+        }
+
+        // same set as the search with the threshold, the sort values and every loop option
+        let sort_values = SortValues::Dense((0..40).map(|id| ((id * 5) % 7) as f32).collect());
+        let options: [fn(SearchContext) -> SearchContext; 5] = [
+            |search_context| search_context,
+            |search_context| search_context.with_early_termination(),
+            |search_context| search_context.with_pivot_pruning(),
+            |search_context| search_context.with_pruning_order(PruningOrder::MaxContribution),
+            |search_context| search_context.with_max_candidates(10),
+        ];
+        let by_id = |mut results: Vec<ScoredCandidate>| {
+            results.sort_by_key(|candidate| candidate.vector_id);
+            results
+        };
+        for with_option in options {
+            for top in [1, 3, 10, 50] {
+                let new_search = || {
+                    with_option(
+                        SearchContext::new(&query, top, &inverted_index)
+                            .with_score_threshold(8.0)
+                            .with_sort_values(&sort_values),
+                    )
+                };
+                let expected = new_search().search();
+                assert!(!expected.is_empty());
+                assert!(expected.iter().all(|candidate| candidate.score >= 8.0));
+
+                let (tx, rx) = std::sync::mpsc::channel();
+                new_search().search_streaming(tx);
+                let streamed: Vec<_> = rx.iter().collect();
+                assert_eq!(by_id(streamed), by_id(expected), "top {}", top);
+            }
+        }
+    }
+
+    #[test]
+    fn search_with_blacklist() {
+        let vectors = [
+            SparseVector::new(vec![1, 2], vec![5.0, 1.0]),
+            SparseVector::new(vec![1, 3], vec![4.0, 2.0]),
+            SparseVector::new(vec![1, 2, 3], vec![3.0, 0.5, 0.5]),
+            SparseVector::new(vec![1], vec![9.0]),
+        ];
+        // dimension 1 is hot, in all the vectors
+        let mut builder = InvertedIndexBuilder::new();
+        for dim in 1..4 {
+            let records = vectors
+                .iter()
+                .enumerate()
+                .filter_map(|(id, vector)| {
+                    let i = vector.indices.iter().position(|&d| d == dim)?;
+                    Some((id as RecordId, vector.weights[i]))
+                })
+                .collect();
+            builder.add(dim, PostingList::from(records));
+        }
+        let inverted_index = InvertedIndex::Ram(builder.build());
+        let query = SparseVector::new(vec![1, 2, 3], vec![1.0, 1.0, 1.0]);
+        let blacklist = HashSet::from([1]);
+
+        let mut search_context =
+            SearchContext::new_with_blacklist(&query, 4, &inverted_index, Some(&blacklist));
+        let results = search_context.search();
+        assert_eq!(
+            results.iter().map(|c| c.vector_id).collect::<Vec<_>>(),
+            vec![1, 0, 2]
+        );
+        for candidate in results {
+            let vector = &vectors[candidate.vector_id as usize];
+            assert_eq!(
+                candidate.score,
+                query.dot_product_excluding(vector, Some(&blacklist), Summation::Naive)
+            );
+        }
+
+        let mut search_context =
+            SearchContext::new_with_blacklist(&query, 1, &inverted_index, None);
+        assert_eq!(search_context.search()[0].vector_id, 3);
+    }
+
+    #[test]
+    fn search_reports_unmatched_dims() {
+        let mut builder = InvertedIndexBuilder::new();
+        builder.add(1, PostingList::from(vec![(1, 1.0), (2, 2.0)]));
+        builder.add(3, PostingList::from(vec![(2, 1.0)]));
+        let inverted_index = InvertedIndex::Ram(builder.build());
+        // 2 is below the last dimension of the index, 7 above it
+        let query = SparseVector::new(vec![1, 2, 3, 7], vec![1.0, 1.0, 1.0, 1.0]);
+
+        let mut search_context = SearchContext::new(&query, 10, &inverted_index);
+        assert_eq!(search_context.unmatched_dims(), &[2, 7]);
+        assert_eq!(search_context.search().len(), 2);
+
+        let blacklist = HashSet::from([3]);
+        let search_context =
+            SearchContext::new_with_blacklist(&query, 10, &inverted_index, Some(&blacklist));
+        assert_eq!(search_context.unmatched_dims(), &[2, 3, 7]);
+    }
+
+    #[test]
+    fn search_min_term_contribution() {
+        let mut builder = InvertedIndexBuilder::new();
+        builder.add(1, PostingList::from(vec![(1, 0.1), (2, 1.0), (3, 2.0)]));
+        builder.add(2, PostingList::from(vec![(1, 3.0), (2, 0.2)]));
+        builder.add(3, PostingList::from(vec![(3, 4.0)]));
+        let inverted_index = InvertedIndex::Ram(builder.build());
+        // dimension 3 has a query weight below the threshold
+        let query = SparseVector::new(vec![1, 2, 3], vec![1.0, 2.0, 0.4]);
+
+        let mut search_context = SearchContext::new(&query, 3, &inverted_index);
+        assert_eq!(
+            search_context.search(),
+            vec![
+                ScoredCandidate {
+                    score: 6.1,
+                    vector_id: 1
+                },
+                ScoredCandidate {
+                    score: 3.6,
+                    vector_id: 3
+                },
+                ScoredCandidate {
+                    score: 1.4,
+                    vector_id: 2
+                },
+            ]
+        );
+
+        let mut search_context =
+            SearchContext::new(&query, 3, &inverted_index).with_min_term_contribution(0.5);
+        // 1: 0.1 trimmed, 2: 0.2 trimmed, 3: query weight 0.4 trimmed
+        assert_eq!(search_context.score_of(1), Some(6.0));
+        assert_eq!(search_context.score_of(3), Some(2.0));
+        assert_eq!(
+            search_context.search(),
+            vec![
+                ScoredCandidate {
+                    score: 6.0,
+                    vector_id: 1
+                },
+                ScoredCandidate {
+                    score: 2.0,
+                    vector_id: 3
+                },
+                ScoredCandidate {
+                    score: 1.0,
+                    vector_id: 2
+                },
+            ]
+        );
+    }
+
+    #[derive(Default)]
+    struct CountingSink {
+        counters: RefCell<HashMap<SearchCounter, u64>>,
+    }
+
+    impl MetricsSink for CountingSink {
+        fn increment(&self, counter: SearchCounter, value: u64) {
+            *self.counters.borrow_mut().entry(counter).or_default() += value;
+        }
+    }
+
+    #[test]
+    fn search_early_termination() {
+        // two high weight records followed by long low weight tails
+        let records: Vec<_> = (0..50)
+            .map(|id| (id, if id < 2 { 10.0 } else { 0.1 }))
+            .collect();
+        let inverted_index = InvertedIndex::Ram(
+            InvertedIndexBuilder::new()
+                .add(1, PostingList::from(records.clone()))
+                .add(2, PostingList::from(records))
+                .build(),
+        );
+        let query = SparseVector::new(vec![1, 2], vec![1.0, 1.0]);
+
+        let exhaustive_sink = CountingSink::default();
+        let exhaustive = SearchContext::new(&query, 2, &inverted_index)
+            .with_metrics(&exhaustive_sink)
+            .search();
+        let early_sink = CountingSink::default();
+        let early = SearchContext::new(&query, 2, &inverted_index)
+            .with_early_termination()
+            .with_metrics(&early_sink)
+            .search();
+
+        assert_eq!(early, exhaustive);
+        let scored =
+            |sink: CountingSink| sink.counters.into_inner()[&SearchCounter::CandidatesScored];
+        assert_eq!(scored(exhaustive_sink), 50);
+        assert_eq!(scored(early_sink), 2);
+    }
+
+    #[test]
+    fn search_wand_matches_search() {
+        // a hot dimension with mostly low weights and a few rare dimensions
+        let mut builder = InvertedIndexBuilder::new();
+        builder.add(
+            1,
+            PostingList::from(
+                (0..2000)
+                    .map(|id| {
+                        (
+                            id,
+                            if id % 97 == 0 {
+                                5.0
+                            } else {
+                                0.01 * (id % 7) as f32
+                            },
+                        )
+                    })
+                    .collect(),
+            ),
+        );
+        for dim in 2..6 {
+            let records = (0..2000)
+                .filter(|id| id % (dim * 11) == 0)
+                .map(|id| (id, ((id * 7 + dim * 3) % 11) as f32 + 0.5))
+                .collect();
+            builder.add(dim, PostingList::from(records));
+        }
+        let inverted_index = InvertedIndex::Ram(builder.build());
+        let query = SparseVector::new(vec![1, 2, 3, 4, 5], vec![1.0, 0.5, 2.0, 0.25, 1.5]);
+
+        for top in [1, 3, 10, 50, 3000] {
+            let (expected, stats) = SearchContext::new(&query, top, &inverted_index)
+                .with_tie_break(TieBreak::ById)
+                .search_with_stats();
+            let mut search_context =
+                SearchContext::new(&query, top, &inverted_index).with_tie_break(TieBreak::ById);
+            assert_eq!(search_context.search_wand(), expected, "top {}", top);
+            if top < 50 {
+                assert!(
+                    search_context.stats.elements_scored < stats.elements_scored,
+                    "top {}: {:?} vs {:?}",
+                    top,
+                    search_context.stats,
+                    stats
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn search_score_threshold() {
+        let mut builder = InvertedIndexBuilder::new();
+        builder.add(
+            1,
+            PostingList::from((0..100).map(|id| (id, (id % 10) as f32)).collect()),
+        );
+        builder.add(2, PostingList::from(vec![(3, 4.0), (50, 1.0), (99, 0.5)]));
+        let inverted_index = InvertedIndex::Ram(builder.build());
+        let query = SparseVector::new(vec![1, 2], vec![1.0, 2.0]);
+        // above the maximum weight of the first list
+        let threshold = 10.0;
+
+        for top in [1, 5, 100] {
+            let expected: Vec<_> = SearchContext::new(&query, top, &inverted_index)
+                .search()
+                .into_iter()
+                .filter(|candidate| candidate.score >= threshold)
+                .collect();
+            let (results, stats) = SearchContext::new(&query, top, &inverted_index)
+                .with_score_threshold(threshold)
+                .search_with_stats();
+            assert_eq!(results, expected, "top {}", top);
+            let wand = SearchContext::new(&query, top, &inverted_index)
+                .with_score_threshold(threshold)
+                .search_wand();
+            assert_eq!(wand, expected, "top {}", top);
+            // the threshold prunes before the queue is full
+            assert!(stats.elements_skipped > 0, "top {}", top);
+        }
+    }
+
+    #[test]
+    fn search_stats() {
+        let inverted_index = InvertedIndex::Ram(
+            InvertedIndexBuilder::new()
+                .add(
+                    1,
+                    PostingList::from(vec![
+                        (1, 10.0),
+                        (2, 20.0),
+                        (3, 30.0),
+                        (4, 1.0),
+                        (5, 2.0),
+                        (6, 3.0),
+                    ]),
+                )
+                .add(2, PostingList::from(vec![(1, 10.0), (2, 20.0), (3, 30.0)]))
+                .add(3, PostingList::from(vec![(1, 10.0), (2, 20.0), (3, 30.0)]))
+                .build(),
+        );
+        let query = SparseVector::new(vec![1, 2, 3], vec![1.0, 1.0, 1.0]);
+
+        let (results, stats) = SearchContext::new(&query, 3, &inverted_index).search_with_stats();
+        assert_eq!(
+            results,
+            SearchContext::new(&query, 3, &inverted_index).search()
+        );
+        // the queue is full after record 3, the tail of the first list is then pruned
+        assert_eq!(
+            stats,
+            SearchStats {
+                advance_calls: 4,
+                elements_scored: 9,
+                pruning_skips: 1,
+                elements_skipped: 3,
+            }
+        );
+    }
+
+    /// A long high weight posting list on the even records and a shorter low weight one
+    /// on the odd records
+    fn non_balanced_index() -> InvertedIndex {
+        let high: Vec<_> = (0..100).map(|i| (2 * i, 1.0)).collect();
+        let low: Vec<_> = (0..40).map(|i| (2 * i + 1, 0.01)).collect();
+        InvertedIndex::Ram(
+            InvertedIndexBuilder::new()
+                .add(1, PostingList::from(high))
+                .add(2, PostingList::from(low))
+                .build(),
+        )
+    }
+
+    #[test]
+    fn search_with_non_balanced() {
+        let inverted_index = non_balanced_index();
+        let query = SparseVector::new(vec![1, 2], vec![1.0, 1.0]);
+
+        let (results, stats) = SearchContext::new(&query, 3, &inverted_index)
+            .with_pruning_order(PruningOrder::Length)
+            .search_with_stats();
+        let (max_contribution_results, max_contribution_stats) =
+            SearchContext::new(&query, 3, &inverted_index)
+                .with_pruning_order(PruningOrder::MaxContribution)
+                .search_with_stats();
+        assert_eq!(max_contribution_results, results);
+        assert_eq!(
+            results.iter().map(|c| c.vector_id).collect::<Vec<_>>(),
+            vec![0, 2, 4]
+        );
+        // the longest list can not be pruned, every record is scored
+        assert_eq!(stats.advance_calls, 141);
+        assert_eq!(stats.elements_skipped, 0);
+        // the low weight list is pruned once the queue holds 3 even records
+        assert_eq!(max_contribution_stats.advance_calls, 103);
+        assert_eq!(max_contribution_stats.elements_skipped, 38);
+
+        // a short list exhausted by the first records does not stop the pruning
+        let high: Vec<_> = (0..100).map(|i| (2 * i, 1.0)).collect();
+        let low: Vec<_> = (0..40).map(|i| (2 * i + 1, 0.01)).collect();
+        let inverted_index = InvertedIndex::Ram(
+            InvertedIndexBuilder::new()
+                .add(1, PostingList::from(high))
+                .add(2, PostingList::from(low))
+                .add(3, PostingList::from(vec![(0, 0.5), (2, 0.5)]))
+                .build(),
+        );
+        let query = SparseVector::new(vec![1, 2, 3], vec![1.0, 1.0, 1.0]);
+        let (results, stats) = SearchContext::new(&query, 3, &inverted_index)
+            .with_pruning_order(PruningOrder::MaxContribution)
+            .search_with_stats();
+        assert_eq!(
+            results.iter().map(|c| c.vector_id).collect::<Vec<_>>(),
+            vec![0, 2, 4]
+        );
+        assert_eq!(stats.advance_calls, 103);
+        assert_eq!(stats.elements_skipped, 38);
+    }
+
+    #[test]
+    fn search_detailed_matches_search() {
+        let inverted_index = non_balanced_index();
+        let query = SparseVector::new(vec![1, 2, 3], vec![1.0, 1.0, 1.0]);
+        let options: [fn(SearchContext) -> SearchContext; 5] = [
+            |search_context| search_context,
+            |search_context| search_context.with_early_termination(),
+            |search_context| search_context.with_pivot_pruning(),
+            |search_context| search_context.with_pruning_order(PruningOrder::MaxContribution),
+            |search_context| search_context.with_max_candidates(10),
+        ];
+        for with_option in options {
+            let (results, stats) =
+                with_option(SearchContext::new(&query, 3, &inverted_index)).search_with_stats();
+            let mut search_context = with_option(SearchContext::new(&query, 3, &inverted_index));
+            let detailed = search_context.search_detailed();
+            assert_eq!(
+                detailed
+                    .iter()
+                    .map(|(candidate, _)| candidate)
+                    .collect::<Vec<_>>(),
+                results.iter().collect::<Vec<_>>()
+            );
+            // same traversal, not only the same results
+            assert_eq!(search_context.stats, stats);
+            // dimension 3 is absent from the index
+            assert!(detailed.iter().all(|(_, matched)| *matched == 1));
+        }
+    }
+
+    #[test]
+    fn sort_by_max_contribution_puts_exhausted_lists_last() {
+        let inverted_index = InvertedIndex::Ram(
+            InvertedIndexBuilder::new()
+                .add(1, PostingList::from(vec![(1, 1.0), (2, 1.0), (3, 1.0)]))
+                .add(2, PostingList::from(vec![(1, 0.1), (2, 0.1), (3, 0.1)]))
+                .add(3, PostingList::from(vec![(1, 0.01)]))
+                .build(),
+        );
+        let query = SparseVector::new(vec![1, 2, 3], vec![1.0, 1.0, 1.0]);
+        let mut search_context = SearchContext::new(&query, 3, &inverted_index);
+        let head_dim = |search_context: &SearchContext| {
+            query.indices[search_context.postings_iterators[0].query_weight_offset]
+        };
+
+        search_context.sort_posting_lists_by_max_contribution();
+        assert_eq!(head_dim(&search_context), 3);
+
+        // the short list is exhausted by the first record, the low weight list is next
+        search_context.advance();
+        search_context.sort_posting_lists_by_max_contribution();
+        assert_eq!(head_dim(&search_context), 2);
+        assert!(search_context.postings_iterators[2]
+            .posting_list_iterator
+            .peek()
+            .is_none());
+    }
+
+    #[test]
+    fn search_with_max_candidates() {
+        // a short low weight list on the odd records, a long high weight one on the even
+        let low: Vec<_> = (0..20).map(|i| (2 * i + 1, 0.01)).collect();
+        let high: Vec<_> = (0..50).map(|i| (2 * i, 1.0)).collect();
+        let inverted_index = InvertedIndex::Ram(
+            InvertedIndexBuilder::new()
+                .add(1, PostingList::from(low))
+                .add(2, PostingList::from(high))
+                .build(),
+        );
+        let query = SparseVector::new(vec![1, 2], vec![1.0, 1.0]);
+
+        let exact = SearchContext::new(&query, 3, &inverted_index).search();
+        let unbounded = SearchContext::new(&query, 3, &inverted_index)
+            .with_max_candidates(1000)
+            .search();
+        assert_eq!(unbounded, exact);
+
+        let (results, stats) = SearchContext::new(&query, 1, &inverted_index)
+            .with_max_candidates(10)
+            .search_with_stats();
+        assert_eq!(results[0].vector_id, 0);
+        // the low weight list is skipped after each candidate, only even records are scored
+        assert_eq!(stats.advance_calls, 10);
+        assert_eq!(stats.elements_scored, 10);
+        assert_eq!(stats.elements_skipped, 10);
+
+        let results = SearchContext::new(&query, 3, &inverted_index)
+            .with_max_candidates(0)
+            .search();
+        assert!(results.is_empty());
+    }
+
+    #[test]
+    fn search_generic_weights() {
+        // integer weights, exact in every weight type
+        let postings: HashMap<DimId, PostingList> = HashMap::from([
+            (
+                1,
+                PostingList::from(vec![(1, 10.0), (2, 20.0), (4, -3.0), (7, 4.0)]),
+            ),
+            (2, PostingList::from(vec![(2, 1.0), (3, 5.0), (7, 2.0)])),
+            (5, PostingList::from(vec![(1, 7.0), (3, 6.0)])),
+        ]);
+        let query = SparseVector::new(vec![1, 2, 5], vec![1.0, 2.0, 3.0]);
+        let expected = SearchContext::from_postings(&query, 4, |id| {
+            postings
+                .get(id)
+                .map(|posting| PostingListIterator::new(&posting.elements))
+        })
+        .search();
+
+        let postings_f16: HashMap<_, PostingList<f16>> = postings
+            .iter()
+            .map(|(dim, posting)| (*dim, PostingList::from_f32(posting)))
+            .collect();
+        let results = SearchContext::from_postings(&query, 4, |id| {
+            postings_f16
+                .get(id)
+                .map(|posting| WeightPostingListIterator::new(&posting.elements))
+        })
+        .search();
+        assert_eq!(results, expected);
+
+        let postings_i8: HashMap<_, PostingList<i8>> = postings
+            .iter()
+            .map(|(dim, posting)| (*dim, PostingList::from_f32(posting)))
+            .collect();
+        let results = SearchContext::from_postings(&query, 4, |id| {
+            postings_i8
+                .get(id)
+                .map(|posting| WeightPostingListIterator::new(&posting.elements))
+        })
+        .search();
+        assert_eq!(results, expected);
+
+        // scores of i8 vectors are accumulated in f32 without overflow
+        let vector: SparseVector<i8> = SparseVector::from_f32(&SparseVector::new(
+            vec![1, 2, 5],
+            vec![100.0, -128.0, 300.0],
+        ));
+        assert_eq!(vector.weights, vec![100, -128, 127]);
+        assert_eq!(vector.dot_product_f32(&vector), 10000.0 + 16384.0 + 16129.0);
+        assert_eq!(vector.to_f32().weights, vec![100.0, -128.0, 127.0]);
+    }
+
+    #[test]
+    fn search_with_pivot_pruning() {
+        // two long low weight lists and a short high weight one
+        let low: Vec<_> = (1..=50).map(|id| (id, 1.0)).collect();
+        let inverted_index = InvertedIndex::Ram(
+            InvertedIndexBuilder::new()
+                .add(1, PostingList::from(low.clone()))
+                .add(2, PostingList::from(low))
+                .add(
+                    3,
+                    PostingList::from(vec![(1, 30.0), (2, 30.0), (3, 30.0), (50, 30.0)]),
+                )
+                .build(),
+        );
+        let query = SparseVector::new(vec![1, 2, 3], vec![1.0, 1.0, 1.0]);
+
+        let (results, stats) = SearchContext::new(&query, 3, &inverted_index).search_with_stats();
+        let (pivot_results, pivot_stats) = SearchContext::new(&query, 3, &inverted_index)
+            .with_pivot_pruning()
+            .search_with_stats();
+        assert_eq!(pivot_results, results);
+        // the longest list alone can not be skipped past the other low weight list
+        assert_eq!(stats.advance_calls, 51);
+        // records 4 to 49 are skipped in both low weight lists
+        assert_eq!(pivot_stats.advance_calls, 5);
+        assert_eq!(pivot_stats.elements_skipped, 2 * 46);
+    }
+
+    #[test]
+    fn search_metrics() {
+        let inverted_index = InvertedIndex::Ram(
+            InvertedIndexBuilder::new()
+                .add(
+                    1,
+                    PostingList::from(vec![
+                        (1, 10.0),
+                        (2, 20.0),
+                        (3, 30.0),
+                        (4, 1.0),
+                        (5, 2.0),
+                        (6, 3.0),
+                    ]),
+                )
+                .add(2, PostingList::from(vec![(1, 10.0), (2, 20.0), (3, 30.0)]))
+                .add(3, PostingList::from(vec![(1, 10.0), (2, 20.0), (3, 30.0)]))
+                .build(),
+        );
+        let sink = CountingSink::default();
+
+        let results = SearchContext::new(
+            &SparseVector::new(vec![1, 2, 3, 100], vec![1.0, 1.0, 1.0, 1.0]),
+            3,
+            &inverted_index,
+        )
+        .with_metrics(&sink)
+        .search();
+        assert_eq!(results.len(), 3);
+
+        // the queue is full after record 3, the tail of the first list is then pruned
+        let counters = sink.counters.into_inner();
+        assert_eq!(counters[&SearchCounter::Queries], 1);
+        assert_eq!(counters[&SearchCounter::PostingListsTouched], 3);
+        assert_eq!(counters[&SearchCounter::CandidatesScored], 3);
+        assert_eq!(counters[&SearchCounter::PruningSkips], 1);
+    }
+
     #[test]
     fn prune_test() {
         let inverted_index_ram = InvertedIndexBuilder::new()
@@ -375,14 +2347,11 @@ mod tests {
 
         let inverted_index = &InvertedIndex::Ram(inverted_index_ram);
 
-        let mut search_context = SearchContext::new(
-            SparseVector {
-                indices: vec![1, 2, 3],
-                weights: vec![1.0, 1.0, 1.0],
-            },
-            3,
-            &inverted_index,
-        );
+        let query = SparseVector {
+            indices: vec![1, 2, 3],
+            weights: vec![1.0, 1.0, 1.0],
+        };
+        let mut search_context = SearchContext::new(&query, 3, inverted_index);
 
         // initial state
         assert_eq!(
@@ -1,23 +1,50 @@
-use crate::sparse_index::common::types::{DimId, RecordId};
+use crate::sparse_index::common::types::{DimId, DimWeight, RecordId};
 use crate::sparse_index::common::vector::SparseVector;
+use crate::sparse_index::immutable::inverted_index::inverted_index_mmap::InvertedIndexMmap;
+use crate::sparse_index::immutable::posting_list::PostingList;
+use crate::storage::SparseVectorStorage;
 use std::collections::HashMap;
+use std::mem::size_of;
+use std::path::Path;
 
-#[derive(Debug)]
+#[derive(Debug, Default)]
 pub struct MutableSparseVectorIndex {
     pub map: HashMap<DimId, Vec<RecordId>>,
+    /// Largest absolute weight by dimension, NaN if any weight is NaN.
+    /// Not lowered by updates and removals, it stays an upper bound.
+    max_weights: HashMap<DimId, DimWeight>,
 }
 
 impl MutableSparseVectorIndex {
     pub fn new() -> MutableSparseVectorIndex {
         MutableSparseVectorIndex {
             map: HashMap::new(),
+            max_weights: HashMap::new(),
         }
     }
 
+    /// Approximate allocated bytes of the postings and the max weights, the control bytes
+    /// of the hash tables are not counted
+    pub fn memory_bytes(&self) -> usize {
+        let map_bytes = self.map.capacity() * size_of::<(DimId, Vec<RecordId>)>();
+        let postings_bytes: usize = self
+            .map
+            .values()
+            .map(|posting| posting.capacity() * size_of::<RecordId>())
+            .sum();
+        let max_weights_bytes = self.max_weights.capacity() * size_of::<(DimId, DimWeight)>();
+        map_bytes + postings_bytes + max_weights_bytes
+    }
+
     pub fn get(&self, index: &DimId) -> Option<&Vec<RecordId>> {
         self.map.get(index)
     }
 
+    /// Upper bound of the absolute weights of a dimension, None for an absent dimension
+    pub fn max_weight(&self, index: &DimId) -> Option<DimWeight> {
+        self.max_weights.get(index).copied()
+    }
+
     pub fn add(&mut self, vector_id: RecordId, sparse_vector: &SparseVector) {
         for index in &sparse_vector.indices {
             self.map
@@ -25,5 +52,104 @@ impl MutableSparseVectorIndex {
                 .or_insert(Vec::new()) // init if not exists
                 .push(vector_id); // add vector id to posting list
         }
+        self.raise_max_weights(sparse_vector);
+    }
+
+    fn raise_max_weights(&mut self, sparse_vector: &SparseVector) {
+        for (index, weight) in sparse_vector.indices.iter().zip(&sparse_vector.weights) {
+            let weight = weight.abs();
+            let max_weight = self.max_weights.entry(*index).or_insert(weight);
+            if !max_weight.is_nan() && (weight.is_nan() || weight > *max_weight) {
+                *max_weight = weight;
+            }
+        }
+    }
+
+    /// Move a record from the postings of `old` dimensions to the postings of `new` ones,
+    /// dimensions in both keep a single entry. Emptied postings are dropped.
+    pub fn update(&mut self, vector_id: RecordId, old: &SparseVector, new: &SparseVector) {
+        for index in &old.indices {
+            if !new.indices.contains(index) {
+                self.remove_from_posting(*index, vector_id);
+            }
+        }
+        for index in &new.indices {
+            if !old.indices.contains(index) {
+                self.map.entry(*index).or_default().push(vector_id);
+            }
+        }
+        self.raise_max_weights(new);
+    }
+
+    /// Remove a record from the postings of its dimensions. Emptied postings are dropped.
+    pub fn remove(&mut self, vector_id: RecordId, sparse_vector: &SparseVector) {
+        for index in &sparse_vector.indices {
+            self.remove_from_posting(*index, vector_id);
+        }
+    }
+
+    fn remove_from_posting(&mut self, index: DimId, vector_id: RecordId) {
+        if let Some(posting) = self.map.get_mut(&index) {
+            posting.retain(|id| *id != vector_id);
+            if posting.is_empty() {
+                self.map.remove(&index);
+                self.max_weights.remove(&index);
+            }
+        }
+    }
+
+    /// Write a mmap index directly from the postings, one posting list in memory at a time.
+    /// Weights are read from the vectors in `storage`.
+    pub fn to_mmap<P: AsRef<Path>>(
+        &self,
+        storage: &SparseVectorStorage,
+        path: P,
+    ) -> std::io::Result<InvertedIndexMmap> {
+        // same layout as the RAM index, one posting per dimension up to the max
+        let posting_count = self.map.keys().max().map_or(1, |max| *max as usize + 1);
+        let posting_lens: Vec<_> = (0..posting_count as DimId)
+            .map(|dim| self.map.get(&dim).map_or(0, Vec::len))
+            .collect();
+        let postings = (0..posting_count as DimId).map(|dim| match self.map.get(&dim) {
+            Some(vector_ids) => storage.posting_builder(dim, vector_ids).build().elements,
+            None => PostingList::default().elements,
+        });
+        InvertedIndexMmap::save_postings(path, &posting_lens, postings)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sparse_index::immutable::inverted_index::InvertedIndex;
+    use tempfile::Builder;
+
+    #[test]
+    fn test_to_mmap_matches_two_step_build() {
+        let mut storage = SparseVectorStorage::new();
+        storage.add(0, SparseVector::new(vec![1, 5, 7], vec![1.0, 2.0, 3.0]));
+        storage.add(1, SparseVector::new(vec![5, 2], vec![0.5, 4.0]));
+        storage.add(3, SparseVector::new(vec![7, 1, 9], vec![2.5, 1.5, 0.1]));
+
+        let two_step_dir = Builder::new().prefix("test_index_dir").tempdir().unwrap();
+        storage.build_immutable_index(Some(two_step_dir.path()));
+        let two_step = InvertedIndex::Mmap(InvertedIndexMmap::load(two_step_dir.path()).unwrap());
+
+        let direct_dir = Builder::new().prefix("test_index_dir").tempdir().unwrap();
+        let direct = storage
+            .mutable_index()
+            .to_mmap(&storage, &direct_dir)
+            .unwrap();
+        let direct = InvertedIndex::Mmap(direct);
+
+        for dim in 0..10 {
+            let two_step_elements = two_step.get(&dim).map(Vec::from_iter);
+            let direct_elements = direct.get(&dim).map(Vec::from_iter);
+            assert_eq!(two_step_elements, direct_elements);
+        }
+        assert_eq!(
+            std::fs::read(InvertedIndexMmap::index_file_path(two_step_dir.path())).unwrap(),
+            std::fs::read(InvertedIndexMmap::index_file_path(direct_dir.path())).unwrap()
+        );
     }
 }
@@ -1,412 +1,3094 @@
-use crate::sparse_index::common::scored_candidate::ScoredCandidate;
-use crate::sparse_index::common::types::RecordId;
-use crate::sparse_index::common::vector::SparseVector;
+use crate::mmap_vector_storage::MmapVectorStorage;
+use crate::sparse_index::common::file_operations::{atomic_save_json, read_json};
+use crate::sparse_index::common::fixed_length_pq::FixedLengthPriorityQueue;
+use crate::sparse_index::common::scored_candidate::{ScoredCandidate, TieBreak};
+use crate::sparse_index::common::types::{DimId, DimWeight, RecordId};
+use crate::sparse_index::common::vector::{SimilarityMetric, SparseVector, SparseVectorError};
 use crate::sparse_index::immutable::inverted_index::inverted_index_mmap::InvertedIndexMmap;
-use crate::sparse_index::immutable::inverted_index::inverted_index_ram::InvertedIndexBuilder;
+use crate::sparse_index::immutable::inverted_index::inverted_index_ram::{
+    InvertedIndexBuilder, InvertedIndexRam,
+};
+use crate::sparse_index::immutable::inverted_index::inverted_index_soa::InvertedIndexSoa;
 use crate::sparse_index::immutable::inverted_index::InvertedIndex;
-use crate::sparse_index::immutable::posting_list::PostingBuilder;
-use crate::sparse_index::immutable::search_context::SearchContext;
+use crate::sparse_index::immutable::posting_list::{
+    PostingBuilder, PostingElement, PostingList, PostingListIter,
+};
+use crate::sparse_index::immutable::search_context::{SearchContext, SortValues};
+use crate::vector_store::{DenseVectorStore, HashMapVectorStore, VectorStore};
+use float_cmp::approx_eq;
 use ordered_float::OrderedFloat;
-use serde_json::{Deserializer, Value};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::borrow::Cow;
+use std::cmp::Reverse;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
 use std::fs::File;
-use std::io::BufReader;
-use std::path::Path;
+use std::io::{BufRead, BufReader};
+use std::mem::size_of;
+use std::ops::Range;
+use std::path::{Path, PathBuf};
 
 use crate::sparse_index::mutable::mutable_index::MutableSparseVectorIndex;
 
+/// Options for building the immutable index
+#[derive(Debug, Default, Clone)]
+pub struct ImmutableIndexConfig {
+    /// Keep only the highest weight elements of each posting list (static pruning).
+    /// Search results become approximate for capped dimensions.
+    pub max_posting_list_len: Option<usize>,
+    /// Memory layout of the posting lists of a RAM index
+    pub posting_layout: PostingLayout,
+    /// Multiply the weights of each dimension by its IDF `ln(N / df)`,
+    /// queries then need no IDF weighting
+    pub apply_idf: bool,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum PostingLayout {
+    /// Array of posting elements
+    #[default]
+    ArrayOfStructs,
+    /// One array per posting element field, for a RAM index only: a mmap index keeps
+    /// the array of structs layout of its file
+    StructOfArrays,
+}
+
+/// Transformations applied to vectors when they are added to the storage
+#[derive(Debug, Default, Clone)]
+pub struct IngestOptions {
+    /// Flush subnormal weights to zero and drop them from the vector
+    pub flush_subnormals: bool,
+    /// Replace NaN and infinite weights by 0, applied first. Otherwise they are stored
+    /// as is and the scores of the vector are NaN or infinite.
+    pub zero_non_finite: bool,
+    /// Scale the vectors to a unit euclidean norm, applied before flushing subnormals.
+    /// Dot products then are cosine similarities, including the scores of an immutable
+    /// index built from the normalized vectors.
+    pub normalize: bool,
+}
+
+/// Options of [`SparseVectorStorage::query_immutable_index_idf`]
+#[derive(Debug, Default, Clone, Copy)]
+pub struct IdfParams {
+    /// Skip the query dimensions in fewer than `min_df` vectors, absent dimensions are
+    /// always skipped
+    pub min_df: usize,
+    /// Use `ln(1 + N / df)`, dimensions in every vector then keep a positive weight
+    pub smooth: bool,
+}
+
+/// Per query options of [`SparseVectorStorage::query_full_scan_with_params`],
+/// [`SparseVectorStorage::query_mutable_index_with_params`] and
+/// [`SparseVectorStorage::query_immutable_index_with_params`]
+#[derive(Debug, Default, Clone, Copy)]
+pub struct QueryParams<'a> {
+    /// Discard the candidates scoring below the threshold, the query may then return
+    /// fewer results than requested
+    pub score_threshold: Option<f32>,
+    /// Query dimensions ignored by this query, e.g. stop words
+    pub blacklist: Option<&'a HashSet<DimId>>,
+    /// Prune the immutable index search with a pivot, see
+    /// [`SearchContext::with_pivot_pruning`], the results are unchanged
+    pub pivot_pruning: bool,
+}
+
+impl QueryParams<'_> {
+    /// Whether a score is below the score threshold
+    fn below_threshold(&self, score: f32) -> bool {
+        self.score_threshold
+            .is_some_and(|threshold| score < threshold)
+    }
+
+    /// The query without the blacklisted dimensions
+    fn query<'q>(&self, query_vector: &'q SparseVector) -> Cow<'q, SparseVector> {
+        match self.blacklist {
+            Some(blacklist) => Cow::Owned(query_vector.without_dims(blacklist)),
+            None => Cow::Borrowed(query_vector),
+        }
+    }
+}
+
+/// Relative margin added to the score bounds of the mutable index query, covers the
+/// rounding differences between the bound and the score summations
+const SCORE_BOUND_SLACK: f32 = 1e-5;
+
+/// Default maximum number of results a query can request
+pub const DEFAULT_MAX_TOP: usize = 100_000;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum QueryError {
+    /// The requested number of results exceeds the configured maximum
+    TopTooLarge { top: usize, max_top: usize },
+    /// Vectors changed since the immutable index was built, rebuild it to search them
+    StaleIndex,
+}
+
+impl fmt::Display for QueryError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            QueryError::TopTooLarge { top, max_top } => {
+                write!(
+                    f,
+                    "Requested top {} exceeds the maximum of {}",
+                    top, max_top
+                )
+            }
+            QueryError::StaleIndex => write!(
+                f,
+                "Immutable index is stale, rebuild it to search the latest vectors"
+            ),
+        }
+    }
+}
+
+impl std::error::Error for QueryError {}
+
+/// Failure to load a JSONL file of SPLADE embeddings, lines are numbered from 1
+#[derive(Debug)]
+pub enum LoadError {
+    Io(std::io::Error),
+    /// The line is not valid JSON
+    Json {
+        line: usize,
+        source: serde_json::Error,
+    },
+    /// The line is valid JSON but not an object of dimension weights
+    NotAnObject {
+        line: usize,
+    },
+    /// A key of the object is not a `u32` dimension id
+    InvalidKey {
+        line: usize,
+        key: String,
+    },
+    /// The value of a dimension is not a number, or is out of the finite `f32` range
+    InvalidWeight {
+        line: usize,
+        key: String,
+    },
+    /// A CSR field is missing or not an array of the expected numbers
+    InvalidField {
+        line: usize,
+        field: String,
+    },
+    /// The CSR arrays do not form a valid sparse vector
+    InvalidVector {
+        line: usize,
+        source: SparseVectorError,
+    },
+}
+
+impl fmt::Display for LoadError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LoadError::Io(err) => write!(f, "IO error: {}", err),
+            LoadError::Json { line, source } => {
+                write!(f, "Line {}: invalid JSON: {}", line, source)
+            }
+            LoadError::NotAnObject { line } => write!(f, "Line {}: expected a JSON object", line),
+            LoadError::InvalidKey { line, key } => {
+                write!(f, "Line {}: invalid dimension id {:?}", line, key)
+            }
+            LoadError::InvalidWeight { line, key } => {
+                write!(
+                    f,
+                    "Line {}: weight of dimension {:?} is not a finite number",
+                    line, key
+                )
+            }
+            LoadError::InvalidField { line, field } => {
+                write!(f, "Line {}: invalid field {:?}", line, field)
+            }
+            LoadError::InvalidVector { line, source } => {
+                write!(f, "Line {}: invalid vector: {}", line, source)
+            }
+        }
+    }
+}
+
+impl std::error::Error for LoadError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            LoadError::Io(err) => Some(err),
+            LoadError::Json { source, .. } => Some(source),
+            LoadError::InvalidVector { source, .. } => Some(source),
+            _ => None,
+        }
+    }
+}
+
+impl From<std::io::Error> for LoadError {
+    fn from(err: std::io::Error) -> Self {
+        LoadError::Io(err)
+    }
+}
+
+/// Content of a saved storage, the indexes are rebuilt on load
+#[derive(Serialize)]
+struct StorageFile<'a> {
+    /// Id of each vector of a sparse store, the vectors are by id without it
+    #[serde(skip_serializing_if = "Option::is_none")]
+    ids: Option<Vec<RecordId>>,
+    vectors: Vec<Option<&'a SparseVector>>,
+    norms: Vec<f32>,
+}
+
+#[derive(Deserialize)]
+struct LoadedStorageFile {
+    #[serde(default)]
+    ids: Option<Vec<RecordId>>,
+    vectors: Vec<Option<SparseVector>>,
+    /// Missing in files written before the norms were saved
+    #[serde(default)]
+    norms: Option<Vec<f32>>,
+}
+
+/// Results of [`SparseVectorStorage::query_immutable_index_detailed`] without the counts
+fn without_match_counts(results: Vec<(ScoredCandidate, u32)>) -> Vec<ScoredCandidate> {
+    results
+        .into_iter()
+        .map(|(candidate, _)| candidate)
+        .collect()
+}
+
 pub struct SparseVectorStorage {
-    vectors: Vec<Option<SparseVector>>, // ordered by id for quick access
+    vectors: Box<dyn VectorStore>, // vectors and their L2 norm by id
+    vector_count: usize,           // number of stored vectors
     mutable_index: MutableSparseVectorIndex, // position -> posting of vector ids
     immutable_index: Option<InvertedIndex>,
+    immutable_index_dirty: bool, // vectors changed since the immutable index was built
+    // ids added since the immutable index was built, None once a vector was replaced or
+    // deleted: only a full build brings the index up to date then
+    added_since_build: Option<HashSet<RecordId>>,
+    immutable_index_config: ImmutableIndexConfig, // of the last build
+    immutable_index_path: Option<PathBuf>,        // mmap directory of the last build
+    ingest_options: IngestOptions,
+    tie_break: TieBreak,
+    sort_values: Option<SortValues>, // secondary ranking key on equal scores
+    similarity_metric: SimilarityMetric,
+    max_top: usize, // guard against huge result queues
+}
+
+impl Default for SparseVectorStorage {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl SparseVectorStorage {
     pub fn new() -> SparseVectorStorage {
+        Self::with_ingest_options(IngestOptions::default())
+    }
+
+    pub fn with_ingest_options(ingest_options: IngestOptions) -> SparseVectorStorage {
+        Self::with_vector_store(Box::new(DenseVectorStore::default()), ingest_options)
+    }
+
+    /// Storage keeping its vectors in `vectors`, e.g. a `HashMapVectorStore` for sparse ids
+    pub fn with_vector_store(
+        vectors: Box<dyn VectorStore>,
+        ingest_options: IngestOptions,
+    ) -> SparseVectorStorage {
         SparseVectorStorage {
-            vectors: Vec::new(),
+            vectors,
+            vector_count: 0,
             mutable_index: MutableSparseVectorIndex::new(),
             immutable_index: None,
+            immutable_index_dirty: false,
+            added_since_build: Some(HashSet::new()),
+            immutable_index_config: ImmutableIndexConfig::default(),
+            immutable_index_path: None,
+            ingest_options,
+            tie_break: TieBreak::default(),
+            sort_values: None,
+            similarity_metric: SimilarityMetric::default(),
+            max_top: DEFAULT_MAX_TOP,
+        }
+    }
+
+    #[allow(non_snake_case)]
+    pub fn load_SPLADE_embeddings(path: &str) -> SparseVectorStorage {
+        Self::load_SPLADE_embeddings_with_opts(path, IngestOptions::default())
+    }
+
+    #[allow(non_snake_case)]
+    pub fn load_SPLADE_embeddings_with_opts(
+        path: &str,
+        ingest_options: IngestOptions,
+    ) -> SparseVectorStorage {
+        Self::load_SPLADE_embeddings_checked_with_opts(path, ingest_options)
+            .unwrap_or_else(|err| panic!("Unable to load {}: {}", path, err))
+    }
+
+    /// Like [`Self::load_SPLADE_embeddings`] but malformed input is reported with its
+    /// line number instead of panicking
+    #[allow(non_snake_case)]
+    pub fn load_SPLADE_embeddings_checked(path: &str) -> Result<SparseVectorStorage, LoadError> {
+        Self::load_SPLADE_embeddings_checked_with_opts(path, IngestOptions::default())
+    }
+
+    #[allow(non_snake_case)]
+    pub fn load_SPLADE_embeddings_checked_with_opts(
+        path: &str,
+        ingest_options: IngestOptions,
+    ) -> Result<SparseVectorStorage, LoadError> {
+        Self::load_jsonl(path, ingest_options, Self::parse_splade_line)
+    }
+
+    /// Load a JSONL file of CSR vectors, `{"indices": [...], "values": [...]}` per line.
+    /// The dimensions of each vector are sorted by index.
+    pub fn load_csr_jsonl(path: &str) -> SparseVectorStorage {
+        Self::load_csr_jsonl_checked(path)
+            .unwrap_or_else(|err| panic!("Unable to load {}: {}", path, err))
+    }
+
+    /// Like [`Self::load_csr_jsonl`] but malformed input is reported with its line number
+    pub fn load_csr_jsonl_checked(path: &str) -> Result<SparseVectorStorage, LoadError> {
+        Self::load_jsonl(path, IngestOptions::default(), Self::parse_csr_line)
+    }
+
+    /// Add the vector parsed from each non-blank line of a JSONL file, in line order
+    fn load_jsonl(
+        path: &str,
+        ingest_options: IngestOptions,
+        parse_line: impl Fn(usize, Value) -> Result<SparseVector, LoadError>,
+    ) -> Result<SparseVectorStorage, LoadError> {
+        let f = File::open(path)?;
+        let reader = BufReader::new(f);
+
+        let mut internal_index = 0;
+        let mut storage = SparseVectorStorage::with_ingest_options(ingest_options);
+
+        // one JSON object per line
+        for (line_index, line) in reader.lines().enumerate() {
+            let line_number = line_index + 1;
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            let value: Value = serde_json::from_str(&line).map_err(|source| LoadError::Json {
+                line: line_number,
+                source,
+            })?;
+            storage.add(internal_index, parse_line(line_number, value)?);
+            internal_index += 1;
+        }
+        Ok(storage)
+    }
+
+    /// `{"dim": weight, ...}`
+    fn parse_splade_line(line_number: usize, value: Value) -> Result<SparseVector, LoadError> {
+        let Value::Object(map) = value else {
+            return Err(LoadError::NotAnObject { line: line_number });
+        };
+        let keys_count = map.len();
+        let mut indices = Vec::with_capacity(keys_count);
+        let mut values = Vec::with_capacity(keys_count);
+        for (key, value) in map {
+            let Ok(index) = key.parse::<u32>() else {
+                return Err(LoadError::InvalidKey {
+                    line: line_number,
+                    key,
+                });
+            };
+            // large f64 values overflow to an infinite f32
+            let Some(weight) = value
+                .as_f64()
+                .map(|weight| weight as f32)
+                .filter(|weight| weight.is_finite())
+            else {
+                return Err(LoadError::InvalidWeight {
+                    line: line_number,
+                    key,
+                });
+            };
+            indices.push(index);
+            values.push(weight);
+        }
+        Ok(SparseVector::new(indices, values))
+    }
+
+    /// `{"indices": [...], "values": [...]}`
+    fn parse_csr_line(line_number: usize, value: Value) -> Result<SparseVector, LoadError> {
+        let Value::Object(map) = value else {
+            return Err(LoadError::NotAnObject { line: line_number });
+        };
+        let invalid_field = |field: &str| LoadError::InvalidField {
+            line: line_number,
+            field: field.to_string(),
+        };
+        let array = |field: &str| {
+            map.get(field)
+                .and_then(Value::as_array)
+                .ok_or_else(|| invalid_field(field))
+        };
+        let indices = array("indices")?
+            .iter()
+            .map(|index| {
+                index
+                    .as_u64()
+                    .and_then(|index| u32::try_from(index).ok())
+                    .ok_or_else(|| invalid_field("indices"))
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        let values = array("values")?
+            .iter()
+            .map(|value| {
+                value
+                    .as_f64()
+                    .map(|value| value as f32)
+                    .ok_or_else(|| invalid_field("values"))
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let invalid_vector = |source| LoadError::InvalidVector {
+            line: line_number,
+            source,
+        };
+        if indices.len() != values.len() {
+            return Err(invalid_vector(SparseVectorError::LengthMismatch {
+                indices: indices.len(),
+                weights: values.len(),
+            }));
+        }
+        let mut vector = SparseVector::new(indices, values);
+        vector.sorted_by_indices();
+        // duplicate indices
+        SparseVector::try_new(vector.indices, vector.weights).map_err(invalid_vector)
+    }
+
+    fn apply_ingest_options(&self, sparse_vector: &mut SparseVector) {
+        if self.ingest_options.zero_non_finite {
+            sparse_vector.zero_non_finite();
+        }
+        if self.ingest_options.normalize {
+            *sparse_vector = sparse_vector.normalized();
+        }
+        if self.ingest_options.flush_subnormals {
+            sparse_vector.flush_subnormals();
+        }
+    }
+
+    /// No upserts allowed, see [`Self::upsert`]
+    pub fn add(&mut self, vector_id: usize, mut sparse_vector: SparseVector) {
+        self.apply_ingest_options(&mut sparse_vector);
+        self.mutable_index
+            .add(vector_id as RecordId, &sparse_vector);
+        self.mark_added(vector_id as RecordId);
+        if self.vectors.get(vector_id as RecordId).is_some() {
+            panic!("Vector {} already exists", vector_id);
+        }
+        self.vectors.insert(vector_id as RecordId, sparse_vector);
+        self.vector_count += 1;
+    }
+
+    /// Insert or replace a vector, the postings of the mutable index follow the new
+    /// dimensions and the sort value of a replaced vector is dropped.
+    /// The immutable index is stale until rebuilt.
+    pub fn upsert(&mut self, vector_id: usize, mut sparse_vector: SparseVector) {
+        self.apply_ingest_options(&mut sparse_vector);
+        self.clear_sort_value(vector_id);
+        let previous = self.vectors.get(vector_id as RecordId);
+        match previous {
+            Some(previous) => {
+                self.mutable_index
+                    .update(vector_id as RecordId, previous, &sparse_vector);
+                self.mark_modified();
+            }
+            None => {
+                self.mutable_index
+                    .add(vector_id as RecordId, &sparse_vector);
+                self.vector_count += 1;
+                self.mark_added(vector_id as RecordId);
+            }
+        }
+        self.vectors.insert(vector_id as RecordId, sparse_vector);
+    }
+
+    /// Remove a vector, its postings from the mutable index and its sort value, missing
+    /// ids are ignored. The immutable index is stale until rebuilt.
+    pub fn delete(&mut self, vector_id: usize) {
+        let Some(previous) = self.vectors.remove(vector_id as RecordId) else {
+            return;
+        };
+        self.clear_sort_value(vector_id);
+        self.mutable_index.remove(vector_id as RecordId, &previous);
+        self.vector_count -= 1;
+        self.mark_modified();
+    }
+
+    /// The immutable index is stale, `append_to_immutable_index` can insert the vector
+    fn mark_added(&mut self, vector_id: RecordId) {
+        self.immutable_index_dirty = true;
+        // without an index the next build covers all the vectors
+        if self.immutable_index.is_some() {
+            if let Some(added_ids) = &mut self.added_since_build {
+                added_ids.insert(vector_id);
+            }
+        }
+    }
+
+    /// The immutable index is stale until a full build
+    fn mark_modified(&mut self) {
+        self.immutable_index_dirty = true;
+        self.added_since_build = None;
+    }
+
+    /// Save the vectors and their norms as JSON, by id whatever the vector store.
+    /// A sparse store, see [`VectorStore::is_sparse`], saves its ids with its vectors
+    /// instead of a slot per id.
+    pub fn save(&self, path: &Path) -> std::io::Result<()> {
+        let file = if self.vectors.is_sparse() {
+            let mut vectors: Vec<_> = self.vectors.iter().collect();
+            vectors.sort_unstable_by_key(|(id, _)| *id);
+            StorageFile {
+                ids: Some(vectors.iter().map(|(id, _)| *id).collect()),
+                norms: vectors
+                    .iter()
+                    .map(|(id, _)| self.vectors.norm(*id).unwrap_or(0.0))
+                    .collect(),
+                vectors: vectors
+                    .into_iter()
+                    .map(|(_, vector)| Some(vector))
+                    .collect(),
+            }
+        } else {
+            let ids = 0..self.vectors.id_end() as RecordId;
+            StorageFile {
+                ids: None,
+                vectors: ids.clone().map(|id| self.vectors.get(id)).collect(),
+                norms: ids.map(|id| self.vectors.norm(id).unwrap_or(0.0)).collect(),
+            }
+        };
+        atomic_save_json(path, &file)
+    }
+
+    /// Load a storage written by `save`, the mutable index is rebuilt.
+    /// The vectors of a sparse file are loaded in a `HashMapVectorStore`.
+    /// Saved norms must match the vectors, they are recomputed if absent.
+    pub fn load(path: &Path) -> std::io::Result<SparseVectorStorage> {
+        let invalid_data = |message| std::io::Error::new(std::io::ErrorKind::InvalidData, message);
+        let file: LoadedStorageFile = read_json(path)?;
+        let (mut storage, ids) = match file.ids {
+            Some(ids) if ids.len() != file.vectors.len() => {
+                return Err(invalid_data("Saved ids do not match the vectors"));
+            }
+            Some(ids) => (
+                SparseVectorStorage::with_vector_store(
+                    Box::new(HashMapVectorStore::default()),
+                    IngestOptions::default(),
+                ),
+                ids,
+            ),
+            None => (
+                SparseVectorStorage::new(),
+                (0..file.vectors.len() as RecordId).collect(),
+            ),
+        };
+        for (id, vector) in ids.iter().zip(file.vectors) {
+            if let Some(vector) = vector {
+                storage.add(*id as usize, vector);
+            }
+        }
+
+        if let Some(norms) = file.norms {
+            let mismatch = norms.len() != ids.len()
+                || ids.iter().zip(&norms).any(|(id, saved)| {
+                    let computed = storage.norm(*id).unwrap_or(0.0);
+                    !approx_eq!(f32, *saved, computed, ulps = 4)
+                });
+            if mismatch {
+                return Err(invalid_data("Saved norms do not match the vectors"));
+            }
+        }
+        Ok(storage)
+    }
+
+    /// Write the vectors to a memory mapped storage in the directory `path`
+    pub fn save_vectors_mmap(&self, path: &Path) -> std::io::Result<MmapVectorStorage> {
+        MmapVectorStorage::save(path, self.vectors.as_ref())
+    }
+
+    /// L2 norm of a stored vector
+    pub fn norm(&self, vector_id: RecordId) -> Option<f32> {
+        self.vectors.norm(vector_id)
+    }
+
+    /// Add a vector with a sort value used by `query_immutable_index` to rank
+    /// equal scores, higher sort values first
+    pub fn add_with_sort_value(
+        &mut self,
+        vector_id: usize,
+        sparse_vector: SparseVector,
+        sort_value: f32,
+    ) {
+        self.add(vector_id, sparse_vector);
+        // keyed like the vectors, a sparse id space stays sparse
+        let keyed_by_id = self.vectors.is_keyed_by_id();
+        let sort_values = self.sort_values.get_or_insert_with(|| match keyed_by_id {
+            true => SortValues::Sparse(HashMap::new()),
+            false => SortValues::Dense(Vec::new()),
+        });
+        sort_values.set(vector_id as RecordId, sort_value);
+    }
+
+    /// Sort value of a vector, `NEG_INFINITY` for a vector added without one
+    fn sort_value(&self, vector_id: RecordId) -> f32 {
+        self.sort_values
+            .as_ref()
+            .map_or(f32::NEG_INFINITY, |sort_values| sort_values.get(vector_id))
+    }
+
+    /// Rank the vector like one added without a sort value
+    fn clear_sort_value(&mut self, vector_id: usize) {
+        if let Some(sort_values) = self.sort_values.as_mut() {
+            sort_values.remove(vector_id as RecordId);
+        }
+    }
+
+    /// Build immutable index from mutable index
+    pub fn build_immutable_index(&mut self, mmap_path: Option<&Path>) {
+        self.build_immutable_index_with_config(mmap_path, &ImmutableIndexConfig::default())
+    }
+
+    /// Build immutable index from mutable index with build options
+    pub fn build_immutable_index_with_config(
+        &mut self,
+        mmap_path: Option<&Path>,
+        config: &ImmutableIndexConfig,
+    ) {
+        let inverted_index_ram = if config.apply_idf || config.max_posting_list_len.is_some() {
+            // both need the whole posting list of a dimension before building it
+            self.build_postings_by_dimension(config)
+        } else {
+            InvertedIndexRam::from_vectors(self.vectors.iter())
+        };
+        // build mmap index if path is provided
+        let index = match mmap_path {
+            None => match config.posting_layout {
+                PostingLayout::ArrayOfStructs => InvertedIndex::Ram(inverted_index_ram),
+                PostingLayout::StructOfArrays => {
+                    InvertedIndex::Soa(InvertedIndexSoa::from_ram(&inverted_index_ram))
+                }
+            },
+            Some(path) => {
+                let mmap = InvertedIndexMmap::convert_and_save(&inverted_index_ram, path).unwrap();
+                // drop and reload for testing
+                drop(mmap);
+                let mmap = InvertedIndexMmap::load(path).unwrap();
+                InvertedIndex::Mmap(mmap)
+            }
+        };
+        self.immutable_index = Some(index);
+        self.immutable_index_dirty = false;
+        self.added_since_build = Some(HashSet::new());
+        self.immutable_index_config = config.clone();
+        self.immutable_index_path = mmap_path.map(Path::to_path_buf);
+    }
+
+    /// Build each posting list from the mutable index postings and the stored weights
+    fn build_postings_by_dimension(&self, config: &ImmutableIndexConfig) -> InvertedIndexRam {
+        let mut inverted_index_builder = InvertedIndexBuilder::new();
+        for (position, vector_ids) in self.mutable_index.map.iter() {
+            let mut posting_list_builder = self.posting_builder(*position, vector_ids);
+            if config.apply_idf {
+                let idf = (self.vector_count as f32 / vector_ids.len() as f32).ln();
+                posting_list_builder.scale_weights(idf);
+            }
+            let posting_list = match config.max_posting_list_len {
+                None => posting_list_builder.build(),
+                Some(max_len) => posting_list_builder.build_capped(max_len),
+            };
+            inverted_index_builder.add(*position, posting_list);
+        }
+        inverted_index_builder.build()
+    }
+
+    /// Insert the postings of the vectors `new_ids` added since the last build into the
+    /// immutable RAM index, only the touched posting lists are updated. Ids indexed already
+    /// are skipped, the index stays stale until all the added vectors are appended.
+    ///
+    /// Falls back to a full rebuild with the last build options if a vector was replaced or
+    /// deleted since the build, for a mmap or struct of arrays index, an index built with
+    /// `apply_idf` or `max_posting_list_len` (both depend on all the vectors), or if no
+    /// index was built.
+    pub fn append_to_immutable_index(&mut self, new_ids: &[RecordId]) {
+        let incremental = !self.immutable_index_config.apply_idf
+            && self.immutable_index_config.max_posting_list_len.is_none();
+        match (&mut self.immutable_index, &mut self.added_since_build) {
+            (Some(InvertedIndex::Ram(index)), Some(added_ids)) if incremental => {
+                for id in new_ids {
+                    if !added_ids.remove(id) {
+                        continue;
+                    }
+                    if let Some(vector) = self.vectors.get(*id) {
+                        index.insert(*id, vector);
+                    }
+                }
+                index.finalize();
+                self.immutable_index_dirty = !added_ids.is_empty();
+            }
+            _ => {
+                let config = self.immutable_index_config.clone();
+                let path = self.immutable_index_path.clone();
+                self.build_immutable_index_with_config(path.as_deref(), &config);
+            }
+        }
+    }
+
+    /// Posting builder for a dimension filled with the stored weights of the given vectors
+    pub(crate) fn posting_builder(
+        &self,
+        position: DimId,
+        vector_ids: &[RecordId],
+    ) -> PostingBuilder {
+        let mut posting_list_builder = PostingBuilder::with_capacity(vector_ids.len());
+        for vec_id in vector_ids {
+            // get vector from storage
+            let sparse_vector = self.get(*vec_id).expect("Vector not found");
+            if let Some(offset) = sparse_vector.indices.iter().position(|x| *x == position) {
+                let weight = sparse_vector.weights[offset];
+                posting_list_builder.add(*vec_id as RecordId, weight);
+            } else {
+                panic!("Vector {} does not contain position {}", vec_id, position);
+            }
+        }
+        posting_list_builder
+    }
+
+    /// Elements of the posting list of a dimension in id order, None for an absent dimension.
+    /// Read from the immutable index if built, with its weights as indexed (IDF scaling,
+    /// capping) and stale until rebuilt, else from the mutable index and stored weights.
+    pub fn posting_for_dimension(&self, dim: DimId) -> Option<Vec<(RecordId, DimWeight)>> {
+        let elements: Vec<_> = match &self.immutable_index {
+            Some(index) => index
+                .get(&dim)?
+                .map(|element| (element.record_id, element.weight))
+                .collect(),
+            None => self
+                .posting_builder(dim, self.mutable_index.get(&dim)?)
+                .build()
+                .elements
+                .iter()
+                .map(|element| (element.record_id, element.weight))
+                .collect(),
+        };
+        (!elements.is_empty()).then_some(elements)
+    }
+
+    /// Estimate the memory needed by an immutable RAM index built from the current data.
+    /// Accounts for all posting elements plus the postings vector padded to the max dimension.
+    pub fn estimate_index_bytes(&self) -> usize {
+        let total_nonzeros: usize = self.mutable_index.map.values().map(Vec::len).sum();
+        let postings_count = self
+            .mutable_index
+            .map
+            .keys()
+            .max()
+            .map_or(0, |max_dim| *max_dim as usize + 1);
+        total_nonzeros * size_of::<PostingElement>() + postings_count * size_of::<PostingList>()
+    }
+
+    /// Bytes used by the stored vectors and the indexes
+    pub fn index_memory_report(&self) -> IndexMemoryReport {
+        IndexMemoryReport {
+            vectors_bytes: self.vectors.memory_bytes(),
+            mutable_index_bytes: self.mutable_index.memory_bytes(),
+            immutable_index_bytes: self
+                .immutable_index
+                .as_ref()
+                .map_or(0, InvertedIndex::memory_bytes),
+            immutable_index_file_bytes: self
+                .immutable_index
+                .as_ref()
+                .map_or(0, InvertedIndex::file_bytes),
+        }
+    }
+
+    /// True if vectors changed since the immutable index was built
+    pub fn is_immutable_index_dirty(&self) -> bool {
+        self.immutable_index_dirty
+    }
+
+    /// Immutable index to search, an error if it is stale.
+    /// Panics if the index was never built.
+    fn searchable_immutable_index(&self) -> Result<&InvertedIndex, QueryError> {
+        if self.immutable_index_dirty {
+            return Err(QueryError::StaleIndex);
+        }
+        Ok(self.built_immutable_index())
+    }
+
+    /// Panics if the index was never built
+    fn built_immutable_index(&self) -> &InvertedIndex {
+        self.immutable_index
+            .as_ref()
+            .expect("Immutable index not built")
+    }
+
+    /// Dimensions used by the corpus with their document frequency, sorted by dimension
+    pub fn vocabulary(&self) -> Vec<(DimId, usize)> {
+        let mut vocabulary: Vec<_> = self
+            .mutable_index
+            .map
+            .iter()
+            .map(|(dim_id, posting)| (*dim_id, posting.len()))
+            .collect();
+        vocabulary.sort_unstable();
+        vocabulary
+    }
+
+    /// Write the vocabulary as a JSON array of `[dim_id, df]` pairs sorted by dimension
+    pub fn export_vocabulary(&self, path: &Path) -> std::io::Result<()> {
+        atomic_save_json(path, &self.vocabulary())
+    }
+
+    /// Tie-breaking applied identically by the full scan, mutable and immutable queries
+    pub fn set_tie_break(&mut self, tie_break: TieBreak) {
+        self.tie_break = tie_break;
+    }
+
+    /// Similarity used by the queries.
+    /// With `SimilarityMetric::NegL2` vectors sharing no dimension with the query still get
+    /// a score, so the candidates can not be taken from the postings: the mutable and
+    /// immutable queries fall back to the full scan, the pruning bound only holds for dot.
+    pub fn set_similarity_metric(&mut self, similarity_metric: SimilarityMetric) {
+        self.similarity_metric = similarity_metric;
+    }
+
+    /// Score of a stored vector according to the similarity metric and the tie-breaking
+    fn score(&self, query_vector: &SparseVector, vector: &SparseVector) -> f32 {
+        match (self.similarity_metric, self.tie_break) {
+            (SimilarityMetric::NegL2, _) => query_vector.neg_squared_l2_distance(vector),
+            (SimilarityMetric::Dot, TieBreak::Unspecified) => query_vector.dot_product(vector),
+            (SimilarityMetric::Dot, TieBreak::ById) => query_vector.dot_product_in_order(vector),
+        }
+    }
+
+    pub fn mutable_index(&self) -> &MutableSparseVectorIndex {
+        &self.mutable_index
+    }
+
+    /// Panics if vector_id is above the highest stored id
+    pub fn get(&self, vector_id: RecordId) -> Option<&SparseVector> {
+        if vector_id as usize >= self.vectors.id_end() {
+            panic!("Vector storage not allocated for {}", vector_id);
+        }
+        self.vectors.get(vector_id)
+    }
+
+    /// Dot product of two stored vectors.
+    /// Returns None if any of the ids is out of bounds or deleted.
+    pub fn similarity(&self, a: RecordId, b: RecordId) -> Option<f32> {
+        let vector_a = self.vectors.get(a)?;
+        let vector_b = self.vectors.get(b)?;
+        Some(vector_a.dot_product(vector_b))
+    }
+
+    /// Dot product matrix of the given vectors, `matrix[i][j]` is the similarity of
+    /// `ids[i]` and `ids[j]`. Out of bounds or deleted ids have a similarity of 0.
+    /// Only the upper triangle is computed, the matrix is symmetric.
+    pub fn pairwise_similarity(&self, ids: &[RecordId]) -> Vec<Vec<f32>> {
+        let mut matrix = vec![vec![0.0; ids.len()]; ids.len()];
+        for (i, a) in ids.iter().enumerate() {
+            for (j, b) in ids.iter().enumerate().skip(i) {
+                let similarity = self.similarity(*a, *b).unwrap_or(0.0);
+                matrix[i][j] = similarity;
+                matrix[j][i] = similarity;
+            }
+        }
+        matrix
+    }
+
+    /// Maximum number of results a query can request, larger requests are rejected
+    pub fn set_max_top(&mut self, max_top: usize) {
+        self.max_top = max_top;
+    }
+
+    /// Validate the number of requested results and cap it at the number of stored vectors
+    fn checked_top(&self, top: usize) -> Result<usize, QueryError> {
+        if top > self.max_top {
+            return Err(QueryError::TopTooLarge {
+                top,
+                max_top: self.max_top,
+            });
+        }
+        Ok(top.min(self.vector_count))
+    }
+
+    /// Streaming top-k over all stored vectors, memory is bounded by `limit`
+    pub fn query_full_scan(
+        &self,
+        limit: usize,
+        query_vector: &SparseVector,
+    ) -> Result<Vec<ScoredCandidate>, QueryError> {
+        self.query_full_scan_with_params(limit, query_vector, &QueryParams::default())
+    }
+
+    /// Like [`Self::query_full_scan`] with per query options
+    pub fn query_full_scan_with_params(
+        &self,
+        limit: usize,
+        query_vector: &SparseVector,
+        params: &QueryParams,
+    ) -> Result<Vec<ScoredCandidate>, QueryError> {
+        let limit = self.checked_top(limit)?;
+        Ok(self.full_scan(limit, &params.query(query_vector), params, None))
+    }
+
+    fn full_scan(
+        &self,
+        limit: usize,
+        query_vector: &SparseVector,
+        params: &QueryParams,
+        excluded_id: Option<RecordId>,
+    ) -> Vec<ScoredCandidate> {
+        if limit == 0 {
+            return Vec::new();
         }
+        // the queue keeps the highest scores and on equal scores the lowest ids,
+        // whatever the push order
+        let scan = |id: RecordId, vector: &SparseVector| {
+            if excluded_id == Some(id) {
+                return None;
+            }
+            let score = self.score(query_vector, vector);
+            if params.below_threshold(score) {
+                return None;
+            }
+            Some(ScoredCandidate {
+                score,
+                vector_id: id,
+            })
+        };
+
+        #[cfg(feature = "parallel")]
+        let result_queue = self.vectors.par_top(limit, &scan);
+        #[cfg(not(feature = "parallel"))]
+        let result_queue = {
+            let mut result_queue = FixedLengthPriorityQueue::new(limit);
+            for (id, vector) in self.vectors.iter() {
+                if let Some(candidate) = scan(id, vector) {
+                    result_queue.push(candidate);
+                }
+            }
+            result_queue
+        };
+
+        let mut scored_candidates = result_queue.into_vec();
+        // sort by score descending, ties by ascending id
+        scored_candidates.sort_by(|a, b| b.cmp(a));
+        scored_candidates
+    }
+
+    pub fn query_mutable_index(
+        &self,
+        top: usize,
+        query_vector: &SparseVector,
+    ) -> Result<Vec<ScoredCandidate>, QueryError> {
+        self.query_mutable_index_with_params(top, query_vector, &QueryParams::default())
+    }
+
+    /// Like [`Self::query_mutable_index`] with per query options
+    pub fn query_mutable_index_with_params(
+        &self,
+        top: usize,
+        query_vector: &SparseVector,
+        params: &QueryParams,
+    ) -> Result<Vec<ScoredCandidate>, QueryError> {
+        let top = self.checked_top(top)?;
+        let query_vector = &*params.query(query_vector);
+        if self.similarity_metric == SimilarityMetric::NegL2 {
+            // all vectors are candidates
+            return Ok(self.full_scan(top, query_vector, params, None));
+        }
+        if top == 0 {
+            return Ok(Vec::new());
+        }
+        // upper bound of the score of each candidate from the dimension max weights
+        let mut bounds: HashMap<RecordId, f32> = HashMap::new();
+        for (index, weight) in query_vector.indices.iter().zip(&query_vector.weights) {
+            if let Some(posting) = self.mutable_index.get(index) {
+                let max_weight = self.mutable_index.max_weight(index).unwrap_or(0.0);
+                let contribution = weight.abs() * max_weight;
+                for vector_id in posting {
+                    *bounds.entry(*vector_id).or_insert(0.0) += contribution;
+                }
+            }
+        }
+        // score the candidates by decreasing bound, stop once no bound can enter the top
+        let mut candidates: Vec<_> = bounds.into_iter().collect();
+        candidates.sort_unstable_by(|a, b| b.1.total_cmp(&a.1).then(a.0.cmp(&b.0)));
+        let mut result_queue: FixedLengthPriorityQueue<ScoredCandidate> =
+            FixedLengthPriorityQueue::new(top);
+        for (vector_id, bound) in candidates {
+            // slack for the rounding of the score summation
+            let bound = bound + bound.abs() * SCORE_BOUND_SLACK;
+            let queue_min = result_queue
+                .top()
+                .filter(|_| result_queue.len() == top)
+                .map(|candidate| candidate.score);
+            let min_score = match (queue_min, params.score_threshold) {
+                (Some(queue_min), Some(threshold)) => Some(queue_min.max(threshold)),
+                (queue_min, threshold) => queue_min.or(threshold),
+            };
+            if min_score.is_some_and(|min_score| bound < min_score) {
+                break;
+            }
+            let vector = self.get(vector_id).expect("must be found in storage");
+            // sparse dot similarity
+            let score = self.score(query_vector, vector);
+            if !params.below_threshold(score) {
+                result_queue.push(ScoredCandidate { score, vector_id });
+            }
+        }
+        let mut scored_candidates = result_queue.into_vec();
+        // sort by score descending, ties by ascending id
+        scored_candidates.sort_by(|a, b| b.cmp(a));
+        Ok(scored_candidates)
+    }
+
+    pub fn query_immutable_index(
+        &self,
+        top: usize,
+        query_vector: &SparseVector,
+    ) -> Result<Vec<ScoredCandidate>, QueryError> {
+        self.query_immutable_index_with_params(top, query_vector, &QueryParams::default())
+    }
+
+    /// Like [`Self::query_immutable_index`] with per query options,
+    /// the score threshold also bounds the pruning of the posting lists
+    pub fn query_immutable_index_with_params(
+        &self,
+        top: usize,
+        query_vector: &SparseVector,
+        params: &QueryParams,
+    ) -> Result<Vec<ScoredCandidate>, QueryError> {
+        self.query_immutable_index_with(top, query_vector, params, None)
+            .map(without_match_counts)
+    }
+
+    /// Number of vectors in the immutable index posting list of `dim`, 0 for an absent
+    /// dimension. Posting lists capped by `max_posting_list_len` report the capped length.
+    pub fn document_frequency(&self, dim: DimId) -> usize {
+        self.built_immutable_index()
+            .get(&dim)
+            .map_or(0, |posting| posting.len_to_end())
+    }
+
+    /// Like [`Self::query_immutable_index`] with the query dimensions absent from the
+    /// immutable index, in query order. Those dimensions matched no vector.
+    pub fn query_immutable_index_with_coverage(
+        &self,
+        top: usize,
+        query_vector: &SparseVector,
+    ) -> Result<(Vec<ScoredCandidate>, Vec<DimId>), QueryError> {
+        let top = self.checked_top(top)?;
+        let index = self.searchable_immutable_index()?;
+        // the dimensions skipped by the search context, also for the searches it does not run
+        let search_context = SearchContext::new(query_vector, top.max(1), index);
+        let unmatched_dims = search_context.unmatched_dims().to_vec();
+        let params = QueryParams::default();
+        let results = match (top, self.similarity_metric) {
+            (0, _) => Vec::new(),
+            // the posting lists bound the dot product only
+            (_, SimilarityMetric::NegL2) => self.full_scan(top, query_vector, &params, None),
+            (_, SimilarityMetric::Dot) => {
+                without_match_counts(self.run_immutable_search(search_context, &params, None))
+            }
+        };
+        Ok((results, unmatched_dims))
+    }
+
+    /// Like [`Self::query_immutable_index`] with each query weight multiplied by the IDF
+    /// `ln(N / df)` of its dimension, N being the number of stored vectors.
+    /// Dimensions absent from the index are dropped from the query.
+    pub fn query_immutable_index_idf(
+        &self,
+        top: usize,
+        query_vector: &SparseVector,
+        idf: IdfParams,
+    ) -> Result<Vec<ScoredCandidate>, QueryError> {
+        let vector_count = self.vector_count as f32;
+        let (indices, weights) = query_vector
+            .indices
+            .iter()
+            .zip(&query_vector.weights)
+            .filter_map(|(&dim, &weight)| {
+                let df = self.document_frequency(dim);
+                if df == 0 || df < idf.min_df {
+                    return None;
+                }
+                let ratio = vector_count / df as f32;
+                let idf = if idf.smooth {
+                    ratio.ln_1p()
+                } else {
+                    ratio.ln()
+                };
+                Some((dim, weight * idf))
+            })
+            .unzip();
+        self.query_immutable_index(top, &SparseVector::new(indices, weights))
+    }
+
+    /// Search the immutable index for each query, results are in the order of `queries`
+    /// and identical to the ones of [`Self::query_immutable_index`].
+    /// The queries are searched in parallel with the `parallel` feature.
+    pub fn query_immutable_index_batch(
+        &self,
+        top: usize,
+        queries: &[SparseVector],
+    ) -> Result<Vec<Vec<ScoredCandidate>>, QueryError> {
+        let top = self.checked_top(top)?;
+        self.searchable_immutable_index()?;
+        let search = |query: &SparseVector| {
+            without_match_counts(
+                self.query_immutable_index_with(top, query, &QueryParams::default(), None)
+                    .expect("top and index already checked"),
+            )
+        };
+        #[cfg(feature = "parallel")]
+        {
+            use rayon::prelude::*;
+            Ok(queries.par_iter().map(search).collect())
+        }
+        #[cfg(not(feature = "parallel"))]
+        {
+            Ok(queries.iter().map(search).collect())
+        }
+    }
+
+    /// Like [`Self::query_immutable_index`] without the `exclude` record,
+    /// e.g. to find documents similar to a stored one used as query
+    pub fn query_immutable_index_excluding(
+        &self,
+        top: usize,
+        query_vector: &SparseVector,
+        exclude: RecordId,
+    ) -> Result<Vec<ScoredCandidate>, QueryError> {
+        self.query_immutable_index_with(top, query_vector, &QueryParams::default(), Some(exclude))
+            .map(without_match_counts)
+    }
+
+    /// Immutable index search with the number of query dimensions matched by each result,
+    /// the common path of the immutable index queries
+    fn query_immutable_index_with(
+        &self,
+        top: usize,
+        query_vector: &SparseVector,
+        params: &QueryParams,
+        excluded_id: Option<RecordId>,
+    ) -> Result<Vec<(ScoredCandidate, u32)>, QueryError> {
+        let top = self.checked_top(top)?;
+        if top == 0 {
+            return Ok(Vec::new());
+        }
+        if self.similarity_metric == SimilarityMetric::NegL2 {
+            // the posting lists bound the dot product only
+            let query_vector = &*params.query(query_vector);
+            let results = self.full_scan(top, query_vector, params, excluded_id);
+            return Ok(results
+                .into_iter()
+                .map(|candidate| {
+                    let matched = self.matched_dims(query_vector, candidate.vector_id);
+                    (candidate, matched)
+                })
+                .collect());
+        }
+        let index = self.searchable_immutable_index()?;
+        let search_context =
+            SearchContext::new_with_blacklist(query_vector, top, index, params.blacklist);
+        Ok(self.run_immutable_search(search_context, params, excluded_id))
+    }
+
+    fn run_immutable_search<P: PostingListIter>(
+        &self,
+        search_context: SearchContext<'_, P>,
+        params: &QueryParams,
+        excluded_id: Option<RecordId>,
+    ) -> Vec<(ScoredCandidate, u32)> {
+        let mut search_context = self.with_query_options(search_context, params);
+        if let Some(id) = excluded_id {
+            search_context = search_context.with_excluded_id(id);
+        }
+        search_context.search_detailed()
+    }
+
+    /// Number of query dimensions present in a stored vector
+    fn matched_dims(&self, query_vector: &SparseVector, vector_id: RecordId) -> u32 {
+        self.vectors.get(vector_id).map_or(0, |vector| {
+            query_vector
+                .indices
+                .iter()
+                .filter(|dim| vector.indices.contains(dim))
+                .count() as u32
+        })
+    }
+
+    /// Apply the query-time options of the storage and of the query to a search
+    fn with_query_options<'a, P: PostingListIter>(
+        &'a self,
+        search_context: SearchContext<'a, P>,
+        params: &QueryParams,
+    ) -> SearchContext<'a, P> {
+        let mut search_context = search_context.with_tie_break(self.tie_break);
+        if let Some(sort_values) = &self.sort_values {
+            search_context = search_context.with_sort_values(sort_values);
+        }
+        if params.pivot_pruning {
+            search_context = search_context.with_pivot_pruning();
+        }
+        match params.score_threshold {
+            Some(threshold) => search_context.with_score_threshold(threshold),
+            None => search_context,
+        }
+    }
+
+    /// Like [`Self::query_immutable_index`] with the number of query dimensions
+    /// matched by each result
+    pub fn query_immutable_index_detailed(
+        &self,
+        top: usize,
+        query_vector: &SparseVector,
+    ) -> Result<Vec<(ScoredCandidate, u32)>, QueryError> {
+        self.query_immutable_index_with(top, query_vector, &QueryParams::default(), None)
+    }
+
+    /// Search the immutable index considering only vectors with an id in `id_range`
+    pub fn query_immutable_index_range(
+        &self,
+        top: usize,
+        query_vector: &SparseVector,
+        id_range: Range<RecordId>,
+    ) -> Result<Vec<ScoredCandidate>, QueryError> {
+        let top = self.checked_top(top)?;
+        if top == 0 {
+            return Ok(Vec::new());
+        }
+        let search_context =
+            SearchContext::new(query_vector, top, self.searchable_immutable_index()?);
+        let mut search_context = self
+            .with_query_options(search_context, &QueryParams::default())
+            .with_id_range(id_range);
+        Ok(search_context.search())
+    }
+
+    /// Search the immutable index with the id space split in `num_partitions` ranges,
+    /// each searched on its own thread, the per range top results are then merged.
+    pub fn query_immutable_index_partitioned(
+        &self,
+        top: usize,
+        query_vector: &SparseVector,
+        num_partitions: usize,
+    ) -> Result<Vec<ScoredCandidate>, QueryError> {
+        let top = self.checked_top(top)?;
+        if top == 0 {
+            return Ok(Vec::new());
+        }
+        if self.similarity_metric == SimilarityMetric::NegL2 {
+            // the posting lists bound the dot product only
+            return Ok(self.full_scan(top, query_vector, &QueryParams::default(), None));
+        }
+        let index = self.searchable_immutable_index()?;
+        let id_end = self.vectors.id_end();
+        let partition_len = id_end.div_ceil(num_partitions.max(1)).max(1);
+        let partition_results: Vec<Vec<(ScoredCandidate, u32)>> = std::thread::scope(|scope| {
+            let handles: Vec<_> = (0..id_end)
+                .step_by(partition_len)
+                .map(|start| {
+                    let end = (start + partition_len).min(id_end);
+                    scope.spawn(move || {
+                        let search_context = SearchContext::new(query_vector, top, index)
+                            .with_id_range(start as RecordId..end as RecordId);
+                        self.run_immutable_search(search_context, &QueryParams::default(), None)
+                    })
+                })
+                .collect();
+            handles
+                .into_iter()
+                .map(|handle| handle.join().expect("partition search panicked"))
+                .collect()
+        });
+
+        // ranked like a single search: by score, sort value, then id
+        let mut results = without_match_counts(partition_results.into_iter().flatten().collect());
+        results.sort_by_cached_key(|candidate| {
+            (
+                Reverse(OrderedFloat(candidate.score)),
+                Reverse(OrderedFloat(self.sort_value(candidate.vector_id))),
+                candidate.vector_id,
+            )
+        });
+        results.truncate(top);
+        Ok(results)
+    }
+
+    /// Best scoring vector of each group for the `top_groups` best groups.
+    /// `group_ids` gives the group of each vector, indexed by vector id.
+    pub fn query_immutable_index_grouped_by(
+        &self,
+        top_groups: usize,
+        query_vector: &SparseVector,
+        group_ids: &[u32],
+    ) -> Result<Vec<ScoredCandidate>, QueryError> {
+        let top_groups = self.checked_top(top_groups)?;
+        let search_context = SearchContext::new(
+            query_vector,
+            top_groups.max(1),
+            self.searchable_immutable_index()?,
+        );
+        let mut search_context = self.with_query_options(search_context, &QueryParams::default());
+        Ok(search_context.search_grouped(group_ids, top_groups))
+    }
+
+    pub fn mutable_index_statistics(&self) -> IndexStatistics {
+        IndexStatistics::from_sizes(
+            self.mutable_index
+                .map
+                .iter()
+                .map(|(key, posting)| (*key, posting.len())),
+            self.mutable_index.map.len(),
+        )
+    }
+
+    pub fn immutable_index_statistics(&self) -> IndexStatistics {
+        IndexStatistics::of_immutable_index(self.immutable_index.as_ref().unwrap())
+    }
+
+    pub fn data_statistics(&self) -> DataStatistics {
+        let mut statistics = DataStatistics {
+            min_index: DimId::MAX,
+            max_value: f32::MIN,
+            min_value: f32::MAX,
+            min_length: usize::MAX,
+            ..DataStatistics::default()
+        };
+        let mut sum_length = 0;
+        for (_, sparse_vector) in self.vectors.iter() {
+            let length = sparse_vector.indices.len();
+            statistics.max_length = statistics.max_length.max(length);
+            statistics.min_length = statistics.min_length.min(length);
+            sum_length += length;
+            for &index in &sparse_vector.indices {
+                statistics.max_index = statistics.max_index.max(index);
+                statistics.min_index = statistics.min_index.min(index);
+            }
+            for &value in &sparse_vector.weights {
+                if value > statistics.max_value {
+                    statistics.max_value = value;
+                }
+                if value < statistics.min_value {
+                    statistics.min_value = value;
+                }
+            }
+            statistics.vector_count += 1;
+        }
+        if statistics.vector_count == 0 {
+            return DataStatistics::default();
+        }
+        if sum_length == 0 {
+            // only empty vectors, no index nor value
+            statistics.min_index = 0;
+            statistics.max_value = 0.0;
+            statistics.min_value = 0.0;
+        }
+        statistics.avg_length = sum_length as f64 / statistics.vector_count as f64;
+        statistics
+    }
+
+    pub fn print_mutable_index_statistics(&self) {
+        println!("\nMutable sparse vector statistics:");
+        self.mutable_index_statistics().print();
+    }
+
+    pub fn print_immutable_index_statistics(&self) {
+        println!("\nImmutable sparse vector statistics:");
+        self.immutable_index_statistics().print();
+    }
+
+    pub fn print_memory_report(&self) {
+        let report = self.index_memory_report();
+        println!("\nMemory usage:");
+        println!("Vectors: {} mb", report.vectors_bytes / 1024 / 1024);
+        println!(
+            "Mutable index: {} mb",
+            report.mutable_index_bytes / 1024 / 1024
+        );
+        println!(
+            "Immutable index: {} mb in RAM, {} mb mapped",
+            report.immutable_index_bytes / 1024 / 1024,
+            report.immutable_index_file_bytes / 1024 / 1024
+        );
+    }
+
+    pub fn print_data_statistics(&self) {
+        let statistics = self.data_statistics();
+        println!("\nStorage statistics:");
+        println!("Data size: {} sparse vectors", statistics.vector_count);
+        println!("Max sparse index: {}", statistics.max_index);
+        println!("Min sparse index: {}", statistics.min_index);
+        println!("Max sparse value: {}", statistics.max_value);
+        println!("Min sparse value: {}", statistics.min_value);
+        println!("Max sparse vector length: {}", statistics.max_length);
+        println!("Min sparse length: {}", statistics.min_length);
+        println!("Avg sparse length: {}", statistics.avg_length);
+    }
+}
+
+/// Statistics of the stored vectors, all zero when the storage is empty.
+/// The index and value fields are zero when the vectors are all empty.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct DataStatistics {
+    pub vector_count: usize,
+    pub max_index: DimId,
+    pub min_index: DimId,
+    pub max_value: f32,
+    pub min_value: f32,
+    pub max_length: usize,
+    pub min_length: usize,
+    pub avg_length: f64,
+}
+
+/// Bytes used by a storage, see [`SparseVectorStorage::index_memory_report`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct IndexMemoryReport {
+    /// Stored vectors and their norms
+    pub vectors_bytes: usize,
+    pub mutable_index_bytes: usize,
+    /// RAM immutable index, in its posting layout
+    pub immutable_index_bytes: usize,
+    /// Mapped file of a mmap immutable index
+    pub immutable_index_file_bytes: u64,
+}
+
+/// Statistics of the non empty posting lists of an index
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct IndexStatistics {
+    pub key_count: usize,
+    /// Allocated posting lists including the empty placeholders, see
+    /// [`InvertedIndexRam::compact`]
+    pub posting_count: usize,
+    pub max_posting_list_size: usize,
+    pub max_posting_list_key: DimId,
+    pub min_posting_list_size: usize,
+    pub min_posting_list_key: DimId,
+}
+
+impl IndexStatistics {
+    pub(crate) fn of_immutable_index(index: &InvertedIndex) -> IndexStatistics {
+        let sizes: Vec<_> = match index {
+            InvertedIndex::Ram(index) => index
+                .iter()
+                .map(|(key, posting)| (key, posting.elements.len()))
+                .collect(),
+            _ => (0..index.posting_count() as DimId)
+                .map(|key| (key, index.get(&key).map_or(0, |it| it.len_to_end())))
+                // exclude empty placeholder posting lists
+                .filter(|(_, size)| *size > 0)
+                .collect(),
+        };
+        IndexStatistics::from_sizes(sizes.into_iter(), index.posting_count())
+    }
+
+    fn from_sizes(
+        sizes: impl Iterator<Item = (DimId, usize)>,
+        posting_count: usize,
+    ) -> IndexStatistics {
+        let mut statistics = IndexStatistics {
+            key_count: 0,
+            posting_count,
+            max_posting_list_size: 0,
+            max_posting_list_key: 0,
+            min_posting_list_size: usize::MAX,
+            min_posting_list_key: 0,
+        };
+        for (key, size) in sizes {
+            statistics.key_count += 1;
+            if size > statistics.max_posting_list_size {
+                statistics.max_posting_list_size = size;
+                statistics.max_posting_list_key = key;
+            }
+            if size < statistics.min_posting_list_size {
+                statistics.min_posting_list_size = size;
+                statistics.min_posting_list_key = key;
+            }
+        }
+        statistics
+    }
+
+    fn print(&self) {
+        println!(
+            "Index size: {} keys in {} posting lists",
+            self.key_count, self.posting_count
+        );
+        println!(
+            "Max posting list size for key {} with {} vector ids",
+            self.max_posting_list_key, self.max_posting_list_size
+        );
+        println!(
+            "Min posting list size for key {} with {} vector ids",
+            self.min_posting_list_key, self.min_posting_list_size
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::mmap_vector_storage::MmapVectorStorage;
+    use crate::snapshot::SearchSnapshot;
+    use crate::sparse_index::common::file_operations::read_json;
+    use crate::sparse_index::common::scored_candidate::{ScoredCandidate, TieBreak};
+    use crate::sparse_index::common::types::{DimId, DimWeight, RecordId};
+    use crate::sparse_index::common::vector::{SimilarityMetric, SparseVector, SparseVectorError};
+    use crate::sparse_index::immutable::inverted_index::inverted_index_mmap::InvertedIndexMmap;
+    use crate::sparse_index::immutable::inverted_index::InvertedIndex;
+    use crate::sparse_index::immutable::posting_list::PostingElement;
+    use crate::sparse_index::immutable::search_context::{PruningOrder, SearchContext, SortValues};
+    use crate::storage::{
+        DataStatistics, IdfParams, ImmutableIndexConfig, IndexStatistics, IngestOptions, LoadError,
+        PostingLayout, QueryError, QueryParams, SparseVectorStorage,
+    };
+    use crate::vector_store::HashMapVectorStore;
+    use crate::SPLADE_DATA_PATH;
+    use float_cmp::approx_eq;
+    use quickcheck::{Arbitrary, Gen};
+    use quickcheck_macros::quickcheck;
+    use std::collections::HashSet;
+    use std::path::Path;
+    use std::sync::{OnceLock, RwLock};
+    use tempfile::Builder;
+
+    const SEARCH_SNAPSHOTS_PATH: &str = "./snapshots";
+
+    fn storage() -> &'static RwLock<SparseVectorStorage> {
+        static STORAGE: OnceLock<RwLock<SparseVectorStorage>> = OnceLock::new();
+        STORAGE.get_or_init(|| {
+            eprintln!("Loading test storage...");
+            let mut storage = SparseVectorStorage::load_SPLADE_embeddings(SPLADE_DATA_PATH);
+            // build immutable index in RAM
+            storage.build_immutable_index(None);
+            RwLock::new(storage)
+        })
+    }
+
+    fn check_data_index_equivalence(storage: &SparseVectorStorage, inverted_index: &InvertedIndex) {
+        for (vector_id, vector) in storage.vectors.iter() {
+            for (index, &stored_weight) in vector.indices.iter().zip(vector.weights.iter()) {
+                let record_id = &vector_id;
+                // control data in mutable index
+                // mutable_index contains record_id for dimension index
+                assert!(storage
+                    .mutable_index
+                    .get(index)
+                    .unwrap()
+                    .contains(record_id));
+
+                // control data in immutable index
+                let mut posting_list = inverted_index.get(index).unwrap();
+                // immutable_index contains correct weight and record_id for dimension index
+                assert_eq!(
+                    posting_list
+                        .find(|element| element.record_id == *record_id)
+                        .map(|element| element.weight),
+                    Some(stored_weight)
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn validate_data_equivalence() {
+        let storage = storage().read().unwrap();
+        let immutable_index = storage.immutable_index.as_ref().unwrap();
+
+        // starting with RAM index
+        matches!(immutable_index, InvertedIndex::Ram(_));
+
+        // check data equivalence RAM index
+        check_data_index_equivalence(&storage, immutable_index);
+
+        // switch to MMAP index
+        let inverted_index_ram = match immutable_index {
+            InvertedIndex::Ram(index) => index,
+            _ => panic!("Unexpected index type"),
+        };
+
+        let tmp_dir_path = Builder::new().prefix("test_index_dir").tempdir().unwrap();
+        {
+            let inverted_index_mmap =
+                InvertedIndexMmap::convert_and_save(&inverted_index_ram, &tmp_dir_path).unwrap();
+
+            // check data equivalence after mmap save
+            let index = InvertedIndex::Mmap(inverted_index_mmap);
+            check_data_index_equivalence(&storage, &index);
+        }
+        // load mmap file
+        let inverted_index_mmap = InvertedIndexMmap::load(&tmp_dir_path).unwrap();
+
+        // check data equivalence after mmap load
+        let index = InvertedIndex::Mmap(inverted_index_mmap);
+        check_data_index_equivalence(&storage, &index);
+    }
+
+    #[test]
+    fn similarity_between_stored_vectors() {
+        let mut storage = SparseVectorStorage::new();
+        storage.add(0, SparseVector::new(vec![1, 2, 3], vec![1.0, 2.0, 3.0]));
+        storage.add(1, SparseVector::new(vec![2, 3, 4], vec![0.5, 1.0, 2.0]));
+        storage.add(3, SparseVector::new(vec![1], vec![4.0]));
+
+        let expected = storage
+            .get(0)
+            .as_ref()
+            .unwrap()
+            .dot_product(storage.get(1).unwrap());
+        assert_eq!(storage.similarity(0, 1), Some(expected));
+        assert_eq!(storage.similarity(1, 0), Some(expected));
+        assert_eq!(storage.similarity(0, 3), Some(4.0));
+
+        // id 2 is not populated, id 10 is out of bounds
+        assert_eq!(storage.similarity(0, 2), None);
+        assert_eq!(storage.similarity(10, 0), None);
+    }
+
+    #[test]
+    fn mutation_marks_immutable_index_dirty() {
+        let mut storage = SparseVectorStorage::new();
+        storage.add(0, SparseVector::new(vec![1, 2], vec![1.0, 2.0]));
+        storage.build_immutable_index(None);
+        assert!(!storage.is_immutable_index_dirty());
+
+        storage.add(1, SparseVector::new(vec![2, 3], vec![1.0, 2.0]));
+        assert!(storage.is_immutable_index_dirty());
+        // the stale index is not searched, the other queries see the new vector
+        let ids = |results: Vec<ScoredCandidate>| -> Vec<RecordId> {
+            results
+                .iter()
+                .map(|candidate| candidate.vector_id)
+                .collect()
+        };
+        let query = SparseVector::new(vec![3], vec![1.0]);
+        assert_eq!(
+            storage.query_immutable_index(10, &query),
+            Err(QueryError::StaleIndex)
+        );
+        assert_eq!(
+            storage.query_immutable_index_batch(10, std::slice::from_ref(&query)),
+            Err(QueryError::StaleIndex)
+        );
+        assert_eq!(ids(storage.query_full_scan(1, &query).unwrap()), vec![1]);
+
+        storage.build_immutable_index(None);
+        assert!(!storage.is_immutable_index_dirty());
+        assert_eq!(
+            ids(storage.query_immutable_index(10, &query).unwrap()),
+            vec![1]
+        );
+    }
+
+    #[test]
+    fn add_zero_non_finite() {
+        let mut storage = SparseVectorStorage::with_ingest_options(IngestOptions {
+            zero_non_finite: true,
+            normalize: true,
+            ..Default::default()
+        });
+        storage.add(
+            0,
+            SparseVector::new(vec![1, 2, 3], vec![f32::NAN, 3.0, f32::INFINITY]),
+        );
+        assert_eq!(
+            storage.get(0).unwrap(),
+            &SparseVector::new(vec![1, 2, 3], vec![0.0, 1.0, 0.0])
+        );
+        storage.build_immutable_index(None);
+        let query = SparseVector::new(vec![1, 2, 3], vec![1.0, 1.0, 1.0]);
+        let results = storage.query_immutable_index(1, &query).unwrap();
+        assert_eq!(results[0].score, 1.0);
+    }
+
+    #[test]
+    fn hashmap_vector_store_sparse_ids() {
+        let mut storage = SparseVectorStorage::with_vector_store(
+            Box::new(HashMapVectorStore::default()),
+            IngestOptions::default(),
+        );
+        storage.add(1_000_000, SparseVector::new(vec![1, 2], vec![3.0, 4.0]));
+        storage.add(2, SparseVector::new(vec![2, 3], vec![1.0, 1.0]));
+        storage.upsert(7, SparseVector::new(vec![3], vec![2.0]));
+        storage.delete(7);
+        assert_eq!(storage.vector_count, 2);
+        assert_eq!(
+            storage.get(1_000_000).unwrap(),
+            &SparseVector::new(vec![1, 2], vec![3.0, 4.0])
+        );
+        assert_eq!(storage.get(3), None);
+        assert_eq!(storage.norm(1_000_000), Some(5.0));
+        assert_eq!(storage.norm(7), None);
+        // no slot allocated for the ids below the highest one
+        assert!(storage.index_memory_report().vectors_bytes < 1_000_000);
+
+        let query = SparseVector::new(vec![2, 3], vec![1.0, 1.0]);
+        let expected = [(1_000_000, 4.0), (2, 2.0)];
+        let full_scan = storage.query_full_scan(10, &query).unwrap();
+        storage.build_immutable_index(None);
+        let immutable = storage.query_immutable_index(10, &query).unwrap();
+        for results in [full_scan, immutable] {
+            let results: Vec<_> = results
+                .iter()
+                .map(|candidate| (candidate.vector_id, candidate.score))
+                .collect();
+            assert_eq!(results, expected);
+        }
+
+        // saved files list the ids instead of a slot per id
+        let tmp_dir_path = Builder::new().prefix("storage_dir").tempdir().unwrap();
+        let path = tmp_dir_path.path().join("storage.json");
+        storage.save(&path).unwrap();
+        assert!(std::fs::metadata(&path).unwrap().len() < 1_000);
+        let loaded = SparseVectorStorage::load(&path).unwrap();
+        assert_eq!(loaded.vectors.id_end(), 1_000_001);
+        assert!(loaded.index_memory_report().vectors_bytes < 1_000_000);
+        for id in [2, 3, 7, 1_000_000] {
+            assert_eq!(loaded.get(id), storage.get(id));
+            assert_eq!(loaded.norm(id), storage.norm(id));
+        }
+
+        let mmap_storage = storage.save_vectors_mmap(tmp_dir_path.path()).unwrap();
+        let file_path = MmapVectorStorage::vectors_file_path(tmp_dir_path.path());
+        assert!(std::fs::metadata(file_path).unwrap().len() < 1_000);
+        assert_eq!(mmap_storage.len(), 1_000_001);
+        for id in [0, 2, 3, 7, 1_000_000] {
+            assert_eq!(
+                mmap_storage.get(id).map(|v| v.to_vector()),
+                storage.get(id).cloned()
+            );
+        }
+        assert!(mmap_storage.get(1_000_001).is_none());
+    }
+
+    #[test]
+    fn add_normalize() {
+        let mut storage = SparseVectorStorage::with_ingest_options(IngestOptions {
+            normalize: true,
+            ..Default::default()
+        });
+        storage.add(0, SparseVector::new(vec![1, 2], vec![3.0, 4.0]));
+        storage.add(1, SparseVector::new(vec![2, 3], vec![0.0, 0.0]));
+        storage.upsert(2, SparseVector::new(vec![1, 3], vec![2.0, 2.0]));
+        assert_eq!(
+            storage.get(0).unwrap(),
+            &SparseVector::new(vec![1, 2], vec![0.6, 0.8])
+        );
+        // zero vector kept as is
+        assert_eq!(
+            storage.get(1).unwrap(),
+            &SparseVector::new(vec![2, 3], vec![0.0, 0.0])
+        );
+        assert!((storage.get(2).unwrap().l2_norm() - 1.0).abs() < 1e-6);
+
+        // the immutable index scores cosine similarities
+        storage.build_immutable_index(None);
+        let query = SparseVector::new(vec![1, 2], vec![3.0, 4.0]).normalized();
+        let results = storage.query_immutable_index(1, &query).unwrap();
+        assert_eq!(results[0].vector_id, 0);
+        assert!((results[0].score - 1.0).abs() < 1e-6);
+    }
+
+    #[test]
+    fn add_flush_subnormals() {
+        let subnormal = f32::MIN_POSITIVE / 4.0;
+        let vector = SparseVector::new(vec![1, 2, 3], vec![0.5, subnormal, 2.0]);
+
+        let mut storage = SparseVectorStorage::with_ingest_options(IngestOptions {
+            flush_subnormals: true,
+            ..Default::default()
+        });
+        storage.add(0, vector.clone());
+        assert_eq!(
+            storage.get(0).unwrap(),
+            &SparseVector::new(vec![1, 3], vec![0.5, 2.0])
+        );
+        // dropped index is not indexed
+        assert!(storage.mutable_index.get(&2).is_none());
+
+        // kept as is by default
+        let mut storage = SparseVectorStorage::new();
+        storage.add(0, vector.clone());
+        assert_eq!(storage.get(0).unwrap(), &vector);
+    }
+
+    #[test]
+    fn search_grouped_by() {
+        let mut storage = SparseVectorStorage::new();
+        for id in 0..12 {
+            storage.add(id, SparseVector::new(vec![1], vec![id as f32]));
+        }
+        storage.build_immutable_index(None);
+        // three groups of four vectors, 11 has no group
+        let group_ids = [0, 1, 2, 0, 1, 2, 0, 1, 2, 0, 1];
+        let query = SparseVector::new(vec![1], vec![1.0]);
+
+        let results = storage
+            .query_immutable_index_grouped_by(5, &query, &group_ids)
+            .unwrap();
+        let ids: Vec<_> = results.iter().map(|c| c.vector_id).collect();
+        // best member of each group, each group only once
+        assert_eq!(ids, vec![10, 9, 8]);
+
+        let results = storage
+            .query_immutable_index_grouped_by(2, &query, &group_ids)
+            .unwrap();
+        let ids: Vec<_> = results.iter().map(|c| c.vector_id).collect();
+        assert_eq!(ids, vec![10, 9]);
+    }
+
+    #[test]
+    fn struct_of_arrays_layout_serves_all_queries() {
+        let mut storage = SparseVectorStorage::new();
+        for id in 0..100u32 {
+            let indices = vec![id % 7, 7 + id % 5, 20 + id % 3];
+            let weights = vec![(id % 11) as f32, 0.5 * (id % 4) as f32, (id % 9) as f32];
+            storage.add(id as usize, SparseVector::new(indices, weights));
+        }
+        let query = SparseVector::new(vec![1, 3, 8, 21], vec![1.0, 2.0, 0.5, 1.5]);
+        let group_ids: Vec<_> = (0..100).map(|id| id % 10).collect();
+        let results = |storage: &SparseVectorStorage| {
+            (
+                storage.query_immutable_index(10, &query).unwrap(),
+                storage
+                    .query_immutable_index_range(10, &query, 20..60)
+                    .unwrap(),
+                storage.query_immutable_index_detailed(10, &query).unwrap(),
+                storage
+                    .query_immutable_index_grouped_by(5, &query, &group_ids)
+                    .unwrap(),
+                storage.immutable_index_statistics(),
+            )
+        };
+
+        storage.build_immutable_index(None);
+        let expected = results(&storage);
+
+        let config = ImmutableIndexConfig {
+            posting_layout: PostingLayout::StructOfArrays,
+            ..Default::default()
+        };
+        storage.build_immutable_index_with_config(None, &config);
+        // a single index in the struct of arrays layout
+        assert!(matches!(
+            storage.immutable_index,
+            Some(InvertedIndex::Soa(_))
+        ));
+        assert_eq!(results(&storage), expected);
+
+        // the layout of the file for a mmap index
+        let tmp_dir_path = Builder::new().prefix("test_index_dir").tempdir().unwrap();
+        storage.build_immutable_index_with_config(Some(tmp_dir_path.path()), &config);
+        assert!(matches!(
+            storage.immutable_index,
+            Some(InvertedIndex::Mmap(_))
+        ));
+        assert_eq!(results(&storage), expected);
+    }
+
+    #[test]
+    fn mutable_index_pruning_matches_scoring_all_candidates() {
+        let mut storage = SparseVectorStorage::new();
+        for id in 0..200u32 {
+            let indices = vec![id % 7, 7 + id % 5, 20 + id % 3];
+            let weights = vec![(id % 11) as f32, 0.5 * (id % 4) as f32, -(id as f32) / 50.0];
+            storage.add(id as usize, SparseVector::new(indices, weights));
+        }
+        // lowered weights leave a stale bound
+        storage.upsert(3, SparseVector::new(vec![3, 8], vec![0.1, 0.1]));
+        assert_eq!(storage.mutable_index().max_weight(&3), Some(10.0));
+
+        let queries = [
+            SparseVector::new(vec![1, 3, 8], vec![1.0, 2.0, 0.5]),
+            SparseVector::new(vec![0, 9, 21], vec![0.3, 1.0, -1.0]),
+            SparseVector::new(vec![4, 22], vec![f32::NAN, 1.0]),
+            SparseVector::new(vec![50], vec![1.0]),
+        ];
+        for query in queries {
+            // scores of all the vectors sharing a dimension with the query
+            let mut expected: Vec<_> = (0..200)
+                .filter_map(|id| {
+                    let vector = storage.get(id)?;
+                    vector
+                        .indices
+                        .iter()
+                        .any(|dim| query.indices.contains(dim))
+                        .then(|| ScoredCandidate {
+                            score: query.dot_product(vector),
+                            vector_id: id,
+                        })
+                })
+                .collect();
+            expected.sort_by(|a, b| b.cmp(a));
+            for top in [1, 5, 50, 500] {
+                let results = storage.query_mutable_index(top, &query).unwrap();
+                let expected = &expected[..top.min(expected.len())];
+                assert_eq!(results.len(), expected.len());
+                for (result, expected) in results.iter().zip(expected) {
+                    assert_eq!(result.vector_id, expected.vector_id);
+                    assert_eq!(result.score.to_bits(), expected.score.to_bits());
+                }
+            }
+        }
+
+        let params = QueryParams {
+            score_threshold: Some(5.0),
+            ..QueryParams::default()
+        };
+        let query = SparseVector::new(vec![1, 3], vec![1.0, 1.0]);
+        let results = storage
+            .query_mutable_index_with_params(50, &query, &params)
+            .unwrap();
+        assert!(!results.is_empty());
+        assert!(results.iter().all(|candidate| candidate.score >= 5.0));
+    }
+
+    #[test]
+    fn posting_for_dimension_from_either_index() {
+        let mut storage = SparseVectorStorage::new();
+        storage.add(0, SparseVector::new(vec![5], vec![3.0]));
+        storage.add(2, SparseVector::new(vec![1, 5, 9], vec![0.5, 1.5, 4.0]));
+        storage.add(4, SparseVector::new(vec![1, 5], vec![1.0, 2.0]));
+        // out of id order in the mutable posting
+        storage.upsert(0, SparseVector::new(vec![1, 5], vec![1.0, 3.0]));
+
+        let expected = vec![(0, 3.0), (2, 1.5), (4, 2.0)];
+        assert_eq!(storage.posting_for_dimension(5), Some(expected.clone()));
+        assert_eq!(
+            storage.posting_for_dimension(1),
+            Some(vec![(0, 1.0), (2, 0.5), (4, 1.0)])
+        );
+        assert_eq!(storage.posting_for_dimension(3), None);
+
+        storage.build_immutable_index(None);
+        assert_eq!(storage.posting_for_dimension(5), Some(expected));
+        assert_eq!(storage.posting_for_dimension(9), Some(vec![(2, 4.0)]));
+        // empty placeholder and beyond the last dimension
+        assert_eq!(storage.posting_for_dimension(3), None);
+        assert_eq!(storage.posting_for_dimension(100), None);
+    }
+
+    #[test]
+    fn export_vocabulary_matches_mutable_index() {
+        let storage = storage().read().unwrap();
+        let tmp_dir_path = Builder::new().prefix("vocabulary_dir").tempdir().unwrap();
+        let path = tmp_dir_path.path().join("vocabulary.json");
+        storage.export_vocabulary(&path).unwrap();
+
+        let vocabulary: Vec<(DimId, usize)> = read_json(&path).unwrap();
+        assert!(vocabulary.windows(2).all(|pair| pair[0].0 < pair[1].0));
+        assert_eq!(vocabulary.len(), storage.mutable_index().map.len());
+        for (dim_id, df) in vocabulary {
+            assert_eq!(storage.mutable_index().get(&dim_id).unwrap().len(), df);
+        }
+    }
+
+    #[test]
+    fn sort_value_breaks_score_ties() {
+        let mut storage = SparseVectorStorage::new();
+        storage.add_with_sort_value(0, SparseVector::new(vec![1, 2], vec![1.0, 3.0]), 5.0);
+        // same score as 0 for the query on dimension 1, more recent
+        storage.add_with_sort_value(1, SparseVector::new(vec![1], vec![1.0]), 10.0);
+        storage.add_with_sort_value(2, SparseVector::new(vec![1], vec![2.0]), 0.0);
+        // no sort value, ranks last among equal scores
+        storage.add(3, SparseVector::new(vec![1], vec![1.0]));
+        storage.build_immutable_index(None);
+
+        let query = SparseVector::new(vec![1], vec![1.0]);
+        let ids = |results: Vec<ScoredCandidate>| -> Vec<RecordId> {
+            results.iter().map(|c| c.vector_id).collect()
+        };
+        assert_eq!(
+            ids(storage.query_immutable_index(4, &query).unwrap()),
+            vec![2, 1, 0, 3]
+        );
+        // the tie at the cut is resolved by the sort value, not the id
+        assert_eq!(
+            ids(storage.query_immutable_index(2, &query).unwrap()),
+            vec![2, 1]
+        );
+        // also across the partitions, one vector each
+        assert_eq!(
+            ids(storage
+                .query_immutable_index_partitioned(2, &query, 4)
+                .unwrap()),
+            vec![2, 1]
+        );
+        // same ranking with every search option of the loop
+        let index = storage.immutable_index.as_ref().unwrap();
+        let sort_values = storage.sort_values.as_ref().unwrap();
+        for (early_termination, pivot_pruning) in [(true, false), (false, true)] {
+            let mut search_context = SearchContext::new(&query, 2, index)
+                .with_sort_values(sort_values)
+                .with_pruning_order(PruningOrder::MaxContribution);
+            if early_termination {
+                search_context = search_context.with_early_termination();
+            }
+            if pivot_pruning {
+                search_context = search_context.with_pivot_pruning();
+            }
+            assert_eq!(ids(search_context.search()), vec![2, 1]);
+        }
+
+        // a replaced or deleted vector loses its sort value
+        storage.upsert(1, SparseVector::new(vec![1], vec![1.0]));
+        storage.delete(2);
+        storage.add(2, SparseVector::new(vec![1], vec![1.0]));
+        storage.build_immutable_index(None);
+        assert_eq!(
+            ids(storage.query_immutable_index(4, &query).unwrap()),
+            vec![0, 1, 2, 3]
+        );
+
+        // sort values of sparse ids are keyed by id like the vectors
+        let mut storage = SparseVectorStorage::with_vector_store(
+            Box::new(HashMapVectorStore::default()),
+            IngestOptions::default(),
+        );
+        storage.add_with_sort_value(2, SparseVector::new(vec![1], vec![1.0]), 0.0);
+        storage.add_with_sort_value(1_000_000, SparseVector::new(vec![1], vec![1.0]), 1.0);
+        assert!(
+            matches!(&storage.sort_values, Some(SortValues::Sparse(sort_values)) if sort_values.len() == 2)
+        );
+        storage.build_immutable_index(None);
+        assert_eq!(
+            ids(storage.query_immutable_index(2, &query).unwrap()),
+            vec![1_000_000, 2]
+        );
+        storage.delete(1_000_000);
+        assert_eq!(storage.sort_value(1_000_000), f32::NEG_INFINITY);
+    }
+
+    #[test]
+    fn search_neg_l2() {
+        let mut storage = SparseVectorStorage::new();
+        let vectors = [
+            SparseVector::new(vec![1, 2], vec![1.0, 1.0]),
+            SparseVector::new(vec![1], vec![3.0]),
+            // no common dimension with the query but a small norm
+            SparseVector::new(vec![7], vec![0.5]),
+            SparseVector::new(vec![2, 5], vec![2.0, 4.0]),
+        ];
+        for (id, vector) in vectors.iter().enumerate() {
+            storage.add(id, vector.clone());
+        }
+        storage.build_immutable_index(None);
+        storage.set_similarity_metric(SimilarityMetric::NegL2);
+
+        let query = SparseVector::new(vec![1, 2], vec![1.0, 0.5]);
+        // manual squared distances
+        let expected = vec![
+            ScoredCandidate {
+                score: -0.25,
+                vector_id: 0,
+            },
+            ScoredCandidate {
+                score: -(1.0 + 0.25 + 0.25),
+                vector_id: 2,
+            },
+            ScoredCandidate {
+                score: -(4.0 + 0.25),
+                vector_id: 1,
+            },
+            ScoredCandidate {
+                score: -(1.0 + 2.25 + 16.0),
+                vector_id: 3,
+            },
+        ];
+        assert_eq!(storage.query_full_scan(4, &query).unwrap(), expected);
+        assert_eq!(storage.query_mutable_index(4, &query).unwrap(), expected);
+        assert_eq!(
+            storage.query_immutable_index(2, &query).unwrap(),
+            expected[..2]
+        );
+    }
+
+    #[test]
+    fn statistics() {
+        let mut storage = SparseVectorStorage::new();
+        storage.add(0, SparseVector::new(vec![1, 2], vec![1.0, 2.0]));
+        storage.add(2, SparseVector::new(vec![3, 1, 7], vec![0.5, 4.0, 3.0]));
+        storage.add(3, SparseVector::new(vec![1], vec![0.25]));
+        storage.build_immutable_index(None);
+
+        assert_eq!(
+            storage.data_statistics(),
+            DataStatistics {
+                vector_count: 3,
+                max_index: 7,
+                min_index: 1,
+                max_value: 4.0,
+                min_value: 0.25,
+                max_length: 3,
+                min_length: 1,
+                avg_length: 2.0,
+            }
+        );
+        assert_eq!(
+            SparseVectorStorage::new().data_statistics(),
+            DataStatistics::default()
+        );
+        let mut empty_vectors = SparseVectorStorage::new();
+        empty_vectors.add(1, SparseVector::new(vec![], vec![]));
+        assert_eq!(
+            empty_vectors.data_statistics(),
+            DataStatistics {
+                vector_count: 1,
+                ..DataStatistics::default()
+            }
+        );
+        // negative weights only
+        let mut negative = SparseVectorStorage::new();
+        negative.add(0, SparseVector::new(vec![4], vec![-2.0]));
+        assert_eq!(negative.data_statistics().max_value, -2.0);
+
+        let immutable = storage.immutable_index_statistics();
+        assert_eq!(
+            immutable,
+            IndexStatistics {
+                key_count: 4,
+                posting_count: 8,
+                max_posting_list_size: 3,
+                max_posting_list_key: 1,
+                min_posting_list_size: 1,
+                min_posting_list_key: 2,
+            }
+        );
+        // ties on the min size depend on the hash map order
+        let mutable = storage.mutable_index_statistics();
+        assert_eq!(mutable.key_count, immutable.key_count);
+        assert_eq!(mutable.max_posting_list_key, immutable.max_posting_list_key);
+        assert_eq!(
+            mutable.min_posting_list_size,
+            immutable.min_posting_list_size
+        );
+    }
+
+    #[test]
+    fn pairwise_similarity_matches_dot_product() {
+        let mut storage = SparseVectorStorage::new();
+        // no vector with id 1
+        let vectors = [
+            Some(SparseVector::new(vec![1, 2], vec![1.0, 2.0])),
+            None,
+            Some(SparseVector::new(vec![3, 1], vec![1.0, 2.0])),
+            Some(SparseVector::new(vec![2, 3, 4], vec![0.5, 1.0, 3.0])),
+        ];
+        for (id, vector) in vectors.iter().enumerate() {
+            if let Some(vector) = vector {
+                storage.add(id, vector.clone());
+            }
+        }
+
+        let ids = [3, 0, 1, 2, 42];
+        let matrix = storage.pairwise_similarity(&ids);
+        assert_eq!(matrix.len(), ids.len());
+        for (i, a) in ids.iter().enumerate() {
+            for (j, b) in ids.iter().enumerate() {
+                let vector_a = vectors.get(*a as usize).and_then(Option::as_ref);
+                let vector_b = vectors.get(*b as usize).and_then(Option::as_ref);
+                let expected = match (vector_a, vector_b) {
+                    (Some(vector_a), Some(vector_b)) => vector_a.dot_product(vector_b),
+                    // missing or out of bounds
+                    _ => 0.0,
+                };
+                assert_eq!(matrix[i][j], expected, "{} {}", a, b);
+            }
+        }
+        assert_eq!(storage.pairwise_similarity(&[]), Vec::<Vec<f32>>::new());
+    }
+
+    #[test]
+    fn build_with_idf_matches_idf_query() {
+        let vectors = [
+            SparseVector::new(vec![1, 2], vec![1.0, 2.0]),
+            SparseVector::new(vec![1, 3], vec![2.0, 1.5]),
+            SparseVector::new(vec![1, 2, 4], vec![0.5, 1.0, 3.0]),
+            SparseVector::new(vec![1, 4], vec![3.0, 0.25]),
+            SparseVector::new(vec![5], vec![1.0]),
+        ];
+        let mut idf_storage = SparseVectorStorage::new();
+        let mut raw_storage = SparseVectorStorage::new();
+        for (id, vector) in vectors.iter().enumerate() {
+            idf_storage.add(id, vector.clone());
+            raw_storage.add(id, vector.clone());
+        }
+        let config = ImmutableIndexConfig {
+            apply_idf: true,
+            ..Default::default()
+        };
+        idf_storage.build_immutable_index_with_config(None, &config);
+        raw_storage.build_immutable_index(None);
+
+        let query = SparseVector::new(vec![1, 2, 3, 4], vec![1.0, 1.0, 0.5, 0.5]);
+        let idf_query = SparseVector::new(
+            query.indices.clone(),
+            query
+                .indices
+                .iter()
+                .zip(&query.weights)
+                .map(|(dim, weight)| {
+                    let df = vectors.iter().filter(|v| v.indices.contains(dim)).count();
+                    weight * (vectors.len() as f32 / df as f32).ln()
+                })
+                .collect(),
+        );
+        let ids =
+            |results: Vec<ScoredCandidate>| results.iter().map(|c| c.vector_id).collect::<Vec<_>>();
+        let idf_results = idf_storage.query_immutable_index(5, &query).unwrap();
+        let raw_results = raw_storage.query_immutable_index(5, &idf_query).unwrap();
+        assert_eq!(ids(idf_results), ids(raw_results));
+    }
+
+    #[test]
+    fn query_with_coverage_reports_unmatched_dims() {
+        let mut storage = SparseVectorStorage::new();
+        storage.add(0, SparseVector::new(vec![1, 2], vec![1.0, 2.0]));
+        storage.add(1, SparseVector::new(vec![2, 5], vec![0.5, 1.0]));
+        storage.build_immutable_index(None);
+
+        let query = SparseVector::new(vec![1, 3, 5, 8, 9], vec![1.0, 1.0, 1.0, 1.0, 1.0]);
+        let (results, unmatched_dims) = storage
+            .query_immutable_index_with_coverage(10, &query)
+            .unwrap();
+        assert_eq!(unmatched_dims, vec![3, 8, 9]);
+        assert_eq!(results, storage.query_immutable_index(10, &query).unwrap());
+
+        for top in [0, 1] {
+            let (results, unmatched_dims) = storage
+                .query_immutable_index_with_coverage(top, &query)
+                .unwrap();
+            assert_eq!(unmatched_dims, vec![3, 8, 9]);
+            assert_eq!(results, storage.query_immutable_index(top, &query).unwrap());
+        }
+        storage.set_similarity_metric(SimilarityMetric::NegL2);
+        let (results, unmatched_dims) = storage
+            .query_immutable_index_with_coverage(10, &query)
+            .unwrap();
+        assert_eq!(unmatched_dims, vec![3, 8, 9]);
+        assert_eq!(results, storage.query_immutable_index(10, &query).unwrap());
+    }
+
+    #[test]
+    fn idf_query_matches_build_with_idf() {
+        let vectors = [
+            SparseVector::new(vec![1, 2], vec![1.0, 2.0]),
+            SparseVector::new(vec![1, 3], vec![2.0, 1.5]),
+            SparseVector::new(vec![1, 2, 4], vec![0.5, 1.0, 3.0]),
+            SparseVector::new(vec![1, 4], vec![3.0, 0.25]),
+            SparseVector::new(vec![5], vec![1.0]),
+        ];
+        let mut idf_storage = SparseVectorStorage::new();
+        let mut raw_storage = SparseVectorStorage::new();
+        for (id, vector) in vectors.iter().enumerate() {
+            idf_storage.add(id, vector.clone());
+            raw_storage.add(id, vector.clone());
+        }
+        let config = ImmutableIndexConfig {
+            apply_idf: true,
+            ..Default::default()
+        };
+        idf_storage.build_immutable_index_with_config(None, &config);
+        raw_storage.build_immutable_index(None);
+        assert_eq!(raw_storage.document_frequency(1), 4);
+        assert_eq!(raw_storage.document_frequency(9), 0);
+
+        // dimension 9 is absent from the index
+        let query = SparseVector::new(vec![1, 2, 3, 4, 9], vec![1.0, 1.0, 0.5, 0.5, 2.0]);
+        let results = raw_storage
+            .query_immutable_index_idf(5, &query, IdfParams::default())
+            .unwrap();
+        assert!(results.iter().all(|c| c.score.is_finite()));
+        let expected = idf_storage.query_immutable_index(5, &query).unwrap();
+        assert_eq!(results.len(), expected.len());
+        for (result, expected) in results.iter().zip(&expected) {
+            assert_eq!(result.vector_id, expected.vector_id);
+            assert!(approx_eq!(f32, result.score, expected.score, ulps = 4));
+        }
+
+        // only dimension 1 is in at least 3 vectors
+        let params = IdfParams {
+            min_df: 3,
+            smooth: true,
+        };
+        let results = raw_storage
+            .query_immutable_index_idf(5, &query, params)
+            .unwrap();
+        assert_eq!(results.len(), 4);
+        assert_eq!(results[0].vector_id, 3);
+        assert!(approx_eq!(
+            f32,
+            results[0].score,
+            3.0 * 1.25f32.ln_1p(),
+            ulps = 4
+        ));
+    }
+
+    #[test]
+    fn partitioned_search_matches_single_threaded() {
+        // a local copy of part of the data, the shared storage keeps its tie-breaking
+        let shared = storage().read().unwrap();
+        let mut storage = SparseVectorStorage::new();
+        for id in 0..2_000 {
+            storage.add(id, shared.get(id as RecordId).unwrap().clone());
+        }
+        drop(shared);
+        storage.set_tie_break(TieBreak::ById);
+        storage.build_immutable_index(None);
+        let query = storage.get(0).unwrap().clone();
+
+        let ids = |results: Vec<ScoredCandidate>| -> Vec<RecordId> {
+            results.iter().map(|c| c.vector_id).collect()
+        };
+        for similarity_metric in [SimilarityMetric::Dot, SimilarityMetric::NegL2] {
+            storage.set_similarity_metric(similarity_metric);
+            for top in [1, 10, 100] {
+                let expected = ids(storage.query_immutable_index(top, &query).unwrap());
+                for num_partitions in [0, 1, 2, 3, 8, 64] {
+                    let results = storage
+                        .query_immutable_index_partitioned(top, &query, num_partitions)
+                        .unwrap();
+                    // contributions are summed in query order in every partition
+                    assert_eq!(
+                        ids(results),
+                        expected,
+                        "{:?} top {} partitions {}",
+                        similarity_metric,
+                        top,
+                        num_partitions
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn load_checked_reports_line_numbers() {
+        let tmp_dir_path = Builder::new().prefix("jsonl_dir").tempdir().unwrap();
+        let path = tmp_dir_path.path().join("vectors.jsonl");
+        let load = |content: &str| {
+            std::fs::write(&path, content).unwrap();
+            SparseVectorStorage::load_SPLADE_embeddings_checked(path.to_str().unwrap())
+        };
+
+        let storage = load("{\"1\": 0.5, \"7\": 2.0}\n\n{\"3\": 1.0}\n").unwrap();
+        assert_eq!(storage.vector_count, 2);
+        assert_eq!(storage.get(1), Some(&SparseVector::new(vec![3], vec![1.0])));
+
+        let valid = "{\"1\": 0.5}\n";
+        assert!(matches!(
+            load(&format!("{valid}{valid}{{\"1\": 0.5")),
+            Err(LoadError::Json { line: 3, .. })
+        ));
+        assert!(matches!(
+            load(&format!("{valid}[1, 2]\n")),
+            Err(LoadError::NotAnObject { line: 2 })
+        ));
+        assert!(matches!(
+            load(&format!("{{\"dim\": 0.5}}\n{valid}")),
+            Err(LoadError::InvalidKey { line: 1, key }) if key == "dim"
+        ));
+        assert!(matches!(
+            load(&format!("{valid}{valid}{{\"4\": \"high\"}}\n")),
+            Err(LoadError::InvalidWeight { line: 3, key }) if key == "4"
+        ));
+        // overflows f32
+        assert!(matches!(
+            load(&format!("{valid}{{\"2\": 1.0, \"5\": -1e300}}\n")),
+            Err(LoadError::InvalidWeight { line: 2, key }) if key == "5"
+        ));
+        assert!(matches!(
+            SparseVectorStorage::load_SPLADE_embeddings_checked("/nonexistent/vectors.jsonl"),
+            Err(LoadError::Io(_))
+        ));
+    }
+
+    #[test]
+    fn load_csr_jsonl() {
+        let tmp_dir_path = Builder::new().prefix("jsonl_dir").tempdir().unwrap();
+        let path = tmp_dir_path.path().join("vectors.jsonl");
+        let load = |content: &str| {
+            std::fs::write(&path, content).unwrap();
+            SparseVectorStorage::load_csr_jsonl_checked(path.to_str().unwrap())
+        };
+
+        std::fs::write(
+            &path,
+            "{\"indices\": [7, 1, 3], \"values\": [2.0, 0.5, 1.0]}\n\n{\"indices\": [], \"values\": []}\n",
+        )
+        .unwrap();
+        let storage = SparseVectorStorage::load_csr_jsonl(path.to_str().unwrap());
+        assert_eq!(storage.vector_count, 2);
+        assert_eq!(
+            storage.get(0),
+            Some(&SparseVector::new(vec![1, 3, 7], vec![0.5, 1.0, 2.0]))
+        );
+        assert_eq!(storage.get(1), Some(&SparseVector::new(vec![], vec![])));
+
+        let valid = "{\"indices\": [1], \"values\": [0.5]}\n";
+        assert!(matches!(
+            load(&format!(
+                "{valid}{{\"indices\": [1, 2], \"values\": [0.5]}}\n"
+            )),
+            Err(LoadError::InvalidVector {
+                line: 2,
+                source: SparseVectorError::LengthMismatch {
+                    indices: 2,
+                    weights: 1
+                }
+            })
+        ));
+        assert!(matches!(
+            load("{\"indices\": [2, 2], \"values\": [0.5, 1.0]}\n"),
+            Err(LoadError::InvalidVector {
+                line: 1,
+                source: SparseVectorError::NotStrictlyIncreasing { position: 1 }
+            })
+        ));
+        assert!(matches!(
+            load(&format!("{valid}{{\"indices\": [-1], \"values\": [0.5]}}\n")),
+            Err(LoadError::InvalidField { line: 2, field }) if field == "indices"
+        ));
+        assert!(matches!(
+            load("{\"indices\": [1]}\n"),
+            Err(LoadError::InvalidField { line: 1, field }) if field == "values"
+        ));
+        assert!(matches!(
+            load(&format!("{valid}[1, 2]\n")),
+            Err(LoadError::NotAnObject { line: 2 })
+        ));
+    }
+
+    #[test]
+    fn append_to_immutable_index_matches_build() {
+        let vector = |id: u32| {
+            SparseVector::new(
+                vec![id % 5, 5 + id % 3, 10 + id],
+                vec![(id % 7) as f32 + 0.5, 1.0, 2.0],
+            )
+        };
+        let elements = |storage: &SparseVectorStorage| -> Vec<_> {
+            // the vectors use the dimensions 0 to 39
+            (0..40)
+                .map(|dim| storage.posting_for_dimension(dim))
+                .collect()
+        };
+        let tmp_dir_path = Builder::new().prefix("test_index_dir").tempdir().unwrap();
+        for mmap_path in [None, Some(tmp_dir_path.path())] {
+            let mut storage = SparseVectorStorage::new();
+            for id in 0..20 {
+                storage.add(id as usize, vector(id));
+            }
+            storage.build_immutable_index(mmap_path);
+
+            let new_ids: Vec<RecordId> = (20..30).collect();
+            for id in &new_ids {
+                storage.add(*id as usize, vector(*id));
+            }
+            assert!(storage.is_immutable_index_dirty());
+            storage.append_to_immutable_index(&new_ids);
+            assert!(!storage.is_immutable_index_dirty());
+            assert_eq!(
+                matches!(storage.immutable_index, Some(InvertedIndex::Mmap(_))),
+                mmap_path.is_some()
+            );
+            let appended = elements(&storage);
+
+            storage.build_immutable_index(mmap_path);
+            assert_eq!(appended, elements(&storage));
+
+            // dimension 2 with the highest weight, in an appended vector
+            let query = SparseVector::new(vec![2, 6], vec![1.0, 0.5]);
+            assert_eq!(
+                storage.query_immutable_index(5, &query).unwrap()[0].vector_id,
+                27
+            );
+        }
+    }
+
+    #[test]
+    fn append_to_immutable_index_covers_all_changes() {
+        let vector = |id: u32| SparseVector::new(vec![id % 3, 3 + id], vec![1.0, 2.0]);
+        let elements = |storage: &SparseVectorStorage| -> Vec<_> {
+            (0..20)
+                .map(|dim| storage.posting_for_dimension(dim))
+                .collect()
+        };
+        let mut storage = SparseVectorStorage::new();
+        for id in 0..5 {
+            storage.add(id as usize, vector(id));
+        }
+        storage.build_immutable_index(None);
+
+        // stale until every added vector is appended, indexed ids are skipped
+        for id in 5..8 {
+            storage.add(id as usize, vector(id));
+        }
+        storage.append_to_immutable_index(&[1, 5, 6]);
+        assert!(storage.is_immutable_index_dirty());
+        storage.append_to_immutable_index(&[6, 7]);
+        assert!(!storage.is_immutable_index_dirty());
+        let appended = elements(&storage);
+        storage.build_immutable_index(None);
+        assert_eq!(appended, elements(&storage));
+
+        // a replaced or deleted vector needs a full build
+        for replace in [true, false] {
+            match replace {
+                true => storage.upsert(2, vector(12)),
+                false => storage.delete(3),
+            }
+            storage.add(8, vector(8));
+            storage.append_to_immutable_index(&[8]);
+            assert!(!storage.is_immutable_index_dirty());
+            let appended = elements(&storage);
+            storage.build_immutable_index(None);
+            assert_eq!(appended, elements(&storage));
+            storage.delete(8);
+            storage.build_immutable_index(None);
+        }
+    }
+
+    #[test]
+    fn upsert_replaces_vector_and_postings() {
+        let mut storage = SparseVectorStorage::new();
+        storage.add(0, SparseVector::new(vec![1, 2], vec![1.0, 2.0]));
+        storage.add(1, SparseVector::new(vec![2, 3], vec![1.0, 1.0]));
+
+        // new id, then replace it twice with overlapping dimensions
+        storage.upsert(3, SparseVector::new(vec![1], vec![5.0]));
+        storage.upsert(0, SparseVector::new(vec![2, 4], vec![3.0, 1.0]));
+        storage.upsert(0, SparseVector::new(vec![4, 2, 5], vec![1.0, 0.5, 2.0]));
+        assert_eq!(storage.vector_count, 3);
+        assert_eq!(
+            storage.get(0),
+            Some(&SparseVector::new(vec![4, 2, 5], vec![1.0, 0.5, 2.0]))
+        );
+        assert_eq!(storage.norm(0), Some((1.0f32 + 0.25 + 4.0).sqrt()));
+
+        let mut postings: Vec<_> = storage
+            .mutable_index()
+            .map
+            .iter()
+            .map(|(dim, ids)| {
+                let mut ids = ids.clone();
+                ids.sort_unstable();
+                (*dim, ids)
+            })
+            .collect();
+        postings.sort_unstable();
+        assert_eq!(
+            postings,
+            vec![
+                (1, vec![3]),
+                (2, vec![0, 1]),
+                (3, vec![1]),
+                (4, vec![0]),
+                (5, vec![0]),
+            ]
+        );
+
+        let query = SparseVector::new(vec![1, 2], vec![1.0, 1.0]);
+        let results = storage.query_mutable_index(10, &query).unwrap();
+        assert_eq!(
+            results
+                .iter()
+                .map(|c| (c.vector_id, c.score))
+                .collect::<Vec<_>>(),
+            vec![(3, 5.0), (1, 1.0), (0, 0.5)]
+        );
+    }
+
+    #[test]
+    fn delete_removes_vector_from_queries() {
+        let mut storage = SparseVectorStorage::new();
+        storage.add(0, SparseVector::new(vec![1, 2], vec![1.0, 2.0]));
+        storage.add(1, SparseVector::new(vec![2, 3], vec![3.0, 1.0]));
+        storage.add(2, SparseVector::new(vec![1], vec![0.5]));
+        let query = SparseVector::new(vec![1, 2, 3], vec![1.0, 1.0, 1.0]);
+        assert_eq!(
+            storage.query_mutable_index(3, &query).unwrap()[0].vector_id,
+            1
+        );
+
+        storage.delete(1);
+        storage.delete(1);
+        storage.delete(42);
+        assert_eq!(storage.get(1), None);
+        assert_eq!(storage.vector_count, 2);
+        assert_eq!(storage.norm(1), None);
+        assert!(storage.mutable_index().get(&3).is_none());
+        assert_eq!(storage.mutable_index().get(&2), Some(&vec![0]));
+
+        let ids =
+            |results: Vec<ScoredCandidate>| results.iter().map(|c| c.vector_id).collect::<Vec<_>>();
+        assert_eq!(
+            ids(storage.query_mutable_index(3, &query).unwrap()),
+            vec![0, 2]
+        );
+        assert_eq!(ids(storage.query_full_scan(3, &query).unwrap()), vec![0, 2]);
+        // the rebuilt immutable index no longer has it either
+        storage.build_immutable_index(None);
+        assert_eq!(
+            ids(storage.query_immutable_index(3, &query).unwrap()),
+            vec![0, 2]
+        );
     }
 
-    #[allow(non_snake_case)]
-    pub fn load_SPLADE_embeddings(path: &str) -> SparseVectorStorage {
-        let f = File::open(path).unwrap();
-        let reader = BufReader::new(f);
-        // steam jsonl values
-        let stream = Deserializer::from_reader(reader).into_iter::<Value>();
-
-        let mut internal_index = 0;
+    #[test]
+    fn query_detailed_match_counts() {
         let mut storage = SparseVectorStorage::new();
-
-        for value in stream {
-            let value = value.expect("Unable to parse JSON");
-            match value {
-                Value::Object(map) => {
-                    let keys_count = map.len();
-                    let mut indices = Vec::with_capacity(keys_count);
-                    let mut values = Vec::with_capacity(keys_count);
-                    for (key, value) in map {
-                        indices.push(key.parse::<u32>().unwrap());
-                        values.push(value.as_f64().unwrap() as f32);
-                    }
-                    storage.add(internal_index, SparseVector::new(indices, values));
-                    internal_index += 1;
-                }
-                _ => panic!("Unexpected value"),
-            }
+        let vectors = [
+            SparseVector::new(vec![1, 2, 3], vec![0.5, 0.5, 0.5]),
+            SparseVector::new(vec![3, 7], vec![4.0, 1.0]),
+            SparseVector::new(vec![9, 2, 3], vec![1.0, 1.0, 1.0]),
+            SparseVector::new(vec![8], vec![1.0]),
+        ];
+        for (id, vector) in vectors.iter().enumerate() {
+            storage.add(id, vector.clone());
         }
-        storage
-    }
+        storage.build_immutable_index(None);
+        let query = SparseVector::new(vec![1, 2, 3], vec![1.0, 1.0, 1.0]);
 
-    /// No upserts allowed
-    pub fn add(&mut self, vector_id: usize, sparse_vector: SparseVector) {
-        self.mutable_index
-            .add(vector_id as RecordId, &sparse_vector);
-        match self.vectors.get_mut(vector_id) {
-            Some(_current) => panic!("Vector {} already exists", vector_id),
-            None => {
-                // out of bounds, resize and insert
-                self.vectors.resize_with(vector_id + 1, || None);
-                self.vectors[vector_id] = Some(sparse_vector);
-            }
+        let results = storage.query_immutable_index_detailed(4, &query).unwrap();
+        // the strongest match has a single term
+        assert_eq!(
+            results
+                .iter()
+                .map(|(candidate, matched)| (candidate.vector_id, *matched))
+                .collect::<Vec<_>>(),
+            vec![(1, 1), (2, 2), (0, 3)]
+        );
+        for (candidate, matched) in results {
+            let vector = &vectors[candidate.vector_id as usize];
+            let intersection = query
+                .indices
+                .iter()
+                .filter(|dim| vector.indices.contains(dim))
+                .count();
+            assert_eq!(matched as usize, intersection);
+            assert_eq!(candidate.score, query.dot_product(vector));
         }
+
+        // every vector is a candidate of the full scan fallback, with its own match count
+        storage.set_similarity_metric(SimilarityMetric::NegL2);
+        let results = storage.query_immutable_index_detailed(4, &query).unwrap();
+        assert_eq!(
+            results
+                .iter()
+                .map(|(candidate, _)| candidate)
+                .collect::<Vec<_>>(),
+            storage
+                .query_immutable_index(4, &query)
+                .unwrap()
+                .iter()
+                .collect::<Vec<_>>()
+        );
+        let matched: HashSet<_> = results
+            .iter()
+            .map(|(candidate, matched)| (candidate.vector_id, *matched))
+            .collect();
+        assert_eq!(matched, HashSet::from([(0, 3), (1, 1), (2, 2), (3, 0)]));
     }
 
-    /// Build immutable index from mutable index
-    pub fn build_immutable_index(&mut self, mmap_path: Option<&Path>) {
-        let mut inverted_index_builder = InvertedIndexBuilder::new();
-        for (position, vector_ids) in self.mutable_index.map.iter() {
-            let mut posting_list_builder = PostingBuilder::new();
-            for vec_id in vector_ids {
-                // get vector from storage
-                let sparse_vector = self.get(*vec_id).as_ref().expect("Vector not found");
-                if let Some(offset) = sparse_vector.indices.iter().position(|x| x == position) {
-                    let weight = sparse_vector.weights[offset];
-                    posting_list_builder.add(*vec_id as RecordId, weight);
-                } else {
-                    panic!("Vector {} does not contain position {}", vec_id, position);
-                }
-            }
-            inverted_index_builder.add(*position, posting_list_builder.build());
+    #[test]
+    fn score_threshold_filters_all_queries() {
+        let query = SparseVector::new(vec![0, 1000, 2839, 3000], vec![1.0, 0.2, 0.9, 0.5]);
+        let top = 50;
+        let storage = storage().read().unwrap();
+        let unfiltered = storage.query_full_scan(top, &query).unwrap();
+        // a threshold between the first and the last results
+        let threshold = unfiltered[top / 2].score;
+        let expected: Vec<_> = unfiltered
+            .into_iter()
+            .filter(|candidate| candidate.score >= threshold)
+            .map(|candidate| candidate.vector_id)
+            .collect();
+        assert!(expected.len() > top / 2 && expected.len() < top);
+
+        let params = QueryParams {
+            score_threshold: Some(threshold),
+            ..QueryParams::default()
+        };
+        let results = [
+            storage.query_full_scan_with_params(top, &query, &params),
+            storage.query_mutable_index_with_params(top, &query, &params),
+            storage.query_immutable_index_with_params(top, &query, &params),
+        ];
+        for results in results {
+            let results = results.unwrap();
+            assert!(results.iter().all(|candidate| candidate.score >= threshold));
+            // equal scores are ordered by id whatever the tie break of the index search
+            let mut ids: Vec<_> = results
+                .into_iter()
+                .map(|candidate| candidate.vector_id)
+                .collect();
+            ids.sort_unstable();
+            let mut expected = expected.clone();
+            expected.sort_unstable();
+            assert_eq!(ids, expected);
         }
+        // the threshold applies to its query only
+        assert_eq!(storage.query_full_scan(top, &query).unwrap().len(), top);
+    }
 
-        // build mmap index if path is provided
-        let index = match mmap_path {
-            None => InvertedIndex::Ram(inverted_index_builder.build()),
-            Some(path) => {
-                let mmap =
-                    InvertedIndexMmap::convert_and_save(&inverted_index_builder.build(), path)
-                        .unwrap();
-                // drop and reload for testing
-                drop(mmap);
-                let mmap = InvertedIndexMmap::load(path).unwrap();
-                InvertedIndex::Mmap(mmap)
-            }
+    #[test]
+    fn blacklisted_hot_dimension_is_ignored() {
+        let hot_dim = 2839;
+        let query = SparseVector::new(vec![0, 1000, hot_dim, 3000], vec![1.0, 0.2, 0.9, 0.5]);
+        let without_hot_dim = SparseVector::new(vec![0, 1000, 3000], vec![1.0, 0.2, 0.5]);
+        let blacklist = HashSet::from([hot_dim]);
+        let params = QueryParams {
+            blacklist: Some(&blacklist),
+            ..QueryParams::default()
         };
-        self.immutable_index = Some(index);
+        let top = 20;
+        let storage = storage().read().unwrap();
+        assert_ne!(
+            storage.query_full_scan(top, &query).unwrap(),
+            storage.query_full_scan(top, &without_hot_dim).unwrap()
+        );
+        assert_eq!(
+            storage
+                .query_full_scan_with_params(top, &query, &params)
+                .unwrap(),
+            storage.query_full_scan(top, &without_hot_dim).unwrap()
+        );
+        assert_eq!(
+            storage
+                .query_mutable_index_with_params(top, &query, &params)
+                .unwrap(),
+            storage.query_mutable_index(top, &without_hot_dim).unwrap()
+        );
+        assert_eq!(
+            storage
+                .query_immutable_index_with_params(top, &query, &params)
+                .unwrap(),
+            storage
+                .query_immutable_index(top, &without_hot_dim)
+                .unwrap()
+        );
     }
 
-    /// Panics if vector_id is out of bounds
-    pub fn get(&self, vector_id: RecordId) -> &Option<SparseVector> {
-        match self.vectors.get(vector_id as usize) {
-            Some(sparse_vector) => sparse_vector,
-            None => panic!("Vector storage not allocated for {}", vector_id),
-        }
+    #[test]
+    fn pivot_pruning_scores_fewer_candidates_on_hot_query() {
+        // dimension 2839 is in most of the vectors
+        let query = SparseVector::new(vec![0, 1000, 2839, 3000], vec![1.0, 0.2, 0.9, 0.5]);
+        let top = 10;
+        let storage = storage().read().unwrap();
+        let index = storage.searchable_immutable_index().unwrap();
+        let (results, stats) = SearchContext::new(&query, top, index).search_with_stats();
+        let (pivot_results, pivot_stats) = SearchContext::new(&query, top, index)
+            .with_pivot_pruning()
+            .search_with_stats();
+        assert_eq!(pivot_results, results);
+        assert!(pivot_stats.advance_calls < stats.advance_calls);
+        assert!(pivot_stats.elements_scored < stats.elements_scored);
+
+        let params = QueryParams {
+            pivot_pruning: true,
+            ..QueryParams::default()
+        };
+        assert_eq!(
+            storage
+                .query_immutable_index_with_params(top, &query, &params)
+                .unwrap(),
+            storage.query_immutable_index(top, &query).unwrap()
+        );
     }
 
-    pub fn query_full_scan(
-        &self,
-        limit: usize,
-        query_vector: &SparseVector,
-    ) -> Vec<ScoredCandidate> {
-        let mut scored_candidates: Vec<_> = self
-            .vectors
+    #[test]
+    fn batch_query_matches_single_queries() {
+        let storage = storage().read().unwrap();
+        let queries: Vec<_> = [0, 7, 42, 100]
             .iter()
-            .enumerate()
-            .filter_map(|(id, v)| v.as_ref().map(|v| (id, v)))
-            .map(|(vector_id, vector)| {
-                // sparse dot similarity
-                let score = query_vector.dot_product(vector);
-                ScoredCandidate {
-                    score,
-                    vector_id: vector_id as RecordId,
-                }
-            })
+            .filter_map(|&id| storage.vectors.get(id as RecordId).cloned())
+            .chain([SparseVector::new(vec![], vec![])])
             .collect();
 
-        // sort by score descending
-        scored_candidates.sort_by(|a, b| OrderedFloat(b.score).cmp(&OrderedFloat(a.score)));
-        // return top n
-        scored_candidates.into_iter().take(limit).collect()
-    }
-
-    pub fn query_mutable_index(
-        &self,
-        top: usize,
-        query_vector: &SparseVector,
-    ) -> Vec<ScoredCandidate> {
-        let mut candidates = Vec::new();
-        for index in &query_vector.indices {
-            if let Some(posting) = self.mutable_index.get(index) {
-                candidates.extend_from_slice(posting);
+        for top in [1, 10, 100] {
+            let batch = storage.query_immutable_index_batch(top, &queries).unwrap();
+            assert_eq!(batch.len(), queries.len());
+            for (query, results) in queries.iter().zip(batch) {
+                let expected = storage.query_immutable_index(top, query).unwrap();
+                assert_eq!(results, expected);
             }
         }
-        // remove duplicates
-        candidates.sort();
-        candidates.dedup();
-        // score candidates
-        let mut scored_candidates: Vec<_> = candidates
-            .into_iter()
-            .map(|vector_id| {
-                let vector = self
-                    .get(vector_id)
-                    .as_ref()
-                    .expect("must be found in storage");
-                // sparse dot similarity
-                let score = query_vector.dot_product(vector);
-                ScoredCandidate { score, vector_id }
-            })
-            .collect();
-        // sort by score descending
-        scored_candidates.sort_by(|a, b| OrderedFloat(b.score).cmp(&OrderedFloat(a.score)));
-
-        // return top n
-        scored_candidates.into_iter().take(top).collect()
+        assert!(storage
+            .query_immutable_index_batch(usize::MAX, &queries)
+            .is_err());
     }
 
-    pub fn query_immutable_index(
-        &self,
-        top: usize,
-        query_vector: SparseVector,
-    ) -> Vec<ScoredCandidate> {
-        let mut search_context =
-            SearchContext::new(query_vector, top, self.immutable_index.as_ref().unwrap());
-        search_context.search()
-    }
+    #[test]
+    fn query_excluding_source_document() {
+        let mut storage = SparseVectorStorage::new();
+        let vectors = [
+            SparseVector::new(vec![1, 2], vec![1.0, 2.0]),
+            SparseVector::new(vec![1, 3], vec![2.0, 1.0]),
+            SparseVector::new(vec![2, 3], vec![1.0, 1.0]),
+            SparseVector::new(vec![2], vec![3.0]),
+            SparseVector::new(vec![4], vec![1.0]),
+        ];
+        for (id, vector) in vectors.iter().enumerate() {
+            storage.add(id, vector.clone());
+        }
+        storage.build_immutable_index(None);
 
-    pub fn print_mutable_index_statistics(&self) {
-        let mut max_posting_list_size = 0;
-        let mut max_posting_list_size_index = 0;
+        for (source, vector) in vectors.iter().enumerate() {
+            let source = source as RecordId;
+            let mut expected = storage.query_immutable_index(4, vector).unwrap();
+            assert!(expected
+                .iter()
+                .any(|candidate| candidate.vector_id == source));
+            expected.retain(|candidate| candidate.vector_id != source);
 
-        let mut min_posting_list_size = usize::MAX;
-        let mut min_posting_list_size_index = 0;
+            let results = storage
+                .query_immutable_index_excluding(4, vector, source)
+                .unwrap();
+            assert!(results
+                .iter()
+                .all(|candidate| candidate.vector_id != source));
+            assert_eq!(results[..expected.len()], expected);
+        }
+    }
 
-        for (k, v) in self.mutable_index.map.iter() {
-            let size = v.len();
-            if size > max_posting_list_size {
-                max_posting_list_size = size;
-                max_posting_list_size_index = *k;
-            }
-            if size < min_posting_list_size {
-                min_posting_list_size = size;
-                min_posting_list_size_index = *k;
-            }
+    #[test]
+    fn query_rejects_oversized_top() {
+        let mut storage = SparseVectorStorage::new();
+        for id in 0..5 {
+            storage.add(
+                id,
+                SparseVector::new(vec![1, 10 + id as u32], vec![1.0, 2.0]),
+            );
         }
-        println!("\nMutable sparse vector statistics:");
-        println!("Index size: {} keys", self.mutable_index.map.len());
-        println!(
-            "Max posting list size for key {} with {} vector ids",
-            max_posting_list_size_index, max_posting_list_size
+        storage.build_immutable_index(None);
+        storage.set_max_top(1_000);
+        let query = SparseVector::new(vec![1], vec![1.0]);
+
+        let expected = Err(QueryError::TopTooLarge {
+            top: usize::MAX,
+            max_top: 1_000,
+        });
+        assert_eq!(storage.query_full_scan(usize::MAX, &query), expected);
+        assert_eq!(storage.query_mutable_index(usize::MAX, &query), expected);
+        assert_eq!(storage.query_immutable_index(usize::MAX, &query), expected);
+        assert_eq!(
+            storage.query_immutable_index_range(usize::MAX, &query, 0..5),
+            expected
         );
-        println!(
-            "Min posting list size for key {} with {} vector ids",
-            min_posting_list_size_index, min_posting_list_size
+        assert_eq!(
+            storage.query_immutable_index_grouped_by(usize::MAX, &query, &[0; 5]),
+            expected
+        );
+
+        // allowed but larger than the number of vectors
+        assert_eq!(
+            storage.query_immutable_index(1_000, &query).unwrap().len(),
+            5
         );
+        assert!(storage.query_immutable_index(0, &query).unwrap().is_empty());
     }
 
-    pub fn print_immutable_index_statistics(&self) {
-        let mut max_posting_list_size = 0;
-        let mut max_posting_list_size_index = 0;
-
-        let mut min_posting_list_size = usize::MAX;
-        let mut min_posting_list_size_index = 0;
-
-        let index = self.immutable_index.as_ref().unwrap();
-        // stats only for ram index
-        if let InvertedIndex::Ram(index) = index {
-            let mut index_size = 0;
-            for (k, posting) in index.postings.iter().enumerate() {
-                let size = posting.elements.len();
-                // exclude empty placeholder posting lists
-                if size > 0 {
-                    index_size += 1;
-                    if size > max_posting_list_size {
-                        max_posting_list_size = size;
-                        max_posting_list_size_index = k;
-                    }
-                    if size < min_posting_list_size {
-                        min_posting_list_size = size;
-                        min_posting_list_size_index = k;
-                    }
-                }
-            }
+    #[test]
+    fn save_load_norms() {
+        let mut storage = SparseVectorStorage::new();
+        storage.add(0, SparseVector::new(vec![1, 2], vec![3.0, 4.0]));
+        storage.add(2, SparseVector::new(vec![5, 7, 9], vec![0.1, 0.2, 0.3]));
+        let tmp_dir_path = Builder::new().prefix("storage_dir").tempdir().unwrap();
+        let path = tmp_dir_path.path().join("storage.json");
+        storage.save(&path).unwrap();
 
-            println!("\nImmutable sparse vector statistics:");
-            println!("Index size: {} keys", index_size);
-            println!(
-                "Max posting list size for key {} with {} vector ids",
-                max_posting_list_size_index, max_posting_list_size
-            );
-            println!(
-                "Min posting list size for key {} with {} vector ids",
-                min_posting_list_size_index, min_posting_list_size
+        let loaded = SparseVectorStorage::load(&path).unwrap();
+        assert_eq!(loaded.norm(0), Some(5.0));
+        assert_eq!(loaded.norm(1), None);
+        for id in [0, 2] {
+            assert_eq!(loaded.get(id), storage.get(id));
+            let fresh = loaded.get(id).unwrap().l2_norm();
+            assert_eq!(loaded.norm(id), Some(fresh));
+        }
+        assert_eq!(loaded.mutable_index().get(&7), Some(&vec![2]));
+
+        // files without norms get them recomputed
+        let mut file: serde_json::Value = read_json(&path).unwrap();
+        file.as_object_mut().unwrap().remove("norms");
+        std::fs::write(&path, file.to_string()).unwrap();
+        let loaded = SparseVectorStorage::load(&path).unwrap();
+        assert_eq!(loaded.norm(0), Some(5.0));
+
+        // norms not matching their vectors are rejected
+        file["norms"] = serde_json::json!([1.0, 0.0, 2.0]);
+        std::fs::write(&path, file.to_string()).unwrap();
+        let err = SparseVectorStorage::load(&path).err().unwrap();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn capped_posting_lists_search() {
+        let mut storage = SparseVectorStorage::new();
+        // dimension 1 is hot, present in every vector
+        for id in 0..10 {
+            let weight = id as f32;
+            storage.add(
+                id,
+                SparseVector::new(vec![1, 2 + id as u32], vec![weight, 1.0]),
             );
         }
+        let config = ImmutableIndexConfig {
+            max_posting_list_len: Some(3),
+            ..Default::default()
+        };
+        storage.build_immutable_index_with_config(None, &config);
+
+        let posting = storage.posting_for_dimension(1).unwrap();
+        assert_eq!(posting, vec![(7, 7.0), (8, 8.0), (9, 9.0)]);
+
+        // top results for the hot dimension are preserved
+        let query = SparseVector::new(vec![1], vec![1.0]);
+        let results = storage.query_immutable_index(3, &query).unwrap();
+        let full_scan = storage.query_full_scan(3, &query).unwrap();
+        assert_eq!(results, full_scan);
+
+        // documents beyond the cap are not reachable through the capped dimension
+        let results = storage.query_immutable_index(5, &query).unwrap();
+        assert_eq!(results.len(), 3);
     }
 
-    pub fn print_data_statistics(&self) {
-        let mut vector_count = 0;
-
-        let mut max_index = 0;
-        let mut max_value = 0.0;
-        let mut min_index = u32::MAX;
-        let mut min_value = f32::MAX;
-        let mut max_length = 0;
-        let mut min_length = usize::MAX;
-        let mut sum_length = 0;
-        for sparse_vector in self.vectors.iter().flatten() {
-            let length = sparse_vector.indices.len();
-            if length > max_length {
-                max_length = length;
-            }
-            if length < min_length {
-                min_length = length;
-            }
-            sum_length += length;
-            for &index in &sparse_vector.indices {
-                if index > max_index {
-                    max_index = index;
-                }
-                if index < min_index {
-                    min_index = index;
-                }
-            }
-            for &value in &sparse_vector.weights {
-                if value > max_value {
-                    max_value = value;
-                }
-                if value < min_value {
-                    min_value = value;
-                }
-            }
-            vector_count += 1;
+    #[test]
+    fn estimate_index_bytes_matches_build() {
+        let mut storage = SparseVectorStorage::new();
+        for id in 0..100 {
+            let indices = (0..id as u32 % 20 + 1).map(|i| i * 7 + id as u32).collect();
+            let weights = vec![1.0; id % 20 + 1];
+            storage.add(id, SparseVector::new(indices, weights));
         }
-        println!("\nStorage statistics:");
-        println!("Data size: {} sparse vectors", vector_count);
-        println!("Max sparse index: {}", max_index);
-        println!("Min sparse index: {}", min_index);
-        println!("Max sparse value: {}", max_value);
-        println!("Min sparse value: {}", min_value);
-        println!("Max sparse vector length: {}", max_length);
-        println!("Min sparse length: {}", min_length);
-        println!(
-            "Avg sparse length: {}",
-            sum_length as f64 / vector_count as f64
+        let estimate = storage.estimate_index_bytes();
+
+        storage.build_immutable_index(None);
+        let actual = match storage.immutable_index.as_ref().unwrap() {
+            InvertedIndex::Ram(index) => index.memory_bytes(),
+            _ => panic!("Unexpected index type"),
+        };
+
+        let tolerance = actual / 20;
+        assert!(
+            estimate.abs_diff(actual) <= tolerance,
+            "estimate {} vs actual {}",
+            estimate,
+            actual
         );
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use crate::sparse_index::common::types::RecordId;
-    use crate::sparse_index::common::vector::SparseVector;
-    use crate::sparse_index::immutable::inverted_index::inverted_index_mmap::InvertedIndexMmap;
-    use crate::sparse_index::immutable::inverted_index::InvertedIndex;
-    use crate::storage::SparseVectorStorage;
-    use crate::SPLADE_DATA_PATH;
-    use float_cmp::approx_eq;
-    use quickcheck::{Arbitrary, Gen};
-    use quickcheck_macros::quickcheck;
-    use std::sync::{OnceLock, RwLock};
-    use tempfile::Builder;
+    #[test]
+    fn index_memory_report() {
+        let mut storage = SparseVectorStorage::new();
+        let empty = storage.index_memory_report();
+        assert_eq!(empty.immutable_index_bytes, 0);
+        assert_eq!(empty.immutable_index_file_bytes, 0);
+        for id in 0..100 {
+            let indices = (0..id as u32 % 20 + 1).map(|i| i * 7 + id as u32).collect();
+            let weights = vec![1.0; id % 20 + 1];
+            storage.add(id, SparseVector::new(indices, weights));
+        }
+        let nonzeros: usize = (0..100).map(|id| id % 20 + 1).sum();
+        let report = storage.index_memory_report();
+        assert!(report.vectors_bytes >= nonzeros * (size_of::<DimId>() + size_of::<DimWeight>()));
+        assert!(report.mutable_index_bytes >= nonzeros * size_of::<RecordId>());
 
-    fn storage() -> &'static RwLock<SparseVectorStorage> {
-        static STORAGE: OnceLock<RwLock<SparseVectorStorage>> = OnceLock::new();
-        STORAGE.get_or_init(|| {
-            eprintln!("Loading test storage...");
-            let mut storage = SparseVectorStorage::load_SPLADE_embeddings(SPLADE_DATA_PATH);
-            // build immutable index in RAM
-            storage.build_immutable_index(None);
-            RwLock::new(storage)
-        })
+        storage.build_immutable_index(None);
+        let report = storage.index_memory_report();
+        assert!(report.immutable_index_bytes >= nonzeros * size_of::<PostingElement>());
+        assert_eq!(report.immutable_index_file_bytes, 0);
+
+        let tmp_dir_path = Builder::new().prefix("test_index_dir").tempdir().unwrap();
+        storage.build_immutable_index(Some(tmp_dir_path.path()));
+        let report = storage.index_memory_report();
+        assert_eq!(report.immutable_index_bytes, 0);
+        let file_length =
+            std::fs::metadata(InvertedIndexMmap::index_file_path(tmp_dir_path.path()))
+                .unwrap()
+                .len();
+        assert_eq!(report.immutable_index_file_bytes, file_length);
     }
 
-    fn check_data_index_equivalence(storage: &SparseVectorStorage, inverted_index: &InvertedIndex) {
-        for (vector_id, vector) in storage.vectors.iter().enumerate() {
-            if let Some(vector) = vector {
-                for (index, &stored_weight) in vector.indices.iter().zip(vector.weights.iter()) {
-                    let record_id = &(vector_id as RecordId);
-                    // control data in mutable index
-                    // mutable_index contains record_id for dimension index
-                    assert!(storage
-                        .mutable_index
-                        .get(index)
-                        .unwrap()
-                        .contains(record_id));
-
-                    // control data in immutable index
-                    let posting_list = inverted_index.get(index).unwrap();
-                    let elem_index = posting_list
-                        .elements
-                        .binary_search_by(|elem| elem.record_id.cmp(record_id));
-                    let elem = posting_list.elements[elem_index.unwrap()];
-                    // immutable_index contains correct weight and record_id for dimension index
-                    assert_eq!(elem.weight, stored_weight);
-                }
+    #[test]
+    fn full_scan_streaming_matches_sort() {
+        let storage = storage().read().unwrap();
+        let queries = [
+            SparseVector::new(vec![0, 1000, 2839, 3000], vec![1.0, 0.2, 0.9, 0.5]),
+            SparseVector::new(vec![1012, 10434, 21517], vec![0.01, 0.01, 100.0]),
+            // absent dimension, every vector ties with a zero score
+            SparseVector::new(vec![50_000], vec![1.0]),
+        ];
+        for query in queries {
+            for limit in [0, 1, 10, 100] {
+                // collect and sort reference
+                let mut expected: Vec<_> = storage
+                    .vectors
+                    .iter()
+                    .map(|(vector_id, vector)| ScoredCandidate {
+                        score: query.dot_product(vector),
+                        vector_id,
+                    })
+                    .collect();
+                expected.sort_by(|a, b| b.cmp(a).then(a.vector_id.cmp(&b.vector_id)));
+                expected.truncate(limit);
+
+                assert_eq!(storage.query_full_scan(limit, &query).unwrap(), expected);
             }
         }
     }
 
     #[test]
-    fn validate_data_equivalence() {
+    fn search_id_range_matches_filtered_full_scan() {
         let storage = storage().read().unwrap();
-        let immutable_index = storage.immutable_index.as_ref().unwrap();
-
-        // starting with RAM index
-        matches!(immutable_index, InvertedIndex::Ram(_));
-
-        // check data equivalence RAM index
-        check_data_index_equivalence(&storage, immutable_index);
-
-        // switch to MMAP index
-        let inverted_index_ram = match immutable_index {
-            InvertedIndex::Ram(index) => index,
-            _ => panic!("Unexpected index type"),
-        };
+        let query = SparseVector::new(vec![0, 1000, 2839, 3000], vec![1.0, 0.2, 0.9, 0.5]);
+        let top = 20;
+        for id_range in [0..100, 500..1500, 1000..1001, 2000..RecordId::MAX] {
+            let results = storage
+                .query_immutable_index_range(top, &query, id_range.clone())
+                .unwrap();
 
-        let tmp_dir_path = Builder::new().prefix("test_index_dir").tempdir().unwrap();
-        {
-            let inverted_index_mmap =
-                InvertedIndexMmap::convert_and_save(&inverted_index_ram, &tmp_dir_path).unwrap();
+            let expected: Vec<_> = storage
+                .query_full_scan(storage.vectors.id_end(), &query)
+                .unwrap()
+                .into_iter()
+                .filter(|candidate| candidate.score > 0.0)
+                .filter(|candidate| id_range.contains(&candidate.vector_id))
+                .take(top)
+                .collect();
 
-            // check data equivalence after mmap save
-            let index = InvertedIndex::Mmap(inverted_index_mmap);
-            check_data_index_equivalence(&storage, &index);
+            assert_eq!(results.len(), expected.len());
+            for (result, expected) in results.iter().zip(expected) {
+                assert!(id_range.contains(&result.vector_id));
+                assert!(approx_eq!(f32, result.score, expected.score));
+            }
         }
-        // load mmap file
-        let inverted_index_mmap = InvertedIndexMmap::load(&tmp_dir_path).unwrap();
-
-        // check data equivalence after mmap load
-        let index = InvertedIndex::Mmap(inverted_index_mmap);
-        check_data_index_equivalence(&storage, &index);
     }
 
-    fn search_equivalence(top: u8, query: SparseVector) {
+    /// Checks the three search methods agree and returns the immutable index results
+    fn search_equivalence(top: usize, query: SparseVector) -> Vec<ScoredCandidate> {
         // memoized storage
         let storage = storage().read().unwrap();
 
         // results from all three search methods
-        let full_scan_results = storage.query_full_scan(top as usize, &query);
-        let mutable_index_results = storage.query_mutable_index(top as usize, &query);
-        let immutable_index_results = storage.query_immutable_index(top as usize, query);
+        let full_scan_results = storage.query_full_scan(top, &query).unwrap();
+        let mutable_index_results = storage.query_mutable_index(top, &query).unwrap();
+        let immutable_index_results = storage.query_immutable_index(top, &query).unwrap();
 
-        // The ties are not broken in any way, so the order of results may differ in terms of vector ids
+        // Equal scores are ordered by ascending id everywhere, but the backends sum the
+        // contributions in different orders: near ties may swap ids, compare the scores only
         for (((i, full), mutable), immutable) in full_scan_results
             .iter()
             .enumerate()
             .zip(mutable_index_results)
-            .zip(immutable_index_results)
+            .zip(immutable_index_results.iter())
         {
             // https://docs.rs/float-cmp/latest/float_cmp/
             assert!(
@@ -428,6 +3110,64 @@ mod tests {
                 immutable.vector_id
             );
         }
+
+        immutable_index_results
+    }
+
+    /// Fraction of the full scan top `k` ids found in the immutable index top `k`,
+    /// averaged over the queries. Tracks the recall of the approximate search modes.
+    fn recall_at_k(storage: &SparseVectorStorage, queries: &[SparseVector], k: usize) -> f32 {
+        let mut recall_sum = 0.0;
+        for query in queries {
+            // the full scan also returns the non matching vectors with a null score
+            let expected: HashSet<_> = storage
+                .query_full_scan(k, query)
+                .unwrap()
+                .iter()
+                .filter(|candidate| candidate.score > 0.0)
+                .map(|candidate| candidate.vector_id)
+                .collect();
+            if expected.is_empty() {
+                recall_sum += 1.0;
+                continue;
+            }
+            let found = storage
+                .query_immutable_index(k, query)
+                .unwrap()
+                .iter()
+                .filter(|candidate| expected.contains(&candidate.vector_id))
+                .count();
+            recall_sum += found as f32 / expected.len() as f32;
+        }
+        recall_sum / queries.len() as f32
+    }
+
+    #[test]
+    fn recall_at_k_of_exact_search() {
+        let storage = storage().read().unwrap();
+        let queries = [
+            SparseVector::new(vec![0, 1000, 2000, 3000], vec![1.0, 0.2, 0.9, 0.5]),
+            // '2839' is very hot
+            SparseVector::new(vec![0, 1000, 2839, 3000], vec![1.0, 0.2, 0.9, 0.5]),
+            // absent dimension, no result
+            SparseVector::new(vec![u32::MAX - 1], vec![1.0]),
+        ];
+        // near ties may swap ids at the boundary
+        assert!(recall_at_k(&storage, &queries, 100) >= 0.99);
+
+        // a storage missing the index results has no recall
+        let mut partial = SparseVectorStorage::new();
+        partial.add(0, SparseVector::new(vec![1, 2], vec![1.0, 1.0]));
+        partial.add(1, SparseVector::new(vec![1], vec![3.0]));
+        partial.add(2, SparseVector::new(vec![1], vec![5.0]));
+        let config = ImmutableIndexConfig {
+            max_posting_list_len: Some(1),
+            ..ImmutableIndexConfig::default()
+        };
+        partial.build_immutable_index_with_config(None, &config);
+        let query = SparseVector::new(vec![1], vec![1.0]);
+        // the capped posting list misses record 1
+        assert_eq!(recall_at_k(&partial, &[query], 2), 0.5);
     }
 
     // More runs with QUICKCHECK_TESTS=100000 cargo test --release validate_search_equivalence
@@ -437,46 +3177,126 @@ mod tests {
         if top == 0 {
             return;
         }
-        search_equivalence(top, query);
+        search_equivalence(top as usize, query);
     }
 
-    // bunch of failing cases detected by quickcheck captured for non regression
-    #[test]
-    fn search_equivalence_example_one() {
-        let top = 51;
-        let query = SparseVector::new(
-            vec![3655, 14336, 19313, 27039],
-            vec![0.01, 0.01, 100.0, 100.0],
-        );
-        search_equivalence(top, query);
-    }
+    #[quickcheck]
+    fn validate_search_wand_equivalence(top: u8, query: SparseVector) {
+        if top == 0 {
+            return;
+        }
+        let top = top as usize;
+        let storage = storage().read().unwrap();
+        let index = storage.searchable_immutable_index().unwrap();
 
-    #[test]
-    fn search_equivalence_example_two() {
-        let top = 8;
-        let query = SparseVector::new(vec![7146, 16390, 20913], vec![0.01, 100.0, 100.0]);
-        search_equivalence(top, query);
+        let expected = SearchContext::new(&query, top, index)
+            .with_tie_break(TieBreak::ById)
+            .search();
+        let wand = SearchContext::new(&query, top, index)
+            .with_tie_break(TieBreak::ById)
+            .search_wand();
+        // compare the bits as NaN scores are not equal to themselves
+        let bits = |results: Vec<ScoredCandidate>| -> Vec<(RecordId, u32)> {
+            results
+                .into_iter()
+                .map(|candidate| (candidate.vector_id, candidate.score.to_bits()))
+                .collect()
+        };
+        assert_eq!(bits(wand), bits(expected));
     }
 
-    #[test]
-    fn search_equivalence_example_three() {
-        let top = 1;
-        let query = SparseVector::new(vec![1012, 10434, 21517], vec![0.01, 0.01, 100.0]);
-        search_equivalence(top, query);
+    #[quickcheck]
+    fn validate_search_iter_matches_full_scan(query: SparseVector) {
+        let storage = storage().read().unwrap();
+        let iter_results: Vec<_> =
+            SearchContext::new(&query, 1, storage.searchable_immutable_index().unwrap())
+                .with_tie_break(TieBreak::ById)
+                .search_iter()
+                .collect();
+
+        // full scan of the matching vectors, summed in query order like `TieBreak::ById`
+        let mut expected: Vec<_> = storage
+            .vectors
+            .iter()
+            .filter(|(_, vector)| vector.indices.iter().any(|dim| query.indices.contains(dim)))
+            .map(|(vector_id, vector)| ScoredCandidate {
+                score: query.dot_product_in_order(vector),
+                vector_id,
+            })
+            .collect();
+        expected.sort_unstable_by(|a, b| b.cmp(a));
+
+        // compare the bits as NaN scores are not equal to themselves
+        let bits = |results: Vec<ScoredCandidate>| -> Vec<(RecordId, u32)> {
+            results
+                .into_iter()
+                .map(|candidate| (candidate.vector_id, candidate.score.to_bits()))
+                .collect()
+        };
+        assert_eq!(bits(iter_results), bits(expected));
     }
 
-    #[test]
-    fn search_equivalence_example_four() {
-        let top = 16;
-        let query = SparseVector::new(vec![2215, 2387, 8111], vec![100.0, 100.0, 100.0]);
-        search_equivalence(top, query);
+    // with deterministic tie-breaking the three methods must agree on the exact id order
+    #[quickcheck]
+    fn validate_search_order_with_tie_break_by_id(top: u8, query: SparseVector) {
+        if top == 0 {
+            return;
+        }
+        let top = top as usize;
+
+        let (full_scan_results, mutable_index_results, immutable_index_results) = {
+            let mut storage = storage().write().unwrap();
+            storage.set_tie_break(TieBreak::ById);
+            let results = (
+                storage.query_full_scan(top, &query).unwrap(),
+                storage.query_mutable_index(top, &query).unwrap(),
+                storage.query_immutable_index(top, &query).unwrap(),
+            );
+            // restore the relaxed default for the other tests
+            storage.set_tie_break(TieBreak::Unspecified);
+            results
+        };
+
+        // the full scan also returns non matching vectors with a null score
+        let matching_ids = |results: Vec<ScoredCandidate>| -> Vec<RecordId> {
+            results
+                .into_iter()
+                .filter(|candidate| candidate.score > 0.0)
+                .map(|candidate| candidate.vector_id)
+                .collect()
+        };
+        let full_scan_ids = matching_ids(full_scan_results);
+        assert_eq!(full_scan_ids, matching_ids(mutable_index_results));
+        assert_eq!(full_scan_ids, matching_ids(immutable_index_results));
     }
 
+    // bunch of failing cases detected by quickcheck captured for non regression
+    // add new ones with `SearchSnapshot::save` into the snapshots directory
     #[test]
-    fn search_equivalence_example_five() {
-        let top = 1;
-        let query = SparseVector::new(vec![9834, 13025, 21650], vec![0.01, 100.0, 100.0]);
-        search_equivalence(top, query);
+    fn search_equivalence_snapshots() {
+        let snapshots = SearchSnapshot::load_dir(Path::new(SEARCH_SNAPSHOTS_PATH)).unwrap();
+        assert!(!snapshots.is_empty());
+
+        for (path, snapshot) in snapshots {
+            let results = search_equivalence(snapshot.top, snapshot.query.clone());
+
+            let expected = snapshot
+                .expected
+                .unwrap_or_else(|| panic!("{:?} has no expected results", path));
+            let ids: Vec<_> = results.iter().map(|result| result.vector_id).collect();
+            let expected_ids: Vec<_> = expected.iter().map(|result| result.vector_id).collect();
+            assert_eq!(ids, expected_ids, "{:?}", path);
+            for (result, expected) in results.iter().zip(expected) {
+                assert!(
+                    approx_eq!(f32, result.score, expected.score),
+                    "{:?} id {} score: {} vs expected {}",
+                    path,
+                    result.vector_id,
+                    result.score,
+                    expected.score
+                );
+            }
+        }
     }
 
     // quickcheck arbitrary impls
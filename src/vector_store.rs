@@ -0,0 +1,218 @@
+#[cfg(feature = "parallel")]
+use crate::sparse_index::common::fixed_length_pq::FixedLengthPriorityQueue;
+#[cfg(feature = "parallel")]
+use crate::sparse_index::common::scored_candidate::ScoredCandidate;
+use crate::sparse_index::common::types::{DimId, DimWeight, RecordId};
+use crate::sparse_index::common::vector::SparseVector;
+#[cfg(feature = "parallel")]
+use rayon::prelude::*;
+use std::collections::HashMap;
+use std::mem::size_of;
+
+/// Stored vectors and their L2 norm by id, the backend of `SparseVectorStorage`
+pub trait VectorStore: Send + Sync {
+    /// `None` for the ids without a vector
+    fn get(&self, id: RecordId) -> Option<&SparseVector>;
+
+    /// L2 norm of a stored vector
+    fn norm(&self, id: RecordId) -> Option<f32>;
+
+    /// Insert or replace a vector, returns the replaced one
+    fn insert(&mut self, id: RecordId, vector: SparseVector) -> Option<SparseVector>;
+
+    /// Remove a vector, returns it if it was stored
+    fn remove(&mut self, id: RecordId) -> Option<SparseVector>;
+
+    /// One past the highest id ever stored, 0 for an empty store
+    fn id_end(&self) -> usize;
+
+    /// Stored vectors with their id, the order depends on the backend
+    fn iter(&self) -> Box<dyn Iterator<Item = (RecordId, &SparseVector)> + Send + '_>;
+
+    /// Top `limit` candidates returned by `scan` for the stored vectors, which are
+    /// scanned in parallel with the native parallel iterator of the backend
+    #[cfg(feature = "parallel")]
+    fn par_top(
+        &self,
+        limit: usize,
+        scan: &(dyn Fn(RecordId, &SparseVector) -> Option<ScoredCandidate> + Sync),
+    ) -> FixedLengthPriorityQueue<ScoredCandidate>;
+
+    /// Bytes used by the vectors and their norms
+    fn memory_bytes(&self) -> usize;
+
+    /// Whether most ids below `id_end` have no vector, the saved files then list the ids
+    /// of the vectors rather than a slot per id
+    fn is_sparse(&self) -> bool {
+        self.iter().count() * 2 < self.id_end()
+    }
+
+    /// Whether the vectors are keyed by id rather than indexed by it, the other per vector
+    /// data of the storage is then keyed by id too
+    fn is_keyed_by_id(&self) -> bool {
+        false
+    }
+}
+
+/// Per thread top-k of the scanned vectors, merged pairwise
+#[cfg(feature = "parallel")]
+fn par_top<'a>(
+    vectors: impl ParallelIterator<Item = (RecordId, &'a SparseVector)>,
+    limit: usize,
+    scan: &(dyn Fn(RecordId, &SparseVector) -> Option<ScoredCandidate> + Sync),
+) -> FixedLengthPriorityQueue<ScoredCandidate> {
+    vectors
+        .filter_map(|(id, vector)| scan(id, vector))
+        .fold(
+            || FixedLengthPriorityQueue::new(limit),
+            |mut result_queue, candidate| {
+                result_queue.push(candidate);
+                result_queue
+            },
+        )
+        .reduce(
+            || FixedLengthPriorityQueue::new(limit),
+            |mut result_queue, other| {
+                result_queue.extend(other.into_vec());
+                result_queue
+            },
+        )
+}
+
+fn vector_heap_bytes(vector: &SparseVector) -> usize {
+    vector.indices.capacity() * size_of::<DimId>()
+        + vector.weights.capacity() * size_of::<DimWeight>()
+}
+
+/// Vectors in a `Vec` indexed by id, a slot is allocated for every id below the highest one
+#[derive(Debug, Default)]
+pub struct DenseVectorStore {
+    vectors: Vec<Option<SparseVector>>,
+    norms: Vec<f32>, // 0 for missing vectors
+}
+
+impl VectorStore for DenseVectorStore {
+    fn get(&self, id: RecordId) -> Option<&SparseVector> {
+        self.vectors.get(id as usize)?.as_ref()
+    }
+
+    fn norm(&self, id: RecordId) -> Option<f32> {
+        self.get(id)?;
+        self.norms.get(id as usize).copied()
+    }
+
+    fn insert(&mut self, id: RecordId, vector: SparseVector) -> Option<SparseVector> {
+        let id = id as usize;
+        if id >= self.vectors.len() {
+            self.vectors.resize_with(id + 1, || None);
+            self.norms.resize(id + 1, 0.0);
+        }
+        self.norms[id] = vector.l2_norm();
+        self.vectors[id].replace(vector)
+    }
+
+    fn remove(&mut self, id: RecordId) -> Option<SparseVector> {
+        let previous = self.vectors.get_mut(id as usize)?.take()?;
+        self.norms[id as usize] = 0.0;
+        Some(previous)
+    }
+
+    fn id_end(&self) -> usize {
+        self.vectors.len()
+    }
+
+    fn iter(&self) -> Box<dyn Iterator<Item = (RecordId, &SparseVector)> + Send + '_> {
+        Box::new(
+            self.vectors
+                .iter()
+                .enumerate()
+                .filter_map(|(id, vector)| Some((id as RecordId, vector.as_ref()?))),
+        )
+    }
+
+    #[cfg(feature = "parallel")]
+    fn par_top(
+        &self,
+        limit: usize,
+        scan: &(dyn Fn(RecordId, &SparseVector) -> Option<ScoredCandidate> + Sync),
+    ) -> FixedLengthPriorityQueue<ScoredCandidate> {
+        let vectors = self
+            .vectors
+            .par_iter()
+            .enumerate()
+            .filter_map(|(id, vector)| Some((id as RecordId, vector.as_ref()?)));
+        par_top(vectors, limit, scan)
+    }
+
+    fn memory_bytes(&self) -> usize {
+        self.vectors.capacity() * size_of::<Option<SparseVector>>()
+            + self.norms.capacity() * size_of::<f32>()
+            + self
+                .iter()
+                .map(|(_, vector)| vector_heap_bytes(vector))
+                .sum::<usize>()
+    }
+}
+
+/// Vectors in a `HashMap` by id, for sparse id spaces where a dense store would
+/// allocate mostly empty slots. Iterates in no particular order.
+#[derive(Debug, Default)]
+pub struct HashMapVectorStore {
+    vectors: HashMap<RecordId, (SparseVector, f32)>, // vector and its norm
+    id_end: usize,
+}
+
+impl VectorStore for HashMapVectorStore {
+    fn is_keyed_by_id(&self) -> bool {
+        true
+    }
+
+    fn get(&self, id: RecordId) -> Option<&SparseVector> {
+        self.vectors.get(&id).map(|(vector, _)| vector)
+    }
+
+    fn norm(&self, id: RecordId) -> Option<f32> {
+        self.vectors.get(&id).map(|(_, norm)| *norm)
+    }
+
+    fn insert(&mut self, id: RecordId, vector: SparseVector) -> Option<SparseVector> {
+        self.id_end = self.id_end.max(id as usize + 1);
+        let norm = vector.l2_norm();
+        self.vectors
+            .insert(id, (vector, norm))
+            .map(|(previous, _)| previous)
+    }
+
+    fn remove(&mut self, id: RecordId) -> Option<SparseVector> {
+        self.vectors.remove(&id).map(|(previous, _)| previous)
+    }
+
+    fn id_end(&self) -> usize {
+        self.id_end
+    }
+
+    fn iter(&self) -> Box<dyn Iterator<Item = (RecordId, &SparseVector)> + Send + '_> {
+        Box::new(self.vectors.iter().map(|(id, (vector, _))| (*id, vector)))
+    }
+
+    #[cfg(feature = "parallel")]
+    fn par_top(
+        &self,
+        limit: usize,
+        scan: &(dyn Fn(RecordId, &SparseVector) -> Option<ScoredCandidate> + Sync),
+    ) -> FixedLengthPriorityQueue<ScoredCandidate> {
+        let vectors = self
+            .vectors
+            .par_iter()
+            .map(|(id, (vector, _))| (*id, vector));
+        par_top(vectors, limit, scan)
+    }
+
+    fn memory_bytes(&self) -> usize {
+        self.vectors.capacity() * size_of::<(RecordId, (SparseVector, f32))>()
+            + self
+                .iter()
+                .map(|(_, vector)| vector_heap_bytes(vector))
+                .sum::<usize>()
+    }
+}
@@ -0,0 +1,130 @@
+use crate::sparse_index::common::vector::SparseVector;
+use crate::storage::SparseVectorStorage;
+use serde::{Deserialize, Serialize};
+use std::fs::File;
+use std::io;
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+/// Query of a recorded workload, one JSON object per line
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub struct RecordedQuery {
+    pub top: usize,
+    pub query: SparseVector,
+}
+
+/// Appends the queries of a workload to a JSONL file to replay them later
+pub struct QueryRecorder {
+    writer: BufWriter<File>,
+}
+
+impl QueryRecorder {
+    /// Start a new workload file, an existing file is truncated
+    pub fn create(path: &Path) -> io::Result<QueryRecorder> {
+        Ok(QueryRecorder {
+            writer: BufWriter::new(File::create(path)?),
+        })
+    }
+
+    pub fn record(&mut self, top: usize, query: &SparseVector) -> io::Result<()> {
+        let recorded = RecordedQuery {
+            top,
+            query: query.clone(),
+        };
+        serde_json::to_writer(&mut self.writer, &recorded)?;
+        self.writer.write_all(b"\n")
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+/// Aggregate latencies of a replayed workload
+#[derive(Debug, Default)]
+pub struct ReplayStats {
+    /// Number of results of each query, in workload order
+    pub result_counts: Vec<usize>,
+    pub total: Duration,
+    pub min: Duration,
+    pub max: Duration,
+}
+
+impl ReplayStats {
+    pub fn mean(&self) -> Duration {
+        match self.result_counts.len() {
+            0 => Duration::ZERO,
+            count => self.total / count as u32,
+        }
+    }
+}
+
+/// Run all the queries of a recorded workload against the immutable index of `storage`
+pub fn replay(path: &Path, storage: &SparseVectorStorage) -> io::Result<ReplayStats> {
+    let mut stats = ReplayStats {
+        min: Duration::MAX,
+        ..Default::default()
+    };
+    for line in BufReader::new(File::open(path)?).lines() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let recorded: RecordedQuery = serde_json::from_str(&line)?;
+        let start = Instant::now();
+        let results = storage
+            .query_immutable_index(recorded.top, &recorded.query)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+        let elapsed = start.elapsed();
+
+        stats.result_counts.push(results.len());
+        stats.total += elapsed;
+        stats.min = stats.min.min(elapsed);
+        stats.max = stats.max.max(elapsed);
+    }
+    if stats.result_counts.is_empty() {
+        stats.min = Duration::ZERO;
+    }
+    Ok(stats)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::Builder;
+
+    #[test]
+    fn record_and_replay() {
+        let mut storage = SparseVectorStorage::new();
+        for id in 0..10 {
+            storage.add(
+                id,
+                SparseVector::new(vec![1, 10 + id as u32], vec![1.0, id as f32]),
+            );
+        }
+        storage.build_immutable_index(None);
+
+        let queries = [
+            (3, SparseVector::new(vec![1], vec![1.0])),
+            (5, SparseVector::new(vec![12, 15], vec![1.0, 2.0])),
+            (2, SparseVector::new(vec![99], vec![1.0])),
+        ];
+        let tmp_dir_path = Builder::new().prefix("workload_dir").tempdir().unwrap();
+        let path = tmp_dir_path.path().join("workload.jsonl");
+        let mut recorder = QueryRecorder::create(&path).unwrap();
+        for (top, query) in &queries {
+            recorder.record(*top, query).unwrap();
+        }
+        recorder.flush().unwrap();
+
+        let stats = replay(&path, &storage).unwrap();
+        let expected_counts: Vec<_> = queries
+            .iter()
+            .map(|(top, query)| storage.query_immutable_index(*top, query).unwrap().len())
+            .collect();
+        assert_eq!(stats.result_counts, expected_counts);
+        assert_eq!(stats.result_counts, vec![3, 2, 0]);
+        assert!(stats.min <= stats.mean() && stats.mean() <= stats.max);
+    }
+}